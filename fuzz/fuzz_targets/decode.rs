@@ -0,0 +1,10 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate crushtool;
+
+// Anything may come back from a map we didn't write, as long as it doesn't
+// panic or allocate more than the input could describe
+fuzz_target!(|data: &[u8]| {
+    let _ = crushtool::decode_crushmap(data);
+});
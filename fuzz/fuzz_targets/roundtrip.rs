@@ -0,0 +1,14 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate crushtool;
+
+use crushtool::{decode_crushmap, encode_crushmap};
+
+// Whatever decodes has to encode and decode to the same map again
+fuzz_target!(|data: &[u8]| {
+    if let Ok(crushmap) = decode_crushmap(data) {
+        let encoded = encode_crushmap(crushmap.clone()).unwrap();
+        assert_eq!(decode_crushmap(&encoded), Ok(crushmap));
+    }
+});
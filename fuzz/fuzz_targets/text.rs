@@ -0,0 +1,20 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate crushtool;
+extern crate rustc_serialize;
+
+use std::str;
+
+use rustc_serialize::json;
+
+use crushtool::{CrushMap, decode_toml, decode_yaml};
+
+// The formats decompile writes and compile reads back
+fuzz_target!(|data: &[u8]| {
+    if let Ok(text) = str::from_utf8(data) {
+        let _ = json::decode::<CrushMap>(text);
+        let _ = decode_yaml(text);
+        let _ = decode_toml(text);
+    }
+});
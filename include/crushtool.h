@@ -0,0 +1,117 @@
+#ifndef CRUSHTOOL_H
+#define CRUSHTOOL_H
+
+/* Generated by cbindgen from src/ffi.rs.  Don't edit by hand. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+/**
+ * Returned in a result slot when an indep rule could not find an item
+ * for that position.
+ */
+#define CRUSH_ITEM_NONE 2147483647
+
+/**
+ * Device weights are 16.16 fixed point.  A device with this weight is
+ * fully "in".
+ */
+#define CRUSH_WEIGHT_IN 65536
+
+/**
+ * CrushMap includes all buckets, rules, etc.
+ */
+typedef struct CrushMap CrushMap;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Decode the compiled map in the len bytes at data.  Returns NULL if it
+ * can't be decoded.
+ *
+ * # Safety
+ *
+ * data must be NULL or point to len readable bytes.
+ */
+struct CrushMap *crush_map_decode(const uint8_t *data, size_t len);
+
+/**
+ * Encode map into the len bytes at buffer.  Returns the size of the
+ * encoded map, or 0 if it can't be encoded or doesn't fit in len bytes.
+ * A NULL buffer only asks for the size, whatever len is.
+ *
+ * # Safety
+ *
+ * map must be NULL or a map from crush_map_decode that hasn't been freed.
+ * buffer must be NULL or point to len writable bytes.
+ */
+size_t crush_map_encode(const struct CrushMap *map, uint8_t *buffer, size_t len);
+
+/**
+ * Store the id of the device or bucket named name in *id.  Returns 0, or
+ * -1 if there is no such item.
+ *
+ * # Safety
+ *
+ * map must be NULL or a map from crush_map_decode that hasn't been freed.
+ * name must be NULL or a NUL terminated string, and id NULL or writable.
+ */
+int32_t crush_map_item_id(const struct CrushMap *map, const char *name, int32_t *id);
+
+/**
+ * The index of the rule named name for crush_map_do_rule, or -1 if there
+ * is no such rule
+ *
+ * # Safety
+ *
+ * map must be NULL or a map from crush_map_decode that hasn't been freed.
+ * name must be NULL or a NUL terminated string.
+ */
+int32_t crush_map_rule_id(const struct CrushMap *map, const char *name);
+
+/**
+ * Place input x with the rule at index rule, writing up to result_max
+ * devices to result.  weights holds the reweight of each of the
+ * weights_len devices, 0x10000 being fully in, or is NULL for all of them
+ * in.  Returns how many devices were written, where a position that
+ * couldn't be filled holds CRUSH_ITEM_NONE, or -1 if there is no such
+ * rule.
+ *
+ * # Safety
+ *
+ * map must be NULL or a map from crush_map_decode that hasn't been freed.
+ * result must be NULL or point to result_max writable int32_t, and is
+ * only allowed to be NULL when result_max is 0.  weights must be NULL or
+ * point to weights_len readable uint32_t.
+ */
+int32_t crush_map_do_rule(const struct CrushMap *map,
+                          int32_t rule,
+                          int32_t x,
+                          int32_t *result,
+                          size_t result_max,
+                          const uint32_t *weights,
+                          size_t weights_len);
+
+/**
+ * Release a map from crush_map_decode.  NULL is ignored.
+ *
+ * # Safety
+ *
+ * map must be NULL or a map from crush_map_decode that hasn't been freed
+ * already.  It can't be used afterwards.
+ */
+void crush_map_free(struct CrushMap *map);
+
+/**
+ * What the last call that failed on this thread went wrong with, or NULL.
+ * The string stays valid until another call fails on this thread.
+ */
+const char *crush_last_error(void);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* CRUSHTOOL_H */
@@ -0,0 +1,180 @@
+//! What changed between two maps
+//!
+//! Buckets, devices and rules are matched up by name, so a bucket that
+//! moved to another id but kept its name and items doesn't show up.  See
+//! compare_maps for what the changes do to the mappings.
+//!
+use ::{BucketTypes, CrushIndex, CrushMap, DeviceId, Rule, RuleId};
+
+fn weight(w: u32) -> String {
+    format!("{:.5}", w as f64 / 0x10000 as f64)
+}
+
+// The buckets of map by name, leaving out the shadow trees
+fn named_buckets<'a>(map: &'a CrushMap, index: &CrushIndex) -> Vec<(String, &'a BucketTypes)> {
+    map.buckets
+        .iter()
+        .filter(|b| b.bucket().is_some() && !index.is_shadow(b.id()))
+        .map(|b| (index.name_or_id(b.id().into()), b))
+        .collect()
+}
+
+// A rule's steps with the ids replaced by names
+fn describe_steps(index: &CrushIndex, rule: &Rule) -> Vec<String> {
+    rule.steps
+        .iter()
+        .map(|s| {
+            let (arg1, arg2) = s.args();
+            let arg1 = s.item()
+                .map(|item| index.name_or_id(item))
+                .unwrap_or_else(|| arg1.to_string());
+            format!("{:?} {} {}", s.op(), arg1, arg2)
+        })
+        .collect()
+}
+
+fn rule_name(map: &CrushMap, ruleno: RuleId) -> String {
+    map.rule_name_map
+        .iter()
+        .find(|n| n.0 == ruleno)
+        .map(|n| n.1.clone())
+        .unwrap_or_else(|| ruleno.to_string())
+}
+
+fn tunables(map: &CrushMap) -> Vec<(&'static str, Option<u32>)> {
+    vec![("choose_local_tries", map.choose_local_tries),
+         ("choose_local_fallback_tries", map.choose_local_fallback_tries),
+         ("choose_total_tries", map.choose_total_tries),
+         ("chooseleaf_descend_once", map.chooseleaf_descend_once),
+         ("chooseleaf_vary_r", map.chooseleaf_vary_r.map(|v| v as u32)),
+         ("straw_calc_version", map.straw_calc_version.map(|v| v as u32)),
+         ("allowed_bucket_algorithms", map.allowed_bucket_algorithms),
+         ("chooseleaf_stable", map.chooseleaf_stable.map(|v| v as u32)),
+         ("msr_descents", map.msr_descents),
+         ("msr_collision_tries", map.msr_collision_tries)]
+}
+
+/// The differences between before and after, one line each.  Lines start
+/// with + for something added, - for something removed and ~ for
+/// something changed.  Empty if the maps are the same.
+pub fn diff_maps(before: &CrushMap, after: &CrushMap) -> Vec<String> {
+    let mut lines = vec![];
+
+    let (old_index, new_index) = (CrushIndex::new(before), CrushIndex::new(after));
+    let old_buckets = named_buckets(before, &old_index);
+    let new_buckets = named_buckets(after, &new_index);
+    for &(ref bucket_name, old) in old_buckets.iter() {
+        let new = match new_buckets.iter().find(|b| b.0 == *bucket_name) {
+            Some(&(_, new)) => new,
+            None => {
+                lines.push(format!("- bucket {}", bucket_name));
+                continue;
+            }
+        };
+        let (o, n) = (old.bucket().unwrap(), new.bucket().unwrap());
+        if o.bucket_type.0 != n.bucket_type.0 {
+            lines.push(format!("~ bucket {}: type {} -> {}",
+                               bucket_name,
+                               o.bucket_type.0,
+                               n.bucket_type.0));
+        }
+        if o.alg != n.alg {
+            lines.push(format!("~ bucket {}: alg {:?} -> {:?}", bucket_name, o.alg, n.alg));
+        }
+        if o.weight != n.weight {
+            lines.push(format!("~ bucket {}: weight {} -> {}",
+                               bucket_name,
+                               weight(o.weight),
+                               weight(n.weight)));
+        }
+        let items = |index: &CrushIndex, id| -> Vec<(String, u32)> {
+            index.items_of(id).into_iter().map(|i| (index.name_or_id(i.0), i.1)).collect()
+        };
+        let (old_items, new_items) = (items(&old_index, o.id), items(&new_index, n.id));
+        for &(ref item, w) in old_items.iter() {
+            match new_items.iter().find(|i| i.0 == *item) {
+                None => lines.push(format!("~ bucket {}: - {}", bucket_name, item)),
+                Some(&(_, new_w)) if new_w != w => {
+                    lines.push(format!("~ bucket {}: {} weight {} -> {}",
+                                       bucket_name,
+                                       item,
+                                       weight(w),
+                                       weight(new_w)))
+                }
+                _ => {}
+            }
+        }
+        for &(ref item, w) in new_items.iter().filter(|n| !old_items.iter().any(|o| o.0 == n.0)) {
+            lines.push(format!("~ bucket {}: + {} weight {}", bucket_name, item, weight(w)));
+        }
+    }
+    for &(ref bucket_name, new) in new_buckets.iter() {
+        if !old_buckets.iter().any(|b| b.0 == *bucket_name) {
+            lines.push(format!("+ bucket {} weight {}",
+                               bucket_name,
+                               weight(new.bucket().unwrap().weight)));
+        }
+    }
+
+    let devices = |map: &CrushMap| -> Vec<(DeviceId, String)> {
+        map.name_map.iter().filter_map(|n| n.0.device().map(|d| (d, n.1.clone()))).collect()
+    };
+    let (old_devices, new_devices) = (devices(before), devices(after));
+    for &(id, ref device) in old_devices.iter() {
+        match new_devices.iter().find(|d| d.1 == *device) {
+            None => lines.push(format!("- device {}", device)),
+            Some(&(new_id, _)) => {
+                if new_id != id {
+                    lines.push(format!("~ device {}: id {} -> {}", device, id, new_id));
+                }
+                let (o, n) = (old_index.device_class(id), new_index.device_class(new_id));
+                if o != n {
+                    lines.push(format!("~ device {}: class {} -> {}",
+                                       device,
+                                       o.unwrap_or("none"),
+                                       n.unwrap_or("none")));
+                }
+            }
+        }
+    }
+    for &(_, ref device) in new_devices.iter() {
+        if !old_devices.iter().any(|d| d.1 == *device) {
+            lines.push(format!("+ device {}", device));
+        }
+    }
+
+    let rules = |map: &CrushMap| -> Vec<(String, Rule)> {
+        map.rules
+            .iter()
+            .enumerate()
+            .filter_map(|(i, r)| r.as_ref().map(|r| (rule_name(map, RuleId(i as i32)), r.clone())))
+            .collect()
+    };
+    let (old_rules, new_rules) = (rules(before), rules(after));
+    for &(ref rule, ref o) in old_rules.iter() {
+        match new_rules.iter().find(|r| r.0 == *rule) {
+            None => lines.push(format!("- rule {}", rule)),
+            Some(&(_, ref n)) => {
+                if o.mask != n.mask {
+                    lines.push(format!("~ rule {}: mask changed", rule));
+                }
+                if describe_steps(&old_index, o) != describe_steps(&new_index, n) {
+                    lines.push(format!("~ rule {}: steps changed", rule));
+                }
+            }
+        }
+    }
+    for &(ref rule, _) in new_rules.iter() {
+        if !old_rules.iter().any(|r| r.0 == *rule) {
+            lines.push(format!("+ rule {}", rule));
+        }
+    }
+
+    for (o, n) in tunables(before).into_iter().zip(tunables(after)) {
+        if o.1 != n.1 {
+            let value = |v: Option<u32>| v.map(|v| v.to_string()).unwrap_or("unset".to_string());
+            lines.push(format!("~ tunable {}: {} -> {}", o.0, value(o.1), value(n.1)));
+        }
+    }
+    lines
+}
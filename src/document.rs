@@ -0,0 +1,880 @@
+//! YAML and TOML
+//!
+//! A map is written out as a MapDocument: items, types, classes and rule
+//! targets are referred to by name and weights are decimal the way `ceph
+//! osd tree` shows them, so a change reads well in a pull request.  Bucket
+//! weights and the tables each algorithm keeps are worked out from the item
+//! weights again when a document is read, except for the straw lengths of
+//! straw buckets which depend on how the map was built.
+//!
+//! Anything else encodable, like a CephCrushMap or one of the reports, can
+//! be written as YAML or TOML with to_yaml and to_toml as well.
+//!
+use std::collections::BTreeMap;
+
+use rustc_serialize::{Decodable, Encodable};
+use rustc_serialize::json::{self, Json};
+use toml::{self, Value};
+use yaml_rust::{Yaml, YamlEmitter, YamlLoader};
+
+use edit::{get_bucket, put_bucket, with_items};
+use io::{update_buckets, update_rule_steps};
+use ::{Bucket, BucketAlg, BucketId, BucketTypes, ChooseArg, CrushBucketList, CrushBucketStraw,
+       CrushBucketStraw2, CrushBucketTree, CrushBucketUniform, CrushHash, CrushIndex, CrushMap,
+       CrushRuleMask, DeviceId, Item, OpCode, Rule, RuleId, RuleType, Ruleset, Step, TypeId};
+
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct DocumentType {
+    pub id: i32,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct DocumentDevice {
+    pub id: i32,
+    pub name: Option<String>,
+    pub class: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct DocumentItem {
+    /// A device or bucket name, or the id of one without a name
+    pub name: String,
+    /// Left out for a bucket weighing what its items add up to
+    pub weight: Option<f64>,
+}
+
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct DocumentBucket {
+    pub id: i32,
+    pub name: Option<String>,
+    pub type_name: String,
+    /// uniform, list, tree, straw or straw2
+    pub alg: String,
+    pub hash: String,
+    pub items: Vec<DocumentItem>,
+    /// Straw buckets only, one per item
+    pub straws: Option<Vec<u32>>,
+}
+
+/// The device class shadow of a bucket
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct DocumentShadow {
+    pub bucket: String,
+    pub class: String,
+    pub shadow: String,
+}
+
+/// A rule step the way crushtool's text format spells it.  item is the
+/// bucket a take starts from, num the count of a choose or the value of a
+/// set step and type_name the type a choose picks.  args is only there for
+/// a step written with arguments its op ignores, and is encoded as it is.
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct DocumentStep {
+    pub op: String,
+    pub item: Option<String>,
+    pub num: Option<i32>,
+    pub type_name: Option<String>,
+    pub args: Option<Vec<i32>>,
+}
+
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct DocumentRule {
+    pub id: usize,
+    pub name: Option<String>,
+    pub ruleset: u8,
+    /// replicated, raid4, erasure, msr_firstn or msr_indep
+    pub rule_type: String,
+    pub min_size: u8,
+    pub max_size: u8,
+    pub steps: Vec<DocumentStep>,
+}
+
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct DocumentChooseArg {
+    pub bucket: String,
+    pub weight_set: Vec<Vec<f64>>,
+    pub ids: Vec<i32>,
+}
+
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct DocumentChooseArgs {
+    pub id: i64,
+    pub buckets: Vec<DocumentChooseArg>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct DocumentTunables {
+    pub choose_local_tries: Option<u32>,
+    pub choose_local_fallback_tries: Option<u32>,
+    pub choose_total_tries: Option<u32>,
+    pub chooseleaf_descend_once: Option<u32>,
+    pub chooseleaf_vary_r: Option<u8>,
+    pub straw_calc_version: Option<u8>,
+    pub allowed_bucket_algorithms: Option<u32>,
+    pub chooseleaf_stable: Option<u8>,
+    pub msr_descents: Option<u32>,
+    pub msr_collision_tries: Option<u32>,
+}
+
+/// A CrushMap with names in place of ids, see the module documentation
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct MapDocument {
+    /// Ceph leaves room for more buckets and rules than the map has
+    pub max_buckets: i32,
+    pub max_rules: u32,
+    pub max_devices: i32,
+    pub tunables: DocumentTunables,
+    pub types: Vec<DocumentType>,
+    /// Device classes by id.  None for maps from before Luminous.
+    pub classes: Option<Vec<DocumentType>>,
+    pub devices: Vec<DocumentDevice>,
+    pub buckets: Vec<DocumentBucket>,
+    pub shadows: Vec<DocumentShadow>,
+    pub rules: Vec<DocumentRule>,
+    pub choose_args: Option<Vec<DocumentChooseArgs>>,
+}
+
+// CRUSH_MAX_RULES in crush.h.  A rule's ruleset is a byte, so no map holds
+// more.
+const MAX_RULES: u32 = 256;
+
+static ALGS: [(&'static str, BucketAlg); 5] = [("uniform", BucketAlg::Uniform),
+                                                ("list", BucketAlg::List),
+                                                ("tree", BucketAlg::Tree),
+                                                ("straw", BucketAlg::Straw),
+                                                ("straw2", BucketAlg::Straw2)];
+
+static RULE_TYPES: [(&'static str, RuleType); 5] = [("replicated", RuleType::Replicated),
+                                                     ("raid4", RuleType::Raid4),
+                                                     ("erasure", RuleType::Erasure),
+                                                     ("msr_firstn", RuleType::MsrFirstN),
+                                                     ("msr_indep", RuleType::MsrIndep)];
+
+static OPS: [(&'static str, OpCode); 16] =
+    [("noop", OpCode::Noop),
+     ("take", OpCode::Take),
+     ("choose_firstn", OpCode::ChooseFirstN),
+     ("choose_indep", OpCode::ChooseIndep),
+     ("emit", OpCode::Emit),
+     ("chooseleaf_firstn", OpCode::ChooseLeafFirstN),
+     ("chooseleaf_indep", OpCode::ChooseLeafIndep),
+     ("set_choose_tries", OpCode::SetChooseTries),
+     ("set_chooseleaf_tries", OpCode::SetChooseLeafTries),
+     ("set_choose_local_tries", OpCode::SetChooseLocalTries),
+     ("set_choose_local_fallback_tries", OpCode::SetChooseLocalFallbackTries),
+     ("set_chooseleaf_vary_r", OpCode::SetChooseLeafVaryR),
+     ("set_chooseleaf_stable", OpCode::SetChooseLeafStable),
+     ("set_msr_descents", OpCode::SetMSRDescents),
+     ("set_msr_collision_tries", OpCode::SetMSRCollisionTries),
+     ("choose_msr", OpCode::ChooseMSR)];
+
+fn spell<T: PartialEq + Clone>(table: &[(&'static str, T)], value: &T) -> String {
+    table.iter().find(|t| t.1 == *value).map(|t| t.0.to_string()).unwrap()
+}
+
+fn parse<T: PartialEq + Clone>(table: &[(&'static str, T)],
+                               what: &str,
+                               name: &str)
+                               -> Result<T, String> {
+    table.iter()
+        .find(|t| t.0 == name)
+        .map(|t| t.1.clone())
+        .ok_or(format!("Unknown {} {}", what, name))
+}
+
+// Five decimals like `ceph osd tree`.  That is close enough for the
+// weight to round back to the same 16.16 fixed point value.
+fn weight(w: u32) -> f64 {
+    (w as f64 / 0x10000 as f64 * 1e5).round() / 1e5
+}
+
+// Drop the last digits rustc_serialize's JSON parser gets wrong
+fn tidy(f: f64) -> f64 {
+    format!("{:.15e}", f).parse().unwrap_or(f)
+}
+
+fn fixed_point(w: f64) -> Result<u32, String> {
+    let fixed = (w * 0x10000 as f64).round();
+    if fixed < 0.0 || fixed > u32::max_value() as f64 {
+        return Err(format!("{} is out of range for a weight", w));
+    }
+    Ok(fixed as u32)
+}
+
+fn ops_with_type(op: &OpCode) -> bool {
+    match *op {
+        OpCode::ChooseFirstN |
+        OpCode::ChooseIndep |
+        OpCode::ChooseLeafFirstN |
+        OpCode::ChooseLeafIndep |
+        OpCode::ChooseMSR => true,
+        _ => false,
+    }
+}
+
+fn sorted<K: Ord + Copy, T: Clone>(mut pairs: Vec<(K, T)>) -> Vec<(K, T)> {
+    pairs.sort_by_key(|p| p.0);
+    pairs
+}
+
+impl MapDocument {
+    pub fn new(map: &CrushMap) -> MapDocument {
+        let index = CrushIndex::new(map);
+        let name = |id: Item| index.name_or_id(id);
+        let type_name =
+            |id: TypeId| index.type_name(id).map(String::from).unwrap_or_else(|| id.to_string());
+        let class_name =
+            |id: i32| index.class_name(id).map(String::from).unwrap_or_else(|| id.to_string());
+
+        let mut devices: Vec<DeviceId> = map.name_map
+            .iter()
+            .filter_map(|n| n.0.device())
+            .collect();
+        if let Some(ref class_map) = map.class_map {
+            devices.extend(class_map.iter().map(|c| c.0));
+        }
+        devices.sort();
+        devices.dedup();
+
+        let buckets = map.buckets
+            .iter()
+            .filter_map(|b| b.bucket().map(|bucket| (b, bucket)))
+            .map(|(b, bucket)| {
+                DocumentBucket {
+                    id: bucket.id.0,
+                    name: index.name(bucket.id.into()).map(String::from),
+                    type_name: type_name(bucket.bucket_type.0),
+                    alg: spell(&ALGS, &bucket.alg),
+                    hash: "rjenkins1".to_string(),
+                    items: bucket.items
+                        .iter()
+                        .zip(b.item_weights())
+                        .map(|(i, w)| {
+                            let derived = i.0
+                                .bucket()
+                                .and_then(|id| get_bucket(map, id).ok())
+                                .map(|b| b.bucket().unwrap().weight == w)
+                                .unwrap_or(false);
+                            DocumentItem {
+                                name: name(i.0),
+                                weight: if derived { None } else { Some(weight(w)) },
+                            }
+                        })
+                        .collect(),
+                    straws: match *b {
+                        BucketTypes::Straw(ref straw) => {
+                            Some(straw.item_weights.iter().map(|w| w.1).collect())
+                        }
+                        _ => None,
+                    },
+                }
+            })
+            .collect();
+
+        let shadows = map.class_bucket
+            .as_ref()
+            .map(|buckets| {
+                buckets.iter()
+                    .flat_map(|b| b.1.iter().map(move |c| (b.0, c.0, c.1)))
+                    .map(|(bucket, class, shadow)| {
+                        DocumentShadow {
+                            bucket: name(bucket.into()),
+                            class: class_name(class),
+                            shadow: name(shadow.into()),
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or(vec![]);
+
+        let rules = map.rules
+            .iter()
+            .enumerate()
+            .filter_map(|(id, r)| r.as_ref().map(|r| (id, r)))
+            .map(|(id, rule)| {
+                DocumentRule {
+                    id: id,
+                    name: map.rule_name_map
+                        .iter()
+                        .find(|n| n.0.index() == id)
+                        .map(|n| n.1.clone()),
+                    ruleset: rule.mask.ruleset.0,
+                    rule_type: spell(&RULE_TYPES, &rule.mask.rule_type),
+                    min_size: rule.mask.min_size,
+                    max_size: rule.mask.max_size,
+                    steps: rule.steps.iter().map(|s| describe_step(&index, s)).collect(),
+                }
+            })
+            .collect();
+
+        MapDocument {
+            max_buckets: map.max_buckets,
+            max_rules: map.max_rules,
+            max_devices: map.max_devices,
+            tunables: DocumentTunables {
+                choose_local_tries: map.choose_local_tries,
+                choose_local_fallback_tries: map.choose_local_fallback_tries,
+                choose_total_tries: map.choose_total_tries,
+                chooseleaf_descend_once: map.chooseleaf_descend_once,
+                chooseleaf_vary_r: map.chooseleaf_vary_r,
+                straw_calc_version: map.straw_calc_version,
+                allowed_bucket_algorithms: map.allowed_bucket_algorithms,
+                chooseleaf_stable: map.chooseleaf_stable,
+                msr_descents: map.msr_descents,
+                msr_collision_tries: map.msr_collision_tries,
+            },
+            types: map.type_map
+                .iter()
+                .map(|t| {
+                    DocumentType {
+                        id: (t.0).0,
+                        name: t.1.clone(),
+                    }
+                })
+                .collect(),
+            classes: map.class_name.as_ref().map(|names| {
+                names.iter()
+                    .map(|n| {
+                        DocumentType {
+                            id: n.0,
+                            name: n.1.clone(),
+                        }
+                    })
+                    .collect()
+            }),
+            devices: devices.into_iter()
+                .map(|id| {
+                    DocumentDevice {
+                        id: id.0,
+                        name: index.name(id.into()).map(String::from),
+                        class: map.class_map
+                            .as_ref()
+                            .and_then(|c| c.iter().find(|c| c.0 == id))
+                            .map(|c| class_name(c.1)),
+                    }
+                })
+                .collect(),
+            buckets: buckets,
+            shadows: shadows,
+            rules: rules,
+            choose_args: map.choose_args.as_ref().map(|choose_args| {
+                choose_args.iter()
+                    .map(|&(id, ref args)| {
+                        DocumentChooseArgs {
+                            id: id,
+                            buckets: args.iter()
+                                .map(|arg| {
+                                    DocumentChooseArg {
+                                        bucket: name(arg.bucket_id.into()),
+                                        weight_set: arg.weight_set
+                                            .iter()
+                                            .map(|ws| ws.iter().map(|w| weight(*w)).collect())
+                                            .collect(),
+                                        ids: arg.ids.clone(),
+                                    }
+                                })
+                                .collect(),
+                        }
+                    })
+                    .collect()
+            }),
+        }
+    }
+
+    /// The map this document describes.  Names, types, classes and rules
+    /// are listed by id in the result the way Ceph encodes them.
+    pub fn to_crushmap(&self) -> Result<CrushMap, String> {
+        let mut name_map: Vec<(Item, String)> = vec![];
+        for device in self.devices.iter() {
+            if device.id < 0 {
+                return Err(format!("Device id {} is negative", device.id));
+            }
+            if let Some(ref name) = device.name {
+                name_map.push((Item::from(device.id), name.clone()));
+            }
+        }
+        for bucket in self.buckets.iter() {
+            if bucket.id >= 0 {
+                return Err(format!("Bucket id {} isn't negative", bucket.id));
+            }
+            if let Some(ref name) = bucket.name {
+                name_map.push((Item::from(bucket.id), name.clone()));
+            }
+        }
+        let name_map = sorted(name_map);
+        for (i, n) in name_map.iter().enumerate() {
+            if name_map[..i].iter().any(|m| m.0 == n.0) {
+                return Err(format!("Item {} is listed twice", n.0));
+            }
+            if name_map[..i].iter().any(|m| m.1 == n.1) {
+                return Err(format!("{} names more than one item", n.1));
+            }
+        }
+        let item = |name: &str| -> Result<Item, String> {
+            name_map.iter()
+                .find(|n| n.1 == name)
+                .map(|n| n.0)
+                .or(name.parse::<i32>().ok().map(Item::from))
+                .ok_or(format!("No device or bucket named {}", name))
+        };
+        let type_map: Vec<(TypeId, String)> =
+            self.types.iter().map(|t| (TypeId(t.id), t.name.clone())).collect();
+        let type_id = |name: &str| -> Result<TypeId, String> {
+            type_map.iter()
+                .find(|t| t.1 == name)
+                .map(|t| t.0)
+                .or(name.parse::<i32>().ok().map(TypeId))
+                .ok_or(format!("No type named {}", name))
+        };
+        let class_name: Option<Vec<(i32, String)>> = self.classes
+            .as_ref()
+            .map(|classes| sorted(classes.iter().map(|c| (c.id, c.name.clone())).collect()));
+        let class_id = |name: &str| -> Result<i32, String> {
+            class_name.as_ref()
+                .and_then(|names| names.iter().find(|n| n.1 == name))
+                .map(|n| n.0)
+                .ok_or(format!("No device class named {}", name))
+        };
+        let bucket_id = |name: &str| -> Result<BucketId, String> {
+            item(name).and_then(|id| id.bucket().ok_or(format!("{} isn't a bucket", name)))
+        };
+
+        let mut map = CrushMap {
+            max_devices: self.max_devices,
+            type_map: type_map.clone(),
+            name_map: name_map.clone(),
+            choose_local_tries: self.tunables.choose_local_tries,
+            choose_local_fallback_tries: self.tunables.choose_local_fallback_tries,
+            choose_total_tries: self.tunables.choose_total_tries,
+            chooseleaf_descend_once: self.tunables.chooseleaf_descend_once,
+            chooseleaf_vary_r: self.tunables.chooseleaf_vary_r,
+            straw_calc_version: self.tunables.straw_calc_version,
+            allowed_bucket_algorithms: self.tunables.allowed_bucket_algorithms,
+            chooseleaf_stable: self.tunables.chooseleaf_stable,
+            msr_descents: self.tunables.msr_descents,
+            msr_collision_tries: self.tunables.msr_collision_tries,
+            class_name: class_name.clone(),
+            ..Default::default()
+        };
+
+        // An item without a weight is a bucket weighing what its own items
+        // add up to, so buckets are built once the ones they hold are
+        let mut pending: Vec<&DocumentBucket> = self.buckets.iter().collect();
+        while !pending.is_empty() {
+            let mut waiting = vec![];
+            for bucket in pending.iter() {
+                let mut items = vec![];
+                for i in bucket.items.iter() {
+                    let id = try!(item(&i.name));
+                    match (i.weight, id) {
+                        (Some(w), _) => items.push((id, try!(fixed_point(w)))),
+                        (None, Item::Device(_)) => {
+                            return Err(format!("Device {} has no weight", i.name))
+                        }
+                        (None, Item::Bucket(bucket)) => {
+                            match get_bucket(&map, bucket) {
+                                Ok(b) => items.push((id, b.bucket().unwrap().weight)),
+                                Err(_) => break,
+                            }
+                        }
+                    }
+                }
+                if items.len() < bucket.items.len() {
+                    waiting.push(*bucket);
+                    continue;
+                }
+                let type_id = try!(type_id(&bucket.type_name));
+                let straw_calc_version = self.tunables.straw_calc_version.unwrap_or(0);
+                put_bucket(&mut map,
+                           try!(make_bucket(bucket, type_id, &items, straw_calc_version)));
+            }
+            if waiting.len() == pending.len() {
+                let names: Vec<String> = waiting.iter()
+                    .map(|b| b.name.clone().unwrap_or_else(|| b.id.to_string()))
+                    .collect();
+                return Err(format!("Can't weigh the items of {}: a bucket they hold is missing \
+                                    or holds one of them",
+                                   names.join(", ")));
+            }
+            pending = waiting;
+        }
+        while map.buckets.len() < self.max_buckets as usize {
+            map.buckets.push(BucketTypes::Unknown);
+        }
+        map.max_buckets = map.buckets.len() as i32;
+
+        if self.max_rules > MAX_RULES {
+            return Err(format!("max_rules {} is more than the {} rules a map can hold",
+                               self.max_rules,
+                               MAX_RULES));
+        }
+        for (i, rule) in self.rules.iter().enumerate() {
+            if rule.id >= self.max_rules as usize {
+                return Err(format!("Rule {} is past max_rules {}", rule.id, self.max_rules));
+            }
+            if self.rules[..i].iter().any(|r| r.id == rule.id) {
+                return Err(format!("Rule {} is listed twice", rule.id));
+            }
+        }
+        for rule in self.rules.iter() {
+            let mut steps = vec![];
+            for step in rule.steps.iter() {
+                let op = try!(parse(&OPS, "step", &step.op));
+                let (arg1, arg2) = match op {
+                    OpCode::Take => {
+                        let target = try!(step.item.as_ref().ok_or(format!("Rule {} takes nothing",
+                                                                           rule.id)));
+                        (try!(item(target)).id(), 0)
+                    }
+                    ref op if ops_with_type(op) => {
+                        let t = match step.type_name {
+                            Some(ref t) => try!(type_id(t)).0,
+                            None => 0,
+                        };
+                        (step.num.unwrap_or(0), t)
+                    }
+                    _ => (step.num.unwrap_or(0), 0),
+                };
+                let (arg1, arg2) = match step.args {
+                    Some(ref args) if args.len() == 2 => (args[0], args[1]),
+                    Some(_) => return Err(format!("Rule {} has a step without two args", rule.id)),
+                    None => (arg1, arg2),
+                };
+                steps.push(Step::from_op(op, arg1, arg2));
+            }
+            while map.rules.len() <= rule.id {
+                map.rules.push(None);
+            }
+            map.rules[rule.id] = Some(Rule {
+                mask: CrushRuleMask {
+                    ruleset: Ruleset(rule.ruleset),
+                    rule_type: try!(parse(&RULE_TYPES, "rule type", &rule.rule_type)),
+                    min_size: rule.min_size,
+                    max_size: rule.max_size,
+                },
+                steps: steps,
+            });
+            if let Some(ref name) = rule.name {
+                map.rule_name_map.push((RuleId(rule.id as i32), name.clone()));
+            }
+        }
+        while map.rules.len() < self.max_rules as usize {
+            map.rules.push(None);
+        }
+        map.max_rules = map.rules.len() as u32;
+        map.rule_name_map = sorted(map.rule_name_map);
+
+        if class_name.is_some() {
+            let mut class_map = vec![];
+            for device in self.devices.iter() {
+                if let Some(ref class) = device.class {
+                    class_map.push((DeviceId(device.id), try!(class_id(class))));
+                }
+            }
+            map.class_map = Some(sorted(class_map));
+
+            let mut class_bucket: Vec<(BucketId, Vec<(i32, BucketId)>)> = vec![];
+            for shadow in self.shadows.iter() {
+                let bucket = try!(bucket_id(&shadow.bucket));
+                let entry = (try!(class_id(&shadow.class)), try!(bucket_id(&shadow.shadow)));
+                match class_bucket.iter().position(|b| b.0 == bucket) {
+                    Some(p) => class_bucket[p].1.push(entry),
+                    None => class_bucket.push((bucket, vec![entry])),
+                }
+            }
+            map.class_bucket = Some(class_bucket);
+        } else if self.devices.iter().any(|d| d.class.is_some()) || !self.shadows.is_empty() {
+            return Err("Device classes are used but none are listed".to_string());
+        }
+
+        if let Some(ref choose_args) = self.choose_args {
+            let mut all = vec![];
+            for c in choose_args.iter() {
+                let mut args = vec![];
+                for arg in c.buckets.iter() {
+                    let mut weight_set = vec![];
+                    for weights in arg.weight_set.iter() {
+                        let mut fixed = vec![];
+                        for w in weights.iter() {
+                            fixed.push(try!(fixed_point(*w)));
+                        }
+                        weight_set.push(fixed);
+                    }
+                    args.push(ChooseArg {
+                        bucket_id: try!(bucket_id(&arg.bucket)),
+                        weight_set: weight_set,
+                        ids: arg.ids.clone(),
+                    });
+                }
+                all.push((c.id, args));
+            }
+            map.choose_args = Some(all);
+        }
+
+        // Fill in the names the same way decoding a compiled map does
+        update_rule_steps(&mut map.rules, &map.name_map, &map.type_map);
+        update_buckets(&mut map.buckets, &map.name_map, &map.type_map);
+        Ok(map)
+    }
+}
+
+// A bucket holding items with the tables its algorithm keeps worked out
+fn make_bucket(bucket: &DocumentBucket,
+               type_id: TypeId,
+               items: &[(Item, u32)],
+               straw_calc_version: u8)
+               -> Result<BucketTypes, String> {
+    let alg = try!(parse(&ALGS, "bucket algorithm", &bucket.alg));
+    if bucket.hash != "rjenkins1" {
+        return Err(format!("Unknown hash {}", bucket.hash));
+    }
+    let empty = Bucket {
+        id: BucketId(bucket.id),
+        bucket_type: (type_id, None),
+        alg: alg.clone(),
+        hash: CrushHash::RJenkins1,
+        weight: 0,
+        size: 0,
+        items: vec![],
+        perm_n: 0,
+        perm: 0,
+    };
+    let template = match alg {
+        BucketAlg::Uniform => {
+            BucketTypes::Uniform(CrushBucketUniform {
+                bucket: empty,
+                item_weight: 0,
+            })
+        }
+        BucketAlg::List => {
+            BucketTypes::List(CrushBucketList {
+                bucket: empty,
+                item_weights: vec![],
+            })
+        }
+        BucketAlg::Tree => {
+            BucketTypes::Tree(CrushBucketTree {
+                bucket: empty,
+                num_nodes: 0,
+                node_weights: vec![],
+            })
+        }
+        BucketAlg::Straw => {
+            BucketTypes::Straw(CrushBucketStraw {
+                bucket: empty,
+                item_weights: vec![],
+            })
+        }
+        BucketAlg::Straw2 => {
+            BucketTypes::Straw2(CrushBucketStraw2 {
+                bucket: empty,
+                item_weights: vec![],
+            })
+        }
+    };
+    let mut b = try!(with_items(&template, items, straw_calc_version));
+    if let (&mut BucketTypes::Straw(ref mut straw), &Some(ref straws)) = (&mut b,
+                                                                        &bucket.straws) {
+        if straws.len() != items.len() {
+            return Err(format!("Bucket {} has {} items but {} straws",
+                               bucket.id,
+                               items.len(),
+                               straws.len()));
+        }
+        for (w, s) in straw.item_weights.iter_mut().zip(straws.iter()) {
+            w.1 = *s;
+        }
+    }
+    {
+        let inner = b.bucket_mut().unwrap();
+        inner.perm = inner.size;
+    }
+    Ok(b)
+}
+
+fn describe_step(index: &CrushIndex, step: &Step) -> DocumentStep {
+    let type_name =
+        |id: TypeId| index.type_name(id).map(String::from).unwrap_or_else(|| id.to_string());
+    let (item, num, step_type) = match *step.effective() {
+        Step::Take { ref item } => (Some(index.name_or_id(item.0)), None, None),
+        Step::Emit | Step::Noop => (None, None, None),
+        Step::Choose { num, ref bucket_type, .. } => {
+            (None, Some(num), Some(type_name(bucket_type.0)))
+        }
+        _ => (None, Some(step.args().0), None),
+    };
+    DocumentStep {
+        op: spell(&OPS, &step.op()),
+        item: item,
+        num: num,
+        type_name: step_type,
+        args: match *step {
+            Step::Unknown { arg1, arg2, .. } => Some(vec![arg1, arg2]),
+            _ => None,
+        },
+    }
+}
+
+fn json_to_yaml(json: &Json) -> Yaml {
+    match *json {
+        Json::I64(i) => Yaml::Integer(i),
+        Json::U64(u) => Yaml::Integer(u as i64),
+        Json::F64(f) => Yaml::Real(tidy(f).to_string()),
+        Json::String(ref s) => Yaml::String(s.clone()),
+        Json::Boolean(b) => Yaml::Boolean(b),
+        Json::Array(ref items) => Yaml::Array(items.iter().map(json_to_yaml).collect()),
+        Json::Object(ref fields) => {
+            // Unset fields are left out, as they are in TOML
+            Yaml::Hash(fields.iter()
+                .filter(|&(_, v)| *v != Json::Null)
+                .map(|(k, v)| (Yaml::String(k.clone()), json_to_yaml(v)))
+                .collect())
+        }
+        Json::Null => Yaml::Null,
+    }
+}
+
+fn yaml_to_json(yaml: &Yaml) -> Result<Json, String> {
+    Ok(match *yaml {
+        Yaml::Integer(i) => Json::I64(i),
+        Yaml::Real(ref r) => {
+            Json::F64(try!(r.parse().map_err(|_| format!("{} isn't a number", r))))
+        }
+        Yaml::String(ref s) => Json::String(s.clone()),
+        Yaml::Boolean(b) => Json::Boolean(b),
+        Yaml::Array(ref items) => {
+            let mut array = vec![];
+            for item in items.iter() {
+                array.push(try!(yaml_to_json(item)));
+            }
+            Json::Array(array)
+        }
+        Yaml::Hash(ref fields) => {
+            let mut object = BTreeMap::new();
+            for (k, v) in fields.iter() {
+                let key = match *k {
+                    Yaml::String(ref s) => s.clone(),
+                    Yaml::Integer(i) => i.to_string(),
+                    _ => return Err(format!("{:?} can't be a key", k)),
+                };
+                object.insert(key, try!(yaml_to_json(v)));
+            }
+            Json::Object(object)
+        }
+        Yaml::Null => Json::Null,
+        _ => return Err("YAML aliases aren't supported".to_string()),
+    })
+}
+
+// TOML has no null so fields that are None are left out
+fn json_to_toml(json: &Json) -> Result<Option<Value>, String> {
+    Ok(Some(match *json {
+        Json::I64(i) => Value::Integer(i),
+        Json::U64(u) => Value::Integer(u as i64),
+        Json::F64(f) => Value::Float(tidy(f)),
+        Json::String(ref s) => Value::String(s.clone()),
+        Json::Boolean(b) => Value::Boolean(b),
+        Json::Array(ref items) => {
+            let mut array = vec![];
+            for item in items.iter() {
+                array.push(try!(try!(json_to_toml(item))
+                    .ok_or("TOML arrays can't hold nothing".to_string())));
+            }
+            Value::Array(array)
+        }
+        Json::Object(ref fields) => {
+            let mut table = BTreeMap::new();
+            for (k, v) in fields.iter() {
+                if let Some(value) = try!(json_to_toml(v)) {
+                    table.insert(k.clone(), value);
+                }
+            }
+            Value::Table(table)
+        }
+        Json::Null => return Ok(None),
+    }))
+}
+
+fn toml_to_json(value: &Value) -> Json {
+    match *value {
+        Value::Integer(i) => Json::I64(i),
+        Value::Float(f) => Json::F64(f),
+        Value::String(ref s) |
+        Value::Datetime(ref s) => Json::String(s.clone()),
+        Value::Boolean(b) => Json::Boolean(b),
+        Value::Array(ref items) => Json::Array(items.iter().map(toml_to_json).collect()),
+        Value::Table(ref fields) => {
+            Json::Object(fields.iter().map(|(k, v)| (k.clone(), toml_to_json(v))).collect())
+        }
+    }
+}
+
+fn to_json<T: Encodable>(value: &T) -> Result<Json, String> {
+    let encoded = try!(json::encode(value).map_err(|e| e.to_string()));
+    Json::from_str(&encoded).map_err(|e| e.to_string())
+}
+
+fn from_json<T: Decodable>(json: Json) -> Result<T, String> {
+    T::decode(&mut json::Decoder::new(json)).map_err(|e| e.to_string())
+}
+
+/// value as a YAML document, laid out the way it would be as JSON
+pub fn to_yaml<T: Encodable>(value: &T) -> Result<String, String> {
+    let mut out = String::new();
+    try!(YamlEmitter::new(&mut out)
+        .dump(&json_to_yaml(&try!(to_json(value))))
+        .map_err(|e| format!("{:?}", e)));
+    out.push('\n');
+    Ok(out)
+}
+
+pub fn from_yaml<T: Decodable>(text: &str) -> Result<T, String> {
+    let docs = try!(YamlLoader::load_from_str(text).map_err(|e| format!("{:?}", e)));
+    let doc = try!(docs.first().ok_or("The YAML document is empty".to_string()));
+    from_json(try!(yaml_to_json(doc)))
+}
+
+/// value as a TOML document.  Only values that encode as a table, like
+/// structs, can be written.
+pub fn to_toml<T: Encodable>(value: &T) -> Result<String, String> {
+    match try!(json_to_toml(&try!(to_json(value)))) {
+        Some(table @ Value::Table(_)) => Ok(table.to_string()),
+        _ => Err("Only a table can be written as TOML".to_string()),
+    }
+}
+
+pub fn from_toml<T: Decodable>(text: &str) -> Result<T, String> {
+    let mut parser = toml::Parser::new(text);
+    match parser.parse() {
+        Some(table) => from_json(toml_to_json(&Value::Table(table))),
+        None => {
+            let errors: Vec<String> = parser.errors
+                .iter()
+                .map(|e| {
+                    let (line, col) = parser.to_linecol(e.lo);
+                    format!("{}:{}: {}", line + 1, col + 1, e.desc)
+                })
+                .collect();
+            Err(errors.join(", "))
+        }
+    }
+}
+
+/// map as a MapDocument in YAML
+pub fn encode_yaml(map: &CrushMap) -> Result<String, String> {
+    to_yaml(&MapDocument::new(map))
+}
+
+pub fn decode_yaml(text: &str) -> Result<CrushMap, String> {
+    from_yaml::<MapDocument>(text).and_then(|doc| doc.to_crushmap())
+}
+
+/// map as a MapDocument in TOML
+pub fn encode_toml(map: &CrushMap) -> Result<String, String> {
+    to_toml(&MapDocument::new(map))
+}
+
+pub fn decode_toml(text: &str) -> Result<CrushMap, String> {
+    from_toml::<MapDocument>(text).and_then(|doc| doc.to_crushmap())
+}
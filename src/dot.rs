@@ -0,0 +1,193 @@
+//! Graphviz rendering of the hierarchy
+//!
+//! Buckets are drawn as boxes labelled with their name, type, algorithm and
+//! weight and devices as ellipses beneath them, with an edge for every item
+//! of a bucket.  `dot -Tsvg` turns the output into a picture.
+//!
+use std::collections::HashSet;
+
+use statistics::rule_layout;
+use ::{Bucket, BucketId, CrushIndex, CrushMap, DeviceId, Item, RuleId, TypeId};
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DotOptions {
+    /// Draw the items of each type in a cluster of their own
+    pub cluster_by_type: bool,
+    /// Highlight the buckets this rule takes and the failure domains beneath
+    /// them, the items of the type its first choose step picks
+    pub rule: Option<RuleId>,
+    /// Draw the device class shadow trees as well
+    pub shadow_buckets: bool,
+}
+
+struct Node {
+    id: Item,
+    item_type: TypeId,
+    label: String,
+}
+
+// Labels break lines with \n so only the quotes are escaped
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\\\""))
+}
+
+fn weight(w: u32) -> String {
+    format!("{:.3}", w as f64 / 0x10000 as f64)
+}
+
+fn type_name(index: &CrushIndex, type_id: TypeId) -> String {
+    index.type_name(type_id).map(String::from).unwrap_or_else(|| format!("type {}", type_id))
+}
+
+// (shadow bucket, the bucket it shadows)
+fn shadow_buckets(map: &CrushMap) -> Vec<(BucketId, BucketId)> {
+    map.class_bucket
+        .as_ref()
+        .map(|buckets| {
+            buckets.iter()
+                .flat_map(|b| b.1.iter().map(move |c| (c.1, b.0)))
+                .collect()
+        })
+        .unwrap_or(vec![])
+}
+
+fn item_type(index: &CrushIndex, id: Item) -> TypeId {
+    match id {
+        Item::Device(_) => TypeId(0),
+        Item::Bucket(bucket) => {
+            index.bucket_by_id(bucket)
+                .and_then(|b| b.bucket())
+                .map(|b| b.bucket_type.0)
+                .unwrap_or(TypeId(0))
+        }
+    }
+}
+
+// The items of domain_type beneath item, stopping at the first one on
+// each path like the mapper does
+fn domains_under(index: &CrushIndex, item: Item, domain_type: TypeId, out: &mut Vec<Item>) {
+    let mut seen = HashSet::new();
+    let mut stack = vec![item];
+    while let Some(item) = stack.pop() {
+        if item_type(index, item) == domain_type {
+            if !out.contains(&item) {
+                out.push(item);
+            }
+            continue;
+        }
+        if let Item::Bucket(bucket) = item {
+            if seen.insert(bucket) {
+                stack.extend(index.children_of(bucket).into_iter().rev());
+            }
+        }
+    }
+}
+
+fn bucket_label(index: &CrushIndex, bucket: &Bucket) -> String {
+    format!("{}\\n{} {}\\n{}",
+            index.name_or_id(bucket.id.into()),
+            bucket.bucket_type.1.clone().unwrap_or_else(|| type_name(index, bucket.bucket_type.0)),
+            format!("{:?}", bucket.alg).to_lowercase(),
+            weight(bucket.weight))
+}
+
+fn device_label(index: &CrushIndex, device: DeviceId, device_weight: u32) -> String {
+    let class = index.device_class(device).map(|c| format!("{} ", c)).unwrap_or_default();
+    format!("{}\\n{}{}", index.name_or_id(device.into()), class, weight(device_weight))
+}
+
+/// Render map as a Graphviz digraph
+pub fn to_dot(map: &CrushMap, options: &DotOptions) -> String {
+    let index = CrushIndex::new(map);
+    let shadows = shadow_buckets(map);
+    let buckets: Vec<&Bucket> = map.buckets
+        .iter()
+        .filter_map(|b| b.bucket())
+        .filter(|b| options.shadow_buckets || !index.is_shadow(b.id))
+        .collect();
+
+    let mut nodes: Vec<Node> = buckets.iter()
+        .map(|b| {
+            Node {
+                id: b.id.into(),
+                item_type: b.bucket_type.0,
+                label: bucket_label(&index, b),
+            }
+        })
+        .collect();
+    let mut edges = vec![];
+    for bucket in buckets.iter() {
+        for (item, item_weight) in index.items_of(bucket.id) {
+            if let Item::Device(device) = item {
+                if !nodes.iter().any(|n| n.id == item) {
+                    nodes.push(Node {
+                        id: item,
+                        item_type: TypeId(0),
+                        label: device_label(&index, device, item_weight),
+                    });
+                }
+            }
+            edges.push((bucket.id, item));
+        }
+    }
+
+    // Without the shadow trees a rule taking one is drawn on the bucket
+    // it shadows
+    let mut roots = vec![];
+    let mut domains = vec![];
+    if let Some(ruleno) = options.rule {
+        let (takes, domain_types) = rule_layout(map, ruleno);
+        for take in takes {
+            roots.push(match shadows.iter().find(|s| Item::Bucket(s.0) == take) {
+                Some(s) if !options.shadow_buckets => s.1.into(),
+                _ => take,
+            });
+        }
+        if let Some(domain_type) = domain_types.first() {
+            for root in roots.iter() {
+                domains_under(&index, *root, *domain_type, &mut domains);
+            }
+        }
+    }
+    let node_line = |node: &Node| {
+        let mut attrs = vec![format!("label={}", quote(&node.label))];
+        if node.id.is_device() {
+            attrs.push("shape=ellipse".to_string());
+        }
+        if roots.contains(&node.id) {
+            attrs.push("color=red".to_string());
+            attrs.push("penwidth=3".to_string());
+        }
+        if domains.contains(&node.id) {
+            attrs.push("style=filled".to_string());
+            attrs.push("fillcolor=lightblue".to_string());
+        }
+        format!("{} [{}];\n", quote(&node.id.to_string()), attrs.join(", "))
+    };
+
+    let mut dot = String::from("digraph crushmap {\n");
+    dot.push_str("  node [shape=box];\n");
+    if options.cluster_by_type {
+        let mut types: Vec<TypeId> = nodes.iter().map(|n| n.item_type).collect();
+        types.sort();
+        types.dedup();
+        // Roots at the top
+        for t in types.into_iter().rev() {
+            dot.push_str(&format!("  subgraph {} {{\n", quote(&format!("cluster_{}", t))));
+            dot.push_str(&format!("    label={};\n", quote(&type_name(&index, t))));
+            for node in nodes.iter().filter(|n| n.item_type == t) {
+                dot.push_str(&format!("    {}", node_line(node)));
+            }
+            dot.push_str("  }\n");
+        }
+    } else {
+        for node in nodes.iter() {
+            dot.push_str(&format!("  {}", node_line(node)));
+        }
+    }
+    for &(from, to) in edges.iter() {
+        dot.push_str(&format!("  {} -> {};\n", quote(&from.to_string()), quote(&to.to_string())));
+    }
+    dot.push_str("}\n");
+    dot
+}
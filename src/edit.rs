@@ -0,0 +1,617 @@
+//! Editing the hierarchy
+//!
+//! Adding, moving, reweighting and removing items the way the `ceph osd
+//! crush` commands do.  The weights of the buckets above an edited one are
+//! kept in step and the device class shadow trees are built again.
+//!
+use statistics::{bucket_items, item_name};
+use ::{Bucket, BucketAlg, BucketId, BucketTypes, CrushBucketList, CrushBucketStraw,
+       CrushBucketStraw2, CrushBucketTree, CrushBucketUniform, CrushHash, CrushIndex, CrushMap,
+       DeviceId, Item, Step};
+
+/// Bookkeeping shared by a series of edits, see Edits::finish
+pub struct Edits {
+    pub straw_calc_version: u8,
+    /// The next unused bucket id.  Ids are never handed out twice so a
+    /// retired id can't be taken before it is preserved as a shadow.
+    pub next_id: BucketId,
+    /// ((bucket, class id), id of the bucket its shadow replaces)
+    pub preserved: Vec<((BucketId, i32), BucketId)>,
+    /// Buckets taken out of the map, kept so a shadow with the same items
+    /// can reuse them as they were
+    pub retired: Vec<BucketTypes>,
+    /// Buckets whose items changed
+    pub changed: Vec<BucketId>,
+}
+
+impl Edits {
+    pub fn new(map: &CrushMap) -> Edits {
+        Edits {
+            straw_calc_version: map.straw_calc_version.unwrap_or(0),
+            next_id: map.next_bucket_id(),
+            preserved: vec![],
+            retired: vec![],
+            changed: vec![],
+        }
+    }
+
+    pub fn new_id(&mut self) -> BucketId {
+        let id = self.next_id;
+        self.next_id.0 -= 1;
+        id
+    }
+
+    /// Bring the rest of the map in line with the edits: the weights of
+    /// the buckets above the changed ones, the shadow trees if the map has
+    /// device classes, and the item names in the buckets and take steps
+    pub fn finish(mut self, map: &mut CrushMap) -> Result<(), String> {
+        try!(update_weights(map, &mut self));
+        if map.class_bucket.is_some() ||
+           map.class_name.as_ref().map(|c| !c.is_empty()).unwrap_or(false) {
+            try!(rebuild_shadows(map, &mut self));
+        }
+        map.max_buckets = map.buckets.len() as i32;
+
+        let names = map.name_map.clone();
+        let name = |id: Item| names.iter().find(|n| n.0 == id).map(|n| n.1.clone());
+        for b in map.buckets.iter_mut().filter_map(|b| b.bucket_mut()) {
+            for item in b.items.iter_mut() {
+                item.1 = name(item.0);
+            }
+        }
+        for rule in map.rules.iter_mut().filter_map(|r| r.as_mut()) {
+            for step in rule.steps.iter_mut() {
+                if let Step::Take { ref mut item } = *step {
+                    item.1 = name(item.0);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn lookup(map: &CrushMap, name: &str) -> Result<Item, String> {
+    map.name_map
+        .iter()
+        .find(|n| n.1 == name)
+        .map(|n| n.0)
+        .ok_or(format!("No bucket named {}", name))
+}
+
+pub fn lookup_bucket(map: &CrushMap, name: &str) -> Result<BucketId, String> {
+    match try!(lookup(map, name)) {
+        Item::Bucket(id) => Ok(id),
+        Item::Device(_) => Err(format!("{} is a device, not a bucket", name)),
+    }
+}
+
+pub fn get_bucket(map: &CrushMap, id: BucketId) -> Result<&BucketTypes, String> {
+    match map.buckets.get(id.index()) {
+        Some(b) if b.bucket().is_some() => Ok(b),
+        _ => Err(format!("Bucket {} doesn't exist", id)),
+    }
+}
+
+pub fn put_bucket(map: &mut CrushMap, bucket: BucketTypes) {
+    let index = bucket.id().index();
+    while map.buckets.len() <= index {
+        map.buckets.push(BucketTypes::Unknown);
+    }
+    map.buckets[index] = bucket;
+}
+
+pub fn device_class(map: &CrushMap, device: DeviceId) -> Option<i32> {
+    map.class_map.as_ref().and_then(|c| c.iter().find(|c| c.0 == device)).map(|c| c.1)
+}
+
+fn tree_height(mut n: usize) -> usize {
+    let mut h = 0;
+    while n & 1 == 0 {
+        h += 1;
+        n >>= 1;
+    }
+    h
+}
+
+fn tree_parent(n: usize) -> usize {
+    let h = tree_height(n);
+    if n & (1 << (h + 1)) != 0 {
+        n - (1 << h)
+    } else {
+        n + (1 << h)
+    }
+}
+
+// crush_calc_straw from Ceph's builder.c.  Straws are worked out from the
+// weights in ascending order.  Version 0 is the original calculation that
+// mishandles equal and zero weights, kept for the maps still using it.
+fn calc_straws(weights: &[u32], version: u8) -> Vec<u32> {
+    let size = weights.len();
+    let mut order: Vec<usize> = vec![];
+    for i in 0..size {
+        let at = order.iter().position(|&j| weights[i] < weights[j]).unwrap_or(order.len());
+        order.insert(at, i);
+    }
+
+    let mut straws = vec![0; size];
+    let mut numleft = size as f64;
+    let mut straw = 1.0f64;
+    let mut wbelow = 0.0f64;
+    let mut lastw = 0.0f64;
+    let mut i = 0;
+    while i < size {
+        // zero weight items get 0 length straws
+        if weights[order[i]] == 0 {
+            i += 1;
+            if version >= 1 {
+                numleft -= 1.0;
+            }
+            continue;
+        }
+        straws[order[i]] = (straw * 65536.0) as u32;
+        i += 1;
+        if i == size {
+            break;
+        }
+
+        let previous = weights[order[i - 1]];
+        let current = weights[order[i]];
+        if version == 0 {
+            if current == previous {
+                continue;
+            }
+            wbelow += (previous as f64 - lastw) * numleft;
+            for j in i..size {
+                if weights[order[j]] != current {
+                    break;
+                }
+                numleft -= 1.0;
+            }
+        } else {
+            wbelow += (previous as f64 - lastw) * numleft;
+            numleft -= 1.0;
+        }
+        let wnext = numleft * (current - previous) as f64;
+        let pbelow = wbelow / (wbelow + wnext);
+        straw *= (1.0 / pbelow).powf(1.0 / numleft);
+        lastw = previous as f64;
+    }
+    straws
+}
+
+// A copy of bucket holding items instead, with the weights its algorithm
+// keeps worked out again
+pub fn with_items(bucket: &BucketTypes,
+                  items: &[(Item, u32)],
+                  straw_calc_version: u8)
+                  -> Result<BucketTypes, String> {
+    let mut b: Bucket = match bucket.bucket() {
+        Some(b) => b.clone(),
+        None => return Err("Can't change the items of an unknown bucket".to_string()),
+    };
+    let weights: Vec<u32> = items.iter().map(|i| i.1).collect();
+    b.items = items.iter().map(|i| (i.0, None)).collect();
+    b.size = items.len() as u32;
+    b.weight = weights.iter().fold(0u32, |sum, w| sum.wrapping_add(*w));
+    b.perm_n = 0;
+    b.perm = 0;
+
+    Ok(match *bucket {
+        BucketTypes::Unknown => unreachable!(),
+        BucketTypes::Uniform(ref uniform) => {
+            if weights.iter().any(|w| *w != weights[0]) {
+                return Err(format!("Uniform bucket {} can't hold items of different weights",
+                                   b.id));
+            }
+            BucketTypes::Uniform(CrushBucketUniform {
+                item_weight: weights.first().cloned().unwrap_or(uniform.item_weight),
+                bucket: b,
+            })
+        }
+        BucketTypes::List(_) => {
+            let mut sum = 0u32;
+            let item_weights = weights.iter()
+                .map(|w| {
+                    sum = sum.wrapping_add(*w);
+                    (*w, sum)
+                })
+                .collect();
+            BucketTypes::List(CrushBucketList {
+                bucket: b,
+                item_weights: item_weights,
+            })
+        }
+        BucketTypes::Tree(_) => {
+            let mut depth = 0;
+            if !weights.is_empty() {
+                depth = 1;
+                let mut t = weights.len() - 1;
+                while t > 0 {
+                    t >>= 1;
+                    depth += 1;
+                }
+            }
+            let num_nodes = 1usize << depth;
+            if num_nodes > u8::max_value() as usize {
+                return Err(format!("Tree bucket {} can't hold {} items", b.id, weights.len()));
+            }
+            let mut node_weights = vec![0u32; num_nodes];
+            for (i, w) in weights.iter().enumerate() {
+                let mut node = ((i + 1) << 1) - 1;
+                node_weights[node] = *w;
+                for _ in 1..depth {
+                    node = tree_parent(node);
+                    node_weights[node] = node_weights[node].wrapping_add(*w);
+                }
+            }
+            BucketTypes::Tree(CrushBucketTree {
+                bucket: b,
+                num_nodes: num_nodes as u8,
+                node_weights: node_weights,
+            })
+        }
+        BucketTypes::Straw(_) => {
+            let straws = calc_straws(&weights, straw_calc_version);
+            BucketTypes::Straw(CrushBucketStraw {
+                bucket: b,
+                item_weights: weights.into_iter().zip(straws).collect(),
+            })
+        }
+        BucketTypes::Straw2(_) => {
+            BucketTypes::Straw2(CrushBucketStraw2 {
+                bucket: b,
+                item_weights: weights,
+            })
+        }
+    })
+}
+
+pub fn set_items(map: &mut CrushMap,
+                 edits: &mut Edits,
+                 id: BucketId,
+                 items: &[(Item, u32)])
+                 -> Result<(), String> {
+    let old_items: Vec<Item> =
+        try!(get_bucket(map, id)).bucket().unwrap().items.iter().map(|i| i.0).collect();
+    let bucket = try!(with_items(try!(get_bucket(map, id)), items, edits.straw_calc_version));
+    put_bucket(map, bucket);
+    edits.changed.push(id);
+
+    // Weight-sets and ids are by position so they follow the items around.
+    // An item new to the bucket starts out with its CRUSH weight.
+    if let Some(ref mut choose_args) = map.choose_args {
+        let args = choose_args.iter_mut().flat_map(|c| c.1.iter_mut());
+        for arg in args.filter(|a| a.bucket_id == id) {
+            let position = |item: Item| old_items.iter().position(|i| *i == item);
+            arg.weight_set = arg.weight_set
+                .iter()
+                .map(|weights| {
+                    items.iter()
+                        .map(|&(item, weight)| {
+                            position(item).and_then(|p| weights.get(p).cloned()).unwrap_or(weight)
+                        })
+                        .collect()
+                })
+                .collect();
+            if !arg.ids.is_empty() {
+                let ids = arg.ids.clone();
+                arg.ids = items.iter()
+                    .map(|&(item, _)| {
+                        position(item).and_then(|p| ids.get(p).cloned()).unwrap_or(item.id())
+                    })
+                    .collect();
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn parents(map: &CrushMap, id: Item) -> Vec<BucketId> {
+    map.buckets
+        .iter()
+        .filter_map(|b| b.bucket())
+        .filter(|b| b.items.iter().any(|i| i.0 == id))
+        .map(|b| b.id)
+        .collect()
+}
+
+// Take bucket id out of the map, keeping it for its shadow
+pub fn retire(map: &mut CrushMap, edits: &mut Edits, id: BucketId) -> Result<(), String> {
+    let bucket = try!(get_bucket(map, id)).clone();
+    map.buckets[id.index()] = BucketTypes::Unknown;
+    edits.retired.push(bucket);
+    Ok(())
+}
+
+// Set the weight of every changed bucket in its parents, and so on up
+fn update_weights(map: &mut CrushMap, edits: &mut Edits) -> Result<(), String> {
+    let mut queue = edits.changed.clone();
+    while let Some(id) = queue.pop() {
+        let weight = match get_bucket(map, id) {
+            Ok(b) => b.bucket().unwrap().weight,
+            Err(_) => continue,
+        };
+        let item = Item::Bucket(id);
+        for p in parents(map, item) {
+            let mut items = bucket_items(map, p);
+            if items.iter().all(|i| i.0 != item || i.1 == weight) {
+                continue;
+            }
+            for item in items.iter_mut().filter(|i| i.0 == item) {
+                item.1 = weight;
+            }
+            try!(set_items(map, edits, p, &items));
+            queue.push(p);
+        }
+    }
+    Ok(())
+}
+
+// The shadow of id for class, building it first if needed
+fn shadow(map: &mut CrushMap,
+          edits: &mut Edits,
+          old_shadows: &[(BucketId, Vec<(i32, BucketId)>)],
+          shadows: &mut Vec<(BucketId, Vec<(i32, BucketId)>)>,
+          id: BucketId,
+          class: i32)
+          -> Result<BucketId, String> {
+    if let Some(s) = shadows.iter()
+        .find(|s| s.0 == id)
+        .and_then(|s| s.1.iter().find(|c| c.0 == class)) {
+        return Ok(s.1);
+    }
+
+    let mut items = vec![];
+    for (item, weight) in bucket_items(map, id) {
+        match item {
+            Item::Device(device) => {
+                if device_class(map, device) == Some(class) {
+                    items.push((item, weight));
+                }
+            }
+            Item::Bucket(bucket) => {
+                let child = try!(shadow(map, edits, old_shadows, shadows, bucket, class));
+                let weight = try!(get_bucket(map, child)).bucket().unwrap().weight;
+                items.push((Item::Bucket(child), weight));
+            }
+        }
+    }
+
+    let shadow_id = match edits.preserved.iter().find(|p| p.0 == (id, class)) {
+        Some(p) => p.1,
+        None => {
+            match old_shadows.iter()
+                .find(|s| s.0 == id)
+                .and_then(|s| s.1.iter().find(|c| c.0 == class)) {
+                Some(c) => c.1,
+                None => edits.new_id(),
+            }
+        }
+    };
+    // A bucket this shadow replaces that held exactly the same items is
+    // kept as it was, straws and all, so nothing beneath it moves
+    let ids: Vec<Item> = items.iter().map(|i| i.0).collect();
+    let weights: Vec<u32> = items.iter().map(|i| i.1).collect();
+    let reused = edits.retired
+        .iter()
+        .find(|b| {
+            b.id() == shadow_id && b.item_weights() == weights &&
+            b.bucket().unwrap().items.iter().map(|i| i.0).collect::<Vec<Item>>() == ids
+        })
+        .cloned();
+    let bucket = match reused {
+        Some(b) => b,
+        None => {
+            let bucket = try!(get_bucket(map, id));
+            let mut b = try!(with_items(bucket, &items, edits.straw_calc_version));
+            b.bucket_mut().unwrap().id = shadow_id;
+            b
+        }
+    };
+    put_bucket(map, bucket);
+
+    let class_name = map.class_name
+        .as_ref()
+        .and_then(|names| names.iter().find(|n| n.0 == class))
+        .map(|n| n.1.clone())
+        .unwrap_or_else(|| class.to_string());
+    let name = format!("{}~{}",
+                       item_name(map, id.into()).unwrap_or_else(|| id.to_string()),
+                       class_name);
+    map.name_map.push((shadow_id.into(), name));
+
+    if !shadows.iter().any(|s| s.0 == id) {
+        shadows.push((id, vec![]));
+    }
+    shadows.iter_mut().find(|s| s.0 == id).unwrap().1.push((class, shadow_id));
+    Ok(shadow_id)
+}
+
+// Replace the shadow trees with new ones for every class, using the
+// preserved ids, then the ids the shadows had before
+fn rebuild_shadows(map: &mut CrushMap, edits: &mut Edits) -> Result<(), String> {
+    let old_shadows = map.class_bucket.take().unwrap_or(vec![]);
+    for &(_, ref classes) in old_shadows.iter() {
+        for &(_, shadow_id) in classes.iter() {
+            if get_bucket(map, shadow_id).is_ok() {
+                try!(retire(map, edits, shadow_id));
+            }
+            map.name_map.retain(|n| n.0 != Item::Bucket(shadow_id));
+        }
+    }
+
+    let classes: Vec<i32> = map.class_name
+        .as_ref()
+        .map(|names| names.iter().map(|n| n.0).collect())
+        .unwrap_or(vec![]);
+    let buckets: Vec<BucketId> =
+        map.buckets.iter().filter_map(|b| b.bucket()).map(|b| b.id).collect();
+    let mut shadows = vec![];
+    for id in buckets {
+        for class in classes.iter() {
+            try!(shadow(map, edits, &old_shadows, &mut shadows, id, *class));
+        }
+    }
+    map.class_bucket = Some(shadows);
+    Ok(())
+}
+
+fn ensure_unused(map: &CrushMap, name: &str) -> Result<(), String> {
+    if map.name_map.iter().any(|n| n.1 == name) {
+        return Err(format!("{} already exists", name));
+    }
+    Ok(())
+}
+
+/// Add an empty straw2 bucket of bucket_type named name.  Returns its id.
+pub fn create_bucket(map: &mut CrushMap,
+                     name: &str,
+                     bucket_type: &str)
+                     -> Result<BucketId, String> {
+    try!(ensure_unused(map, name));
+    let type_id = try!(map.type_map
+        .iter()
+        .find(|t| t.1 == bucket_type)
+        .map(|t| t.0)
+        .ok_or(format!("No type named {}", bucket_type)));
+    let id = map.next_bucket_id();
+    put_bucket(map,
+               BucketTypes::Straw2(CrushBucketStraw2 {
+                   bucket: Bucket {
+                       id: id,
+                       bucket_type: (type_id, Some(bucket_type.to_string())),
+                       alg: BucketAlg::Straw2,
+                       hash: CrushHash::RJenkins1,
+                       weight: 0,
+                       size: 0,
+                       items: vec![],
+                       perm_n: 0,
+                       perm: 0,
+                   },
+                   item_weights: vec![],
+               }));
+    map.name_map.push((id.into(), name.to_string()));
+    try!(Edits::new(map).finish(map));
+    Ok(id)
+}
+
+/// Add a device with a 16.16 fixed point weight to the bucket named
+/// parent, like `ceph osd crush add`
+pub fn add_device(map: &mut CrushMap,
+                  id: DeviceId,
+                  name: &str,
+                  weight: u32,
+                  parent: &str)
+                  -> Result<(), String> {
+    if id.0 < 0 {
+        return Err(format!("Device id {} is negative", id));
+    }
+    if map.name_map.iter().any(|n| n.0 == Item::Device(id)) {
+        return Err(format!("Device {} already exists", id));
+    }
+    try!(ensure_unused(map, name));
+    let parent = try!(lookup_bucket(map, parent));
+    let mut edits = Edits::new(map);
+    let mut items = bucket_items(map, parent);
+    items.push((id.into(), weight));
+    try!(set_items(map, &mut edits, parent, &items));
+    map.name_map.push((id.into(), name.to_string()));
+    if id.0 >= map.max_devices {
+        map.max_devices = id.0 + 1;
+    }
+    edits.finish(map)
+}
+
+// Whether item is id or beneath it
+fn is_beneath(map: &CrushMap, item: BucketId, id: BucketId) -> bool {
+    CrushIndex::new(map).subtree(id.into()).contains(&item.into())
+}
+
+/// Move the bucket named name beneath the bucket named parent, out of the
+/// buckets it is in now
+pub fn move_bucket(map: &mut CrushMap, name: &str, parent: &str) -> Result<(), String> {
+    let id = try!(lookup_bucket(map, name));
+    let parent = try!(lookup_bucket(map, parent));
+    if is_beneath(map, parent, id) {
+        return Err(format!("{} is beneath {}", item_name(map, parent.into()).unwrap(), name));
+    }
+    let mut edits = Edits::new(map);
+    for p in parents(map, id.into()) {
+        let items: Vec<(Item, u32)> =
+            bucket_items(map, p).into_iter().filter(|i| i.0 != Item::Bucket(id)).collect();
+        try!(set_items(map, &mut edits, p, &items));
+    }
+    let weight = try!(get_bucket(map, id)).bucket().unwrap().weight;
+    let mut items = bucket_items(map, parent);
+    items.push((id.into(), weight));
+    try!(set_items(map, &mut edits, parent, &items));
+    edits.finish(map)
+}
+
+/// Set the 16.16 fixed point CRUSH weight of the device named name in
+/// every bucket it is in
+pub fn reweight_device(map: &mut CrushMap, name: &str, weight: u32) -> Result<(), String> {
+    let id = try!(lookup(map, name));
+    if id.is_bucket() {
+        return Err(format!("{} is a bucket, reweight the devices beneath it", name));
+    }
+    let mut edits = Edits::new(map);
+    let holders = parents(map, id);
+    if holders.is_empty() {
+        return Err(format!("{} isn't in any bucket", name));
+    }
+    for p in holders {
+        let mut items = bucket_items(map, p);
+        for item in items.iter_mut().filter(|i| i.0 == id) {
+            item.1 = weight;
+        }
+        try!(set_items(map, &mut edits, p, &items));
+    }
+    edits.finish(map)
+}
+
+/// Remove a device, or a bucket with nothing in it, from the map
+pub fn remove_item(map: &mut CrushMap, name: &str) -> Result<(), String> {
+    let id = try!(lookup(map, name));
+    if id.bucket().map(|b| CrushIndex::new(map).is_shadow(b)).unwrap_or(false) {
+        return Err(format!("{} is a shadow bucket", name));
+    }
+    if let Item::Bucket(bucket) = id {
+        if !bucket_items(map, bucket).is_empty() {
+            return Err(format!("{} isn't empty", name));
+        }
+        let takes = map.rules
+            .iter()
+            .position(|r| {
+                r.as_ref().map(|r| r.steps.iter().any(|s| s.item() == Some(id)))
+                    .unwrap_or(false)
+            });
+        if let Some(rule) = takes {
+            return Err(format!("Rule {} takes {}", rule, name));
+        }
+    }
+
+    let mut edits = Edits::new(map);
+    for p in parents(map, id) {
+        let items: Vec<(Item, u32)> =
+            bucket_items(map, p).into_iter().filter(|i| i.0 != id).collect();
+        try!(set_items(map, &mut edits, p, &items));
+    }
+    map.name_map.retain(|n| n.0 != id);
+    match id {
+        Item::Bucket(bucket) => {
+            map.buckets[bucket.index()] = BucketTypes::Unknown;
+            if let Some(ref mut choose_args) = map.choose_args {
+                for &mut (_, ref mut args) in choose_args.iter_mut() {
+                    args.retain(|a| a.bucket_id != bucket);
+                }
+            }
+        }
+        Item::Device(device) => {
+            if let Some(ref mut class_map) = map.class_map {
+                class_map.retain(|c| c.0 != device);
+            }
+        }
+    }
+    edits.finish(map)
+}
@@ -0,0 +1,106 @@
+//! Rules for erasure code profiles
+//!
+//! Generates the rule `ceph osd erasure-code-profile set` followed by
+//! `ceph osd pool create ... erasure` would, for the jerasure style k+m
+//! layout and for the lrc plugin's locality groups.
+//!
+use rule_builder::{RuleBuilder, RuleBuilderError};
+use ::{CrushMap, Rule, RuleId};
+
+#[derive(Clone, Debug, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct ErasureCodeProfile {
+    /// Number of data chunks
+    pub k: u32,
+    /// Number of coding chunks
+    pub m: u32,
+    /// The type every chunk, or with locality every chunk of a group, is
+    /// placed in a different one of
+    pub failure_domain: String,
+    /// The bucket to take.  Defaults to "default"
+    pub root: Option<String>,
+    /// Only place chunks on devices of this class
+    pub device_class: Option<String>,
+    /// Group the k+m chunks into groups of `locality`, each with an extra
+    /// local parity chunk, as the lrc plugin's l parameter does
+    pub locality: Option<u32>,
+    /// The type each locality group is kept within.  Required with
+    /// locality.
+    pub locality_domain: Option<String>,
+}
+
+fn invalid<T>(reason: &str) -> Result<T, RuleBuilderError> {
+    Err(RuleBuilderError::InvalidProfile(reason.to_string()))
+}
+
+impl ErasureCodeProfile {
+    /// A k+m profile with a host failure domain
+    pub fn new(k: u32, m: u32) -> ErasureCodeProfile {
+        ErasureCodeProfile {
+            k: k,
+            m: m,
+            failure_domain: "host".to_string(),
+            root: None,
+            device_class: None,
+            locality: None,
+            locality_domain: None,
+        }
+    }
+
+    /// Number of chunks each object is split into, and so the pool size
+    pub fn size(&self) -> u32 {
+        match self.locality {
+            Some(l) if l > 0 => self.k + self.m + (self.k + self.m) / l,
+            _ => self.k + self.m,
+        }
+    }
+
+    /// A builder for the profile's rule.  Both min_size and max_size are
+    /// the profile's size since a pool using it can't be any other size.
+    pub fn rule_builder(&self, name: &str) -> Result<RuleBuilder, RuleBuilderError> {
+        if self.k < 1 {
+            return invalid("k must be at least 1");
+        }
+        if self.m < 1 {
+            return invalid("m must be at least 1");
+        }
+        let size = self.size();
+        if size > u8::max_value() as u32 {
+            return invalid("k+m is larger than a rule can hold");
+        }
+
+        let root = self.root.clone().unwrap_or_else(|| "default".to_string());
+        let builder = RuleBuilder::erasure(name)
+            .min_size(size as u8)
+            .max_size(size as u8);
+        let builder = match self.device_class {
+            Some(ref class) => builder.take_class(&root, class),
+            None => builder.take(&root),
+        };
+
+        let builder = match (self.locality, self.locality_domain.as_ref()) {
+            (None, _) => builder.chooseleaf_indep(0, &self.failure_domain),
+            (Some(l), Some(domain)) => {
+                if l == 0 || (self.k + self.m) % l != 0 {
+                    return invalid("k+m must be a multiple of the locality");
+                }
+                // One locality domain per group, then l data or coding
+                // chunks and the group's local parity chunk within it
+                let groups = (self.k + self.m) / l;
+                builder.choose_indep(groups as i32, domain)
+                    .chooseleaf_indep(l as i32 + 1, &self.failure_domain)
+            }
+            (Some(_), None) => return invalid("locality needs a locality_domain"),
+        };
+        Ok(builder.emit())
+    }
+
+    /// Resolve the profile's rule against map without adding it
+    pub fn build_rule(&self, map: &CrushMap, name: &str) -> Result<Rule, RuleBuilderError> {
+        try!(self.rule_builder(name)).build(map)
+    }
+
+    /// Add the profile's rule to map, see RuleBuilder::add_to
+    pub fn add_rule(&self, map: &mut CrushMap, name: &str) -> Result<RuleId, RuleBuilderError> {
+        try!(self.rule_builder(name)).add_to(map)
+    }
+}
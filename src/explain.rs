@@ -0,0 +1,125 @@
+//! Explain a single placement
+//!
+//! Runs one input through a rule with the mapper's trace turned on so a
+//! surprising mapping can be followed step by step: which bucket chose
+//! what with which r, what was rejected and why, and where retries
+//! happened.
+//!
+use std::fmt;
+
+use mapper::{check_mappable, do_rule_traced, RejectReason, TraceEvent, CRUSH_ITEM_NONE};
+use statistics::describe_item;
+use ::{ChooseArg, CrushIndex, CrushMap, Item, RuleId};
+
+#[derive(Clone, Debug, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct Explanation {
+    pub rule: RuleId,
+    pub x: i32,
+    pub num_rep: usize,
+    pub events: Vec<TraceEvent>,
+    pub result: Vec<i32>,
+}
+
+/// Map x with the rule and record everything the mapper does.  See
+/// mapper::do_rule for the arguments and mapper::check_mappable for the
+/// rules it fails for.
+pub fn explain(map: &CrushMap,
+               ruleno: RuleId,
+               x: i32,
+               num_rep: usize,
+               weights: &[u32],
+               choose_args: &[ChooseArg])
+               -> Result<Explanation, String> {
+    try!(check_mappable(map, ruleno));
+    let (result, events) = do_rule_traced(map, ruleno, x, num_rep, weights, choose_args);
+    Ok(Explanation {
+        rule: ruleno,
+        x: x,
+        num_rep: num_rep,
+        events: events,
+        result: result,
+    })
+}
+
+/// Describes an Explanation using the names in a map
+pub struct ExplanationDisplay<'a> {
+    explanation: &'a Explanation,
+    index: CrushIndex<'a>,
+}
+
+impl Explanation {
+    /// Display the explanation with the item names from map
+    pub fn display<'a>(&'a self, map: &'a CrushMap) -> ExplanationDisplay<'a> {
+        ExplanationDisplay {
+            explanation: self,
+            index: CrushIndex::new(map),
+        }
+    }
+}
+
+impl<'a> ExplanationDisplay<'a> {
+    fn items(&self, items: &[i32]) -> String {
+        let names: Vec<String> = items.iter()
+            .map(|i| if *i == CRUSH_ITEM_NONE {
+                "NONE".to_string()
+            } else {
+                describe_item(&self.index, Item::from(*i))
+            })
+            .collect();
+        format!("[{}]", names.join(", "))
+    }
+}
+
+impl<'a> fmt::Display for ExplanationDisplay<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let e = self.explanation;
+        try!(write!(f, "rule {} x {} num_rep {}", e.rule, e.x, e.num_rep));
+        for event in e.events.iter() {
+            match *event {
+                TraceEvent::Step { index, ref op, arg1, arg2 } => {
+                    try!(write!(f, "\nstep {}: {:?} {} {}", index, op, arg1, arg2))
+                }
+                TraceEvent::Choose { bucket, r, item } => {
+                    try!(write!(f,
+                                "\n  {} r={} -> {}",
+                                describe_item(&self.index, Item::from(bucket)),
+                                r,
+                                describe_item(&self.index, Item::from(item))))
+                }
+                TraceEvent::Reject { item, ref reason } => {
+                    let why = match *reason {
+                        RejectReason::Collision => "already chosen",
+                        RejectReason::Out => "out",
+                        RejectReason::NoLeaf => "no usable device beneath it",
+                        RejectReason::BadItem => "bad item",
+                        RejectReason::EmptyBucket => "empty bucket",
+                    };
+                    try!(write!(f,
+                                "\n  reject {}: {}",
+                                describe_item(&self.index, Item::from(item)),
+                                why))
+                }
+                TraceEvent::Retry { local, ftotal, flocal } => {
+                    try!(write!(f,
+                                "\n  retry {} (ftotal {} flocal {})",
+                                if local { "in the same bucket" } else { "descent" },
+                                ftotal,
+                                flocal))
+                }
+                TraceEvent::Placed { position, item } => {
+                    try!(write!(f,
+                                "\n  position {}: {}",
+                                position,
+                                describe_item(&self.index, Item::from(item))))
+                }
+                TraceEvent::GiveUp { position } => {
+                    try!(write!(f, "\n  position {}: gave up", position))
+                }
+                TraceEvent::Emit { ref items } => {
+                    try!(write!(f, "\n  emit {}", self.items(items)))
+                }
+            }
+        }
+        write!(f, "\nresult: {}", self.items(&e.result))
+    }
+}
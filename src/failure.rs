@@ -0,0 +1,180 @@
+//! Failure simulation
+//!
+//! Mark devices or whole buckets out and compare the placements before
+//! and after to see what would be degraded, what can no longer be fully
+//! replicated and where the data would move to.
+//!
+use std::collections::BTreeMap;
+use std::fmt;
+
+use mapper::CRUSH_ITEM_NONE;
+use statistics::simulate;
+use ::{CrushIndex, CrushMap, Item, RuleId};
+
+/// An input whose mapping changed
+#[derive(Clone, Debug, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct PlacementChange {
+    pub x: i32,
+    pub before: Vec<i32>,
+    pub after: Vec<i32>,
+}
+
+/// Replicas a device loses or gains because of the failure
+#[derive(Clone, Debug, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct DeviceMovement {
+    pub id: i32,
+    pub name: Option<String>,
+    pub lost: u64,
+    pub gained: u64,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct FailureReport {
+    pub rule: RuleId,
+    pub num_rep: usize,
+    pub inputs: u64,
+    /// Every device that was marked out
+    pub out: Vec<i32>,
+    /// Inputs that had at least one replica on a device that was marked out
+    pub degraded: Vec<i32>,
+    /// Inputs that map to fewer than num_rep devices after the failure
+    pub undersized: Vec<i32>,
+    /// Number of replicas that have to move
+    pub remapped_replicas: u64,
+    pub total_replicas: u64,
+    pub changes: Vec<PlacementChange>,
+    pub movements: Vec<DeviceMovement>,
+}
+
+/// Resolve a device or bucket given either by id or by name
+pub fn resolve_item(map: &CrushMap, item: &str) -> Option<Item> {
+    match item.parse::<i32>() {
+        Ok(id) => Some(Item::from(id)),
+        Err(_) => map.name_map.iter().find(|n| n.1 == item).map(|n| n.0),
+    }
+}
+
+fn placed(mapping: &[i32]) -> Vec<i32> {
+    mapping.iter().cloned().filter(|i| *i != CRUSH_ITEM_NONE).collect()
+}
+
+/// Map every x in [min_x, max_x] with and without the `failed` devices
+/// and buckets and report the difference.  Buckets take all of the devices
+/// beneath them out.  `weights` are the device reweight values before the
+/// failure, see mapper::do_rule.  Fails like statistics::simulate.
+pub fn simulate_failure(map: &CrushMap,
+                        ruleno: RuleId,
+                        num_rep: usize,
+                        min_x: i32,
+                        max_x: i32,
+                        weights: &[u32],
+                        failed: &[Item])
+                        -> Result<FailureReport, String> {
+    let index = CrushIndex::new(map);
+    let mut out: Vec<i32> = index.devices_under(failed, 0).into_iter().map(|d| (d.0).0).collect();
+    out.sort();
+
+    let mut failed_weights = weights.to_vec();
+    for device in out.iter() {
+        if let Some(weight) = failed_weights.get_mut(*device as usize) {
+            *weight = 0;
+        }
+    }
+
+    let before = try!(simulate(map, ruleno, num_rep, min_x, max_x, weights));
+    let after = try!(simulate(map, ruleno, num_rep, min_x, max_x, &failed_weights));
+
+    let mut degraded = vec![];
+    let mut undersized = vec![];
+    let mut changes = vec![];
+    let mut remapped_replicas = 0;
+    let mut total_replicas = 0;
+    let mut movements: BTreeMap<i32, (u64, u64)> = BTreeMap::new();
+
+    let inputs = (min_x as i64..max_x as i64 + 1).map(|x| x as i32);
+    for (x, (old, new)) in inputs.zip(before.into_iter().zip(after.into_iter())) {
+        let old_placed = placed(&old);
+        let new_placed = placed(&new);
+        total_replicas += old_placed.len() as u64;
+        if old_placed.iter().any(|d| out.binary_search(d).is_ok()) {
+            degraded.push(x);
+        }
+        if new_placed.len() < num_rep {
+            undersized.push(x);
+        }
+        if old == new {
+            continue;
+        }
+        for device in old_placed.iter().filter(|d| !new_placed.contains(d)) {
+            movements.entry(*device).or_insert((0, 0)).0 += 1;
+        }
+        for device in new_placed.iter().filter(|d| !old_placed.contains(d)) {
+            movements.entry(*device).or_insert((0, 0)).1 += 1;
+            remapped_replicas += 1;
+        }
+        changes.push(PlacementChange {
+            x: x,
+            before: old,
+            after: new,
+        });
+    }
+
+    Ok(FailureReport {
+        rule: ruleno,
+        num_rep: num_rep,
+        inputs: (max_x as i64 - min_x as i64 + 1).max(0) as u64,
+        out: out,
+        degraded: degraded,
+        undersized: undersized,
+        remapped_replicas: remapped_replicas,
+        total_replicas: total_replicas,
+        changes: changes,
+        movements: movements.into_iter()
+            .map(|(id, (lost, gained))| {
+                DeviceMovement {
+                    id: id,
+                    name: index.name(Item::from(id)).map(String::from),
+                    lost: lost,
+                    gained: gained,
+                }
+            })
+            .collect(),
+    })
+}
+
+impl fmt::Display for FailureReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f,
+                      "rule {} num_rep {}: marked out {:?}",
+                      self.rule,
+                      self.num_rep,
+                      self.out));
+        try!(writeln!(f, "degraded:   {}/{}", self.degraded.len(), self.inputs));
+        try!(writeln!(f, "undersized: {}/{}", self.undersized.len(), self.inputs));
+        if !self.undersized.is_empty() {
+            try!(writeln!(f,
+                          "  inputs with fewer than {} replicas: {:?}",
+                          self.num_rep,
+                          self.undersized));
+        }
+        let percent = if self.total_replicas > 0 {
+            100.0 * self.remapped_replicas as f64 / self.total_replicas as f64
+        } else {
+            0.0
+        };
+        try!(writeln!(f,
+                      "remapped:   {}/{} replicas ({:.2}%)",
+                      self.remapped_replicas,
+                      self.total_replicas,
+                      percent));
+        try!(write!(f, "{:>6} {:<16} {:>8} {:>8}", "id", "name", "lost", "gained"));
+        for m in self.movements.iter() {
+            let name = match m.name {
+                Some(ref n) => n.clone(),
+                None => m.id.to_string(),
+            };
+            try!(write!(f, "\n{:>6} {:<16} {:>8} {:>8}", m.id, name, m.lost, m.gained));
+        }
+        Ok(())
+    }
+}
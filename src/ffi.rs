@@ -0,0 +1,213 @@
+//! A C interface to the decoder, the encoder and the mapper
+//!
+//! Maps are handed out as opaque CrushMap pointers that the caller owns
+//! and releases with crush_map_free.  A function that fails returns NULL,
+//! 0 or -1 and leaves a description of what went wrong for
+//! crush_last_error.  Panics are caught before they reach the caller.
+//! NULL pointers are refused, any other pointer has to be valid for what
+//! the function's Safety section says it does with it.
+//!
+//! include/crushtool.h declares all of this.  Regenerate it after changing
+//! a signature with
+//! `cbindgen --config cbindgen.toml --output include/crushtool.h`.
+//!
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use io::decode_crushmap;
+use mapper::{check_mappable, default_weights, do_rule};
+use ::{CrushMap, RuleId};
+
+thread_local!(static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None));
+
+// Run f, turning an error or a panic into failed and a message for
+// crush_last_error
+fn guard<T, F>(failed: T, f: F) -> T
+    where F: FnOnce() -> Result<T, String>
+{
+    let err = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => return value,
+        Ok(Err(err)) => err,
+        Err(_) => "crushtool panicked".to_string(),
+    };
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(err).ok());
+    failed
+}
+
+// A NULL map is refused, anything else is taken to be a live map from
+// crush_map_decode
+unsafe fn map_arg<'a>(map: *const CrushMap) -> Result<&'a CrushMap, String> {
+    if map.is_null() {
+        return Err("The map is NULL".to_string());
+    }
+    Ok(&*map)
+}
+
+unsafe fn name_arg<'a>(name: *const c_char) -> Result<&'a str, String> {
+    if name.is_null() {
+        return Err("The name is NULL".to_string());
+    }
+    CStr::from_ptr(name).to_str().map_err(|_| "The name isn't UTF-8".to_string())
+}
+
+/// Decode the compiled map in the len bytes at data.  Returns NULL if it
+/// can't be decoded.
+///
+/// # Safety
+///
+/// data must be NULL or point to len readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn crush_map_decode(data: *const u8, len: usize) -> *mut CrushMap {
+    guard(ptr::null_mut(), || {
+        if data.is_null() {
+            return Err("The data is NULL".to_string());
+        }
+        let map = try!(decode_crushmap(slice::from_raw_parts(data, len)));
+        Ok(Box::into_raw(Box::new(map)))
+    })
+}
+
+/// Encode map into the len bytes at buffer.  Returns the size of the
+/// encoded map, or 0 if it can't be encoded or doesn't fit in len bytes.
+/// A NULL buffer only asks for the size, whatever len is.
+///
+/// # Safety
+///
+/// map must be NULL or a map from crush_map_decode that hasn't been freed.
+/// buffer must be NULL or point to len writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn crush_map_encode(map: *const CrushMap,
+                                          buffer: *mut u8,
+                                          len: usize)
+                                          -> usize {
+    guard(0, || {
+        let map = try!(map_arg(map));
+        let mut encoded = vec![];
+        try!(map.encode_to(&mut encoded).map_err(|e| e.to_string()));
+        if buffer.is_null() {
+            return Ok(encoded.len());
+        }
+        if encoded.len() > len {
+            return Err(format!("The map needs {} bytes, the buffer holds {}",
+                               encoded.len(),
+                               len));
+        }
+        ptr::copy_nonoverlapping(encoded.as_ptr(), buffer, encoded.len());
+        Ok(encoded.len())
+    })
+}
+
+/// Store the id of the device or bucket named name in *id.  Returns 0, or
+/// -1 if there is no such item.
+///
+/// # Safety
+///
+/// map must be NULL or a map from crush_map_decode that hasn't been freed.
+/// name must be NULL or a NUL terminated string, and id NULL or writable.
+#[no_mangle]
+pub unsafe extern "C" fn crush_map_item_id(map: *const CrushMap,
+                                           name: *const c_char,
+                                           id: *mut i32)
+                                           -> i32 {
+    guard(-1, || {
+        let name = try!(name_arg(name));
+        let item = try!(try!(map_arg(map))
+            .name_map
+            .iter()
+            .find(|n| n.1 == name)
+            .map(|n| n.0)
+            .ok_or(format!("No item {}", name)));
+        if id.is_null() {
+            return Err("The id is NULL".to_string());
+        }
+        *id = item.id();
+        Ok(0)
+    })
+}
+
+/// The index of the rule named name for crush_map_do_rule, or -1 if there
+/// is no such rule
+///
+/// # Safety
+///
+/// map must be NULL or a map from crush_map_decode that hasn't been freed.
+/// name must be NULL or a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn crush_map_rule_id(map: *const CrushMap, name: *const c_char) -> i32 {
+    guard(-1, || {
+        let name = try!(name_arg(name));
+        try!(map_arg(map))
+            .rule_id(name)
+            .map(|rule| rule.0)
+            .ok_or(format!("No rule named {}", name))
+    })
+}
+
+/// Place input x with the rule at index rule, writing up to result_max
+/// devices to result.  weights holds the reweight of each of the
+/// weights_len devices, 0x10000 being fully in, or is NULL for all of them
+/// in.  Returns how many devices were written, where a position that
+/// couldn't be filled holds CRUSH_ITEM_NONE, or -1 if there is no such
+/// rule.
+///
+/// # Safety
+///
+/// map must be NULL or a map from crush_map_decode that hasn't been freed.
+/// result must be NULL or point to result_max writable int32_t, and is
+/// only allowed to be NULL when result_max is 0.  weights must be NULL or
+/// point to weights_len readable uint32_t.
+#[no_mangle]
+pub unsafe extern "C" fn crush_map_do_rule(map: *const CrushMap,
+                                           rule: i32,
+                                           x: i32,
+                                           result: *mut i32,
+                                           result_max: usize,
+                                           weights: *const u32,
+                                           weights_len: usize)
+                                           -> i32 {
+    guard(-1, || {
+        let map = try!(map_arg(map));
+        let rule = RuleId(rule);
+        try!(check_mappable(map, rule));
+        if result.is_null() && result_max > 0 {
+            return Err("The result is NULL".to_string());
+        }
+        let placed = if weights.is_null() {
+            do_rule(map, rule, x, result_max, &default_weights(map))
+        } else {
+            do_rule(map,
+                    rule,
+                    x,
+                    result_max,
+                    slice::from_raw_parts(weights, weights_len))
+        };
+        if !placed.is_empty() {
+            ptr::copy_nonoverlapping(placed.as_ptr(), result, placed.len());
+        }
+        Ok(placed.len() as i32)
+    })
+}
+
+/// Release a map from crush_map_decode.  NULL is ignored.
+///
+/// # Safety
+///
+/// map must be NULL or a map from crush_map_decode that hasn't been freed
+/// already.  It can't be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn crush_map_free(map: *mut CrushMap) {
+    if !map.is_null() {
+        drop(Box::from_raw(map));
+    }
+}
+
+/// What the last call that failed on this thread went wrong with, or NULL.
+/// The string stays valid until another call fails on this thread.
+#[no_mangle]
+pub extern "C" fn crush_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map(|e| e.as_ptr()).unwrap_or(ptr::null()))
+}
@@ -0,0 +1,66 @@
+//! Robert Jenkins' function for mixing 32-bit values, as used by CRUSH.
+//!
+//! http://burtleburtle.net/bob/hash/evahash.html
+//! a, b = random bits, c = input and output
+//!
+use std::num::Wrapping;
+
+const CRUSH_HASH_SEED: u32 = 1315423911;
+
+macro_rules! hashmix {
+    ($a:ident, $b:ident, $c:ident) => {
+        $a = $a - $b; $a = $a - $c; $a = $a ^ ($c >> 13);
+        $b = $b - $c; $b = $b - $a; $b = $b ^ ($a << 8);
+        $c = $c - $a; $c = $c - $b; $c = $c ^ ($b >> 13);
+        $a = $a - $b; $a = $a - $c; $a = $a ^ ($c >> 12);
+        $b = $b - $c; $b = $b - $a; $b = $b ^ ($a << 16);
+        $c = $c - $a; $c = $c - $b; $c = $c ^ ($b >> 5);
+        $a = $a - $b; $a = $a - $c; $a = $a ^ ($c >> 3);
+        $b = $b - $c; $b = $b - $a; $b = $b ^ ($a << 10);
+        $c = $c - $a; $c = $c - $b; $c = $c ^ ($b >> 15);
+    }
+}
+
+pub fn crush_hash32_2(a: u32, b: u32) -> u32 {
+    let mut a = Wrapping(a);
+    let mut b = Wrapping(b);
+    let mut hash = Wrapping(CRUSH_HASH_SEED) ^ a ^ b;
+    let mut x = Wrapping(231232);
+    let mut y = Wrapping(1232);
+    hashmix!(a, b, hash);
+    hashmix!(x, a, hash);
+    hashmix!(b, y, hash);
+    hash.0
+}
+
+pub fn crush_hash32_3(a: u32, b: u32, c: u32) -> u32 {
+    let mut a = Wrapping(a);
+    let mut b = Wrapping(b);
+    let mut c = Wrapping(c);
+    let mut hash = Wrapping(CRUSH_HASH_SEED) ^ a ^ b ^ c;
+    let mut x = Wrapping(231232);
+    let mut y = Wrapping(1232);
+    hashmix!(a, b, hash);
+    hashmix!(c, x, hash);
+    hashmix!(y, a, hash);
+    hashmix!(b, x, hash);
+    hashmix!(y, c, hash);
+    hash.0
+}
+
+pub fn crush_hash32_4(a: u32, b: u32, c: u32, d: u32) -> u32 {
+    let mut a = Wrapping(a);
+    let mut b = Wrapping(b);
+    let mut c = Wrapping(c);
+    let mut d = Wrapping(d);
+    let mut hash = Wrapping(CRUSH_HASH_SEED) ^ a ^ b ^ c ^ d;
+    let mut x = Wrapping(231232);
+    let mut y = Wrapping(1232);
+    hashmix!(a, b, hash);
+    hashmix!(c, d, hash);
+    hashmix!(a, x, hash);
+    hashmix!(y, b, hash);
+    hashmix!(c, x, hash);
+    hashmix!(y, d, hash);
+    hash.0
+}
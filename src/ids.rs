@@ -0,0 +1,219 @@
+//! Typed ids
+//!
+//! Devices, buckets, types and rules are all numbered with plain
+//! integers in a compiled map, and it's easy to hand one kind to code
+//! expecting another.  These wrap the numbers so the compiler tells them
+//! apart.  Each encodes as the bare integer, so the JSON of a map is the
+//! same as it was before they existed.
+//!
+use std::cmp::Ordering;
+use std::fmt;
+
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
+
+/// A device, numbered from 0
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct DeviceId(pub i32);
+
+/// A bucket, numbered down from -1
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct BucketId(pub i32);
+
+/// A bucket type, or 0 for devices
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct TypeId(pub i32);
+
+/// A rule's index in CrushMap.rules
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct RuleId(pub i32);
+
+/// The legacy ruleset of a rule.  Before Luminous several rules could
+/// share one and pools named their rule by it; since then it's expected to
+/// equal the RuleId.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Ruleset(pub u8);
+
+/// Something a bucket can hold.  Device ids are never negative and
+/// bucket ids always are.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Item {
+    Device(DeviceId),
+    Bucket(BucketId),
+}
+
+impl BucketId {
+    /// Where the bucket sits in CrushMap.buckets
+    pub fn index(&self) -> usize {
+        (-1 - self.0) as usize
+    }
+
+    pub fn from_index(index: usize) -> BucketId {
+        BucketId(-1 - index as i32)
+    }
+}
+
+impl RuleId {
+    /// Where the rule sits in CrushMap.rules
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl Item {
+    pub fn id(&self) -> i32 {
+        match *self {
+            Item::Device(d) => d.0,
+            Item::Bucket(b) => b.0,
+        }
+    }
+
+    pub fn device(&self) -> Option<DeviceId> {
+        match *self {
+            Item::Device(d) => Some(d),
+            Item::Bucket(_) => None,
+        }
+    }
+
+    pub fn bucket(&self) -> Option<BucketId> {
+        match *self {
+            Item::Device(_) => None,
+            Item::Bucket(b) => Some(b),
+        }
+    }
+
+    pub fn is_device(&self) -> bool {
+        self.device().is_some()
+    }
+
+    pub fn is_bucket(&self) -> bool {
+        self.bucket().is_some()
+    }
+}
+
+// Items sort by id, buckets before devices, the way the maps list them
+impl Ord for Item {
+    fn cmp(&self, other: &Item) -> Ordering {
+        self.id().cmp(&other.id())
+    }
+}
+
+impl PartialOrd for Item {
+    fn partial_cmp(&self, other: &Item) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl From<i32> for Item {
+    fn from(id: i32) -> Item {
+        if id < 0 {
+            Item::Bucket(BucketId(id))
+        } else {
+            Item::Device(DeviceId(id))
+        }
+    }
+}
+
+impl From<Item> for i32 {
+    fn from(item: Item) -> i32 {
+        item.id()
+    }
+}
+
+impl From<DeviceId> for Item {
+    fn from(id: DeviceId) -> Item {
+        Item::Device(id)
+    }
+}
+
+impl From<BucketId> for Item {
+    fn from(id: BucketId) -> Item {
+        Item::Bucket(id)
+    }
+}
+
+macro_rules! plain_id {
+    ($id:ident) => {
+        impl From<i32> for $id {
+            fn from(id: i32) -> $id {
+                $id(id)
+            }
+        }
+
+        impl From<$id> for i32 {
+            fn from(id: $id) -> i32 {
+                id.0
+            }
+        }
+
+        impl fmt::Display for $id {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+
+        impl Encodable for $id {
+            fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+                self.0.encode(s)
+            }
+        }
+
+        impl Decodable for $id {
+            fn decode<D: Decoder>(d: &mut D) -> Result<$id, D::Error> {
+                Ok($id(try!(i32::decode(d))))
+            }
+        }
+    }
+}
+
+plain_id!(DeviceId);
+plain_id!(BucketId);
+plain_id!(TypeId);
+plain_id!(RuleId);
+
+impl From<u8> for Ruleset {
+    fn from(ruleset: u8) -> Ruleset {
+        Ruleset(ruleset)
+    }
+}
+
+impl From<Ruleset> for u8 {
+    fn from(ruleset: Ruleset) -> u8 {
+        ruleset.0
+    }
+}
+
+impl fmt::Display for Ruleset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Encodable for Ruleset {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        self.0.encode(s)
+    }
+}
+
+impl Decodable for Ruleset {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Ruleset, D::Error> {
+        Ok(Ruleset(try!(u8::decode(d))))
+    }
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.id().fmt(f)
+    }
+}
+
+impl Encodable for Item {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        self.id().encode(s)
+    }
+}
+
+impl Decodable for Item {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Item, D::Error> {
+        Ok(Item::from(try!(i32::decode(d))))
+    }
+}
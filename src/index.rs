@@ -0,0 +1,217 @@
+//! Lookups by name and id
+//!
+//! A CrushMap keeps its names and buckets in vectors, so finding one
+//! means a scan.  CrushIndex hashes them once so that queries against
+//! maps with many thousands of devices stay cheap.  It borrows the map,
+//! so build one for a series of lookups and a new one after changing it.
+//!
+use std::collections::{HashMap, HashSet};
+
+use ::{BucketId, BucketTypes, CrushMap, DeviceId, Item, TypeId};
+
+pub struct CrushIndex<'a> {
+    map: &'a CrushMap,
+    /// Bucket id to its position in map.buckets
+    buckets: HashMap<BucketId, usize>,
+    ids: HashMap<&'a str, Item>,
+    names: HashMap<Item, &'a str>,
+    types: HashMap<&'a str, TypeId>,
+    type_names: HashMap<TypeId, &'a str>,
+    /// Device to the id of its class
+    classes: HashMap<DeviceId, i32>,
+    class_names: HashMap<i32, &'a str>,
+    /// Every bucket holding an item, shadow buckets last
+    parents: HashMap<Item, Vec<BucketId>>,
+    shadows: HashSet<BucketId>,
+}
+
+impl<'a> CrushIndex<'a> {
+    pub fn new(map: &'a CrushMap) -> CrushIndex<'a> {
+        let shadows: HashSet<BucketId> = map.class_bucket
+            .iter()
+            .flat_map(|buckets| buckets.iter())
+            .flat_map(|b| b.1.iter().map(|c| c.1))
+            .collect();
+        let mut buckets = HashMap::new();
+        let mut parents: HashMap<Item, Vec<BucketId>> = HashMap::new();
+        for (position, bucket) in map.buckets.iter().enumerate() {
+            if let Some(b) = bucket.bucket() {
+                buckets.entry(b.id).or_insert(position);
+                for item in b.items.iter() {
+                    parents.entry(item.0).or_insert_with(Vec::new).push(b.id);
+                }
+            }
+        }
+        for p in parents.values_mut() {
+            p.sort_by_key(|id| shadows.contains(id));
+        }
+        // The first of a repeated name or id wins, as it does for a scan
+        let mut ids = HashMap::new();
+        let mut names = HashMap::new();
+        for n in map.name_map.iter() {
+            ids.entry(&n.1[..]).or_insert(n.0);
+            names.entry(n.0).or_insert(&n.1[..]);
+        }
+        let mut types = HashMap::new();
+        let mut type_names = HashMap::new();
+        for t in map.type_map.iter() {
+            types.entry(&t.1[..]).or_insert(t.0);
+            type_names.entry(t.0).or_insert(&t.1[..]);
+        }
+        let mut classes = HashMap::new();
+        for c in map.class_map.iter().flat_map(|c| c.iter()) {
+            classes.entry(c.0).or_insert(c.1);
+        }
+        let mut class_names = HashMap::new();
+        for n in map.class_name.iter().flat_map(|n| n.iter()) {
+            class_names.entry(n.0).or_insert(&n.1[..]);
+        }
+        CrushIndex {
+            map: map,
+            buckets: buckets,
+            ids: ids,
+            names: names,
+            types: types,
+            type_names: type_names,
+            classes: classes,
+            class_names: class_names,
+            parents: parents,
+            shadows: shadows,
+        }
+    }
+
+    pub fn bucket_by_id(&self, id: BucketId) -> Option<&'a BucketTypes> {
+        self.buckets.get(&id).map(|&position| &self.map.buckets[position])
+    }
+
+    pub fn bucket_by_name(&self, name: &str) -> Option<&'a BucketTypes> {
+        self.id(name).and_then(|id| id.bucket()).and_then(|id| self.bucket_by_id(id))
+    }
+
+    /// The id of the device or bucket called name
+    pub fn id(&self, name: &str) -> Option<Item> {
+        self.ids.get(name).cloned()
+    }
+
+    /// The name of a device or bucket
+    pub fn name(&self, id: Item) -> Option<&'a str> {
+        self.names.get(&id).cloned()
+    }
+
+    /// The name of a device or bucket, or its id if it has none
+    pub fn name_or_id(&self, id: Item) -> String {
+        self.name(id).map(String::from).unwrap_or_else(|| id.to_string())
+    }
+
+    pub fn device_name(&self, id: DeviceId) -> Option<&'a str> {
+        self.name(Item::Device(id))
+    }
+
+    pub fn type_id(&self, name: &str) -> Option<TypeId> {
+        self.types.get(name).cloned()
+    }
+
+    pub fn type_name(&self, id: TypeId) -> Option<&'a str> {
+        self.type_names.get(&id).cloned()
+    }
+
+    pub fn class_name(&self, class: i32) -> Option<&'a str> {
+        self.class_names.get(&class).cloned()
+    }
+
+    /// The name of a device's class
+    pub fn device_class(&self, id: DeviceId) -> Option<&'a str> {
+        self.classes.get(&id).and_then(|c| self.class_name(*c))
+    }
+
+    /// Whether a bucket is one of the shadow buckets of a device class
+    pub fn is_shadow(&self, id: BucketId) -> bool {
+        self.shadows.contains(&id)
+    }
+
+    /// The bucket holding an item.  Devices with a class also sit in
+    /// that class's shadow buckets, which are left out here but not from
+    /// parents_of.
+    pub fn parent_of(&self, id: Item) -> Option<BucketId> {
+        match self.parents_of(id).first() {
+            Some(p) if !self.shadows.contains(p) => Some(*p),
+            _ => None,
+        }
+    }
+
+    /// Every bucket holding an item
+    pub fn parents_of(&self, id: Item) -> &[BucketId] {
+        self.parents.get(&id).map(|p| &p[..]).unwrap_or(&[])
+    }
+
+    /// The items of a bucket
+    pub fn children_of(&self, id: BucketId) -> Vec<Item> {
+        self.items_of(id).into_iter().map(|i| i.0).collect()
+    }
+
+    /// The items of a bucket along with their weights
+    pub fn items_of(&self, id: BucketId) -> Vec<(Item, u32)> {
+        match self.bucket_by_id(id) {
+            Some(b) => {
+                b.bucket()
+                    .unwrap()
+                    .items
+                    .iter()
+                    .map(|i| i.0)
+                    .zip(b.item_weights())
+                    .collect()
+            }
+            None => vec![],
+        }
+    }
+
+    /// The parent of an item, its parent and so on up to the root
+    pub fn ancestors(&self, id: Item) -> Vec<BucketId> {
+        let mut ancestors = vec![];
+        let mut id = id;
+        while let Some(parent) = self.parent_of(id) {
+            if ancestors.contains(&parent) {
+                break;
+            }
+            ancestors.push(parent);
+            id = parent.into();
+        }
+        ancestors
+    }
+
+    /// An item and everything beneath it, depth first
+    pub fn subtree(&self, id: Item) -> Vec<Item> {
+        let mut subtree = vec![];
+        let mut seen = HashSet::new();
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            subtree.push(id);
+            if let Item::Bucket(bucket) = id {
+                stack.extend(self.children_of(bucket).into_iter().rev());
+            }
+        }
+        subtree
+    }
+
+    /// Every device beneath items along with its CRUSH weight, depth first
+    /// and each device once.  weight is used for the items that are
+    /// devices themselves.
+    pub fn devices_under(&self, items: &[Item], weight: u32) -> Vec<(DeviceId, u32)> {
+        let mut devices = vec![];
+        let mut seen = HashSet::new();
+        let mut stack: Vec<(Item, u32)> = items.iter().rev().map(|i| (*i, weight)).collect();
+        while let Some((item, weight)) = stack.pop() {
+            if !seen.insert(item) {
+                continue;
+            }
+            match item {
+                Item::Device(device) => devices.push((device, weight)),
+                Item::Bucket(bucket) => stack.extend(self.items_of(bucket).into_iter().rev()),
+            }
+        }
+        devices
+    }
+}
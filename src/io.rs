@@ -9,23 +9,46 @@
 //! (although the algorithm has evolved somewhat since then):
 //! http://www.ssrc.ucsc.edu/Papers/weil-sc06.pdf
 //!
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
-use std::io::{self, ErrorKind};
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::Path;
 use std::string::FromUtf8Error;
 
 use byteorder::{LittleEndian, WriteBytesExt};
 use num::FromPrimitive;
-use nom::{IResult, le_u8, le_u16, le_i32, le_u32};
+use nom::{IResult, Needed, le_u8, le_u16, le_i32, le_u32, le_i64};
 
 
 use ::{EncodingError, BucketAlg, RuleType, CrushHash, OpCode, CrushBucketUniform, CrushBucketList,
-       CrushBucketTree, CrushBucketStraw2, CrushBucketStraw, BucketTypes, Bucket, CrushRuleStep,
-       CrushRuleMask, Rule, CrushMap};
+       CrushBucketTree, CrushBucketStraw2, CrushBucketStraw, BucketTypes, Bucket, Step,
+       CrushRuleMask, Rule, CrushMap, ChooseArg, BucketId, DeviceId, Item, Ruleset, TypeId};
 
 
 static CRUSH_MAGIC: u32 = 0x00010000;  /* for detecting algorithm revisions */
 
+// count! reserves room for every element up front.  The counts come from the
+// map itself, so refuse any that the remaining input couldn't hold given the
+// smallest encoding of an element.
+macro_rules! bounded_count (
+    ($i:expr, $submac:ident!( $($args:tt)* ), $count:expr, $min_size:expr) => (
+        {
+            let count: usize = $count;
+            let needed = count.saturating_mul($min_size);
+            if needed > $i.len() {
+                IResult::Incomplete(Needed::Size(needed))
+            } else {
+                count!($i, $submac!($($args)*), count)
+            }
+        }
+    );
+    ($i:expr, $f:expr, $count:expr, $min_size:expr) => (
+        bounded_count!($i, call!($f), $count, $min_size)
+    );
+);
+
 impl EncodingError {
     pub fn new(err: String) -> EncodingError {
         EncodingError::IoError(io::Error::new(ErrorKind::Other, err))
@@ -54,7 +77,7 @@ impl From<io::Error> for EncodingError {
 
 // trait Parse {
 //     fn parse<'a>(input: &'a [u8]) -> IResult<&[u8], Self>;
-//     fn compile(&self) -> Result<Vec<u8>, EncodingError>;
+//     fn compile<W: Write>(&self, w: &mut W) -> Result<(), EncodingError>;
 // }
 
 impl CrushBucketUniform {
@@ -72,12 +95,11 @@ impl CrushBucketUniform {
         )
     }
 
-    fn compile(&self) -> Result<Vec<u8>, EncodingError> {
-        let mut buffer: Vec<u8> = Vec::new();
-        buffer.extend(try!(self.bucket.compile()));
-        try!(buffer.write_u32::<LittleEndian>(self.item_weight));
+    fn compile<W: Write>(&self, w: &mut W) -> Result<(), EncodingError> {
+        try!(self.bucket.compile(w));
+        try!(w.write_u32::<LittleEndian>(self.item_weight));
 
-        Ok(buffer)
+        Ok(())
     }
 }
 
@@ -86,9 +108,9 @@ impl CrushBucketList {
         chain!(
             input,
             bucket: call!(Bucket::parse)~
-            item_weights: count!(
+            item_weights: bounded_count!(
                 pair!(le_u32, le_u32),
-                bucket.size as usize),
+                bucket.size as usize, 8),
             ||{
                 CrushBucketList{
                     bucket: bucket,
@@ -98,16 +120,15 @@ impl CrushBucketList {
         )
     }
 
-    fn compile(&self) -> Result<Vec<u8>, EncodingError> {
-        let mut buffer: Vec<u8> = Vec::new();
-        buffer.extend(try!(self.bucket.compile()));
+    fn compile<W: Write>(&self, w: &mut W) -> Result<(), EncodingError> {
+        try!(self.bucket.compile(w));
 
         for weights in self.item_weights.iter() {
-            try!(buffer.write_u32::<LittleEndian>(weights.0));
-            try!(buffer.write_u32::<LittleEndian>(weights.1));
+            try!(w.write_u32::<LittleEndian>(weights.0));
+            try!(w.write_u32::<LittleEndian>(weights.1));
         }
 
-        Ok(buffer)
+        Ok(())
     }
 }
 
@@ -117,7 +138,7 @@ impl CrushBucketTree {
             input,
             bucket: call!(Bucket::parse)~
             num_nodes: le_u8~
-            node_weights: count!(le_u32, num_nodes as usize),
+            node_weights: bounded_count!(le_u32, num_nodes as usize, 4),
             ||{
                 CrushBucketTree{
                     bucket: bucket,
@@ -127,17 +148,16 @@ impl CrushBucketTree {
             }
         )
     }
-    fn compile(&self) -> Result<Vec<u8>, EncodingError> {
-        let mut buffer: Vec<u8> = Vec::new();
-        buffer.extend(try!(self.bucket.compile()));
+    fn compile<W: Write>(&self, w: &mut W) -> Result<(), EncodingError> {
+        try!(self.bucket.compile(w));
 
-        try!(buffer.write_u8(self.num_nodes));
+        try!(w.write_u8(self.num_nodes));
 
         for weight in self.node_weights.iter() {
-            try!(buffer.write_u32::<LittleEndian>(*weight));
+            try!(w.write_u32::<LittleEndian>(*weight));
         }
 
-        Ok(buffer)
+        Ok(())
     }
 }
 
@@ -146,7 +166,7 @@ impl CrushBucketStraw2 {
         chain!(
             input,
             bucket: call!(Bucket::parse)~
-            item_weights: count!(le_u32, bucket.size as usize),
+            item_weights: bounded_count!(le_u32, bucket.size as usize, 4),
             ||{
                 CrushBucketStraw2{
                     bucket: bucket,
@@ -155,15 +175,14 @@ impl CrushBucketStraw2 {
             }
         )
     }
-    fn compile(&self) -> Result<Vec<u8>, EncodingError> {
-        let mut buffer: Vec<u8> = Vec::new();
-        buffer.extend(try!(self.bucket.compile()));
+    fn compile<W: Write>(&self, w: &mut W) -> Result<(), EncodingError> {
+        try!(self.bucket.compile(w));
 
         for weight in self.item_weights.iter() {
-            try!(buffer.write_u32::<LittleEndian>(*weight));
+            try!(w.write_u32::<LittleEndian>(*weight));
         }
 
-        Ok(buffer)
+        Ok(())
     }
 }
 
@@ -203,7 +222,7 @@ impl CrushBucketStraw {
         chain!(
             input,
             bucket: call!(Bucket::parse)~
-            item_weights: count!(pair!(le_u32, le_u32), bucket.size as usize),
+            item_weights: bounded_count!(pair!(le_u32, le_u32), bucket.size as usize, 8),
             //straws: le_u32,
             ||{
                 CrushBucketStraw{
@@ -214,16 +233,15 @@ impl CrushBucketStraw {
             }
         )
     }
-    fn compile(&self) -> Result<Vec<u8>, EncodingError> {
-        let mut buffer: Vec<u8> = Vec::new();
-        buffer.extend(try!(self.bucket.compile()));
+    fn compile<W: Write>(&self, w: &mut W) -> Result<(), EncodingError> {
+        try!(self.bucket.compile(w));
 
         for weights in self.item_weights.iter() {
-            try!(buffer.write_u32::<LittleEndian>(weights.0));
-            try!(buffer.write_u32::<LittleEndian>(weights.1));
+            try!(w.write_u32::<LittleEndian>(weights.0));
+            try!(w.write_u32::<LittleEndian>(weights.1));
         }
 
-        Ok(buffer)
+        Ok(())
     }
 }
 
@@ -289,8 +307,9 @@ fn try_le_u8(input: &[u8]) -> IResult<&[u8], Option<u8>> {
     }
 }
 
+// The map may end before a tunable but never in the middle of one
 fn try_le_u32(input: &[u8]) -> IResult<&[u8], Option<u32>> {
-    if input.len() < 4 {
+    if input.is_empty() {
         IResult::Done(input, None)
     } else {
         chain!(input,
@@ -306,42 +325,209 @@ fn parse_string(i: &[u8]) -> IResult<&[u8], String> {
     trace!("parse_string input: {:?}", i);
     chain!(i,
         length: decode_32_or_64 ~
-        s: dbg!(take_str!(length)),
+        s: take_str!(length),
         ||{
             s.to_string()
         }
     )
 }
 
-fn parse_string_map(input: &[u8]) -> IResult<&[u8], Vec<(i32, String)>> {
+fn parse_string_map<K: From<i32>>(input: &[u8]) -> IResult<&[u8], Vec<(K, String)>> {
     trace!("parse_string_map input: {:?}", input);
     chain!(input,
         count: le_u32~
-        string_map: dbg!(
-            count!(
-                pair!(le_i32,
-                    dbg!(call!(parse_string))), count as usize)),
+        string_map: bounded_count!(
+            pair!(map!(le_i32, K::from), call!(parse_string)), count as usize, 8),
         ||{
             string_map
         }
     )
 }
 
-pub fn encode_string_map(input: Vec<(i32, String)>) -> Result<Vec<u8>, EncodingError> {
-    let mut buffer = Vec::new();
+pub fn encode_string_map<K, W>(input: &[(K, String)], w: &mut W) -> Result<(), EncodingError>
+    where K: Into<i32> + Copy,
+          W: Write
+{
     // Count
-    try!(buffer.write_u32::<LittleEndian>(input.len() as u32));
+    try!(w.write_u32::<LittleEndian>(input.len() as u32));
 
-    for pair in input.into_iter() {
-        try!(buffer.write_i32::<LittleEndian>(pair.0));
+    for pair in input.iter() {
+        try!(w.write_i32::<LittleEndian>(pair.0.into()));
 
         // String length
-        try!(buffer.write_u32::<LittleEndian>(pair.1.len() as u32));
+        try!(w.write_u32::<LittleEndian>(pair.1.len() as u32));
         // String data
-        buffer.extend(pair.1.into_bytes());
+        try!(w.write_all(pair.1.as_bytes()));
     }
 
-    Ok(buffer)
+    Ok(())
+}
+
+// Sections added in newer releases are only present if there is input left
+fn optional<'a, T, F>(input: &'a [u8], parser: F) -> IResult<&'a [u8], Option<T>>
+    where F: Fn(&'a [u8]) -> IResult<&'a [u8], T>
+{
+    if input.is_empty() {
+        return IResult::Done(input, None);
+    }
+    match parser(input) {
+        IResult::Done(unparsed_data, value) => IResult::Done(unparsed_data, Some(value)),
+        IResult::Incomplete(needed) => IResult::Incomplete(needed),
+        IResult::Error(e) => IResult::Error(e),
+    }
+}
+
+fn parse_int_map<K: From<i32>, V: From<i32>>(input: &[u8]) -> IResult<&[u8], Vec<(K, V)>> {
+    trace!("parse_int_map input: {:?}", input);
+    chain!(input,
+        count: le_u32~
+        int_map: bounded_count!(pair!(map!(le_i32, K::from), map!(le_i32, V::from)),
+                                count as usize,
+                                8),
+        ||{
+            int_map
+        }
+    )
+}
+
+fn parse_class_bucket(input: &[u8]) -> IResult<&[u8], Vec<(BucketId, Vec<(i32, BucketId)>)>> {
+    trace!("parse_class_bucket input: {:?}", input);
+    chain!(input,
+        count: le_u32~
+        class_bucket: bounded_count!(pair!(map!(le_i32, BucketId), call!(parse_int_map)),
+                                     count as usize,
+                                     8),
+        ||{
+            class_bucket
+        }
+    )
+}
+
+// The device classes were added as a group, choose_args follows them
+fn parse_classes(input: &[u8])
+                 -> IResult<&[u8],
+                            (Vec<(DeviceId, i32)>,
+                             Vec<(i32, String)>,
+                             Vec<(BucketId, Vec<(i32, BucketId)>)>)> {
+    chain!(input,
+        class_map: call!(parse_int_map)~
+        class_name: call!(parse_string_map)~
+        class_bucket: call!(parse_class_bucket),
+        ||{
+            (class_map, class_name, class_bucket)
+        }
+    )
+}
+
+fn parse_weight_set(input: &[u8]) -> IResult<&[u8], Vec<u32>> {
+    chain!(input,
+        size: le_u32~
+        weights: bounded_count!(le_u32, size as usize, 4),
+        ||{
+            weights
+        }
+    )
+}
+
+impl ChooseArg {
+    fn parse<'a>(input: &'a [u8]) -> IResult<&[u8], Self> {
+        trace!("choose arg input: {:?}", input);
+        chain!(
+            input,
+            bucket_index: le_u32~
+            weight_set_size: le_u32~
+            weight_set: bounded_count!(call!(parse_weight_set), weight_set_size as usize, 4)~
+            ids_size: le_u32~
+            ids: bounded_count!(le_i32, ids_size as usize, 4),
+            ||{
+                ChooseArg{
+                    bucket_id: BucketId::from_index(bucket_index as usize),
+                    weight_set: weight_set,
+                    ids: ids,
+                }
+            }
+        )
+    }
+    fn compile<W: Write>(&self, w: &mut W) -> Result<(), EncodingError> {
+        try!(w.write_u32::<LittleEndian>(self.bucket_id.index() as u32));
+        try!(w.write_u32::<LittleEndian>(self.weight_set.len() as u32));
+        for weights in self.weight_set.iter() {
+            try!(w.write_u32::<LittleEndian>(weights.len() as u32));
+            for weight in weights.iter() {
+                try!(w.write_u32::<LittleEndian>(*weight));
+            }
+        }
+        try!(w.write_u32::<LittleEndian>(self.ids.len() as u32));
+        for id in self.ids.iter() {
+            try!(w.write_i32::<LittleEndian>(*id));
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_choose_args(input: &[u8]) -> IResult<&[u8], Vec<(i64, Vec<ChooseArg>)>> {
+    trace!("parse_choose_args input: {:?}", input);
+    chain!(input,
+        count: le_u32~
+        choose_args: bounded_count!(
+            pair!(le_i64,
+                chain!(
+                    size: le_u32~
+                    args: bounded_count!(call!(ChooseArg::parse), size as usize, 12),
+                    ||{
+                        args
+                    }
+                )
+            ), count as usize, 12),
+        ||{
+            choose_args
+        }
+    )
+}
+
+pub fn encode_int_map<K, V, W>(input: &[(K, V)], w: &mut W) -> Result<(), EncodingError>
+    where K: Into<i32> + Copy,
+          V: Into<i32> + Copy,
+          W: Write
+{
+    try!(w.write_u32::<LittleEndian>(input.len() as u32));
+
+    for pair in input.iter() {
+        try!(w.write_i32::<LittleEndian>(pair.0.into()));
+        try!(w.write_i32::<LittleEndian>(pair.1.into()));
+    }
+
+    Ok(())
+}
+
+fn encode_class_bucket<W: Write>(input: &[(BucketId, Vec<(i32, BucketId)>)],
+                                  w: &mut W)
+                                  -> Result<(), EncodingError> {
+    try!(w.write_u32::<LittleEndian>(input.len() as u32));
+
+    for pair in input.iter() {
+        try!(w.write_i32::<LittleEndian>(pair.0.into()));
+        try!(encode_int_map(&pair.1, w));
+    }
+
+    Ok(())
+}
+
+fn encode_choose_args<W: Write>(input: &[(i64, Vec<ChooseArg>)],
+                                 w: &mut W)
+                                 -> Result<(), EncodingError> {
+    try!(w.write_u32::<LittleEndian>(input.len() as u32));
+
+    for &(index, ref args) in input.iter() {
+        try!(w.write_i64::<LittleEndian>(index));
+        try!(w.write_u32::<LittleEndian>(args.len() as u32));
+        for arg in args.iter() {
+            try!(arg.compile(w));
+        }
+    }
+
+    Ok(())
 }
 
 fn parse_bucket<'a>(input: &'a [u8]) -> IResult<&[u8], BucketTypes> {
@@ -362,7 +548,7 @@ fn parse_bucket<'a>(input: &'a [u8]) -> IResult<&[u8], BucketTypes> {
                     trace!("Trying to decode uniform bucket");
                     chain!(
                         input,
-                        uniform_bucket: dbg!(call!(CrushBucketUniform::parse)),
+                        uniform_bucket: call!(CrushBucketUniform::parse),
                         ||{
                             BucketTypes::Uniform(uniform_bucket)
                         }
@@ -372,7 +558,7 @@ fn parse_bucket<'a>(input: &'a [u8]) -> IResult<&[u8], BucketTypes> {
                     trace!("Trying to decode list bucket");
                     chain!(
                         input,
-                        list_bucket: dbg!(call!(CrushBucketList::parse)),
+                        list_bucket: call!(CrushBucketList::parse),
                         ||{
                             BucketTypes::List(list_bucket)
                         }
@@ -382,7 +568,7 @@ fn parse_bucket<'a>(input: &'a [u8]) -> IResult<&[u8], BucketTypes> {
                     trace!("Trying to decode tree bucket");
                     chain!(
                         input,
-                        tree_bucket: dbg!(call!(CrushBucketTree::parse)),
+                        tree_bucket: call!(CrushBucketTree::parse),
                         ||{
                             BucketTypes::Tree(tree_bucket)
                         }
@@ -392,7 +578,7 @@ fn parse_bucket<'a>(input: &'a [u8]) -> IResult<&[u8], BucketTypes> {
                     trace!("Trying to decode straw bucket");
                     chain!(
                         input,
-                        straw_bucket: dbg!(call!(CrushBucketStraw::parse)),
+                        straw_bucket: call!(CrushBucketStraw::parse),
                         ||{
                             BucketTypes::Straw(straw_bucket)
                         }
@@ -402,7 +588,7 @@ fn parse_bucket<'a>(input: &'a [u8]) -> IResult<&[u8], BucketTypes> {
                     trace!("Trying to decode straw2 bucket");
                     chain!(
                         input,
-                        straw_bucket: dbg!(call!(CrushBucketStraw2::parse)),
+                        straw_bucket: call!(CrushBucketStraw2::parse),
                         ||{
                             BucketTypes::Straw2(straw_bucket)
                         }
@@ -424,26 +610,26 @@ impl Bucket {
         trace!("bucket input: {:?}", input);
         chain!(
             input,
-            le_u32~
             //switch on algorithm
+            alg_word: le_u32~
             id: le_i32~
-            bucket_type_bits: le_u16 ~
-            bucket_type: expr_opt!(OpCode::from_u16(bucket_type_bits)) ~
-            alg_bits: le_u8~
+            // Any type id is fine.  Its name is resolved later once we
+            // know the type_map
+            bucket_type: le_u16 ~
+            // The algorithm is repeated here and has to match
+            alg_bits: verify!(le_u8, |bits: u8| bits as u32 == alg_word)~
             alg: expr_opt!(BucketAlg::from_u8(alg_bits))~
             hash_bits: le_u8~
             hash: expr_opt!(CrushHash::from_u8(hash_bits))~
             weight: le_u32~
             size: le_u32~
-            items: dbg!(
-                count!(
-                    pair!(le_i32, call!(none)), size as usize
-                )
+            items: bounded_count!(
+                pair!(map!(le_i32, Item::from), call!(none)), size as usize, 4
             ),
             ||{
                 Bucket{
-                    id: id,
-                    bucket_type: bucket_type,
+                    id: BucketId(id),
+                    bucket_type: (TypeId(bucket_type as i32), None),
                     alg: alg,
                     hash: hash,
                     weight: weight,
@@ -455,43 +641,42 @@ impl Bucket {
             }
         )
     }
-    fn compile(&self) -> Result<Vec<u8>, EncodingError> {
-        let mut buffer: Vec<u8> = Vec::new();
-        try!(buffer.write_u32::<LittleEndian>(self.alg.clone() as u32));
-        try!(buffer.write_i32::<LittleEndian>(self.id));
-        try!(buffer.write_u16::<LittleEndian>(self.bucket_type.clone() as u16));
-        try!(buffer.write_u8(self.alg.clone() as u8));
-        try!(buffer.write_u8(self.hash.clone() as u8));
-        try!(buffer.write_u32::<LittleEndian>(self.weight));
-        try!(buffer.write_u32::<LittleEndian>(self.size));
+    fn compile<W: Write>(&self, w: &mut W) -> Result<(), EncodingError> {
+        try!(w.write_u32::<LittleEndian>(self.alg.clone() as u32));
+        try!(w.write_i32::<LittleEndian>(self.id.0));
+        let bucket_type = (self.bucket_type.0).0;
+        if bucket_type < 0 || bucket_type > u16::max_value() as i32 {
+            return Err(EncodingError::InvalidType);
+        }
+        try!(w.write_u16::<LittleEndian>(bucket_type as u16));
+        try!(w.write_u8(self.alg.clone() as u8));
+        try!(w.write_u8(self.hash.clone() as u8));
+        try!(w.write_u32::<LittleEndian>(self.weight));
+        try!(w.write_u32::<LittleEndian>(self.size));
 
         for item in self.items.iter() {
-            try!(buffer.write_i32::<LittleEndian>(item.0));
+            try!(w.write_i32::<LittleEndian>(item.0.id()));
         }
 
-        Ok(buffer)
+        Ok(())
     }
 
-    fn update_name_mapping(&mut self, name_map: &Vec<(i32, String)>) {
-        trace!("Updating name mapping with {:?}", name_map);
-        let mut new_items: Vec<(i32, Option<String>)> = Vec::with_capacity(self.items.len());
-
-        // I want to preserve the vec ordering
-        for item_tuple in self.items.iter_mut() {
-            let mut resolved_item: (i32, Option<String>) = (item_tuple.0, None);
+    // Change bucket_type.1 from None to the type's name
+    fn update_type_mapping(&mut self, type_map: &Vec<(TypeId, String)>) {
+        self.bucket_type.1 = type_map.iter()
+            .find(|t| t.0 == self.bucket_type.0)
+            .map(|t| t.1.clone());
+    }
 
-            for name in name_map {
-                if name.0 == item_tuple.0 {
-                    resolved_item.1 = Some(name.1.clone());
-                }
-            }
-            new_items.push(resolved_item);
+    fn update_name_mapping(&mut self, name_map: &HashMap<Item, &String>) {
+        trace!("Updating name mapping with {:?}", name_map);
+        for item in self.items.iter_mut() {
+            item.1 = name_map.get(&item.0).map(|name| (*name).clone());
         }
-        self.items = new_items;
     }
 }
 
-impl CrushRuleStep {
+impl Step {
     fn parse<'a>(input: &'a [u8]) -> IResult<&[u8], Self> {
         trace!("rule step input: {:?}", input);
         chain!(
@@ -501,49 +686,35 @@ impl CrushRuleStep {
             arg1: le_i32~
             arg2: le_i32,
             ||{
-                CrushRuleStep{
-                    op: op_code,
-                    //These get resolved later once we know the type_map
-                    arg1: (arg1, None),
-                    arg2: (arg2, None),
-                }
+                //The names get resolved later once we know the name_map and type_map
+                Step::from_op(op_code, arg1, arg2)
             }
         )
     }
-    // Change the arg's.1 from None to a proper name
-    fn update_arg_mapping(&mut self, type_map: &Vec<(i32, String)>) {
+    // Name the item a take starts from or the type a choose picks
+    fn update_arg_mapping(&mut self,
+                          name_map: &HashMap<Item, &String>,
+                          type_map: &Vec<(TypeId, String)>) {
         trace!("Updating arg mapping with {:?}", type_map);
-        for tuple in type_map {
-            if tuple.0 == self.arg1.0 {
-                self.arg1.1 = Some(tuple.1.clone());
+        match *self {
+            Step::Take { ref mut item } => {
+                item.1 = name_map.get(&item.0).map(|name| (*name).clone());
             }
-            if tuple.0 == self.arg2.0 {
-                self.arg2.1 = Some(tuple.1.clone());
+            Step::Choose { ref mut bucket_type, .. } => {
+                bucket_type.1 = type_map.iter()
+                    .find(|t| t.0 == bucket_type.0)
+                    .map(|t| t.1.clone());
             }
+            _ => {}
         }
     }
-    fn compile(&self) -> Result<Vec<u8>, EncodingError> {
-        let mut buffer: Vec<u8> = Vec::new();
-        try!(buffer.write_u32::<LittleEndian>(self.op.clone() as u32));
-        try!(buffer.write_i32::<LittleEndian>(self.arg1.0));
-        try!(buffer.write_i32::<LittleEndian>(self.arg2.0));
+    fn compile<W: Write>(&self, w: &mut W) -> Result<(), EncodingError> {
+        let (arg1, arg2) = self.args();
+        try!(w.write_u32::<LittleEndian>(self.op() as u32));
+        try!(w.write_i32::<LittleEndian>(arg1));
+        try!(w.write_i32::<LittleEndian>(arg2));
 
-        Ok(buffer)
-    }
-}
-
-impl fmt::Debug for CrushRuleStep {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f,
-            r#"CrushRuleStep {{
-                op: {:?},
-                arg1: {:?},
-                arg2: {:?},
-            }}"#,
-            self.op,
-            self.arg1,
-            self.arg2,
-        )
+        Ok(())
     }
 }
 
@@ -559,7 +730,7 @@ impl CrushRuleMask {
             max_size: le_u8,
             ||{
                 CrushRuleMask{
-                    ruleset: ruleset,
+                    ruleset: Ruleset(ruleset),
                     rule_type: rule_type,
                     min_size: min_size,
                     max_size: max_size,
@@ -567,14 +738,13 @@ impl CrushRuleMask {
             }
         )
     }
-    fn compile(&self) -> Result<Vec<u8>, EncodingError> {
-        let mut buffer: Vec<u8> = Vec::new();
-        try!(buffer.write_u8(self.ruleset));
-        try!(buffer.write_u8(self.rule_type.clone() as u8));
-        try!(buffer.write_u8(self.min_size));
-        try!(buffer.write_u8(self.max_size));
+    fn compile<W: Write>(&self, w: &mut W) -> Result<(), EncodingError> {
+        try!(w.write_u8(self.ruleset.0));
+        try!(w.write_u8(self.rule_type.clone() as u8));
+        try!(w.write_u8(self.min_size));
+        try!(w.write_u8(self.max_size));
 
-        Ok(buffer)
+        Ok(())
     }
 }
 
@@ -608,8 +778,8 @@ impl Rule {
                         unparsed_data,
                         //Length.  We don't need this because we know how long the Vec is
                         length: le_u32~
-                        mask: dbg!(call!(CrushRuleMask::parse))~
-                        steps: dbg!(count!(call!(CrushRuleStep::parse), length as usize)),
+                        mask: call!(CrushRuleMask::parse)~
+                        steps: bounded_count!(call!(Step::parse), length as usize, 12),
                         ||{
                             Some(Rule{
                                 mask: mask,
@@ -627,32 +797,33 @@ impl Rule {
             }
         }
     }
-    fn compile(&self) -> Result<Vec<u8>, EncodingError> {
-        let mut buffer: Vec<u8> = Vec::new();
+    fn compile<W: Write>(&self, w: &mut W) -> Result<(), EncodingError> {
         // YES
-        try!(buffer.write_u32::<LittleEndian>(1));
+        try!(w.write_u32::<LittleEndian>(1));
 
-        try!(buffer.write_u32::<LittleEndian>(self.steps.len() as u32));
-        buffer.extend(try!(self.mask.compile()));
+        try!(w.write_u32::<LittleEndian>(self.steps.len() as u32));
+        try!(self.mask.compile(w));
         // Steps length
         for step in self.steps.iter() {
-            buffer.extend(try!(step.compile()));
+            try!(step.compile(w));
         }
 
-        Ok(buffer)
+        Ok(())
     }
 }
 
-// Try to update the CrushRuleStep's now that we know the type_map.  I wish Ceph
-// had included the type_map first in the compiled crush so I could skip this workaround.
+// Try to update the Steps now that we know the name_map and type_map.  I wish Ceph
+// had included them first in the compiled crush so I could skip this workaround.
 pub fn update_rule_steps<'a>(rules: &'a mut Vec<Option<Rule>>,
-                             type_map: &Vec<(i32, String)>)
+                             name_map: &Vec<(Item, String)>,
+                             type_map: &Vec<(TypeId, String)>)
                              -> &'a mut Vec<Option<Rule>> {
+    let names: HashMap<Item, &String> = name_map.iter().map(|n| (n.0, &n.1)).collect();
     for rule in rules.iter_mut() {
         match *rule {
             Some(ref mut r) => {
                 for step in r.steps.iter_mut() {
-                    step.update_arg_mapping(type_map)
+                    step.update_arg_mapping(&names, type_map)
                 }
             }
             None => {
@@ -664,27 +835,21 @@ pub fn update_rule_steps<'a>(rules: &'a mut Vec<Option<Rule>>,
 }
 
 pub fn update_buckets<'a>(crush_buckets: &'a mut Vec<BucketTypes>,
-                          name_map: &Vec<(i32, String)>)
+                          name_map: &Vec<(Item, String)>,
+                          type_map: &Vec<(TypeId, String)>)
                           -> &'a mut Vec<BucketTypes> {
+    let names: HashMap<Item, &String> = name_map.iter().map(|n| (n.0, &n.1)).collect();
     for crush_bucket in crush_buckets.iter_mut() {
-        match *crush_bucket {
-            BucketTypes::Uniform(ref mut uniform) => {
-                uniform.bucket.update_name_mapping(name_map);
-            }
-            BucketTypes::List(ref mut list) => {
-                list.bucket.update_name_mapping(name_map);
-            }
-            BucketTypes::Tree(ref mut tree) => {
-                tree.bucket.update_name_mapping(name_map);
-            }
-            BucketTypes::Straw(ref mut straw) => {
-                straw.bucket.update_name_mapping(name_map);
-            }
-            BucketTypes::Straw2(ref mut straw) => {
-                straw.bucket.update_name_mapping(name_map);
-            }
-            BucketTypes::Unknown => {}
-        }
+        let bucket = match *crush_bucket {
+            BucketTypes::Uniform(ref mut uniform) => &mut uniform.bucket,
+            BucketTypes::List(ref mut list) => &mut list.bucket,
+            BucketTypes::Tree(ref mut tree) => &mut tree.bucket,
+            BucketTypes::Straw(ref mut straw) => &mut straw.bucket,
+            BucketTypes::Straw2(ref mut straw) => &mut straw.bucket,
+            BucketTypes::Unknown => continue,
+        };
+        bucket.update_name_mapping(&names);
+        bucket.update_type_mapping(type_map);
     }
     crush_buckets
 }
@@ -709,6 +874,12 @@ impl fmt::Debug for CrushMap {
                 straw_calc_version: {:?},
                 allowed_bucket_algorithms: {:?},
                 chooseleaf_stable: {:?},
+                msr_descents: {:?},
+                msr_collision_tries: {:?},
+                class_map: {:?},
+                class_name: {:?},
+                class_bucket: {:?},
+                choose_args: {:?},
             }}"#,
             self.magic,
             self.max_buckets,
@@ -726,6 +897,12 @@ impl fmt::Debug for CrushMap {
             self.straw_calc_version,
             self.allowed_bucket_algorithms,
             self.chooseleaf_stable,
+            self.msr_descents,
+            self.msr_collision_tries,
+            self.class_map,
+            self.class_name,
+            self.class_bucket,
+            self.choose_args,
         )
     }
 }
@@ -735,20 +912,22 @@ pub fn parse_crushmap<'a>(input: &'a [u8]) -> IResult<&[u8], CrushMap> {
     chain!(
         input,
         //preamble
-        crush_magic: le_u32 ~
+        crush_magic: verify!(le_u32, |magic: u32| magic == CRUSH_MAGIC) ~
 
         max_buckets: le_i32 ~
         max_rules: le_u32 ~
         max_devices: le_i32 ~
 
-        buckets: dbg!(count!(
+        buckets: bounded_count!(
             call!(parse_bucket),
-            max_buckets as usize
-        ))~
-        rules: dbg!(count!(
+            max_buckets as usize,
+            4
+        )~
+        rules: bounded_count!(
             call!(Rule::parse),
-            max_rules as usize
-        ))~
+            max_rules as usize,
+            4
+        )~
         type_map: call!(parse_string_map)~
         name_map: call!(parse_string_map)~
         rule_name_map: call!(parse_string_map)~
@@ -761,8 +940,22 @@ pub fn parse_crushmap<'a>(input: &'a [u8]) -> IResult<&[u8], CrushMap> {
         chooseleaf_vary_r: call!(try_le_u8) ~
         straw_calc_version: call!(try_le_u8) ~
         allowed_bucket_algorithms: call!(try_le_u32) ~
-        chooseleaf_stable: call!(try_le_u8),
+        chooseleaf_stable: call!(try_le_u8) ~
+
+        //Luminous device classes and choose_args
+        classes: call!(optional, parse_classes) ~
+        choose_args: call!(optional, parse_choose_args) ~
+
+        //Squid MSR tunables
+        msr_descents: call!(try_le_u32) ~
+        msr_collision_tries: call!(try_le_u32),
         || {
+            let (class_map, class_name, class_bucket) = match classes {
+                Some((class_map, class_name, class_bucket)) => {
+                    (Some(class_map), Some(class_name), Some(class_bucket))
+                }
+                None => (None, None, None),
+            };
             CrushMap{
                 magic: crush_magic,
                 max_buckets: max_buckets,
@@ -783,146 +976,185 @@ pub fn parse_crushmap<'a>(input: &'a [u8]) -> IResult<&[u8], CrushMap> {
                 straw_calc_version: straw_calc_version,
                 allowed_bucket_algorithms: allowed_bucket_algorithms,
                 chooseleaf_stable: chooseleaf_stable,
+                msr_descents: msr_descents,
+                msr_collision_tries: msr_collision_tries,
+                class_map: class_map,
+                class_name: class_name,
+                class_bucket: class_bucket,
+                choose_args: choose_args,
             }
         }
     )
 }
 
 
-
+/// Decode a compiled map.  Anything past the sections known here is left
+/// out, see decode_crushmap_trailing.
 pub fn decode_crushmap<'a>(input: &'a [u8]) -> Result<CrushMap, String> {
-    let mut result = parse_crushmap(input);
-    match result {
-        IResult::Done(_, ref mut map) => {
+    decode_crushmap_trailing(input).map(|(map, _)| map)
+}
+
+/// Decode a map along with the number of bytes at the end of input that
+/// weren't decoded.  Newer releases may add sections after the ones known
+/// here, which are lost when the map is encoded again.
+pub fn decode_crushmap_trailing<'a>(input: &'a [u8]) -> Result<(CrushMap, usize), String> {
+    match parse_crushmap(input) {
+        IResult::Done(unparsed_data, mut map) => {
             // Resolve the argument types
-            update_rule_steps(&mut map.rules, &map.type_map);
+            update_rule_steps(&mut map.rules, &map.name_map, &map.type_map);
 
-            // Resolve the item names
-            update_buckets(&mut map.buckets, &map.name_map);
+            // Resolve the item names and bucket types
+            update_buckets(&mut map.buckets, &map.name_map, &map.type_map);
 
-            // TODO: Can we get rid of this clone?
-            return Ok(map.clone());
+            Ok((map, unparsed_data.len()))
         }
         IResult::Error(_) => Err("parsing error".to_string()),
         IResult::Incomplete(_) => Err("Incomplete".to_string()),
     }
 }
 
-
-pub fn encode_crushmap(crushmap: CrushMap) -> Result<Vec<u8>, EncodingError> {
-    let mut buffer: Vec<u8> = Vec::new();
-    try!(buffer.write_u32::<LittleEndian>(CRUSH_MAGIC));
-
-    try!(buffer.write_i32::<LittleEndian>(crushmap.max_buckets));
-    try!(buffer.write_u32::<LittleEndian>(crushmap.max_rules));
-    try!(buffer.write_i32::<LittleEndian>(crushmap.max_devices));
-
-    for bucket in crushmap.buckets.iter() {
-        match bucket {
-            &BucketTypes::Uniform(ref uniform) => {
-                trace!("Trying to encode uniform bucket");
-                buffer.extend(try!(uniform.compile()));
-            }
-            &BucketTypes::List(ref list) => {
-                trace!("Trying to encode list bucket");
-                buffer.extend(try!(list.compile()));
-            }
-            &BucketTypes::Tree(ref tree) => {
-                trace!("Trying to encode tree bucket");
-                buffer.extend(try!(tree.compile()));
-            }
-            &BucketTypes::Straw(ref straw) => {
-                trace!("Trying to encode straw bucket");
-                buffer.extend(try!(straw.compile()));
-            }
-            &BucketTypes::Straw2(ref straw) => {
-                trace!("Trying to encode straw2 bucket");
-                buffer.extend(try!(straw.compile()));
-            }
-            &BucketTypes::Unknown => {
-                try!(buffer.write_u32::<LittleEndian>(0));
-            }
+impl CrushMap {
+    /// Decode a map read from r.  The parser needs the whole map at once
+    /// so r is read to the end first.  Fails if anything follows the
+    /// sections known here, see decode_from_trailing to keep going.
+    pub fn decode_from<R: Read>(r: &mut R) -> Result<CrushMap, String> {
+        match try!(CrushMap::decode_from_trailing(r)) {
+            (map, 0) => Ok(map),
+            (_, trailing) => Err(format!("{} bytes at the end weren't decoded", trailing)),
         }
     }
 
-    for rule in crushmap.rules.into_iter() {
-        if rule.is_some() {
-            let unwrapped_rule = rule.unwrap();
-            buffer.extend(try!(unwrapped_rule.compile()));
-        } else {
-            // yes bits == 0
-            try!(buffer.write_u32::<LittleEndian>(0));
-        }
+    /// Same as decode_from but also returns the number of bytes at the end
+    /// that weren't decoded, see decode_crushmap_trailing
+    pub fn decode_from_trailing<R: Read>(r: &mut R) -> Result<(CrushMap, usize), String> {
+        let mut buffer = vec![];
+        try!(r.read_to_end(&mut buffer).map_err(|e| format!("Can't read the crushmap: {}", e)));
+        decode_crushmap_trailing(&buffer)
     }
-    buffer.extend(try!(encode_string_map(crushmap.type_map)));
-    buffer.extend(try!(encode_string_map(crushmap.name_map)));
-    buffer.extend(try!(encode_string_map(crushmap.rule_name_map)));
 
-    match crushmap.choose_local_tries {
-        Some(val) => {
-            try!(buffer.write_u32::<LittleEndian>(val));
-        }
-        None => {
-            try!(buffer.write_u32::<LittleEndian>(0));
-        }
-    };
+    /// Decode the map in the file at path, see decode_from
+    pub fn decode_file<P: AsRef<Path>>(path: P) -> Result<CrushMap, String> {
+        let path = path.as_ref();
+        let mut file = try!(File::open(path)
+            .map_err(|e| format!("Can't open {}: {}", path.display(), e)));
+        CrushMap::decode_from(&mut file).map_err(|e| format!("{}: {}", path.display(), e))
+    }
 
-    match crushmap.choose_local_fallback_tries {
-        Some(val) => {
-            try!(buffer.write_u32::<LittleEndian>(val));
-        }
-        None => {
-            try!(buffer.write_u32::<LittleEndian>(0));
+    /// Encode the map the way Ceph does straight into w.  Wrap files and
+    /// sockets in a BufWriter, every field is a separate write.
+    pub fn encode_to<W: Write>(&self, w: &mut W) -> Result<(), EncodingError> {
+        try!(w.write_u32::<LittleEndian>(CRUSH_MAGIC));
+
+        try!(w.write_i32::<LittleEndian>(self.max_buckets));
+        try!(w.write_u32::<LittleEndian>(self.max_rules));
+        try!(w.write_i32::<LittleEndian>(self.max_devices));
+
+        for bucket in self.buckets.iter() {
+            match bucket {
+                &BucketTypes::Uniform(ref uniform) => {
+                    trace!("Trying to encode uniform bucket");
+                    try!(uniform.compile(w));
+                }
+                &BucketTypes::List(ref list) => {
+                    trace!("Trying to encode list bucket");
+                    try!(list.compile(w));
+                }
+                &BucketTypes::Tree(ref tree) => {
+                    trace!("Trying to encode tree bucket");
+                    try!(tree.compile(w));
+                }
+                &BucketTypes::Straw(ref straw) => {
+                    trace!("Trying to encode straw bucket");
+                    try!(straw.compile(w));
+                }
+                &BucketTypes::Straw2(ref straw) => {
+                    trace!("Trying to encode straw2 bucket");
+                    try!(straw.compile(w));
+                }
+                &BucketTypes::Unknown => {
+                    try!(w.write_u32::<LittleEndian>(0));
+                }
+            }
         }
-    };
-    match crushmap.choose_total_tries {
-        Some(val) => {
-            try!(buffer.write_u32::<LittleEndian>(val));
+
+        for rule in self.rules.iter() {
+            if let Some(ref rule) = *rule {
+                try!(rule.compile(w));
+            } else {
+                // yes bits == 0
+                try!(w.write_u32::<LittleEndian>(0));
+            }
         }
-        None => {
-            try!(buffer.write_u32::<LittleEndian>(0));
+        try!(encode_string_map(&self.type_map, w));
+        try!(encode_string_map(&self.name_map, w));
+        try!(encode_string_map(&self.rule_name_map, w));
+
+        // Each release appended the tunables it introduced, so older maps end
+        // after the last tunable they know about.  Stop where the decoder did.
+        let squid = self.msr_descents.is_some() || self.msr_collision_tries.is_some();
+        let luminous = squid || self.class_map.is_some() || self.class_name.is_some() ||
+                       self.class_bucket.is_some() || self.choose_args.is_some();
+        let present = [self.choose_local_tries.is_some(),
+                       self.choose_local_fallback_tries.is_some(),
+                       self.choose_total_tries.is_some(),
+                       self.chooseleaf_descend_once.is_some(),
+                       self.chooseleaf_vary_r.is_some(),
+                       self.straw_calc_version.is_some(),
+                       self.allowed_bucket_algorithms.is_some(),
+                       self.chooseleaf_stable.is_some()];
+        let tunables = if luminous {
+            present.len()
+        } else {
+            present.iter().rposition(|p| *p).map(|p| p + 1).unwrap_or(0)
+        };
+
+        if tunables > 0 {
+            try!(w.write_u32::<LittleEndian>(self.choose_local_tries.unwrap_or(0)));
         }
-    };
-    match crushmap.chooseleaf_descend_once {
-        Some(val) => {
-            try!(buffer.write_u32::<LittleEndian>(val));
+        if tunables > 1 {
+            try!(w.write_u32::<LittleEndian>(self.choose_local_fallback_tries.unwrap_or(0)));
         }
-        None => {
-            try!(buffer.write_u32::<LittleEndian>(0));
+        if tunables > 2 {
+            try!(w.write_u32::<LittleEndian>(self.choose_total_tries.unwrap_or(0)));
         }
-    };
-    match crushmap.chooseleaf_vary_r {
-        Some(val) => {
-            try!(buffer.write_u8(val));
+        if tunables > 3 {
+            try!(w.write_u32::<LittleEndian>(self.chooseleaf_descend_once.unwrap_or(0)));
         }
-        None => {
-            try!(buffer.write_u8(0));
+        if tunables > 4 {
+            try!(w.write_u8(self.chooseleaf_vary_r.unwrap_or(0)));
         }
-    };
-    match crushmap.straw_calc_version {
-        Some(val) => {
-            try!(buffer.write_u8(val));
+        if tunables > 5 {
+            try!(w.write_u8(self.straw_calc_version.unwrap_or(0)));
         }
-        None => {
-            try!(buffer.write_u8(0));
+        if tunables > 6 {
+            try!(w.write_u32::<LittleEndian>(self.allowed_bucket_algorithms.unwrap_or(0)));
         }
-    };
-    match crushmap.allowed_bucket_algorithms {
-        Some(val) => {
-            try!(buffer.write_u32::<LittleEndian>(val));
+        if tunables > 7 {
+            try!(w.write_u8(self.chooseleaf_stable.unwrap_or(0)));
         }
-        None => {
-            try!(buffer.write_u32::<LittleEndian>(0));
+
+        // Pre-Luminous maps end here
+        if luminous {
+            try!(encode_int_map(self.class_map.as_ref().map(|m| &m[..]).unwrap_or(&[]), w));
+            try!(encode_string_map(self.class_name.as_ref().map(|n| &n[..]).unwrap_or(&[]), w));
+            try!(encode_class_bucket(self.class_bucket.as_ref().map(|b| &b[..]).unwrap_or(&[]),
+                                     w));
         }
-    };
-    match crushmap.chooseleaf_stable {
-        Some(val) => {
-            try!(buffer.write_u8(val));
+        // Luminous maps without a weight-set may end before choose_args
+        if self.choose_args.is_some() || squid {
+            try!(encode_choose_args(self.choose_args.as_ref().map(|a| &a[..]).unwrap_or(&[]), w));
         }
-        None => {
-            try!(buffer.write_u8(0));
+        if squid {
+            try!(w.write_u32::<LittleEndian>(self.msr_descents.unwrap_or(0)));
+            try!(w.write_u32::<LittleEndian>(self.msr_collision_tries.unwrap_or(0)));
         }
+
+        Ok(())
     }
+}
 
+pub fn encode_crushmap(crushmap: CrushMap) -> Result<Vec<u8>, EncodingError> {
+    let mut buffer: Vec<u8> = Vec::new();
+    try!(crushmap.encode_to(&mut buffer));
     Ok(buffer)
 }
@@ -19,8 +19,11 @@ extern crate log;
 extern crate nom;
 extern crate num;
 extern crate rustc_serialize;
+extern crate toml;
 extern crate uuid;
+extern crate yaml_rust;
 
+use std::borrow::Cow;
 use std::io as std_io;
 use std::string::FromUtf8Error;
 
@@ -28,9 +31,60 @@ use uuid::Uuid;
 
 // use rustc_serialize::json;
 
+mod diff;
+mod document;
+mod dot;
+mod edit;
+mod erasure;
+mod explain;
+mod failure;
+/// The extern "C" functions behind include/crushtool.h
+pub mod ffi;
+mod hash;
+mod ids;
+mod index;
 mod io;
-
-pub use io::{encode_crushmap, decode_crushmap};
+mod ln;
+mod mapper;
+mod pool;
+mod reclassify;
+mod rule_builder;
+mod satisfiability;
+mod statistics;
+mod tree;
+mod upmap;
+mod validate;
+mod weight_set;
+
+pub use diff::diff_maps;
+pub use document::{decode_toml, decode_yaml, encode_toml, encode_yaml, from_toml, from_yaml,
+                   to_toml, to_yaml, DocumentBucket, DocumentChooseArg, DocumentChooseArgs,
+                   DocumentDevice, DocumentItem, DocumentRule, DocumentShadow, DocumentStep,
+                   DocumentTunables, DocumentType, MapDocument};
+pub use dot::{to_dot, DotOptions};
+pub use edit::{add_device, create_bucket, move_bucket, remove_item, reweight_device};
+pub use erasure::ErasureCodeProfile;
+pub use explain::{explain, Explanation, ExplanationDisplay};
+pub use failure::{resolve_item, simulate_failure, DeviceMovement, FailureReport,
+                  PlacementChange};
+pub use ids::{BucketId, DeviceId, Item, RuleId, Ruleset, TypeId};
+pub use index::CrushIndex;
+pub use io::{encode_crushmap, decode_crushmap, decode_crushmap_trailing};
+pub use mapper::{check_mappable, choose_args_for, do_rule, do_rule_counting_tries,
+                 do_rule_for_pool, do_rule_traced, do_rule_with_choose_args, find_rule,
+                 default_weights, ChooseTries, RejectReason, TraceEvent, CRUSH_ITEM_NONE,
+                 CRUSH_WEIGHT_IN, DEFAULT_CHOOSE_ARGS};
+pub use pool::Pool;
+pub use reclassify::{compare_maps, Reclassify, RuleComparison};
+pub use rule_builder::{RuleBuilder, RuleBuilderError};
+pub use satisfiability::{check_rule, check_rules, RuleCheck, RuleCheckDisplay, Shortfall,
+                         SizeCheck};
+pub use statistics::{simulate, simulate_counting_tries, UtilizationReport, DeviceUtilization,
+                     DomainUtilization};
+pub use tree::{Tree, TreeEntry};
+pub use upmap::{calc_pg_upmaps, UpmapItem};
+pub use validate::validate;
+pub use weight_set::{optimize_choose_args, WeightSetOptimization};
 
 /// Set the crush tunables to Argonaut
 ///
@@ -131,6 +185,8 @@ enum_from_primitive!{
         Replicated = 1,
         Raid4 = 2, //NOTE: never implemented
         Erasure = 3,
+        MsrFirstN = 4,
+        MsrIndep = 5,
     }
 }
 
@@ -165,7 +221,15 @@ enum_from_primitive!{
         SetChooseLeafTries = 9, /*/ override chooseleaf_descend_once */
         SetChooseLocalTries = 10,
         SetChooseLocalFallbackTries = 11,
-        SetChooseLeafVaryR = 12
+        SetChooseLeafVaryR = 12,
+        SetChooseLeafStable = 13,
+
+        /* MSR ("multi-step retry") rules */
+        SetMSRDescents = 14, /*/ override the number of full descents */
+        SetMSRCollisionTries = 15, /*/ override collision retries per step */
+        /* arg1 = num items to pick
+        / arg2 = type*/
+        ChooseMSR = 16
     }
 }
 
@@ -231,9 +295,20 @@ impl BucketTypes {
         }
     }
 
-    pub fn id(&self) -> i32 {
+    pub fn bucket_mut(&mut self) -> Option<&mut Bucket> {
+        match *self {
+            BucketTypes::Unknown => None,
+            BucketTypes::Uniform(ref mut b) => Some(&mut b.bucket),
+            BucketTypes::List(ref mut b) => Some(&mut b.bucket),
+            BucketTypes::Tree(ref mut b) => Some(&mut b.bucket),
+            BucketTypes::Straw(ref mut b) => Some(&mut b.bucket),
+            BucketTypes::Straw2(ref mut b) => Some(&mut b.bucket),
+        }
+    }
+
+    pub fn id(&self) -> BucketId {
         match *self {
-            BucketTypes::Unknown => 65536,
+            BucketTypes::Unknown => BucketId(65536),
             BucketTypes::Uniform(ref b) => b.bucket.id,
             BucketTypes::List(ref b) => b.bucket.id,
             BucketTypes::Tree(ref b) => b.bucket.id,
@@ -241,14 +316,33 @@ impl BucketTypes {
             BucketTypes::Straw2(ref b) => b.bucket.id,
         }
     }
+
+    /// The 16.16 fixed point weight of each item, in the same order as
+    /// Bucket.items
+    pub fn item_weights(&self) -> Vec<u32> {
+        match *self {
+            BucketTypes::Unknown => vec![],
+            BucketTypes::Uniform(ref b) => vec![b.item_weight; b.bucket.items.len()],
+            BucketTypes::List(ref b) => b.item_weights.iter().map(|w| w.0).collect(),
+            BucketTypes::Tree(ref b) => {
+                // Leaves live at the odd node indexes
+                (0..b.bucket.items.len())
+                    .map(|i| b.node_weights.get(2 * i + 1).cloned().unwrap_or(0))
+                    .collect()
+            }
+            BucketTypes::Straw(ref b) => b.item_weights.iter().map(|w| w.0).collect(),
+            BucketTypes::Straw2(ref b) => b.item_weights.clone(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Eq, Hash, PartialEq, RustcDecodable, RustcEncodable)]
 pub struct Bucket {
     /// this'll be negative
-    pub id: i32,
-    /// non-zero; type=0 is reserved for devices
-    pub bucket_type: OpCode,
+    pub id: BucketId,
+    /// non-zero; type=0 is reserved for devices.  The type id and its
+    /// name in type_map, if it has one
+    pub bucket_type: (TypeId, Option<String>),
     /// Which algorithm to use
     pub alg: BucketAlg,
     /// which hash function to use
@@ -257,7 +351,7 @@ pub struct Bucket {
     pub weight: u32,
     pub size: u32,
     /// num items
-    pub items: Vec<(i32, Option<String>)>,
+    pub items: Vec<(Item, Option<String>)>,
     // cached random permutation: used for uniform bucket and for
     // the linear search fallback for the other bucket types.
     // /
@@ -267,24 +361,210 @@ pub struct Bucket {
     pub perm: u32,
 }
 
+/// How a choose step picks its items
+#[derive(Debug, Clone, Copy, Eq, Hash, PartialEq, RustcDecodable, RustcEncodable)]
+pub enum ChooseMode {
+    /// Fill the result in order, for replicated pools
+    FirstN,
+    /// Keep every other position where it is when one item fails, for
+    /// erasure coded pools
+    Indep,
+    /// As FirstN, then one device beneath each item chosen
+    LeafFirstN,
+    /// As Indep, then one device beneath each item chosen
+    LeafIndep,
+    /// Multi-step retry, which only means something in an MSR rule
+    MSR,
+}
+
+impl ChooseMode {
+    pub fn firstn(&self) -> bool {
+        match *self {
+            ChooseMode::FirstN | ChooseMode::LeafFirstN => true,
+            _ => false,
+        }
+    }
+
+    /// Whether a device is chosen beneath each item as well
+    pub fn leaf(&self) -> bool {
+        match *self {
+            ChooseMode::LeafFirstN | ChooseMode::LeafIndep => true,
+            _ => false,
+        }
+    }
+}
+
 /// CRUSH uses user-defined "rules" to describe how inputs should be
 /// mapped to devices.  A rule consists of sequence of steps to perform
 /// to generate the set of output devices.
 ///
-#[derive(Clone, Eq, PartialEq, RustcDecodable, RustcEncodable)]
-pub struct CrushRuleStep {
-    pub op: OpCode,
-    pub arg1: (i32, Option<String>),
-    pub arg2: (i32, Option<String>),
+/// The item and type names are filled in when a map is decoded and
+/// ignored when it is encoded.
+///
+#[derive(Debug, Clone, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub enum Step {
+    Noop,
+    /// Start from an item
+    Take { item: (Item, Option<String>) },
+    /// Pick num items of bucket_type beneath each item taken or chosen so
+    /// far.  A num of 0 or less is relative to the number of replicas.
+    Choose {
+        mode: ChooseMode,
+        num: i32,
+        bucket_type: (TypeId, Option<String>),
+    },
+    /// Add the items chosen to the result
+    Emit,
+    /// Override choose_total_tries
+    SetChooseTries(i32),
+    /// Override chooseleaf_descend_once
+    SetChooseLeafTries(i32),
+    SetChooseLocalTries(i32),
+    SetChooseLocalFallbackTries(i32),
+    SetChooseLeafVaryR(i32),
+    SetChooseLeafStable(i32),
+    /// Override the number of full descents of an MSR rule
+    SetMSRDescents(i32),
+    /// Override the collision retries per MSR step
+    SetMSRCollisionTries(i32),
+    /// A step written with a value in an argument its op has no use for.
+    /// It is kept as it was so that it encodes the same, see effective
+    /// for the step it acts as.
+    Unknown { op: OpCode, arg1: i32, arg2: i32 },
+}
+
+impl Step {
+    /// The step an op code and its two arguments are encoded as.  Ceph
+    /// writes both arguments for every op and ignores the ones an op has
+    /// no use for.  Steps where those aren't 0 become Step::Unknown.
+    pub fn from_op(op: OpCode, arg1: i32, arg2: i32) -> Step {
+        let step = Step::read_op(op.clone(), arg1, arg2);
+        if step.args() == (arg1, arg2) {
+            step
+        } else {
+            Step::Unknown {
+                op: op,
+                arg1: arg1,
+                arg2: arg2,
+            }
+        }
+    }
+
+    // The step an op acts as, whatever is in the arguments it ignores
+    fn read_op(op: OpCode, arg1: i32, arg2: i32) -> Step {
+        let choose = |mode| {
+            Step::Choose {
+                mode: mode,
+                num: arg1,
+                bucket_type: (TypeId(arg2), None),
+            }
+        };
+        match op {
+            OpCode::Noop => Step::Noop,
+            OpCode::Take => Step::Take { item: (Item::from(arg1), None) },
+            OpCode::ChooseFirstN => choose(ChooseMode::FirstN),
+            OpCode::ChooseIndep => choose(ChooseMode::Indep),
+            OpCode::Emit => Step::Emit,
+            OpCode::ChooseLeafFirstN => choose(ChooseMode::LeafFirstN),
+            OpCode::ChooseLeafIndep => choose(ChooseMode::LeafIndep),
+            OpCode::SetChooseTries => Step::SetChooseTries(arg1),
+            OpCode::SetChooseLeafTries => Step::SetChooseLeafTries(arg1),
+            OpCode::SetChooseLocalTries => Step::SetChooseLocalTries(arg1),
+            OpCode::SetChooseLocalFallbackTries => Step::SetChooseLocalFallbackTries(arg1),
+            OpCode::SetChooseLeafVaryR => Step::SetChooseLeafVaryR(arg1),
+            OpCode::SetChooseLeafStable => Step::SetChooseLeafStable(arg1),
+            OpCode::SetMSRDescents => Step::SetMSRDescents(arg1),
+            OpCode::SetMSRCollisionTries => Step::SetMSRCollisionTries(arg1),
+            OpCode::ChooseMSR => choose(ChooseMode::MSR),
+        }
+    }
+
+    pub fn op(&self) -> OpCode {
+        match *self {
+            Step::Noop => OpCode::Noop,
+            Step::Take { .. } => OpCode::Take,
+            Step::Choose { mode, .. } => {
+                match mode {
+                    ChooseMode::FirstN => OpCode::ChooseFirstN,
+                    ChooseMode::Indep => OpCode::ChooseIndep,
+                    ChooseMode::LeafFirstN => OpCode::ChooseLeafFirstN,
+                    ChooseMode::LeafIndep => OpCode::ChooseLeafIndep,
+                    ChooseMode::MSR => OpCode::ChooseMSR,
+                }
+            }
+            Step::Emit => OpCode::Emit,
+            Step::SetChooseTries(_) => OpCode::SetChooseTries,
+            Step::SetChooseLeafTries(_) => OpCode::SetChooseLeafTries,
+            Step::SetChooseLocalTries(_) => OpCode::SetChooseLocalTries,
+            Step::SetChooseLocalFallbackTries(_) => OpCode::SetChooseLocalFallbackTries,
+            Step::SetChooseLeafVaryR(_) => OpCode::SetChooseLeafVaryR,
+            Step::SetChooseLeafStable(_) => OpCode::SetChooseLeafStable,
+            Step::SetMSRDescents(_) => OpCode::SetMSRDescents,
+            Step::SetMSRCollisionTries(_) => OpCode::SetMSRCollisionTries,
+            Step::Unknown { ref op, .. } => op.clone(),
+        }
+    }
+
+    /// The two arguments the step is encoded with
+    pub fn args(&self) -> (i32, i32) {
+        match *self {
+            Step::Noop | Step::Emit => (0, 0),
+            Step::Take { ref item } => (item.0.id(), 0),
+            Step::Choose { num, ref bucket_type, .. } => (num, (bucket_type.0).0),
+            Step::SetChooseTries(n) |
+            Step::SetChooseLeafTries(n) |
+            Step::SetChooseLocalTries(n) |
+            Step::SetChooseLocalFallbackTries(n) |
+            Step::SetChooseLeafVaryR(n) |
+            Step::SetChooseLeafStable(n) |
+            Step::SetMSRDescents(n) |
+            Step::SetMSRCollisionTries(n) => (n, 0),
+            Step::Unknown { arg1, arg2, .. } => (arg1, arg2),
+        }
+    }
+
+    /// The step as CRUSH carries it out: an Unknown step acts like its op
+    /// with the arguments it has no use for left out
+    pub fn effective(&self) -> Cow<Step> {
+        match *self {
+            Step::Unknown { ref op, arg1, arg2 } => {
+                Cow::Owned(Step::read_op(op.clone(), arg1, arg2))
+            }
+            _ => Cow::Borrowed(self),
+        }
+    }
+
+    /// The item a take step starts from
+    pub fn item(&self) -> Option<Item> {
+        match *self.effective() {
+            Step::Take { ref item } => Some(item.0),
+            _ => None,
+        }
+    }
+
+    /// The type a choose step picks
+    pub fn bucket_type(&self) -> Option<TypeId> {
+        match *self.effective() {
+            Step::Choose { ref bucket_type, .. } => Some(bucket_type.0),
+            _ => None,
+        }
+    }
 }
 
 /// The rule mask is used to describe what the rule is intended for.
 /// Given a ruleset and size of output set, we search through the
 /// rule list for a matching rule_mask.
 ///
+/// Since Luminous a rule is identified by its index in CrushMap.rules,
+/// the rule id, and pools refer to rules by id.  ruleset is still
+/// encoded but is expected to equal the rule id; Quincy and newer write
+/// the rule id here and ignore min_size and max_size.  Maps from older
+/// clusters may have several rules sharing a ruleset, see
+/// CrushMap::has_legacy_rulesets.
+///
 #[derive(Clone, Eq, PartialEq, RustcDecodable, RustcEncodable)]
 pub struct CrushRuleMask {
-    pub ruleset: u8,
+    pub ruleset: Ruleset,
     pub rule_type: RuleType,
     pub min_size: u8,
     pub max_size: u8,
@@ -293,7 +573,29 @@ pub struct CrushRuleMask {
 #[derive(Debug, Clone, Eq, PartialEq, RustcDecodable, RustcEncodable)]
 pub struct Rule {
     pub mask: CrushRuleMask,
-    pub steps: Vec<CrushRuleStep>,
+    pub steps: Vec<Step>,
+}
+
+impl Rule {
+    /// True for the multi-step retry rule types, which Ceph maps with
+    /// crush_msr_do_rule instead of the choose steps
+    pub fn is_msr(&self) -> bool {
+        match self.mask.rule_type {
+            RuleType::MsrFirstN | RuleType::MsrIndep => true,
+            _ => false,
+        }
+    }
+}
+
+/// Overrides for a single straw2 bucket.  weight_set holds one set of
+/// item weights per replica position; the last set is used for any
+/// position beyond the end.  ids replaces the item ids fed into the
+/// hash.  Either may be empty.
+#[derive(Debug, Clone, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct ChooseArg {
+    pub bucket_id: BucketId,
+    pub weight_set: Vec<Vec<u32>>,
+    pub ids: Vec<i32>,
 }
 
 /// CrushMap includes all buckets, rules, etc.
@@ -307,9 +609,9 @@ pub struct CrushMap {
     pub buckets: Vec<BucketTypes>,
     pub rules: Vec<Option<Rule>>,
 
-    pub type_map: Vec<(i32, String)>,
-    pub name_map: Vec<(i32, String)>,
-    pub rule_name_map: Vec<(i32, String)>,
+    pub type_map: Vec<(TypeId, String)>,
+    pub name_map: Vec<(Item, String)>,
+    pub rule_name_map: Vec<(RuleId, String)>,
 
     /// choose local retries before re-descent
     pub choose_local_tries: Option<u32>,
@@ -342,6 +644,23 @@ pub struct CrushMap {
     /// no local retry) so that data migrations would be optimal when some
     /// device fails.
     pub chooseleaf_stable: Option<u8>,
+    /// Squid and newer maps.  How many times an MSR rule descends from
+    /// the top of the hierarchy and how many times each choosemsr step
+    /// retries a collision before it gives up.  They are encoded after
+    /// choose_args, which is why an older decoder doesn't trip over them.
+    pub msr_descents: Option<u32>,
+    pub msr_collision_tries: Option<u32>,
+
+    /// Luminous and newer maps.  The device class of each device
+    pub class_map: Option<Vec<(DeviceId, i32)>>,
+    /// Device class id to name
+    pub class_name: Option<Vec<(i32, String)>>,
+    /// For each bucket id, the shadow bucket id of each device class
+    pub class_bucket: Option<Vec<(BucketId, Vec<(i32, BucketId)>)>>,
+    /// Alternate weights and ids the mapper can use instead of the
+    /// ones in the buckets.  Keyed by pool id, or -1 for the weight-set
+    /// shared by every pool.
+    pub choose_args: Option<Vec<(i64, Vec<ChooseArg>)>>,
 }
 
 impl CrushMap {
@@ -357,20 +676,60 @@ impl CrushMap {
     }
 
     #[inline]
-    pub fn next_bucket_id(&self) -> i32 {
-        self.buckets
+    pub fn next_bucket_id(&self) -> BucketId {
+        BucketId(self.buckets
             .iter()
             .map(|b| b.bucket())
             .filter(|b| b.is_some())
-            .map(|b| b.unwrap().id)
+            .map(|b| b.unwrap().id.0)
             .min()
-            .unwrap_or(0) - 1
+            .unwrap_or(0) - 1)
     }
 
     pub fn add_bucket(mut self, bucket_type: BucketTypes) -> Self {
         self.buckets.push(bucket_type);
         self
     }
+
+    /// The rule with id id
+    pub fn rule(&self, id: RuleId) -> Option<&Rule> {
+        if id.0 < 0 {
+            return None;
+        }
+        match self.rules.get(id.index()) {
+            Some(&Some(ref rule)) => Some(rule),
+            _ => None,
+        }
+    }
+
+    /// The id of the rule named name
+    pub fn rule_id(&self, name: &str) -> Option<RuleId> {
+        self.rule_name_map
+            .iter()
+            .find(|n| n.1 == name && self.rule(n.0).is_some())
+            .map(|n| n.0)
+    }
+
+    /// The ids of the rules in a legacy ruleset, see mapper::find_rule for
+    /// picking one of them by type and size
+    pub fn rules_in(&self, ruleset: Ruleset) -> Vec<RuleId> {
+        self.rules
+            .iter()
+            .enumerate()
+            .filter(|&(_, rule)| rule.as_ref().map(|r| r.mask.ruleset == ruleset).unwrap_or(false))
+            .map(|(id, _)| RuleId(id as i32))
+            .collect()
+    }
+
+    /// True if any rule's ruleset differs from its rule id.  Such a map
+    /// predates Luminous and its pools refer to rules by ruleset, see
+    /// mapper::find_rule.
+    pub fn has_legacy_rulesets(&self) -> bool {
+        self.rules.iter().enumerate().any(|(id, rule)| match *rule {
+            Some(ref rule) => rule.mask.ruleset.0 as usize != id,
+            None => false,
+        })
+    }
 }
 
 impl Default for CrushMap {
@@ -382,17 +741,17 @@ impl Default for CrushMap {
             max_devices: 0,
             buckets: vec![],
             rules: vec![],
-            type_map: vec![(0, "osd".to_string()),
-                           (1, "host".to_string()),
-                           (2, "chassis".to_string()),
-                           (3, "rack".to_string()),
-                           (4, "row".to_string()),
-                           (5, "pdu".to_string()),
-                           (6, "pod".to_string()),
-                           (7, "room".to_string()),
-                           (8, "datacenter".to_string()),
-                           (9, "region".to_string()),
-                           (10, "root".to_string())],
+            type_map: vec![(TypeId(0), "osd".to_string()),
+                           (TypeId(1), "host".to_string()),
+                           (TypeId(2), "chassis".to_string()),
+                           (TypeId(3), "rack".to_string()),
+                           (TypeId(4), "row".to_string()),
+                           (TypeId(5), "pdu".to_string()),
+                           (TypeId(6), "pod".to_string()),
+                           (TypeId(7), "room".to_string()),
+                           (TypeId(8), "datacenter".to_string()),
+                           (TypeId(9), "region".to_string()),
+                           (TypeId(10), "root".to_string())],
             name_map: vec![],
             rule_name_map: vec![],
             choose_local_tries: Some(2),
@@ -403,6 +762,12 @@ impl Default for CrushMap {
             straw_calc_version: Some(0),
             allowed_bucket_algorithms: Some(0),
             chooseleaf_stable: Some(22),
+            msr_descents: None,
+            msr_collision_tries: None,
+            class_map: None,
+            class_name: None,
+            class_bucket: None,
+            choose_args: None,
         }
     }
 }
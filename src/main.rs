@@ -6,80 +6,639 @@ extern crate crushtool;
 extern crate rustc_serialize;
 
 use std::fs::File;
-use std::io::{self, Error};
+use std::io;
 use std::io::prelude::*;
+use std::process;
 
+use rustc_serialize::Encodable;
 use rustc_serialize::json;
 
-use clap::{Arg, App};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 
-use crushtool::{decode_crushmap, encode_crushmap, CrushMap};
-// use crushtool::{CrushMap, BucketTypes, CrushBucketStraw, OpCode, BucketAlg, CrushRuleStep,
-//                 Bucket, CrushRuleMask, CrushHash, Rule, RuleType, CephVersion};
-// use crushtool::{CephCrushMap, CephDisk as Disk, CephHost as Host, CephPool as Pool, CephBucket,
-//                 CephBucketType};
+use crushtool::{add_device, calc_pg_upmaps, check_rules, choose_args_for, compare_maps,
+                create_bucket, decode_crushmap_trailing, decode_toml, decode_yaml,
+                default_weights, diff_maps, encode_crushmap, encode_toml, encode_yaml, explain,
+                move_bucket, optimize_choose_args, remove_item, resolve_item, reweight_device,
+                simulate, simulate_counting_tries, simulate_failure, to_dot, to_toml, to_yaml,
+                validate, CrushMap, DeviceId, DotOptions, Pool, Reclassify, RuleId, Tree,
+                UtilizationReport, DEFAULT_CHOOSE_ARGS};
 
 arg_enum!{
-  enum Mode {
-    compile,
-    decompile
+  enum Format {
+    text,
+    json,
+    yaml,
+    toml
   }
 }
 
-fn main() {
+// validate found problems, diff found differences or a map didn't round-trip
+const EXIT_FOUND: i32 = 1;
+// Anything that kept the command from running
+const EXIT_ERROR: i32 = 2;
 
-    let matches = App::new("crushtool")
-        .version(crate_version!())
-        .arg(Arg::with_name("mode")
-            .short("m")
-            .required(true)
+fn input_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("input")
+        .short("i")
+        .long("input")
+        .help("Crushmap to read, compiled or as JSON, YAML or TOML.  Defaults to stdin")
+        .takes_value(true)
+}
+
+fn output_arg<'a, 'b>(help: &'a str) -> Arg<'a, 'b> {
+    Arg::with_name("output")
+        .short("o")
+        .long("output")
+        .help(help)
+        .takes_value(true)
+}
+
+fn format_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("format")
+        .long("format")
+        .help("Output format, text unless the command says otherwise")
+        .takes_value(true)
+        .possible_values(&Format::variants())
+}
+
+fn report_command<'a, 'b>(name: &str, about: &'a str) -> App<'a, 'b> {
+    SubCommand::with_name(name)
+        .about(about)
+        .arg(input_arg())
+        .arg(output_arg("File to write the results to.  Defaults to stdout"))
+        .arg(format_arg())
+}
+
+fn rule_args<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.arg(Arg::with_name("rule")
+            .long("rule")
+            .help("Rule to place with")
             .takes_value(true)
-            .help("Compile or decompile the crushmap")
-            .possible_values(&Mode::variants()))
-        .arg(Arg::with_name("custom")
-            .short("c")
-            .help("EXPERIMENTAL:: This will read in the custom crushmap syntax"))
-        .arg(Arg::with_name("output")
-            .short("o")
-            .help("Output file to put compiled crushmap into")
-            .takes_value(true))
-        .get_matches();
-
-    // This unwrap is safe because the required is true
-    let mode = value_t!(matches.value_of("mode"), Mode).unwrap();
-
-    match mode {
-        Mode::compile => {
-            let mut input = String::new();
-            io::stdin().read_to_string(&mut input).expect("We couldn't read from STDIN");
-            input = input.trim_right().into();
-
-            let input_map: CrushMap = if matches.is_present("custom") {
-                panic!("Coming soon!")
-            } else {
-                json::decode(&input).expect("The provided crushmap JSON could not be understood")
-            };
-            write_to_file(matches.value_of("output").unwrap_or("crushmap"), input_map)
-                .expect("Failed to write the crushmap to the file")
+            .default_value("0"))
+        .arg(Arg::with_name("num-rep")
+            .long("num-rep")
+            .help("Number of replicas to place for each input")
+            .takes_value(true)
+            .default_value("3"))
+}
+
+fn range_args<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.arg(Arg::with_name("min-x")
+            .long("min-x")
+            .help("First input to place")
+            .takes_value(true)
+            .default_value("0"))
+        .arg(Arg::with_name("max-x")
+            .long("max-x")
+            .help("Last input to place")
+            .takes_value(true)
+            .default_value("1023"))
+}
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("crushtool")
+        .version(crate_version!())
+        .about("Inspect, test and edit Ceph crushmaps")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .after_help("Exits with 0 on success, 1 if validate found problems, diff found \
+                     differences or verify-roundtrip found a mismatch and 2 on errors.")
+        .subcommand(SubCommand::with_name("decompile")
+            .about("Decode a crushmap into JSON, or into YAML or TOML that names items \
+                    instead of numbering them")
+            .arg(input_arg())
+            .arg(output_arg("File to write the map to.  Defaults to stdout"))
+            .arg(format_arg()))
+        .subcommand(SubCommand::with_name("compile")
+            .about("Encode a JSON, YAML or TOML crushmap")
+            .arg(input_arg())
+            .arg(output_arg("File to write the compiled map to.  Defaults to stdout")))
+        .subcommand(report_command("tree", "List the hierarchy like `ceph osd tree`"))
+        .subcommand(range_args(rule_args(report_command("test",
+                                                        "Report how evenly a rule spreads \
+                                                         a range of inputs")))
+            .arg(Arg::with_name("show-choose-tries")
+                .long("show-choose-tries")
+                .help("Include a histogram of how many tries each placement needed")))
+        .subcommand(report_command("validate",
+                                   "List structural problems and rules that can never be \
+                                    fully placed"))
+        .subcommand(range_args(report_command("diff",
+                                              "List what changed between the input and \
+                                               another map"))
+            .arg(Arg::with_name("other")
+                .help("The map to compare the input with")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("mappings")
+                .long("mappings")
+                .help("Also count the mappings that differ for every rule in both maps")))
+        .subcommand(SubCommand::with_name("edit")
+            .about("Change the hierarchy.  Buckets are added first, then devices, then \
+                    buckets are moved, devices reweighted and items removed")
+            .arg(input_arg())
+            .arg(output_arg("File to write the compiled map to.  Defaults to stdout"))
+            .arg(Arg::with_name("add-bucket")
+                .long("add-bucket")
+                .help("Add an empty bucket of a type")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(2)
+                .value_names(&["name", "type"]))
+            .arg(Arg::with_name("add-device")
+                .long("add-device")
+                .help("Add a device to a bucket")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(4)
+                .value_names(&["id", "name", "weight", "parent"]))
+            .arg(Arg::with_name("move")
+                .long("move")
+                .help("Move a bucket beneath another one")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(2)
+                .value_names(&["name", "parent"]))
+            .arg(Arg::with_name("reweight-device")
+                .long("reweight-device")
+                .help("Set the CRUSH weight of a device")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(2)
+                .value_names(&["name", "weight"]))
+            .arg(Arg::with_name("remove-item")
+                .long("remove-item")
+                .help("Remove a device or an empty bucket")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)))
+        .subcommand(report_command("upmap", "Balance pools with pg-upmap-items")
+            .arg(Arg::with_name("pool")
+                .long("pool")
+                .help("A pool to balance as id:pg_num:size:rule")
+                .takes_value(true)
+                .required(true)
+                .multiple(true)
+                .number_of_values(1))
+            .arg(Arg::with_name("upmap-deviation")
+                .long("upmap-deviation")
+                .help("Max number of PGs a device may deviate from its target")
+                .takes_value(true)
+                .default_value("5"))
+            .arg(Arg::with_name("upmap-max")
+                .long("upmap-max")
+                .help("Max number of PG replicas to move")
+                .takes_value(true)
+                .default_value("100")))
+        .subcommand(SubCommand::with_name("optimize")
+            .about("Write a choose_args weight-set that evens out a pool.  The result is \
+                    reported on stderr so the map can be written to stdout")
+            .arg(input_arg())
+            .arg(output_arg("File to write the optimized map to").required(true))
+            .arg(format_arg())
+            .arg(Arg::with_name("pool")
+                .long("pool")
+                .help("The pool to balance as id:pg_num:size:rule")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("iterations")
+                .long("iterations")
+                .help("Max number of weight-set optimization iterations")
+                .takes_value(true)
+                .default_value("50"))
+            .arg(Arg::with_name("choose-args-index")
+                .long("choose-args-index")
+                .help("Which choose_args to optimize: a pool id or -1 for the compat weight-set")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .default_value("-1")))
+        .subcommand(range_args(rule_args(report_command("failure",
+                                                        "Report the impact of marking \
+                                                         devices or buckets out")))
+            .arg(Arg::with_name("mark-out")
+                .long("mark-out")
+                .help("Device or bucket, by id or name, to mark out")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .allow_hyphen_values(true)))
+        .subcommand(report_command("check", "List the rules the hierarchy can't satisfy"))
+        .subcommand(rule_args(report_command("explain", "Trace the placement of a single input"))
+            .arg(Arg::with_name("x")
+                .long("x")
+                .help("Input to explain")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .default_value("0")))
+        .subcommand(range_args(SubCommand::with_name("reclassify")
+                .about("Convert per-class trees to device classes.  The mappings that \
+                        changed are reported on stderr so the map can be written to stdout")
+                .arg(input_arg())
+                .arg(output_arg("File to write the reclassified map to").required(true))
+                .arg(format_arg()))
+            .arg(Arg::with_name("reclassify-root")
+                .long("reclassify-root")
+                .help("A root and a device class: every device beneath the root gets the class")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(2)
+                .value_names(&["root", "class"]))
+            .arg(Arg::with_name("reclassify-bucket")
+                .long("reclassify-bucket")
+                .help("A bucket pattern like %-ssd, a device class and a default parent: \
+                       matching buckets are merged into the bucket % stands for")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(3)
+                .value_names(&["pattern", "class", "default-parent"])))
+        .subcommand(SubCommand::with_name("dot")
+            .about("Draw the hierarchy for Graphviz")
+            .arg(input_arg())
+            .arg(output_arg("File to write the graph to.  Defaults to stdout"))
+            .arg(Arg::with_name("rule")
+                .long("rule")
+                .help("Highlight the roots and failure domains of this rule")
+                .takes_value(true))
+            .arg(Arg::with_name("cluster-by-type")
+                .long("cluster-by-type")
+                .help("Group the items of each type together"))
+            .arg(Arg::with_name("shadow-buckets")
+                .long("shadow-buckets")
+                .help("Draw the device class shadow trees")))
+        .subcommand(SubCommand::with_name("verify-roundtrip")
+            .about("Check that a compiled map is encoded again byte for byte, so it is safe \
+                    to edit with this tool")
+            .arg(input_arg()))
+}
+
+fn main() {
+    let matches = app().get_matches();
+    match run(&matches) {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "crushtool: {}", e);
+            process::exit(EXIT_ERROR);
         }
-        Mode::decompile => {
-            let mut buffer = Vec::new();
-            io::stdin().read_to_end(&mut buffer).expect("Couldn't read from STDIN");
-            let crushmap = decode_crushmap(&buffer)
-                .expect("Could not decode the provided crushmap");
-            println!("{}",
-                     json::encode(&crushmap).expect("Couldn't encode the crushap as JSON"));
+    }
+}
+
+fn read_input(path: Option<&str>) -> Result<Vec<u8>, String> {
+    let mut buffer = Vec::new();
+    match path {
+        Some(path) if path != "-" => {
+            let mut f = try!(File::open(path).map_err(|e| format!("Can't open {}: {}", path, e)));
+            try!(f.read_to_end(&mut buffer).map_err(|e| format!("Can't read {}: {}", path, e)));
+        }
+        _ => {
+            try!(io::stdin()
+                .read_to_end(&mut buffer)
+                .map_err(|e| format!("Can't read from stdin: {}", e)));
         }
     }
+    Ok(buffer)
+}
 
+fn write_output(matches: &ArgMatches, bytes: &[u8]) -> Result<(), String> {
+    match matches.value_of("output") {
+        Some(path) if path != "-" => {
+            let mut f = try!(File::create(path)
+                .map_err(|e| format!("Can't create {}: {}", path, e)));
+            f.write_all(bytes).map_err(|e| format!("Can't write {}: {}", path, e))
+        }
+        _ => io::stdout().write_all(bytes).map_err(|e| format!("Can't write to stdout: {}", e)),
+    }
 }
 
-fn write_to_file(filename: &str, crushmap: CrushMap) -> Result<(), Error> {
+// Reports in JSON, YAML or TOML
+fn encode<T: Encodable>(value: &T, format: Format) -> Result<String, String> {
+    match format {
+        Format::yaml => to_yaml(value),
+        Format::toml => to_toml(value),
+        _ => {
+            json::encode(value)
+                .map(|encoded| encoded + "\n")
+                .map_err(|e| format!("Can't encode as JSON: {}", e))
+        }
+    }
+}
 
-    let compiled_crushmap = encode_crushmap(crushmap)
-        .expect("Could not compile this input into a valid crushmap");
+fn format(matches: &ArgMatches, default: Format) -> Result<Format, String> {
+    match matches.value_of("format") {
+        Some(_) => value_t!(matches, "format", Format).map_err(|e| e.to_string()),
+        None => Ok(default),
+    }
+}
 
-    let mut f = try!(File::create(filename));
-    try!(f.write_all(&compiled_crushmap));
-    Ok(())
+// Write a report as text, or encoded as JSON or YAML
+fn report<T, F>(matches: &ArgMatches, value: &T, text: F) -> Result<(), String>
+    where T: Encodable,
+          F: FnOnce() -> String
+{
+    let out = match try!(format(matches, Format::text)) {
+        Format::text => text() + "\n",
+        format => try!(encode(value, format)),
+    };
+    write_output(matches, out.as_bytes())
+}
+
+// A map decompiled to JSON, or written out as YAML or TOML
+fn parse_map(buffer: &[u8]) -> Result<CrushMap, String> {
+    let text = try!(String::from_utf8(buffer.to_vec())
+        .map_err(|_| "it is neither JSON, YAML nor TOML".to_string()));
+    if text.trim_left().starts_with('{') {
+        return json::decode(&text).map_err(|e| format!("invalid JSON: {}", e));
+    }
+    // TOML starts with a key = value line or a [table]
+    let first = text.lines().map(|l| l.trim()).find(|l| !l.is_empty() && !l.starts_with('#'));
+    match first {
+        Some(line) if line.starts_with('[') || line.split(':').next().unwrap().contains('=') => {
+            decode_toml(&text)
+        }
+        _ => decode_yaml(&text),
+    }
+}
+
+// Decode a compiled map, warning about anything past the sections known
+// here since it is lost when the map is written out again
+fn decode_map(buffer: &[u8]) -> Result<CrushMap, String> {
+    let (map, trailing) = try!(decode_crushmap_trailing(buffer)
+        .map_err(|e| format!("Could not decode the crushmap: {:?}", e)));
+    if trailing > 0 {
+        let _ = writeln!(io::stderr(),
+                         "crushtool: warning: {} bytes at the end of the crushmap weren't decoded",
+                         trailing);
+    }
+    Ok(map)
+}
+
+// A compiled map, told apart by its magic, or a text one
+fn read_map(path: Option<&str>) -> Result<CrushMap, String> {
+    let buffer = try!(read_input(path));
+    if buffer.starts_with(&[0x00, 0x00, 0x01, 0x00]) {
+        decode_map(&buffer)
+    } else {
+        parse_map(&buffer).map_err(|e| format!("Could not decode the crushmap: {}", e))
+    }
+}
+
+fn write_map(matches: &ArgMatches, map: CrushMap) -> Result<(), String> {
+    let compiled = try!(encode_crushmap(map)
+        .map_err(|e| format!("Could not compile the crushmap: {:?}", e)));
+    write_output(matches, &compiled)
+}
+
+fn value<T: ::std::str::FromStr>(matches: &ArgMatches, name: &str) -> Result<T, String> {
+    value_t!(matches, name, T).map_err(|e| e.to_string())
+}
+
+fn values<'a>(matches: &'a ArgMatches, name: &str) -> Vec<&'a str> {
+    matches.values_of(name).map(|v| v.collect()).unwrap_or(vec![])
+}
+
+// Weights are given the way `ceph osd tree` shows them
+fn fixed_point(weight: &str) -> Result<u32, String> {
+    let w: f64 = try!(weight.parse().map_err(|_| format!("{} isn't a weight", weight)));
+    if w < 0.0 || w * 65536.0 > u32::max_value() as f64 {
+        return Err(format!("{} is out of range for a weight", weight));
+    }
+    Ok((w * 65536.0).round() as u32)
+}
+
+fn run(matches: &ArgMatches) -> Result<i32, String> {
+    match matches.subcommand() {
+        ("decompile", Some(matches)) => {
+            let crushmap = try!(read_map(matches.value_of("input")));
+            let out = match try!(format(matches, Format::json)) {
+                Format::text => return Err("decompile writes json, yaml or toml".to_string()),
+                Format::json => try!(encode(&crushmap, Format::json)),
+                Format::yaml => try!(encode_yaml(&crushmap)),
+                Format::toml => try!(encode_toml(&crushmap)),
+            };
+            try!(write_output(matches, out.as_bytes()));
+        }
+        ("compile", Some(matches)) => {
+            let crushmap = try!(read_map(matches.value_of("input")));
+            try!(write_map(matches, crushmap));
+        }
+        ("tree", Some(matches)) => {
+            let crushmap = try!(read_map(matches.value_of("input")));
+            let tree = Tree::new(&crushmap);
+            try!(report(matches, &tree, || tree.to_string()));
+        }
+        ("test", Some(matches)) => {
+            let crushmap = try!(read_map(matches.value_of("input")));
+            let rule = RuleId(try!(value(matches, "rule")));
+            let num_rep: usize = try!(value(matches, "num-rep"));
+            let min_x: i32 = try!(value(matches, "min-x"));
+            let max_x: i32 = try!(value(matches, "max-x"));
+
+            let weights = default_weights(&crushmap);
+            let utilization = if matches.is_present("show-choose-tries") {
+                let (mappings, tries) =
+                    try!(simulate_counting_tries(&crushmap, rule, num_rep, min_x, max_x, &weights));
+                UtilizationReport::new(&crushmap, rule, num_rep, &mappings, &weights)
+                    .with_choose_tries(tries)
+            } else {
+                let mappings = try!(simulate(&crushmap, rule, num_rep, min_x, max_x, &weights));
+                UtilizationReport::new(&crushmap, rule, num_rep, &mappings, &weights)
+            };
+            try!(report(matches, &utilization, || utilization.to_string()));
+        }
+        ("validate", Some(matches)) => {
+            let crushmap = try!(read_map(matches.value_of("input")));
+            let problems = validate(&crushmap);
+            try!(report(matches, &problems, || if problems.is_empty() {
+                "no problems found".to_string()
+            } else {
+                problems.join("\n")
+            }));
+            if !problems.is_empty() {
+                return Ok(EXIT_FOUND);
+            }
+        }
+        ("diff", Some(matches)) => {
+            let before = try!(read_map(matches.value_of("input")));
+            let after = try!(read_map(matches.value_of("other")));
+            let min_x: i32 = try!(value(matches, "min-x"));
+            let max_x: i32 = try!(value(matches, "max-x"));
+
+            let lines = diff_maps(&before, &after);
+            let comparisons = if matches.is_present("mappings") {
+                compare_maps(&before, &after, min_x, max_x)
+            } else {
+                vec![]
+            };
+            try!(report(matches, &(&lines, &comparisons), || {
+                let mut text = lines.clone();
+                text.extend(comparisons.iter().map(|c| c.to_string()));
+                if text.is_empty() {
+                    "no differences".to_string()
+                } else {
+                    text.join("\n")
+                }
+            }));
+            if !lines.is_empty() || comparisons.iter().any(|c| c.mismatched > 0) {
+                return Ok(EXIT_FOUND);
+            }
+        }
+        ("edit", Some(matches)) => {
+            let mut crushmap = try!(read_map(matches.value_of("input")));
+            for bucket in values(matches, "add-bucket").chunks(2) {
+                try!(create_bucket(&mut crushmap, bucket[0], bucket[1]));
+            }
+            for device in values(matches, "add-device").chunks(4) {
+                let id = try!(device[0].parse().map_err(|_| format!("{} isn't an id", device[0])));
+                let weight = try!(fixed_point(device[2]));
+                try!(add_device(&mut crushmap, DeviceId(id), device[1], weight, device[3]));
+            }
+            for bucket in values(matches, "move").chunks(2) {
+                try!(move_bucket(&mut crushmap, bucket[0], bucket[1]));
+            }
+            for device in values(matches, "reweight-device").chunks(2) {
+                let weight = try!(fixed_point(device[1]));
+                try!(reweight_device(&mut crushmap, device[0], weight));
+            }
+            for item in values(matches, "remove-item") {
+                try!(remove_item(&mut crushmap, item));
+            }
+            try!(write_map(matches, crushmap));
+        }
+        ("upmap", Some(matches)) => {
+            let crushmap = try!(read_map(matches.value_of("input")));
+            let pools = try!(values_t!(matches, "pool", Pool).map_err(|e| e.to_string()));
+            let deviation: f64 = try!(value(matches, "upmap-deviation"));
+            let max: usize = try!(value(matches, "upmap-max"));
+
+            let weights = default_weights(&crushmap);
+            let items = try!(calc_pg_upmaps(&crushmap, &pools, deviation, max, &weights));
+            try!(report(matches, &items, || {
+                items.iter().map(|i| i.to_string()).collect::<Vec<String>>().join("\n")
+            }));
+        }
+        ("optimize", Some(matches)) => {
+            let mut crushmap = try!(read_map(matches.value_of("input")));
+            let pool: Pool = try!(value(matches, "pool"));
+            let iterations: usize = try!(value(matches, "iterations"));
+            let index: i64 = try!(value(matches, "choose-args-index"));
+
+            let weights = default_weights(&crushmap);
+            let result =
+                try!(optimize_choose_args(&mut crushmap, &pool, index, iterations, &weights));
+            try!(write_map(matches, crushmap));
+            let report = match try!(format(matches, Format::text)) {
+                Format::text => {
+                    format!("stddev {:.2} -> {:.2} after {} iterations\n",
+                            result.initial_stddev,
+                            result.final_stddev,
+                            result.iterations)
+                }
+                format => try!(encode(&result, format)),
+            };
+            try!(write!(io::stderr(), "{}", report).map_err(|e| e.to_string()));
+        }
+        ("failure", Some(matches)) => {
+            let crushmap = try!(read_map(matches.value_of("input")));
+            let rule = RuleId(try!(value(matches, "rule")));
+            let num_rep: usize = try!(value(matches, "num-rep"));
+            let min_x: i32 = try!(value(matches, "min-x"));
+            let max_x: i32 = try!(value(matches, "max-x"));
+            let mut failed = vec![];
+            for item in values(matches, "mark-out") {
+                failed.push(try!(resolve_item(&crushmap, item)
+                    .ok_or(format!("No device or bucket named {}", item))));
+            }
+
+            let weights = default_weights(&crushmap);
+            let impact =
+                try!(simulate_failure(&crushmap, rule, num_rep, min_x, max_x, &weights, &failed));
+            try!(report(matches, &impact, || impact.to_string()));
+        }
+        ("check", Some(matches)) => {
+            let crushmap = try!(read_map(matches.value_of("input")));
+            let checks = check_rules(&crushmap);
+            try!(report(matches, &checks, || {
+                checks.iter()
+                    .map(|c| c.display(&crushmap).to_string())
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            }));
+        }
+        ("explain", Some(matches)) => {
+            let crushmap = try!(read_map(matches.value_of("input")));
+            let rule = RuleId(try!(value(matches, "rule")));
+            let num_rep: usize = try!(value(matches, "num-rep"));
+            let x: i32 = try!(value(matches, "x"));
+
+            let weights = default_weights(&crushmap);
+            let choose_args = choose_args_for(&crushmap, DEFAULT_CHOOSE_ARGS);
+            let explanation = try!(explain(&crushmap, rule, x, num_rep, &weights, choose_args));
+            try!(report(matches, &explanation, || explanation.display(&crushmap).to_string()));
+        }
+        ("reclassify", Some(matches)) => {
+            let original = try!(read_map(matches.value_of("input")));
+            let min_x: i32 = try!(value(matches, "min-x"));
+            let max_x: i32 = try!(value(matches, "max-x"));
+
+            let mut reclassify = Reclassify::new();
+            for root in values(matches, "reclassify-root").chunks(2) {
+                reclassify = reclassify.root(root[0], root[1]);
+            }
+            for bucket in values(matches, "reclassify-bucket").chunks(3) {
+                reclassify = reclassify.bucket(bucket[0], bucket[1], bucket[2]);
+            }
+            let mut crushmap = original.clone();
+            try!(reclassify.apply(&mut crushmap)
+                .map_err(|e| format!("Could not reclassify the crushmap: {}", e)));
+
+            let comparisons = compare_maps(&original, &crushmap, min_x, max_x);
+            try!(write_map(matches, crushmap));
+            let report = match try!(format(matches, Format::text)) {
+                Format::text => comparisons.iter().map(|c| format!("{}\n", c)).collect(),
+                format => try!(encode(&comparisons, format)),
+            };
+            try!(write!(io::stderr(), "{}", report).map_err(|e| e.to_string()));
+        }
+        ("dot", Some(matches)) => {
+            let crushmap = try!(read_map(matches.value_of("input")));
+            let rule = match matches.value_of("rule") {
+                Some(_) => Some(RuleId(try!(value(matches, "rule")))),
+                None => None,
+            };
+            let options = DotOptions {
+                cluster_by_type: matches.is_present("cluster-by-type"),
+                rule: rule,
+                shadow_buckets: matches.is_present("shadow-buckets"),
+            };
+            try!(write_output(matches, to_dot(&crushmap, &options).as_bytes()));
+        }
+        ("verify-roundtrip", Some(matches)) => {
+            let original = try!(read_input(matches.value_of("input")));
+            if !original.starts_with(&[0x00, 0x00, 0x01, 0x00]) {
+                return Err("verify-roundtrip needs a compiled crushmap".to_string());
+            }
+            let crushmap = try!(decode_map(&original));
+            let encoded = try!(encode_crushmap(crushmap)
+                .map_err(|e| format!("Could not compile the crushmap: {:?}", e)));
+            // Past the end of the shorter one counts as a difference too
+            let mismatch = original.iter()
+                .zip(encoded.iter())
+                .position(|(a, b)| a != b)
+                .or_else(|| if original.len() != encoded.len() {
+                    Some(original.len().min(encoded.len()))
+                } else {
+                    None
+                });
+            match mismatch {
+                Some(offset) => {
+                    println!("mismatch at byte {}: {} bytes read, {} bytes encoded",
+                             offset,
+                             original.len(),
+                             encoded.len());
+                    return Ok(EXIT_FOUND);
+                }
+                None => println!("{} bytes encoded identically", original.len()),
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(0)
 }
@@ -0,0 +1,1478 @@
+//! A port of Ceph's crush/mapper.c
+//!
+//! Given a CrushMap, a rule and an input value x this calculates the
+//! ordered set of devices the input maps to.  The results are intended
+//! to be identical to what a Ceph cluster calculates for the same map.
+//!
+use hash::{crush_hash32_2, crush_hash32_3, crush_hash32_4};
+use ln::crush_ln;
+use ::{BucketTypes, ChooseArg, ChooseMode, CrushMap, OpCode, Rule, RuleId, RuleType, Ruleset,
+       Step};
+
+/// Returned in a result slot when an indep rule could not find an item
+/// for that position.
+pub const CRUSH_ITEM_NONE: i32 = 0x7fffffff;
+const CRUSH_ITEM_UNDEF: i32 = 0x7ffffffe;
+
+/// Device weights are 16.16 fixed point.  A device with this weight is
+/// fully "in".
+pub const CRUSH_WEIGHT_IN: u32 = 0x10000;
+
+/// The choose_args index of the weight-set used by pools without one of
+/// their own, the one `ceph osd crush weight-set create-compat` makes.
+pub const DEFAULT_CHOOSE_ARGS: i64 = -1;
+
+// Scratch space for the uniform bucket permutation cache.
+#[derive(Clone)]
+struct BucketWork {
+    perm_x: u32,
+    perm_n: u32,
+    perm: Vec<u32>,
+}
+
+/// Why a candidate item was turned down
+#[derive(Clone, Debug, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub enum RejectReason {
+    /// Already chosen for another position
+    Collision,
+    /// The device is out, or partially out and lost the draw
+    Out,
+    /// A chooseleaf step found no usable device beneath the item
+    NoLeaf,
+    /// The item doesn't exist or is a device of the wrong type
+    BadItem,
+    /// The bucket has no items
+    EmptyBucket,
+}
+
+/// One thing the mapper did while placing an input, see do_rule_traced
+#[derive(Clone, Debug, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub enum TraceEvent {
+    /// A rule step is about to be executed
+    Step {
+        index: usize,
+        op: OpCode,
+        arg1: i32,
+        arg2: i32,
+    },
+    /// The bucket picked item with r
+    Choose { bucket: i32, r: i32, item: i32 },
+    Reject { item: i32, reason: RejectReason },
+    /// Try again, either within the same bucket or with a new descent from
+    /// the top of the step.  ftotal and flocal count the failures so far.
+    Retry { local: bool, ftotal: u32, flocal: u32 },
+    /// item was chosen for position
+    Placed { position: usize, item: i32 },
+    /// Nothing could be found for position
+    GiveUp { position: usize },
+    /// The working set was added to the result
+    Emit { items: Vec<i32> },
+}
+
+/// How hard the mapper had to work to place items, the same numbers
+/// `crushtool --show-choose-tries` prints.  Inner chooseleaf descents are
+/// counted as well as the outer choose.
+#[derive(Clone, Debug, Default, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct ChooseTries {
+    /// histogram[n] is the number of items that were placed after n
+    /// failed tries
+    pub histogram: Vec<u64>,
+    /// Positions that were given up on once choose_total_tries ran out
+    pub exhausted_total: u64,
+    /// Collisions that used up choose_local_tries and had to start a new
+    /// descent instead of retrying in the same bucket
+    pub exhausted_local: u64,
+}
+
+impl ChooseTries {
+    fn placed(&mut self, ftotal: u32) {
+        let ftotal = ftotal as usize;
+        if self.histogram.len() <= ftotal {
+            self.histogram.resize(ftotal + 1, 0);
+        }
+        self.histogram[ftotal] += 1;
+    }
+
+    /// Add the counts from other
+    pub fn merge(&mut self, other: &ChooseTries) {
+        if self.histogram.len() < other.histogram.len() {
+            self.histogram.resize(other.histogram.len(), 0);
+        }
+        for (count, other) in self.histogram.iter_mut().zip(other.histogram.iter()) {
+            *count += *other;
+        }
+        self.exhausted_total += other.exhausted_total;
+        self.exhausted_local += other.exhausted_local;
+    }
+}
+
+struct Work {
+    buckets: Vec<BucketWork>,
+    // Only collected by do_rule_traced
+    trace: Option<Vec<TraceEvent>>,
+    // Only collected by do_rule_counting_tries
+    tries: Option<ChooseTries>,
+}
+
+impl Work {
+    fn record(&mut self, event: TraceEvent) {
+        if let Some(ref mut trace) = self.trace {
+            trace.push(event);
+        }
+    }
+
+    fn record_step(&mut self, index: usize, step: &Step) {
+        if self.trace.is_some() {
+            let (arg1, arg2) = step.args();
+            self.record(TraceEvent::Step {
+                index: index,
+                op: step.op(),
+                arg1: arg1,
+                arg2: arg2,
+            });
+        }
+    }
+
+    fn record_placed(&mut self, ftotal: u32) {
+        if let Some(ref mut tries) = self.tries {
+            tries.placed(ftotal);
+        }
+    }
+
+    fn new(map: &CrushMap) -> Work {
+        Work {
+            trace: None,
+            tries: None,
+            buckets: map.buckets
+                .iter()
+                .map(|b| {
+                    BucketWork {
+                        perm_x: 0,
+                        perm_n: 0,
+                        perm: vec![0; b.bucket().map(|b| b.size as usize).unwrap_or(0)],
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+// The bucket item refers to.  A bucket whose id doesn't match the slot it
+// was decoded into can't be indexed by its id, so it's as unusable as an
+// empty slot.
+fn bucket_at(map: &CrushMap, item: i32) -> Option<&BucketTypes> {
+    if item >= 0 {
+        return None;
+    }
+    match map.buckets.get((-1 - item) as usize) {
+        Some(b) if b.bucket().map(|b| b.id.0) == Some(item) => Some(b),
+        _ => None,
+    }
+}
+
+fn bucket_perm_choose(bucket: &BucketTypes, work: &mut BucketWork, x: i32, r: i32) -> i32 {
+    let b = bucket.bucket().unwrap();
+    let size = b.size;
+    let pr = (r as u32) % size;
+
+    // start a new permutation if x has changed
+    if work.perm_x != x as u32 || work.perm_n == 0 {
+        work.perm_x = x as u32;
+
+        // optimize common r=0 case
+        if pr == 0 {
+            let s = crush_hash32_3(x as u32, b.id.0 as u32, 0) % size;
+            work.perm[0] = s;
+            // magic value, see below
+            work.perm_n = 0xffff;
+            return b.items[s as usize].0.id();
+        }
+
+        for i in 0..size {
+            work.perm[i as usize] = i;
+        }
+        work.perm_n = 0;
+    } else if work.perm_n == 0xffff {
+        // clean up after the r=0 case above
+        for i in 1..size {
+            work.perm[i as usize] = i;
+        }
+        let first = work.perm[0] as usize;
+        work.perm[first] = 0;
+        work.perm_n = 1;
+    }
+
+    // calculate permutation up to pr
+    while work.perm_n <= pr {
+        let p = work.perm_n;
+        // no point in swapping the final entry
+        if p < size - 1 {
+            let i = crush_hash32_3(x as u32, b.id.0 as u32, p) % (size - p);
+            if i != 0 {
+                work.perm.swap((p + i) as usize, p as usize);
+            }
+        }
+        work.perm_n += 1;
+    }
+    let s = work.perm[pr as usize];
+    b.items[s as usize].0.id()
+}
+
+fn tree_height(n: i32) -> i32 {
+    let mut h = 0;
+    let mut n = n;
+    while (n & 1) == 0 {
+        h += 1;
+        n >>= 1;
+    }
+    h
+}
+
+fn tree_left(x: i32) -> i32 {
+    x - (1 << (tree_height(x) - 1))
+}
+
+fn tree_right(x: i32) -> i32 {
+    x + (1 << (tree_height(x) - 1))
+}
+
+fn generate_exponential_distribution(x: i32, y: i32, z: i32, weight: u32) -> i64 {
+    let u = crush_hash32_3(x as u32, y as u32, z as u32) & 0xffff;
+
+    // the natural log lookup table maps [0,0xffff] (corresponding to real
+    // numbers [1/0x10000, 1] to [0, 0xffffffffffff] (corresponding to real
+    // numbers [-11.090355,0]).
+    let ln = crush_ln(u) as i64 - 0x1000000000000;
+
+    // divide by 16.16 fixed-point weight.  note that the ln value is
+    // negative, so a larger weight means a larger (less negative) value
+    // for draw.
+    ln / weight as i64
+}
+
+fn bucket_choose(bucket: &BucketTypes,
+                 work: &mut BucketWork,
+                 x: i32,
+                 r: i32,
+                 arg: Option<&ChooseArg>,
+                 position: usize)
+                 -> i32 {
+    match *bucket {
+        BucketTypes::Uniform(_) => bucket_perm_choose(bucket, work, x, r),
+        BucketTypes::List(ref list) => {
+            let b = &list.bucket;
+            for i in (0..b.items.len()).rev() {
+                let mut w = crush_hash32_4(x as u32,
+                                           b.items[i].0.id() as u32,
+                                           r as u32,
+                                           b.id.0 as u32) as u64;
+                w &= 0xffff;
+                w *= list.item_weights[i].1 as u64;
+                w >>= 16;
+                if w < list.item_weights[i].0 as u64 {
+                    return b.items[i].0.id();
+                }
+            }
+            b.items[0].0.id()
+        }
+        BucketTypes::Tree(ref tree) => {
+            // start at root
+            let mut n = (tree.num_nodes >> 1) as i32;
+            while n & 1 == 0 {
+                // pick point in [0, w)
+                let w = tree.node_weights[n as usize] as u64;
+                let t = (crush_hash32_4(x as u32, n as u32, r as u32, tree.bucket.id.0 as u32) as
+                         u64 * w) >> 32;
+
+                // descend to the left or right?
+                let l = tree_left(n);
+                if t < tree.node_weights[l as usize] as u64 {
+                    n = l;
+                } else {
+                    n = tree_right(n);
+                }
+            }
+            // With nothing weighing anything beneath a node it keeps going
+            // right, past the last item if the tree isn't full
+            match tree.bucket.items.get((n >> 1) as usize) {
+                Some(item) => item.0.id(),
+                None => tree.bucket.items[0].0.id(),
+            }
+        }
+        BucketTypes::Straw(ref straw) => {
+            let b = &straw.bucket;
+            let mut high = 0;
+            let mut high_draw: u64 = 0;
+            for (i, item) in b.items.iter().enumerate() {
+                let mut draw = (crush_hash32_3(x as u32, item.0.id() as u32, r as u32) &
+                                0xffff) as u64;
+                draw *= straw.item_weights[i].1 as u64;
+                if i == 0 || draw > high_draw {
+                    high = i;
+                    high_draw = draw;
+                }
+            }
+            b.items[high].0.id()
+        }
+        BucketTypes::Straw2(ref straw) => {
+            let b = &straw.bucket;
+            let weights = match arg {
+                Some(arg) if !arg.weight_set.is_empty() => {
+                    &arg.weight_set[::std::cmp::min(position, arg.weight_set.len() - 1)]
+                }
+                _ => &straw.item_weights,
+            };
+            let ids: Vec<i32> = match arg {
+                Some(arg) if !arg.ids.is_empty() => arg.ids.clone(),
+                _ => b.items.iter().map(|i| i.0.id()).collect(),
+            };
+            let mut high = 0;
+            let mut high_draw: i64 = 0;
+            for i in 0..b.items.len() {
+                // An item a short weight_set or ids leaves out can't be chosen
+                let weight = weights.get(i).cloned().unwrap_or(0);
+                let draw = if weight > 0 && i < ids.len() {
+                    generate_exponential_distribution(x, ids[i], r, weight)
+                } else {
+                    i64::min_value()
+                };
+                if i == 0 || draw > high_draw {
+                    high = i;
+                    high_draw = draw;
+                }
+            }
+            b.items[high].0.id()
+        }
+        BucketTypes::Unknown => CRUSH_ITEM_NONE,
+    }
+}
+
+fn is_out(weights: &[u32], item: i32, x: i32) -> bool {
+    if item as usize >= weights.len() {
+        return true;
+    }
+    let weight = weights[item as usize];
+    if weight >= CRUSH_WEIGHT_IN {
+        return false;
+    }
+    if weight == 0 {
+        return true;
+    }
+    (crush_hash32_2(x as u32, item as u32) & 0xffff) >= weight
+}
+
+// The type of item.  Devices are always type 0
+fn item_type(map: &CrushMap, item: i32) -> Option<u16> {
+    if item >= 0 {
+        return Some(0);
+    }
+    bucket_at(map, item).and_then(|b| b.bucket()).map(|b| (b.bucket_type.0).0 as u16)
+}
+
+struct ChooseParams<'a> {
+    weights: &'a [u32],
+    // choose_args indexed by bucket index
+    choose_args: Vec<Option<&'a ChooseArg>>,
+    x: i32,
+    local_retries: u32,
+    local_fallback_retries: u32,
+    vary_r: u32,
+    stable: u32,
+}
+
+fn choose_firstn(map: &CrushMap,
+                 work: &mut Work,
+                 params: &ChooseParams,
+                 bucket: &BucketTypes,
+                 numrep: i32,
+                 item_type_wanted: u16,
+                 out: &mut [i32],
+                 out_pos: usize,
+                 out_size: usize,
+                 tries: u32,
+                 recurse_tries: u32,
+                 recurse_to_leaf: bool,
+                 mut out2: Option<&mut [i32]>,
+                 parent_r: i32)
+                 -> usize {
+    let x = params.x;
+    let mut outpos = out_pos;
+    let mut count = out_size;
+    let mut item = 0;
+
+    let mut rep = if params.stable != 0 { 0 } else { outpos as i32 };
+    while rep < numrep && count > 0 {
+        // keep trying until we get a non-out, non-colliding item
+        let mut ftotal: u32 = 0;
+        let mut skip_rep = false;
+        loop {
+            let mut retry_descent = false;
+            // initial bucket
+            let mut input = bucket;
+
+            // choose through intervening buckets
+            let mut flocal: u32 = 0;
+            loop {
+                let mut collide = false;
+                let mut retry_bucket = false;
+                let r = rep + parent_r + ftotal as i32;
+                let in_bucket = input.bucket().unwrap();
+                let in_idx = in_bucket.id.index();
+
+                let reject;
+                if in_bucket.size == 0 {
+                    work.record(TraceEvent::Reject {
+                        item: in_bucket.id.0,
+                        reason: RejectReason::EmptyBucket,
+                    });
+                    reject = true;
+                } else {
+                    // bucket choose
+                    item = if params.local_fallback_retries > 0 &&
+                              flocal >= (in_bucket.size >> 1) &&
+                              flocal > params.local_fallback_retries {
+                        bucket_perm_choose(input, &mut work.buckets[in_idx], x, r)
+                    } else {
+                        bucket_choose(input,
+                                      &mut work.buckets[in_idx],
+                                      x,
+                                      r,
+                                      params.choose_args[in_idx],
+                                      outpos)
+                    };
+                    work.record(TraceEvent::Choose {
+                        bucket: in_bucket.id.0,
+                        r: r,
+                        item: item,
+                    });
+                    if item >= map.max_devices {
+                        trace!("   bad item {}", item);
+                        work.record(TraceEvent::Reject {
+                            item: item,
+                            reason: RejectReason::BadItem,
+                        });
+                        skip_rep = true;
+                        break;
+                    }
+
+                    // desired type?
+                    let itemtype = match item_type(map, item) {
+                        Some(t) => t,
+                        None => {
+                            trace!("   bad item type {}", item);
+                            work.record(TraceEvent::Reject {
+                                item: item,
+                                reason: RejectReason::BadItem,
+                            });
+                            skip_rep = true;
+                            break;
+                        }
+                    };
+
+                    // keep going?
+                    if itemtype != item_type_wanted {
+                        match bucket_at(map, item) {
+                            Some(b) if item < 0 => {
+                                input = b;
+                                continue;
+                            }
+                            _ => {
+                                trace!("   bad item type {}", itemtype);
+                                work.record(TraceEvent::Reject {
+                                    item: item,
+                                    reason: RejectReason::BadItem,
+                                });
+                                skip_rep = true;
+                                break;
+                            }
+                        }
+                    }
+
+                    // collision?
+                    collide = out[..outpos].iter().any(|o| *o == item);
+                    if collide {
+                        work.record(TraceEvent::Reject {
+                            item: item,
+                            reason: RejectReason::Collision,
+                        });
+                    }
+
+                    let mut rejected = false;
+                    if !collide && recurse_to_leaf {
+                        if item < 0 {
+                            let sub_r = if params.vary_r != 0 {
+                                r >> (params.vary_r - 1)
+                            } else {
+                                0
+                            };
+                            let sub_bucket = bucket_at(map, item).unwrap();
+                            let sub_numrep = if params.stable != 0 {
+                                1
+                            } else {
+                                outpos as i32 + 1
+                            };
+                            let got = choose_firstn(map,
+                                                    work,
+                                                    params,
+                                                    sub_bucket,
+                                                    sub_numrep,
+                                                    0,
+                                                    out2.as_mut().unwrap(),
+                                                    outpos,
+                                                    count,
+                                                    recurse_tries,
+                                                    0,
+                                                    false,
+                                                    None,
+                                                    sub_r);
+                            if got <= outpos {
+                                // didn't get leaf
+                                work.record(TraceEvent::Reject {
+                                    item: item,
+                                    reason: RejectReason::NoLeaf,
+                                });
+                                rejected = true;
+                            }
+                        } else {
+                            // we already have a leaf!
+                            out2.as_mut().unwrap()[outpos] = item;
+                        }
+                    }
+
+                    if !rejected && !collide && itemtype == 0 {
+                        // out?
+                        rejected = is_out(params.weights, item, x);
+                        if rejected {
+                            work.record(TraceEvent::Reject {
+                                item: item,
+                                reason: RejectReason::Out,
+                            });
+                        }
+                    }
+                    reject = rejected;
+                }
+
+                if reject || collide {
+                    ftotal += 1;
+                    flocal += 1;
+
+                    if collide && flocal <= params.local_retries {
+                        // retry locally a few times
+                        retry_bucket = true;
+                    } else if params.local_fallback_retries > 0 &&
+                              flocal <= in_bucket.size + params.local_fallback_retries {
+                        // exhaustive bucket search
+                        retry_bucket = true;
+                    } else if ftotal < tries {
+                        // then retry descent
+                        retry_descent = true;
+                    } else {
+                        // else give up
+                        skip_rep = true;
+                        if let Some(ref mut tries) = work.tries {
+                            tries.exhausted_total += 1;
+                        }
+                    }
+                    if collide && !retry_bucket {
+                        if let Some(ref mut tries) = work.tries {
+                            tries.exhausted_local += 1;
+                        }
+                    }
+                    trace!("  reject {}  collide {}  ftotal {}  flocal {}",
+                           reject,
+                           collide,
+                           ftotal,
+                           flocal);
+                    if retry_bucket || retry_descent {
+                        work.record(TraceEvent::Retry {
+                            local: retry_bucket,
+                            ftotal: ftotal,
+                            flocal: flocal,
+                        });
+                    }
+                }
+                if !retry_bucket {
+                    break;
+                }
+            }
+            if !retry_descent {
+                break;
+            }
+        }
+
+        rep += 1;
+        if skip_rep {
+            trace!("skip rep");
+            work.record(TraceEvent::GiveUp { position: outpos });
+            continue;
+        }
+
+        trace!("CHOOSE got {}", item);
+        work.record(TraceEvent::Placed {
+            position: outpos,
+            item: item,
+        });
+        work.record_placed(ftotal);
+        out[outpos] = item;
+        outpos += 1;
+        count -= 1;
+    }
+    trace!("CHOOSE returns {}", outpos);
+    outpos
+}
+
+fn choose_indep(map: &CrushMap,
+                work: &mut Work,
+                params: &ChooseParams,
+                bucket: &BucketTypes,
+                left: usize,
+                numrep: i32,
+                item_type_wanted: u16,
+                out: &mut [i32],
+                outpos: usize,
+                tries: u32,
+                recurse_tries: u32,
+                recurse_to_leaf: bool,
+                mut out2: Option<&mut [i32]>,
+                parent_r: i32) {
+    let x = params.x;
+    let mut left = left;
+    let endpos = outpos + left;
+
+    // initially my result is undefined
+    for rep in outpos..endpos {
+        out[rep] = CRUSH_ITEM_UNDEF;
+        if let Some(ref mut out2) = out2 {
+            out2[rep] = CRUSH_ITEM_UNDEF;
+        }
+    }
+
+    let mut ftotal = 0;
+    while left > 0 && ftotal < tries {
+        if ftotal > 0 {
+            work.record(TraceEvent::Retry {
+                local: false,
+                ftotal: ftotal,
+                flocal: 0,
+            });
+        }
+        for rep in outpos..endpos {
+            if out[rep] != CRUSH_ITEM_UNDEF {
+                continue;
+            }
+            // initial bucket
+            let mut input = bucket;
+
+            // choose through intervening buckets
+            loop {
+                // note: we base the choice on the position even in the
+                // nested call.  that means that if the first layer chooses
+                // the same bucket in a different position, we will tend to
+                // choose a different item in that bucket.  this will
+                // involve more devices in data movement and tend to
+                // distribute the load.
+                let in_bucket = input.bucket().unwrap();
+                let mut r = rep as i32 + parent_r;
+
+                // be careful
+                match *input {
+                    BucketTypes::Uniform(_) if in_bucket.size % numrep as u32 == 0 => {
+                        // r'=r+(n+1)*f_total
+                        r += (numrep + 1) * ftotal as i32;
+                    }
+                    _ => {
+                        // r' = r + n*f_total
+                        r += numrep * ftotal as i32;
+                    }
+                }
+
+                // bucket choose
+                if in_bucket.size == 0 {
+                    trace!("   empty bucket");
+                    work.record(TraceEvent::Reject {
+                        item: in_bucket.id.0,
+                        reason: RejectReason::EmptyBucket,
+                    });
+                    break;
+                }
+
+                let in_idx = in_bucket.id.index();
+                let item = bucket_choose(input,
+                                         &mut work.buckets[in_idx],
+                                         x,
+                                         r,
+                                         params.choose_args[in_idx],
+                                         outpos);
+                work.record(TraceEvent::Choose {
+                    bucket: in_bucket.id.0,
+                    r: r,
+                    item: item,
+                });
+                let itemtype = if item >= map.max_devices {
+                    None
+                } else {
+                    item_type(map, item)
+                };
+                let itemtype = match itemtype {
+                    Some(t) => t,
+                    None => {
+                        trace!("   bad item {}", item);
+                        work.record(TraceEvent::Reject {
+                            item: item,
+                            reason: RejectReason::BadItem,
+                        });
+                        out[rep] = CRUSH_ITEM_NONE;
+                        if let Some(ref mut out2) = out2 {
+                            out2[rep] = CRUSH_ITEM_NONE;
+                        }
+                        left -= 1;
+                        break;
+                    }
+                };
+
+                // keep going?
+                if itemtype != item_type_wanted {
+                    match bucket_at(map, item) {
+                        Some(b) if item < 0 => {
+                            input = b;
+                            continue;
+                        }
+                        _ => {
+                            trace!("   bad item type {}", itemtype);
+                            work.record(TraceEvent::Reject {
+                                item: item,
+                                reason: RejectReason::BadItem,
+                            });
+                            out[rep] = CRUSH_ITEM_NONE;
+                            if let Some(ref mut out2) = out2 {
+                                out2[rep] = CRUSH_ITEM_NONE;
+                            }
+                            left -= 1;
+                            break;
+                        }
+                    }
+                }
+
+                // collision?
+                if out[outpos..endpos].iter().any(|o| *o == item) {
+                    work.record(TraceEvent::Reject {
+                        item: item,
+                        reason: RejectReason::Collision,
+                    });
+                    break;
+                }
+
+                if recurse_to_leaf {
+                    let out2 = out2.as_mut().unwrap();
+                    if item < 0 {
+                        choose_indep(map,
+                                     work,
+                                     params,
+                                     bucket_at(map, item).unwrap(),
+                                     1,
+                                     numrep,
+                                     0,
+                                     out2,
+                                     rep,
+                                     recurse_tries,
+                                     0,
+                                     false,
+                                     None,
+                                     r);
+                        if out2[rep] == CRUSH_ITEM_NONE {
+                            // placed nothing; no leaf
+                            work.record(TraceEvent::Reject {
+                                item: item,
+                                reason: RejectReason::NoLeaf,
+                            });
+                            break;
+                        }
+                    } else {
+                        // we already have a leaf!
+                        out2[rep] = item;
+                    }
+                }
+
+                // out?
+                if itemtype == 0 && is_out(params.weights, item, x) {
+                    work.record(TraceEvent::Reject {
+                        item: item,
+                        reason: RejectReason::Out,
+                    });
+                    break;
+                }
+
+                // yay!
+                work.record(TraceEvent::Placed {
+                    position: rep,
+                    item: item,
+                });
+                work.record_placed(ftotal);
+                out[rep] = item;
+                left -= 1;
+                break;
+            }
+        }
+        ftotal += 1;
+    }
+    for rep in outpos..endpos {
+        if out[rep] == CRUSH_ITEM_UNDEF {
+            work.record(TraceEvent::GiveUp { position: rep });
+            if let Some(ref mut tries) = work.tries {
+                tries.exhausted_total += 1;
+            }
+            out[rep] = CRUSH_ITEM_NONE;
+        }
+        if let Some(ref mut out2) = out2 {
+            if out2[rep] == CRUSH_ITEM_UNDEF {
+                out2[rep] = CRUSH_ITEM_NONE;
+            }
+        }
+    }
+}
+
+// MSR ("multi-step retry") rules, crush_msr_do_rule.  Instead of retrying
+// within the bucket a collision or an out device was found in, an MSR rule
+// descends from the take again, up to msr_descents times, and fills only
+// the positions still empty.  Every choosemsr step picks that many items
+// of its type beneath each item the step before picked, so a take ...
+// emit block fills the product of their counts.  A position is only ever
+// filled once, and an item picked for a step keeps its place as soon as a
+// device beneath it does.
+
+/// Ceph's defaults for maps without the MSR tunables
+const MSR_DESCENTS: u32 = 100;
+const MSR_COLLISION_TRIES: u32 = 100;
+
+struct MsrParams<'a> {
+    weights: &'a [u32],
+    // choose_args indexed by bucket index
+    choose_args: Vec<Option<&'a ChooseArg>>,
+    x: i32,
+    collision_tries: u32,
+    // (items to pick, type) of each choosemsr step, top down
+    steps: Vec<(usize, u16)>,
+    // How many positions each item a step picks fills
+    spans: Vec<usize>,
+}
+
+// The progress of one take ... emit block.  picked[k][p] is the item step
+// k picked for path p, the index of the item among everything step k
+// picks.  out holds the positions of the block.
+struct MsrState {
+    picked: Vec<Vec<i32>>,
+    out: Vec<i32>,
+    // Emitted by the blocks before this one
+    earlier: Vec<i32>,
+    descent: u32,
+}
+
+impl MsrState {
+    fn filled(&self, start: usize, end: usize) -> usize {
+        self.out[start..end].iter().filter(|o| **o != CRUSH_ITEM_UNDEF).count()
+    }
+
+    // Forget what the steps below step picked beneath path
+    fn forget_below(&mut self, params: &MsrParams, step: usize, path: usize) {
+        let mut first = path;
+        let mut count = 1;
+        for k in step + 1..params.steps.len() {
+            first *= params.steps[k].0;
+            count *= params.steps[k].0;
+            for p in first..first + count {
+                self.picked[k][p] = CRUSH_ITEM_UNDEF;
+            }
+        }
+    }
+}
+
+// From bucket down to the first item of type wanted, like the loop in
+// choose_indep
+fn msr_descend(map: &CrushMap,
+               work: &mut Work,
+               params: &MsrParams,
+               bucket: &BucketTypes,
+               wanted: u16,
+               r: i32,
+               position: usize)
+               -> Option<i32> {
+    let mut input = bucket;
+    loop {
+        let in_bucket = input.bucket().unwrap();
+        if in_bucket.size == 0 {
+            work.record(TraceEvent::Reject {
+                item: in_bucket.id.0,
+                reason: RejectReason::EmptyBucket,
+            });
+            return None;
+        }
+        let in_idx = in_bucket.id.index();
+        let item = bucket_choose(input,
+                                 &mut work.buckets[in_idx],
+                                 params.x,
+                                 r,
+                                 params.choose_args[in_idx],
+                                 position);
+        work.record(TraceEvent::Choose {
+            bucket: in_bucket.id.0,
+            r: r,
+            item: item,
+        });
+        let itemtype = if item >= map.max_devices {
+            None
+        } else {
+            item_type(map, item)
+        };
+        match itemtype {
+            Some(t) if t == wanted => return Some(item),
+            Some(_) if item < 0 => input = bucket_at(map, item).unwrap(),
+            _ => {
+                trace!("   bad item {}", item);
+                work.record(TraceEvent::Reject {
+                    item: item,
+                    reason: RejectReason::BadItem,
+                });
+                return None;
+            }
+        }
+    }
+}
+
+// Fill the empty positions beneath bucket, which the step before step
+// picked for path
+fn msr_choose(map: &CrushMap,
+              work: &mut Work,
+              params: &MsrParams,
+              state: &mut MsrState,
+              step: usize,
+              bucket: &BucketTypes,
+              path: usize) {
+    let (num, wanted) = params.steps[step];
+    let span = params.spans[step];
+    let last = step + 1 == params.steps.len();
+    for i in 0..num {
+        let p = path * num + i;
+        let start = p * span;
+        if start >= state.out.len() {
+            break;
+        }
+        let end = ::std::cmp::min(start + span, state.out.len());
+        let filled = state.filled(start, end);
+        if filled == end - start {
+            continue;
+        }
+        if filled > 0 {
+            // Some of the positions beneath it are placed, so the item stays
+            let item = state.picked[step][p];
+            msr_choose(map, work, params, state, step + 1, bucket_at(map, item).unwrap(), p);
+            continue;
+        }
+        state.picked[step][p] = CRUSH_ITEM_UNDEF;
+        state.forget_below(params, step, p);
+
+        let tries = state.descent.wrapping_mul(params.collision_tries);
+        for t in 0..params.collision_tries {
+            let r = (i as u32).wrapping_add((num as u32).wrapping_mul(tries.wrapping_add(t)));
+            let item = match msr_descend(map, work, params, bucket, wanted, r as i32, start) {
+                Some(item) => item,
+                None => continue,
+            };
+            if state.picked[step].contains(&item) || (last && state.earlier.contains(&item)) {
+                work.record(TraceEvent::Reject {
+                    item: item,
+                    reason: RejectReason::Collision,
+                });
+                continue;
+            }
+            if last {
+                if wanted == 0 && is_out(params.weights, item, params.x) {
+                    work.record(TraceEvent::Reject {
+                        item: item,
+                        reason: RejectReason::Out,
+                    });
+                    continue;
+                }
+                work.record(TraceEvent::Placed {
+                    position: state.earlier.len() + start,
+                    item: item,
+                });
+                work.record_placed(state.descent);
+                state.picked[step][p] = item;
+                state.out[start] = item;
+                break;
+            }
+            let below = match bucket_at(map, item) {
+                Some(below) => below,
+                None => {
+                    // A device can't be chosen beneath
+                    work.record(TraceEvent::Reject {
+                        item: item,
+                        reason: RejectReason::BadItem,
+                    });
+                    continue;
+                }
+            };
+            state.picked[step][p] = item;
+            msr_choose(map, work, params, state, step + 1, below, p);
+            if state.filled(start, end) > 0 {
+                break;
+            }
+            // Nothing usable beneath it.  Pick another.
+            work.record(TraceEvent::Reject {
+                item: item,
+                reason: RejectReason::NoLeaf,
+            });
+            state.picked[step][p] = CRUSH_ITEM_UNDEF;
+            state.forget_below(params, step, p);
+        }
+    }
+}
+
+// Run the choosemsr steps of a block beneath take and return what it
+// emits
+fn msr_block(map: &CrushMap,
+             work: &mut Work,
+             params: &MsrParams,
+             take: &BucketTypes,
+             descents: u32,
+             firstn: bool,
+             earlier: &[i32],
+             room: usize)
+             -> Vec<i32> {
+    let mut state = MsrState {
+        picked: vec![],
+        out: vec![CRUSH_ITEM_UNDEF; ::std::cmp::min(params.spans[0] * params.steps[0].0, room)],
+        earlier: earlier.to_vec(),
+        descent: 0,
+    };
+    let mut paths = 1;
+    for &(num, _) in params.steps.iter() {
+        paths *= num;
+        state.picked.push(vec![CRUSH_ITEM_UNDEF; paths]);
+    }
+    while state.descent < descents && state.out.contains(&CRUSH_ITEM_UNDEF) {
+        if state.descent > 0 {
+            work.record(TraceEvent::Retry {
+                local: false,
+                ftotal: state.descent,
+                flocal: 0,
+            });
+        }
+        msr_choose(map, work, params, &mut state, 0, take, 0);
+        state.descent += 1;
+    }
+    let earlier = earlier.len();
+    for (position, item) in state.out.iter_mut().enumerate() {
+        if *item == CRUSH_ITEM_UNDEF {
+            work.record(TraceEvent::GiveUp { position: earlier + position });
+            if let Some(ref mut tries) = work.tries {
+                tries.exhausted_total += 1;
+            }
+            *item = CRUSH_ITEM_NONE;
+        }
+    }
+    // msr_firstn leaves out what it couldn't place, msr_indep keeps the
+    // positions
+    if firstn {
+        state.out.retain(|o| *o != CRUSH_ITEM_NONE);
+    }
+    state.out
+}
+
+fn msr_do_rule(map: &CrushMap,
+               rule: &Rule,
+               x: i32,
+               result_max: usize,
+               weights: &[u32],
+               choose_args: Vec<Option<&ChooseArg>>,
+               work: &mut Work)
+               -> Vec<i32> {
+    let mut result: Vec<i32> = Vec::with_capacity(result_max);
+    let mut descents = map.msr_descents.unwrap_or(MSR_DESCENTS);
+    let mut params = MsrParams {
+        weights: weights,
+        choose_args: choose_args,
+        x: x,
+        collision_tries: map.msr_collision_tries.unwrap_or(MSR_COLLISION_TRIES),
+        steps: vec![],
+        spans: vec![],
+    };
+    let firstn = rule.mask.rule_type == RuleType::MsrFirstN;
+    let mut take = None;
+
+    for (index, step) in rule.steps.iter().enumerate() {
+        work.record_step(index, step);
+        match *step.effective() {
+            Step::Take { ref item } => {
+                take = bucket_at(map, item.0.id());
+                if take.is_none() {
+                    trace!(" bad take value {}", item.0.id());
+                }
+                params.steps.clear();
+            }
+            Step::SetMSRDescents(n) => {
+                if n > 0 {
+                    descents = n as u32;
+                }
+            }
+            Step::SetMSRCollisionTries(n) => {
+                if n > 0 {
+                    params.collision_tries = n as u32;
+                }
+            }
+            Step::Choose { mode: ChooseMode::MSR, num, ref bucket_type } => {
+                let numrep = if num <= 0 { num + result_max as i32 } else { num };
+                params.steps.push((numrep.max(0) as usize, (bucket_type.0).0 as u16));
+            }
+            Step::Emit => {
+                let start = result.len();
+                let room = result_max - start;
+                let placeable = !params.steps.is_empty() && params.steps.iter().all(|s| s.0 > 0);
+                match take {
+                    Some(bucket) if placeable && room > 0 => {
+                        params.spans = (0..params.steps.len())
+                            .map(|k| params.steps[k + 1..].iter().map(|s| s.0).product())
+                            .collect();
+                        let emitted =
+                            msr_block(map, work, &params, bucket, descents, firstn, &result, room);
+                        result.extend(emitted);
+                    }
+                    _ => {}
+                }
+                if work.trace.is_some() {
+                    work.record(TraceEvent::Emit { items: result[start..].to_vec() });
+                }
+                take = None;
+                params.steps.clear();
+            }
+            // Only choosemsr steps mean something in an MSR rule
+            _ => {}
+        }
+    }
+    result
+}
+
+/// Find a rule matching the ruleset, rule type and output size.  Only maps
+/// from before Luminous need this; since then pools name the rule id
+/// itself.
+pub fn find_rule(map: &CrushMap,
+                 ruleset: Ruleset,
+                 rule_type: RuleType,
+                 size: u8)
+                 -> Option<RuleId> {
+    map.rules
+        .iter()
+        .position(|rule| match *rule {
+            Some(ref r) => {
+                r.mask.ruleset == ruleset && r.mask.rule_type == rule_type &&
+                r.mask.min_size <= size && r.mask.max_size >= size
+            }
+            None => false,
+        })
+        .map(|id| RuleId(id as i32))
+}
+
+/// Err if there is no rule with id ruleno
+pub fn check_mappable(map: &CrushMap, ruleno: RuleId) -> Result<(), String> {
+    match map.rule(ruleno) {
+        Some(_) => Ok(()),
+        None => Err(format!("No rule {}", ruleno)),
+    }
+}
+
+/// The default device weight vector: every device fully in.
+pub fn default_weights(map: &CrushMap) -> Vec<u32> {
+    vec![CRUSH_WEIGHT_IN; map.max_devices.max(0) as usize]
+}
+
+/// The weight-set in map.choose_args a pool places with: the one stored
+/// under the pool's id, else the DEFAULT_CHOOSE_ARGS one, else none.  This
+/// is the fallback Ceph's CrushWrapper::choose_args_get_with_fallback does.
+pub fn choose_args_for(map: &CrushMap, pool: i64) -> &[ChooseArg] {
+    let find = |index: i64| {
+        map.choose_args
+            .as_ref()
+            .and_then(|all| all.iter().find(|a| a.0 == index))
+            .map(|a| &a.1[..])
+    };
+    find(pool).or_else(|| find(DEFAULT_CHOOSE_ARGS)).unwrap_or(&[])
+}
+
+/// Calculate a mapping with the given rule.
+///
+/// * `ruleno`: the rule's id
+/// * `x`: the input to map.  For Ceph this is the placement seed of a PG
+/// * `result_max`: the maximum number of items to return
+/// * `weights`: 16.16 fixed point device weights (the osd reweight
+///   values) indexed by device id.  Devices beyond the end are out.
+///
+/// Straw2 buckets use the DEFAULT_CHOOSE_ARGS weight-set in
+/// map.choose_args if there is one, see do_rule_for_pool for a pool's own.
+/// Indep rules return CRUSH_ITEM_NONE for any position that could not
+/// be filled so that the positions of the other items are preserved, and
+/// so do msr_indep rules.
+pub fn do_rule(map: &CrushMap,
+               ruleno: RuleId,
+               x: i32,
+               result_max: usize,
+               weights: &[u32])
+               -> Vec<i32> {
+    do_rule_for_pool(map, DEFAULT_CHOOSE_ARGS, ruleno, x, result_max, weights)
+}
+
+/// Same as do_rule but with the weight-set choose_args_for picks for pool
+pub fn do_rule_for_pool(map: &CrushMap,
+                        pool: i64,
+                        ruleno: RuleId,
+                        x: i32,
+                        result_max: usize,
+                        weights: &[u32])
+                        -> Vec<i32> {
+    let choose_args = choose_args_for(map, pool);
+    do_rule_with_choose_args(map, ruleno, x, result_max, weights, choose_args)
+}
+
+/// Same as do_rule but straw2 buckets with an entry in choose_args use
+/// its weights and ids instead of their own, whatever map.choose_args
+/// holds.  The weight-set optimizer uses it to try out weight-sets.
+pub fn do_rule_with_choose_args(map: &CrushMap,
+                                ruleno: RuleId,
+                                x: i32,
+                                result_max: usize,
+                                weights: &[u32],
+                                choose_args: &[ChooseArg])
+                                -> Vec<i32> {
+    map_rule(map, ruleno, x, result_max, weights, choose_args, &mut Work::new(map))
+}
+
+/// Same as do_rule_with_choose_args but also returns everything the mapper
+/// did along the way: the steps, every bucket it chose from with which r,
+/// each rejection and retry, and what was placed where.
+pub fn do_rule_traced(map: &CrushMap,
+                      ruleno: RuleId,
+                      x: i32,
+                      result_max: usize,
+                      weights: &[u32],
+                      choose_args: &[ChooseArg])
+                      -> (Vec<i32>, Vec<TraceEvent>) {
+    let mut work = Work::new(map);
+    work.trace = Some(vec![]);
+    let result = map_rule(map, ruleno, x, result_max, weights, choose_args, &mut work);
+    (result, work.trace.unwrap_or(vec![]))
+}
+
+/// Same as do_rule_with_choose_args but also adds how many tries each
+/// item needed to tries
+pub fn do_rule_counting_tries(map: &CrushMap,
+                              ruleno: RuleId,
+                              x: i32,
+                              result_max: usize,
+                              weights: &[u32],
+                              choose_args: &[ChooseArg],
+                              tries: &mut ChooseTries)
+                              -> Vec<i32> {
+    let mut work = Work::new(map);
+    work.tries = Some(ChooseTries::default());
+    let result = map_rule(map, ruleno, x, result_max, weights, choose_args, &mut work);
+    if let Some(ref counted) = work.tries {
+        tries.merge(counted);
+    }
+    result
+}
+
+fn map_rule(map: &CrushMap,
+            ruleno: RuleId,
+            x: i32,
+            result_max: usize,
+            weights: &[u32],
+            choose_args: &[ChooseArg],
+            work: &mut Work)
+            -> Vec<i32> {
+    let mut result: Vec<i32> = Vec::with_capacity(result_max);
+    let rule = match map.rule(ruleno) {
+        Some(rule) => rule,
+        None => {
+            trace!(" bad ruleno {}", ruleno);
+            return result;
+        }
+    };
+    let mut args: Vec<Option<&ChooseArg>> = vec![None; map.buckets.len()];
+    for arg in choose_args.iter() {
+        if let Some(slot) = args.get_mut(arg.bucket_id.index()) {
+            *slot = Some(arg);
+        }
+    }
+    if rule.is_msr() {
+        return msr_do_rule(map, rule, x, result_max, weights, args, work);
+    }
+
+    // the original choose_total_tries value was off by one (it counted
+    // "retries" and not "tries").  add one.
+    let mut choose_tries = map.choose_total_tries.unwrap_or(0) + 1;
+    let mut choose_leaf_tries = 0;
+    // the local tries values were counted as "retries", though, and need
+    // no adjustment
+    let mut choose_local_retries = map.choose_local_tries.unwrap_or(0);
+    let mut choose_local_fallback_retries = map.choose_local_fallback_tries.unwrap_or(0);
+    let mut vary_r = map.chooseleaf_vary_r.unwrap_or(0) as u32;
+    let mut stable = map.chooseleaf_stable.unwrap_or(0) as u32;
+
+    let mut w: Vec<i32> = Vec::with_capacity(result_max);
+    let mut o: Vec<i32> = vec![0; result_max];
+    let mut c: Vec<i32> = vec![0; result_max];
+
+    for (index, step) in rule.steps.iter().enumerate() {
+        work.record_step(index, step);
+        match *step.effective() {
+            Step::Take { ref item } => {
+                let id = item.0.id();
+                if (id >= 0 && id < map.max_devices) || bucket_at(map, id).is_some() {
+                    w.clear();
+                    w.push(id);
+                } else {
+                    trace!(" bad take value {}", id);
+                }
+            }
+            Step::SetChooseTries(n) => {
+                if n > 0 {
+                    choose_tries = n as u32;
+                }
+            }
+            Step::SetChooseLeafTries(n) => {
+                if n > 0 {
+                    choose_leaf_tries = n as u32;
+                }
+            }
+            Step::SetChooseLocalTries(n) => {
+                if n >= 0 {
+                    choose_local_retries = n as u32;
+                }
+            }
+            Step::SetChooseLocalFallbackTries(n) => {
+                if n >= 0 {
+                    choose_local_fallback_retries = n as u32;
+                }
+            }
+            Step::SetChooseLeafVaryR(n) => {
+                if n >= 0 {
+                    vary_r = n as u32;
+                }
+            }
+            Step::SetChooseLeafStable(n) => {
+                if n >= 0 {
+                    stable = n as u32;
+                }
+            }
+            // The MSR steps only mean something in an MSR rule
+            Step::Choose { mode: ChooseMode::MSR, .. } => {}
+            Step::Choose { mode, num, ref bucket_type } => {
+                if w.is_empty() {
+                    continue;
+                }
+                let firstn = mode.firstn();
+                let recurse_to_leaf = mode.leaf();
+                let item_type = (bucket_type.0).0 as u16;
+                let params = ChooseParams {
+                    weights: weights,
+                    choose_args: args.clone(),
+                    x: x,
+                    local_retries: choose_local_retries,
+                    local_fallback_retries: choose_local_fallback_retries,
+                    vary_r: vary_r,
+                    stable: stable,
+                };
+
+                // reset output
+                let mut osize = 0;
+                for wi in w.iter() {
+                    let mut numrep = num;
+                    if numrep <= 0 {
+                        numrep += result_max as i32;
+                        if numrep <= 0 {
+                            continue;
+                        }
+                    }
+                    // make sure bucket id is valid
+                    let bucket = match bucket_at(map, *wi) {
+                        Some(b) => b,
+                        None => {
+                            // w[i] is probably CRUSH_ITEM_NONE
+                            trace!("  bad w[i] {}", wi);
+                            continue;
+                        }
+                    };
+                    if firstn {
+                        let recurse_tries = if choose_leaf_tries > 0 {
+                            choose_leaf_tries
+                        } else if map.chooseleaf_descend_once.unwrap_or(0) != 0 {
+                            1
+                        } else {
+                            choose_tries
+                        };
+                        osize += choose_firstn(map,
+                                               work,
+                                               &params,
+                                               bucket,
+                                               numrep,
+                                               item_type,
+                                               &mut o[osize..],
+                                               0,
+                                               result_max - osize,
+                                               choose_tries,
+                                               recurse_tries,
+                                               recurse_to_leaf,
+                                               Some(&mut c[osize..]),
+                                               0);
+                    } else {
+                        let out_size = ::std::cmp::min(numrep as usize, result_max - osize);
+                        choose_indep(map,
+                                     work,
+                                     &params,
+                                     bucket,
+                                     out_size,
+                                     numrep,
+                                     item_type,
+                                     &mut o[osize..],
+                                     0,
+                                     choose_tries,
+                                     if choose_leaf_tries > 0 {
+                                         choose_leaf_tries
+                                     } else {
+                                         1
+                                     },
+                                     recurse_to_leaf,
+                                     Some(&mut c[osize..]),
+                                     0);
+                        osize += out_size;
+                    }
+                }
+
+                // copy final _leaf_ values to output set
+                w.clear();
+                if recurse_to_leaf {
+                    w.extend_from_slice(&c[..osize]);
+                } else {
+                    w.extend_from_slice(&o[..osize]);
+                }
+            }
+            Step::Emit => {
+                let start = result.len();
+                for item in w.iter() {
+                    if result.len() >= result_max {
+                        break;
+                    }
+                    result.push(*item);
+                }
+                if work.trace.is_some() {
+                    work.record(TraceEvent::Emit { items: result[start..].to_vec() });
+                }
+                w.clear();
+            }
+            Step::Noop |
+            Step::SetMSRDescents(_) |
+            Step::SetMSRCollisionTries(_) |
+            Step::Unknown { .. } => {}
+        }
+    }
+    result
+}
@@ -0,0 +1,72 @@
+//! Enough of a Ceph pool to calculate where its placement groups map.
+//!
+use std::str::FromStr;
+
+use hash::crush_hash32_2;
+use mapper::do_rule_for_pool;
+use ::{CrushMap, RuleId};
+
+#[derive(Clone, Debug, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct Pool {
+    pub id: u32,
+    /// Number of placement groups.  pgp_num is assumed to be the same.
+    pub pg_num: u32,
+    /// Number of replicas, or k+m for an erasure coded pool
+    pub size: usize,
+    /// The id of the rule it places with
+    pub rule: RuleId,
+}
+
+// Number of bits needed to represent v
+fn cbits(v: u32) -> u32 {
+    32 - v.leading_zeros()
+}
+
+fn ceph_stable_mod(x: u32, b: u32, bmask: u32) -> u32 {
+    if (x & bmask) < b {
+        x & bmask
+    } else {
+        x & (bmask >> 1)
+    }
+}
+
+impl Pool {
+    /// The placement seed of a placement group.  This is the x that gets
+    /// handed to CRUSH.  Pools are assumed to have the hashpspool flag set,
+    /// which has been the default since Firefly.
+    pub fn pg_to_pps(&self, ps: u32) -> u32 {
+        let pgp_num_mask = (1u32 << cbits(self.pg_num.saturating_sub(1))) - 1;
+        crush_hash32_2(ceph_stable_mod(ps, self.pg_num, pgp_num_mask), self.id)
+    }
+
+    /// The devices a placement group maps to before any upmap items are
+    /// applied, with the pool's weight-set if the map has one
+    pub fn raw_pg_to_osds(&self, map: &CrushMap, ps: u32, weights: &[u32]) -> Vec<i32> {
+        let x = self.pg_to_pps(ps) as i32;
+        do_rule_for_pool(map, self.id as i64, self.rule, x, self.size, weights)
+    }
+
+    /// The pgid as Ceph prints it, ie: 1.1f
+    pub fn pgid(&self, ps: u32) -> String {
+        format!("{}.{:x}", self.id, ps)
+    }
+}
+
+/// Parses pool definitions of the form id:pg_num:size:rule
+impl FromStr for Pool {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Pool, String> {
+        let parts: Vec<&str> = s.split(':').collect();
+        if parts.len() != 4 {
+            return Err(format!("Invalid pool {}. Expected id:pg_num:size:rule", s));
+        }
+        let invalid = |field: &str| format!("Invalid {} in pool {}", field, s);
+        Ok(Pool {
+            id: try!(parts[0].parse().map_err(|_| invalid("id"))),
+            pg_num: try!(parts[1].parse().map_err(|_| invalid("pg_num"))),
+            size: try!(parts[2].parse().map_err(|_| invalid("size"))),
+            rule: RuleId(try!(parts[3].parse().map_err(|_| invalid("rule")))),
+        })
+    }
+}
@@ -0,0 +1,358 @@
+//! Convert legacy per-class hierarchies to device classes
+//!
+//! Before Luminous the only way to keep ssds and hdds apart was a tree for
+//! each, an `ssd` root next to `default` with `node1-ssd` next to `node1`.
+//! Reclassifying merges the trees into one, gives every device the class
+//! its old tree stood for and builds the class shadow trees with the ids of
+//! the buckets they replace.  The rules keep taking the same ids, so almost
+//! nothing moves.  This is `crushtool --reclassify`; compare_maps shows how
+//! many mappings changed.
+//!
+//! ```ignore
+//! try!(Reclassify::new()
+//!     .root("default", "hdd")
+//!     .bucket("%-ssd", "ssd", "default")
+//!     .bucket("ssd", "ssd", "default")
+//!     .apply(&mut crushmap));
+//! ```
+//!
+use std::fmt;
+
+use edit::{device_class, get_bucket, lookup_bucket, parents, put_bucket, retire, set_items,
+           with_items, Edits};
+use mapper::{default_weights, do_rule};
+use statistics::{bucket_items, item_name};
+use ::{BucketId, CrushMap, DeviceId, Item, RuleId};
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Reclassify {
+    /// (root, class)
+    roots: Vec<(String, String)>,
+    /// (pattern, class, default parent)
+    buckets: Vec<(String, String, String)>,
+}
+
+fn class_id(map: &mut CrushMap, class: &str) -> i32 {
+    if map.class_name.is_none() {
+        map.class_name = Some(vec![]);
+    }
+    let names = map.class_name.as_mut().unwrap();
+    if let Some(n) = names.iter().find(|n| n.1 == class) {
+        return n.0;
+    }
+    let id = names.iter().map(|n| n.0 + 1).max().unwrap_or(0);
+    names.push((id, class.to_string()));
+    id
+}
+
+fn set_class(map: &mut CrushMap, device: DeviceId, class: i32) -> Result<(), String> {
+    match device_class(map, device) {
+        Some(c) if c == class => Ok(()),
+        Some(c) => {
+            Err(format!("Device {} already has class {}",
+                        item_name(map, device.into()).unwrap_or_else(|| device.to_string()),
+                        c))
+        }
+        None => {
+            if map.class_map.is_none() {
+                map.class_map = Some(vec![]);
+            }
+            map.class_map.as_mut().unwrap().push((device, class));
+            Ok(())
+        }
+    }
+}
+
+// The part of name the % in pattern stands for, or None if name doesn't
+// match.  A pattern without a % has to be all of name.
+fn pattern_match(pattern: &str, name: &str) -> Option<String> {
+    match pattern.find('%') {
+        Some(at) => {
+            let (prefix, suffix) = (&pattern[..at], &pattern[at + 1..]);
+            if name.len() > prefix.len() + suffix.len() && name.starts_with(prefix) &&
+               name.ends_with(suffix) {
+                Some(name[prefix.len()..name.len() - suffix.len()].to_string())
+            } else {
+                None
+            }
+        }
+        None if pattern == name => Some(name.to_string()),
+        None => None,
+    }
+}
+
+// The buckets beneath id, id included, parents before children
+fn tree_buckets(map: &CrushMap, id: BucketId, out: &mut Vec<BucketId>) {
+    if out.contains(&id) {
+        return;
+    }
+    out.push(id);
+    for child in bucket_items(map, id).into_iter().filter_map(|i| i.0.bucket()) {
+        tree_buckets(map, child, out);
+    }
+}
+
+// Move bucket id to new_id.  Everything but the rules follows it; the
+// rules keep the old id, which becomes a shadow.
+fn renumber(map: &mut CrushMap,
+            edits: &mut Edits,
+            id: BucketId,
+            new_id: BucketId)
+            -> Result<(), String> {
+    let mut bucket = try!(get_bucket(map, id)).clone();
+    try!(retire(map, edits, id));
+    bucket.bucket_mut().unwrap().id = new_id;
+    put_bucket(map, bucket);
+
+    for b in map.buckets.iter_mut().filter_map(|b| b.bucket_mut()) {
+        for item in b.items.iter_mut().filter(|i| i.0 == Item::Bucket(id)) {
+            item.0 = new_id.into();
+        }
+    }
+    for name in map.name_map.iter_mut().filter(|n| n.0 == Item::Bucket(id)) {
+        name.0 = new_id.into();
+    }
+    if let Some(ref mut class_bucket) = map.class_bucket {
+        for entry in class_bucket.iter_mut().filter(|c| c.0 == id) {
+            entry.0 = new_id;
+        }
+    }
+    // The weight-sets go with both: the shadow holds the same items
+    if let Some(ref mut choose_args) = map.choose_args {
+        for &mut (_, ref mut args) in choose_args.iter_mut() {
+            let copies: Vec<_> = args.iter()
+                .filter(|a| a.bucket_id == id)
+                .map(|a| {
+                    let mut copy = a.clone();
+                    copy.bucket_id = new_id;
+                    copy
+                })
+                .collect();
+            args.extend(copies);
+        }
+    }
+    Ok(())
+}
+
+fn preserve(edits: &mut Edits,
+            map: &CrushMap,
+            bucket: BucketId,
+            class: i32,
+            old: BucketId)
+            -> Result<(), String> {
+    if let Some(p) = edits.preserved.iter().find(|p| p.0 == (bucket, class)) {
+        return Err(format!("Both {} and {} would become the same shadow of {}",
+                           p.1,
+                           old,
+                           item_name(map, bucket.into()).unwrap_or_else(|| bucket.to_string())));
+    }
+    edits.preserved.push(((bucket, class), old));
+    Ok(())
+}
+
+// Every device beneath root gets the class and every bucket a new id.  The
+// old ids are preserved as the class's shadows.
+fn reclassify_root(map: &mut CrushMap,
+                   edits: &mut Edits,
+                   root: &str,
+                   class: &str)
+                   -> Result<(), String> {
+    let root_id = try!(lookup_bucket(map, root));
+    let class = class_id(map, class);
+    let mut tree = vec![];
+    tree_buckets(map, root_id, &mut tree);
+    for id in tree.iter() {
+        for (item, _) in bucket_items(map, *id) {
+            if let Item::Device(device) = item {
+                try!(set_class(map, device, class));
+            }
+        }
+    }
+    for id in tree {
+        let new_id = edits.new_id();
+        try!(renumber(map, edits, id, new_id));
+        try!(preserve(edits, map, new_id, class, id));
+    }
+    Ok(())
+}
+
+impl Reclassify {
+    pub fn new() -> Reclassify {
+        Reclassify::default()
+    }
+
+    /// Every device beneath root gets class.  The tree's buckets get new ids
+    /// and the old ones go to their class shadows, so a rule taking root
+    /// now takes `root class <class>`.
+    pub fn root(mut self, root: &str, class: &str) -> Self {
+        self.roots.push((root.to_string(), class.to_string()));
+        self
+    }
+
+    /// Merge the buckets matching pattern into their counterparts without a
+    /// class.  A `%` in pattern stands for the counterpart's name, so
+    /// `%-ssd` merges `node1-ssd` into `node1`, creating `node1` beneath
+    /// default_parent if there is none.  Without a `%` the bucket named
+    /// pattern merges into default_parent itself.  The devices moved get
+    /// class and a merged bucket's id goes to the counterpart's shadow.
+    pub fn bucket(mut self, pattern: &str, class: &str, default_parent: &str) -> Self {
+        self.buckets.push((pattern.to_string(), class.to_string(), default_parent.to_string()));
+        self
+    }
+
+    fn is_merged(&self, name: &str) -> bool {
+        self.buckets.iter().any(|b| pattern_match(&b.0, name).is_some())
+    }
+
+    fn reclassify_bucket(&self,
+                         map: &mut CrushMap,
+                         edits: &mut Edits,
+                         pattern: &str,
+                         class: &str,
+                         default_parent: &str)
+                         -> Result<(), String> {
+        let class = class_id(map, class);
+        let parent = try!(lookup_bucket(map, default_parent));
+        let matched: Vec<(BucketId, String)> = map.name_map
+            .iter()
+            .filter(|n| !n.1.contains('~'))
+            .filter_map(|n| n.0.bucket().map(|id| (id, &n.1)))
+            .filter_map(|(id, name)| {
+                pattern_match(pattern, name).map(|target| {
+                    if pattern.contains('%') {
+                        (id, target)
+                    } else {
+                        (id, default_parent.to_string())
+                    }
+                })
+            })
+            .collect();
+
+        for (id, target_name) in matched {
+            let target = match lookup_bucket(map, &target_name) {
+                Ok(target) => target,
+                Err(_) => {
+                    // An empty bucket like the merged one beneath the
+                    // default parent
+                    let mut bucket =
+                        try!(with_items(try!(get_bucket(map, id)), &[], edits.straw_calc_version));
+                    let new_id = edits.new_id();
+                    bucket.bucket_mut().unwrap().id = new_id;
+                    put_bucket(map, bucket);
+                    map.name_map.push((new_id.into(), target_name.clone()));
+                    let mut items = bucket_items(map, parent);
+                    items.push((new_id.into(), 0));
+                    try!(set_items(map, edits, parent, &items));
+                    new_id
+                }
+            };
+            if target == id {
+                return Err(format!("{} can't be merged into itself", target_name));
+            }
+
+            let mut items = bucket_items(map, target);
+            for (item, weight) in bucket_items(map, id) {
+                if let Item::Device(device) = item {
+                    try!(set_class(map, device, class));
+                } else if item_name(map, item).map(|n| self.is_merged(&n)).unwrap_or(false) {
+                    // Merged into its own counterpart
+                    continue;
+                }
+                if !items.iter().any(|i| i.0 == item) {
+                    items.push((item, weight));
+                }
+            }
+            try!(set_items(map, edits, target, &items));
+
+            for p in parents(map, id.into()) {
+                let items: Vec<(Item, u32)> =
+                    bucket_items(map, p).into_iter().filter(|i| i.0 != Item::Bucket(id)).collect();
+                try!(set_items(map, edits, p, &items));
+            }
+            map.name_map.retain(|n| n.0 != Item::Bucket(id));
+            try!(retire(map, edits, id));
+            try!(preserve(edits, map, target, class, id));
+        }
+        Ok(())
+    }
+
+    /// Reclassify map.  The roots are done first, then the bucket patterns
+    /// in the order they were given.  Afterwards the weights of the
+    /// buckets above the merged ones are updated and the shadow trees of
+    /// every class are built again.
+    pub fn apply(&self, map: &mut CrushMap) -> Result<(), String> {
+        let mut edits = Edits::new(map);
+        for &(ref root, ref class) in self.roots.iter() {
+            try!(reclassify_root(map, &mut edits, root, class));
+        }
+        for &(ref pattern, ref class, ref default_parent) in self.buckets.iter() {
+            try!(self.reclassify_bucket(map, &mut edits, pattern, class, default_parent));
+        }
+        edits.finish(map)
+    }
+}
+
+/// How many of the mappings of a rule differ between two maps
+#[derive(Clone, Debug, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct RuleComparison {
+    /// Index of the rule in CrushMap.rules
+    pub rule: RuleId,
+    pub name: Option<String>,
+    pub num_rep: usize,
+    pub mappings: u64,
+    pub mismatched: u64,
+}
+
+impl fmt::Display for RuleComparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = self.name.clone().unwrap_or_else(|| self.rule.to_string());
+        write!(f,
+               "rule {} ({}) num_rep {}: {}/{} mismatched mappings ({:.4})",
+               self.rule,
+               name,
+               self.num_rep,
+               self.mismatched,
+               self.mappings,
+               self.mismatched as f64 / self.mappings.max(1) as f64)
+    }
+}
+
+/// Map the inputs min_x to max_x with every rule both maps have, for every
+/// size the rule's mask accepts in before, and count the mappings that
+/// differ.  Every device is fully in.
+pub fn compare_maps(before: &CrushMap,
+                    after: &CrushMap,
+                    min_x: i32,
+                    max_x: i32)
+                    -> Vec<RuleComparison> {
+    let before_weights = default_weights(before);
+    let after_weights = default_weights(after);
+    let mut comparisons = vec![];
+    for ruleno in (0..before.rules.len()).map(|i| RuleId(i as i32)) {
+        let rule = match (before.rule(ruleno), after.rule(ruleno)) {
+            (Some(rule), Some(_)) => rule,
+            _ => continue,
+        };
+        for num_rep in rule.mask.min_size as usize..rule.mask.max_size as usize + 1 {
+            let mut comparison = RuleComparison {
+                rule: ruleno,
+                name: before.rule_name_map
+                    .iter()
+                    .find(|n| n.0 == ruleno)
+                    .map(|n| n.1.clone()),
+                num_rep: num_rep,
+                mappings: 0,
+                mismatched: 0,
+            };
+            for x in (min_x as i64..max_x as i64 + 1).map(|x| x as i32) {
+                comparison.mappings += 1;
+                if do_rule(before, ruleno, x, num_rep, &before_weights) !=
+                   do_rule(after, ruleno, x, num_rep, &after_weights) {
+                    comparison.mismatched += 1;
+                }
+            }
+            comparisons.push(comparison);
+        }
+    }
+    comparisons
+}
@@ -0,0 +1,287 @@
+//! A fluent builder for rules
+//!
+//! Steps refer to buckets and types by name.  The names are resolved
+//! against the map's name_map and type_map when the rule is added.
+//!
+//! ```ignore
+//! let ruleno = try!(RuleBuilder::replicated("fast")
+//!     .take("default")
+//!     .chooseleaf_firstn(0, "host")
+//!     .emit()
+//!     .add_to(&mut crushmap));
+//! ```
+//!
+use ::{BucketId, ChooseMode, CrushMap, CrushRuleMask, Item, Rule, RuleId, RuleType, Ruleset,
+       Step};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RuleBuilderError {
+    /// A take step names an item that isn't in name_map
+    UnknownItem(String),
+    /// A take step names a device class that isn't in class_name, or the
+    /// item has no shadow bucket for it
+    UnknownDeviceClass(String),
+    /// A choose step names a type that isn't in type_map
+    UnknownType(String),
+    /// rule_name_map already has a rule by this name
+    DuplicateName(String),
+    /// All 256 rulesets are in use
+    TooManyRules,
+    /// An erasure code profile that can't be turned into a rule
+    InvalidProfile(String),
+}
+
+// The id of the bucket that holds only the devices of class beneath id
+fn shadow_bucket(map: &CrushMap, id: Item, class: &str) -> Result<BucketId, RuleBuilderError> {
+    let unknown = || RuleBuilderError::UnknownDeviceClass(class.to_string());
+    let class_id = try!(map.class_name
+        .as_ref()
+        .and_then(|names| names.iter().find(|n| n.1 == class))
+        .map(|n| n.0)
+        .ok_or_else(&unknown));
+    map.class_bucket
+        .as_ref()
+        .and_then(|buckets| buckets.iter().find(|b| Item::Bucket(b.0) == id))
+        .and_then(|b| b.1.iter().find(|c| c.0 == class_id))
+        .map(|c| c.1)
+        .ok_or_else(&unknown)
+}
+
+#[derive(Debug, Clone)]
+enum BuilderStep {
+    Take(String, Option<String>),
+    Choose(ChooseMode, i32, String),
+    Set(Step),
+    Emit,
+}
+
+#[derive(Debug, Clone)]
+pub struct RuleBuilder {
+    name: String,
+    rule_type: RuleType,
+    min_size: u8,
+    max_size: u8,
+    steps: Vec<BuilderStep>,
+}
+
+impl RuleBuilder {
+    /// A replicated rule accepting sizes 1 to 10
+    pub fn replicated(name: &str) -> RuleBuilder {
+        RuleBuilder {
+            name: name.to_string(),
+            rule_type: RuleType::Replicated,
+            min_size: 1,
+            max_size: 10,
+            steps: vec![],
+        }
+    }
+
+    /// An erasure rule accepting sizes 3 to 20.  Like the rules Ceph
+    /// creates for erasure coded pools it starts with set_chooseleaf_tries 5
+    /// and set_choose_tries 100, since an indep rule can't shift replicas
+    /// around to work past a collision.
+    pub fn erasure(name: &str) -> RuleBuilder {
+        RuleBuilder {
+            name: name.to_string(),
+            rule_type: RuleType::Erasure,
+            min_size: 3,
+            max_size: 20,
+            steps: vec![],
+        }
+        .set_chooseleaf_tries(5)
+        .set_choose_tries(100)
+    }
+
+    /// An MSR rule that leaves out any position it can't fill, accepting
+    /// sizes 1 to 10
+    pub fn msr_firstn(name: &str) -> RuleBuilder {
+        RuleBuilder {
+            name: name.to_string(),
+            rule_type: RuleType::MsrFirstN,
+            min_size: 1,
+            max_size: 10,
+            steps: vec![],
+        }
+    }
+
+    /// An MSR rule that returns CRUSH_ITEM_NONE for any position it can't
+    /// fill, accepting sizes 3 to 20
+    pub fn msr_indep(name: &str) -> RuleBuilder {
+        RuleBuilder {
+            name: name.to_string(),
+            rule_type: RuleType::MsrIndep,
+            min_size: 3,
+            max_size: 20,
+            steps: vec![],
+        }
+    }
+
+    pub fn min_size(mut self, min_size: u8) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    pub fn max_size(mut self, max_size: u8) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Start descending from a bucket or device
+    pub fn take(mut self, item: &str) -> Self {
+        self.steps.push(BuilderStep::Take(item.to_string(), None));
+        self
+    }
+
+    /// Start descending from the shadow tree that only holds the devices of
+    /// `class` beneath item, ie: `step take default class ssd`
+    pub fn take_class(mut self, item: &str, class: &str) -> Self {
+        self.steps.push(BuilderStep::Take(item.to_string(), Some(class.to_string())));
+        self
+    }
+
+    fn choose(mut self, mode: ChooseMode, num: i32, item_type: &str) -> Self {
+        self.steps.push(BuilderStep::Choose(mode, num, item_type.to_string()));
+        self
+    }
+
+    /// Choose `num` items of `item_type`.  Zero or less is relative to the
+    /// number of replicas the pool asks for.
+    pub fn choose_firstn(self, num: i32, item_type: &str) -> Self {
+        self.choose(ChooseMode::FirstN, num, item_type)
+    }
+
+    pub fn choose_indep(self, num: i32, item_type: &str) -> Self {
+        self.choose(ChooseMode::Indep, num, item_type)
+    }
+
+    /// Choose `num` items of `item_type` and then a device beneath each
+    pub fn chooseleaf_firstn(self, num: i32, item_type: &str) -> Self {
+        self.choose(ChooseMode::LeafFirstN, num, item_type)
+    }
+
+    pub fn chooseleaf_indep(self, num: i32, item_type: &str) -> Self {
+        self.choose(ChooseMode::LeafIndep, num, item_type)
+    }
+
+    /// An MSR choose step.  Every choosemsr step between a take and an emit
+    /// is retried together.  Only MSR rules use these.
+    pub fn choose_msr(self, num: i32, item_type: &str) -> Self {
+        self.choose(ChooseMode::MSR, num, item_type)
+    }
+
+    fn set(mut self, step: Step) -> Self {
+        self.steps.push(BuilderStep::Set(step));
+        self
+    }
+
+    pub fn set_choose_tries(self, tries: i32) -> Self {
+        self.set(Step::SetChooseTries(tries))
+    }
+
+    pub fn set_chooseleaf_tries(self, tries: i32) -> Self {
+        self.set(Step::SetChooseLeafTries(tries))
+    }
+
+    pub fn set_choose_local_tries(self, tries: i32) -> Self {
+        self.set(Step::SetChooseLocalTries(tries))
+    }
+
+    pub fn set_choose_local_fallback_tries(self, tries: i32) -> Self {
+        self.set(Step::SetChooseLocalFallbackTries(tries))
+    }
+
+    pub fn set_chooseleaf_vary_r(self, vary_r: i32) -> Self {
+        self.set(Step::SetChooseLeafVaryR(vary_r))
+    }
+
+    pub fn set_chooseleaf_stable(self, stable: i32) -> Self {
+        self.set(Step::SetChooseLeafStable(stable))
+    }
+
+    pub fn set_msr_descents(self, descents: i32) -> Self {
+        self.set(Step::SetMSRDescents(descents))
+    }
+
+    pub fn set_msr_collision_tries(self, tries: i32) -> Self {
+        self.set(Step::SetMSRCollisionTries(tries))
+    }
+
+    pub fn emit(mut self) -> Self {
+        self.steps.push(BuilderStep::Emit);
+        self
+    }
+
+    /// Resolve the names in the steps against map without adding the rule
+    /// to it.  The ruleset is left at 0.
+    pub fn build(&self, map: &CrushMap) -> Result<Rule, RuleBuilderError> {
+        let mut steps = vec![];
+        for step in self.steps.iter() {
+            steps.push(match *step {
+                BuilderStep::Take(ref item, ref class) => {
+                    let id = try!(map.name_map
+                        .iter()
+                        .find(|n| n.1 == *item)
+                        .map(|n| n.0)
+                        .ok_or(RuleBuilderError::UnknownItem(item.clone())));
+                    // Shadow buckets are named like item~class
+                    let item = match *class {
+                        Some(ref class) => {
+                            (Item::Bucket(try!(shadow_bucket(map, id, class))),
+                             Some(format!("{}~{}", item, class)))
+                        }
+                        None => (id, Some(item.clone())),
+                    };
+                    Step::Take { item: item }
+                }
+                BuilderStep::Choose(mode, num, ref item_type) => {
+                    let type_id = try!(map.type_map
+                        .iter()
+                        .find(|t| t.1 == *item_type)
+                        .map(|t| t.0)
+                        .ok_or(RuleBuilderError::UnknownType(item_type.clone())));
+                    Step::Choose {
+                        mode: mode,
+                        num: num,
+                        bucket_type: (type_id, Some(item_type.clone())),
+                    }
+                }
+                BuilderStep::Set(ref step) => step.clone(),
+                BuilderStep::Emit => Step::Emit,
+            });
+        }
+        Ok(Rule {
+            mask: CrushRuleMask {
+                ruleset: Ruleset(0),
+                rule_type: self.rule_type.clone(),
+                min_size: self.min_size,
+                max_size: self.max_size,
+            },
+            steps: steps,
+        })
+    }
+
+    /// Build the rule and add it to map in the first free slot, the same
+    /// as `ceph osd crush rule create-*` does.  The ruleset is set to the
+    /// slot and the rule's name is registered in rule_name_map.  Returns the
+    /// rule's id.
+    pub fn add_to(&self, map: &mut CrushMap) -> Result<RuleId, RuleBuilderError> {
+        if map.rule_name_map.iter().any(|n| n.1 == self.name) {
+            return Err(RuleBuilderError::DuplicateName(self.name.clone()));
+        }
+        let mut rule = try!(self.build(map));
+        let ruleno = map.rules.iter().position(|r| r.is_none()).unwrap_or(map.rules.len());
+        if ruleno > u8::max_value() as usize {
+            return Err(RuleBuilderError::TooManyRules);
+        }
+        rule.mask.ruleset = Ruleset(ruleno as u8);
+        if ruleno == map.rules.len() {
+            map.rules.push(Some(rule));
+        } else {
+            map.rules[ruleno] = Some(rule);
+        }
+        map.max_rules = map.rules.len() as u32;
+        let id = RuleId(ruleno as i32);
+        map.rule_name_map.push((id, self.name.clone()));
+        Ok(id)
+    }
+}
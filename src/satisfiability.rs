@@ -0,0 +1,273 @@
+//! Rule satisfiability
+//!
+//! Checks, without mapping anything, whether the hierarchy beneath each
+//! take step of a rule has enough distinct failure domains for every size
+//! the rule's mask accepts.  A `chooseleaf firstn 0 type rack` against a
+//! tree with two racks can place two replicas at most, no matter how many
+//! inputs are tried.
+//!
+use std::collections::HashSet;
+use std::fmt;
+
+use statistics::describe_item;
+use ::{CrushIndex, CrushMap, Item, RuleId, Step, TypeId};
+
+/// A choose step that can't find enough failure domains beneath an item
+#[derive(Clone, Debug, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct Shortfall {
+    /// The take target the step descends from
+    pub take: i32,
+    /// Index of the choose step in the rule
+    pub step: usize,
+    /// The item the step chooses beneath.  This is the take target for
+    /// the first choose step and a domain picked by an earlier step
+    /// otherwise.
+    pub item: i32,
+    pub domain_type: i32,
+    pub wanted: usize,
+    /// Domains of domain_type beneath item that have weight and can
+    /// satisfy the steps after this one
+    pub available: usize,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct SizeCheck {
+    pub size: usize,
+    pub shortfalls: Vec<Shortfall>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct RuleCheck {
+    /// Index of the rule in CrushMap.rules
+    pub rule: RuleId,
+    pub name: Option<String>,
+    /// One check for every size in [min_size, max_size]
+    pub sizes: Vec<SizeCheck>,
+}
+
+impl RuleCheck {
+    /// True if the rule accepts some size but none of them can be fully
+    /// placed
+    pub fn never_satisfiable(&self) -> bool {
+        !self.accepts_no_size() && self.sizes.iter().all(|s| !s.shortfalls.is_empty())
+    }
+
+    /// True if the mask's min_size is above its max_size, so there was
+    /// no size to check
+    pub fn accepts_no_size(&self) -> bool {
+        self.sizes.is_empty()
+    }
+
+    pub fn is_satisfiable(&self, size: usize) -> bool {
+        self.sizes.iter().any(|s| s.size == size && s.shortfalls.is_empty())
+    }
+
+    /// Display the check with the item and type names from map
+    pub fn display<'a>(&'a self, map: &'a CrushMap) -> RuleCheckDisplay<'a> {
+        RuleCheckDisplay {
+            check: self,
+            map: map,
+        }
+    }
+}
+
+struct ChooseStep {
+    index: usize,
+    numrep: i32,
+    domain_type: TypeId,
+    recurse_to_leaf: bool,
+}
+
+fn bucket_type(index: &CrushIndex, id: Item) -> Option<TypeId> {
+    match id {
+        Item::Device(_) => Some(TypeId(0)),
+        Item::Bucket(bucket) => {
+            index.bucket_by_id(bucket).and_then(|b| b.bucket()).map(|b| b.bucket_type.0)
+        }
+    }
+}
+
+// The distinct items of domain_type with a non-zero weight beneath item.
+// Like the mapper this stops descending at the first item of the type.
+fn domains_under(index: &CrushIndex, item: Item, domain_type: TypeId) -> Vec<Item> {
+    let mut domains = vec![];
+    let mut seen = HashSet::new();
+    let mut stack = vec![item];
+    while let Some(item) = stack.pop() {
+        let bucket = match item {
+            Item::Bucket(bucket) => bucket,
+            Item::Device(_) => continue,
+        };
+        if !seen.insert(bucket) {
+            continue;
+        }
+        for (child, weight) in index.items_of(bucket) {
+            if weight == 0 {
+                continue;
+            }
+            match bucket_type(index, child) {
+                Some(t) if t == domain_type => {
+                    if !domains.contains(&child) {
+                        domains.push(child);
+                    }
+                }
+                Some(_) if child.is_bucket() => stack.push(child),
+                _ => {}
+            }
+        }
+    }
+    domains
+}
+
+fn has_device(index: &CrushIndex, item: Item) -> bool {
+    index.devices_under(&[item], 0x10000).iter().any(|d| d.1 > 0)
+}
+
+fn check_steps(index: &CrushIndex,
+               take: Item,
+               item: Item,
+               steps: &[ChooseStep],
+               size: usize,
+               shortfalls: &mut Vec<Shortfall>)
+               -> bool {
+    let step = match steps.first() {
+        Some(step) => step,
+        None => return true,
+    };
+    let rest = &steps[1..];
+    // Same as the mapper: zero or less is relative to the size and a step
+    // that ends up with nothing to pick is skipped
+    let wanted = if step.numrep <= 0 {
+        size as i32 + step.numrep
+    } else {
+        step.numrep
+    };
+    if wanted <= 0 {
+        return check_steps(index, take, item, rest, size, shortfalls);
+    }
+
+    let domains = domains_under(index, item, step.domain_type);
+    // Only count the first shortfall beneath each domain so a missing
+    // level isn't reported once per bucket above it
+    let mut nested = vec![];
+    let available = domains.iter()
+        .filter(|d| !step.recurse_to_leaf || has_device(index, **d))
+        .filter(|d| check_steps(index, take, **d, rest, size, &mut nested))
+        .count();
+    if let Some(first) = nested.into_iter().next() {
+        shortfalls.push(first);
+    }
+    if available < wanted as usize {
+        shortfalls.push(Shortfall {
+            take: take.id(),
+            step: step.index,
+            item: item.id(),
+            domain_type: step.domain_type.0,
+            wanted: wanted as usize,
+            available: available,
+        });
+        return false;
+    }
+    true
+}
+
+fn choose_step(index: usize, step: &Step) -> Option<ChooseStep> {
+    match *step.effective() {
+        Step::Choose { mode, num, ref bucket_type } => {
+            Some(ChooseStep {
+                index: index,
+                numrep: num,
+                domain_type: bucket_type.0,
+                recurse_to_leaf: mode.leaf(),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Check a rule for every size in its mask's [min_size, max_size].  Every
+/// take ... emit sequence in the rule is checked on its own.  Returns None
+/// if there is no rule at ruleno.
+pub fn check_rule(map: &CrushMap, ruleno: RuleId) -> Option<RuleCheck> {
+    let rule = match map.rule(ruleno) {
+        Some(rule) => rule,
+        None => return None,
+    };
+
+    // (take target, choose steps) for each take in the rule
+    let mut segments: Vec<(Item, Vec<ChooseStep>)> = vec![];
+    for (index, step) in rule.steps.iter().enumerate() {
+        if let Some(take) = step.item() {
+            segments.push((take, vec![]));
+        } else if let Some(choose) = choose_step(index, step) {
+            if let Some(segment) = segments.last_mut() {
+                segment.1.push(choose);
+            }
+        }
+    }
+
+    let index = CrushIndex::new(map);
+    let sizes = (rule.mask.min_size as usize..rule.mask.max_size as usize + 1)
+        .map(|size| {
+            let mut shortfalls = vec![];
+            for &(take, ref steps) in segments.iter() {
+                check_steps(&index, take, take, steps, size, &mut shortfalls);
+            }
+            SizeCheck {
+                size: size,
+                shortfalls: shortfalls,
+            }
+        })
+        .collect();
+
+    Some(RuleCheck {
+        rule: ruleno,
+        name: map.rule_name_map.iter().find(|n| n.0 == ruleno).map(|n| n.1.clone()),
+        sizes: sizes,
+    })
+}
+
+/// check_rule for every rule in the map
+pub fn check_rules(map: &CrushMap) -> Vec<RuleCheck> {
+    (0..map.rules.len()).filter_map(|ruleno| check_rule(map, RuleId(ruleno as i32))).collect()
+}
+
+/// Describes the shortfalls of a RuleCheck using the names in a map
+pub struct RuleCheckDisplay<'a> {
+    check: &'a RuleCheck,
+    map: &'a CrushMap,
+}
+
+impl<'a> fmt::Display for RuleCheckDisplay<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let check = self.check;
+        let index = CrushIndex::new(self.map);
+        let name = check.name.clone().unwrap_or_else(|| check.rule.to_string());
+        let status = if check.accepts_no_size() {
+            "accepts no size"
+        } else if check.never_satisfiable() {
+            "never satisfiable"
+        } else if check.sizes.iter().all(|s| s.shortfalls.is_empty()) {
+            "ok"
+        } else {
+            "partially satisfiable"
+        };
+        try!(write!(f, "rule {} ({}): {}", check.rule, name, status));
+        for size in check.sizes.iter().filter(|s| !s.shortfalls.is_empty()) {
+            try!(write!(f, "\n  size {}:", size.size));
+            for shortfall in size.shortfalls.iter() {
+                let type_name = index.type_name(TypeId(shortfall.domain_type))
+                    .map(String::from)
+                    .unwrap_or_else(|| format!("type {}", shortfall.domain_type));
+                try!(write!(f,
+                            "\n    step {} wants {} {} beneath {}, only {} available",
+                            shortfall.step,
+                            shortfall.wanted,
+                            type_name,
+                            describe_item(&index, Item::from(shortfall.item)),
+                            shortfall.available));
+            }
+        }
+        Ok(())
+    }
+}
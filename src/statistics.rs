@@ -0,0 +1,357 @@
+//! Utilization statistics for a simulated placement population
+//!
+//! Run a range of inputs through a rule and compare how many
+//! placements each device received against what its weight says it
+//! should have received.
+//!
+use std::collections::HashMap;
+use std::f64;
+use std::fmt;
+
+use mapper::{check_mappable, choose_args_for, do_rule, do_rule_counting_tries, ChooseTries,
+             CRUSH_ITEM_NONE, DEFAULT_CHOOSE_ARGS};
+use ::{BucketId, CrushIndex, CrushMap, DeviceId, Item, RuleId, TypeId};
+
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct DeviceUtilization {
+    pub id: i32,
+    pub name: Option<String>,
+    /// CRUSH weight multiplied by the device's reweight value
+    pub weight: f64,
+    pub placements: u64,
+    pub expected: f64,
+    /// placements / expected
+    pub ratio: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct DomainUtilization {
+    pub id: i32,
+    pub name: Option<String>,
+    pub placements: u64,
+    pub expected: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct UtilizationReport {
+    pub rule: RuleId,
+    pub num_rep: usize,
+    /// Number of inputs that were mapped
+    pub inputs: u64,
+    /// Number of inputs that came back with fewer than num_rep devices
+    pub short_mappings: u64,
+    pub total_placements: u64,
+    pub devices: Vec<DeviceUtilization>,
+    /// The type the rule separates replicas across, if it has one
+    pub failure_domain: Option<String>,
+    pub domains: Vec<DomainUtilization>,
+    /// Standard deviation of the per device placement counts from
+    /// their expected values
+    pub stddev: f64,
+    /// Largest and smallest (placements - expected) over all devices
+    pub max_deviation: f64,
+    pub min_deviation: f64,
+    /// How many tries the placements needed, if they were counted
+    pub choose_tries: Option<ChooseTries>,
+}
+
+/// Map every x in [min_x, max_x] with the rule and return the results
+/// in input order.  Fails for a rule the mapper can't place with, see
+/// mapper::check_mappable.
+pub fn simulate(map: &CrushMap,
+                ruleno: RuleId,
+                num_rep: usize,
+                min_x: i32,
+                max_x: i32,
+                weights: &[u32])
+                -> Result<Vec<Vec<i32>>, String> {
+    try!(check_mappable(map, ruleno));
+    // Widened so that max_x can be i32::MAX
+    Ok((min_x as i64..max_x as i64 + 1)
+        .map(|x| do_rule(map, ruleno, x as i32, num_rep, weights))
+        .collect())
+}
+
+/// Same as simulate but also counts how many tries every placement
+/// needed
+pub fn simulate_counting_tries(map: &CrushMap,
+                               ruleno: RuleId,
+                               num_rep: usize,
+                               min_x: i32,
+                               max_x: i32,
+                               weights: &[u32])
+                               -> Result<(Vec<Vec<i32>>, ChooseTries), String> {
+    try!(check_mappable(map, ruleno));
+    let mut tries = ChooseTries::default();
+    let choose_args = choose_args_for(map, DEFAULT_CHOOSE_ARGS);
+    let mappings = (min_x as i64..max_x as i64 + 1)
+        .map(|x| {
+            do_rule_counting_tries(map, ruleno, x as i32, num_rep, weights, choose_args, &mut tries)
+        })
+        .collect();
+    Ok((mappings, tries))
+}
+
+// A scan, for the edits that change the names as they go.  Everything
+// else looks names up in a CrushIndex.
+pub fn item_name(map: &CrushMap, id: Item) -> Option<String> {
+    map.name_map.iter().find(|n| n.0 == id).map(|n| n.1.clone())
+}
+
+// An item's name followed by its id, or just the id if it has no name
+pub fn describe_item(index: &CrushIndex, id: Item) -> String {
+    match index.name(id) {
+        Some(name) => format!("{} ({})", name, id),
+        None => id.to_string(),
+    }
+}
+
+// The items of bucket id along with their weights.  A bucket is normally
+// found in its own slot, the edits rely on that while they change the map.
+pub fn bucket_items(map: &CrushMap, id: BucketId) -> Vec<(Item, u32)> {
+    let in_slot = match map.buckets.get(id.index()) {
+        Some(b) if b.bucket().map(|b| b.id) == Some(id) => Some(b),
+        _ => None,
+    };
+    match in_slot.or_else(|| map.buckets.iter().find(|b| b.bucket().map(|b| b.id) == Some(id))) {
+        Some(b) => {
+            b.bucket()
+                .unwrap()
+                .items
+                .iter()
+                .map(|i| i.0)
+                .zip(b.item_weights())
+                .collect()
+        }
+        None => vec![],
+    }
+}
+
+// The roots a rule takes and the type of each of its choose steps, outermost
+// first
+pub fn rule_layout(map: &CrushMap, ruleno: RuleId) -> (Vec<Item>, Vec<TypeId>) {
+    let mut roots = vec![];
+    let mut domain_types = vec![];
+    if let Some(rule) = map.rule(ruleno) {
+        for step in rule.steps.iter() {
+            roots.extend(step.item());
+            domain_types.extend(step.bucket_type());
+        }
+    }
+    (roots, domain_types)
+}
+
+// How many of `placements` each device beneath the rule's roots should
+// receive given its CRUSH weight and reweight value
+pub fn expected_placements(map: &CrushMap,
+                           ruleno: RuleId,
+                           placements: f64,
+                           weights: &[u32])
+                           -> Vec<(DeviceId, f64)> {
+    let (roots, _) = rule_layout(map, ruleno);
+    let candidates = CrushIndex::new(map).devices_under(&roots, 0x10000);
+    let effective: Vec<(DeviceId, f64)> = candidates.iter()
+        .map(|&(id, crush_weight)| {
+            let reweight = weights.get(id.0 as usize).cloned().unwrap_or(0);
+            (id, crush_weight as f64 * reweight as f64)
+        })
+        .collect();
+    let total: f64 = effective.iter().map(|e| e.1).sum();
+    effective.into_iter()
+        .map(|(id, weight)| {
+            (id,
+             if total > 0.0 {
+                placements * weight / total
+            } else {
+                0.0
+            })
+        })
+        .collect()
+}
+
+impl UtilizationReport {
+    /// Summarize the output of simulate().  `weights` should be the same
+    /// device weights the mappings were calculated with.
+    pub fn new(map: &CrushMap,
+               ruleno: RuleId,
+               num_rep: usize,
+               mappings: &[Vec<i32>],
+               weights: &[u32])
+               -> UtilizationReport {
+        let index = CrushIndex::new(map);
+        let (roots, domain_types) = rule_layout(map, ruleno);
+        let failure_domain = domain_types.first().cloned();
+
+        let mut candidates = index.devices_under(&roots, 0x10000);
+        candidates.sort();
+
+        let mut counts: HashMap<i32, u64> = HashMap::new();
+        let mut total_placements = 0;
+        let mut short_mappings = 0;
+        for mapping in mappings.iter() {
+            let placed = mapping.iter().filter(|i| **i != CRUSH_ITEM_NONE).count();
+            if placed < num_rep {
+                short_mappings += 1;
+            }
+            for item in mapping.iter().filter(|i| **i != CRUSH_ITEM_NONE) {
+                *counts.entry(*item).or_insert(0) += 1;
+                total_placements += 1;
+            }
+        }
+
+        let effective = |id: DeviceId, crush_weight: u32| -> f64 {
+            let reweight = weights.get(id.0 as usize).cloned().unwrap_or(0);
+            (crush_weight as f64 / 65536.0) * (reweight as f64 / 65536.0)
+        };
+        let total_weight: f64 = candidates.iter().map(|d| effective(d.0, d.1)).sum();
+
+        let devices: Vec<DeviceUtilization> = candidates.iter()
+            .map(|&(id, crush_weight)| {
+                let weight = effective(id, crush_weight);
+                let placements = counts.get(&id.0).cloned().unwrap_or(0);
+                let expected = if total_weight > 0.0 {
+                    total_placements as f64 * weight / total_weight
+                } else {
+                    0.0
+                };
+                DeviceUtilization {
+                    id: id.0,
+                    name: index.name(id.into()).map(String::from),
+                    weight: weight,
+                    placements: placements,
+                    expected: expected,
+                    ratio: if expected > 0.0 {
+                        placements as f64 / expected
+                    } else {
+                        0.0
+                    },
+                }
+            })
+            .collect();
+
+        let weighted: Vec<f64> = devices.iter()
+            .filter(|d| d.weight > 0.0)
+            .map(|d| d.placements as f64 - d.expected)
+            .collect();
+        let (stddev, max_deviation, min_deviation) = if weighted.is_empty() {
+            (0.0, 0.0, 0.0)
+        } else {
+            ((weighted.iter().map(|d| d * d).sum::<f64>() / weighted.len() as f64).sqrt(),
+             weighted.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+             weighted.iter().cloned().fold(f64::INFINITY, f64::min))
+        };
+
+        let mut domains = vec![];
+        if let Some(domain_type) = failure_domain {
+            let by_id: HashMap<i32, &DeviceUtilization> =
+                devices.iter().map(|d| (d.id, d)).collect();
+            for bucket in map.buckets.iter().filter_map(|b| b.bucket()) {
+                if bucket.bucket_type.0 != domain_type {
+                    continue;
+                }
+                let in_rule: Vec<&DeviceUtilization> = index.devices_under(&[bucket.id.into()], 0)
+                    .iter()
+                    .filter_map(|m| by_id.get(&(m.0).0).cloned())
+                    .collect();
+                if in_rule.is_empty() {
+                    continue;
+                }
+                domains.push(DomainUtilization {
+                    id: bucket.id.0,
+                    name: index.name(bucket.id.into()).map(String::from),
+                    placements: in_rule.iter().map(|d| d.placements).sum(),
+                    expected: in_rule.iter().map(|d| d.expected).sum(),
+                });
+            }
+        }
+
+        UtilizationReport {
+            rule: ruleno,
+            num_rep: num_rep,
+            inputs: mappings.len() as u64,
+            short_mappings: short_mappings,
+            total_placements: total_placements,
+            devices: devices,
+            failure_domain: failure_domain.and_then(|t| {
+                map.type_map.iter().find(|m| m.0 == t).map(|m| m.1.clone())
+            }),
+            domains: domains,
+            stddev: stddev,
+            max_deviation: max_deviation,
+            min_deviation: min_deviation,
+            choose_tries: None,
+        }
+    }
+
+    /// Include the tries counted by simulate_counting_tries in the report
+    pub fn with_choose_tries(mut self, choose_tries: ChooseTries) -> Self {
+        self.choose_tries = Some(choose_tries);
+        self
+    }
+}
+
+fn display_name(id: i32, name: &Option<String>) -> String {
+    match *name {
+        Some(ref n) => n.clone(),
+        None => id.to_string(),
+    }
+}
+
+impl fmt::Display for UtilizationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f,
+                      "rule {} num_rep {} result size == {}:\t{}/{}",
+                      self.rule,
+                      self.num_rep,
+                      self.num_rep,
+                      self.inputs - self.short_mappings,
+                      self.inputs));
+        try!(writeln!(f,
+                      "{:>6} {:<16} {:>10} {:>10} {:>12} {:>8}",
+                      "id",
+                      "name",
+                      "weight",
+                      "stored",
+                      "expected",
+                      "ratio"));
+        for d in self.devices.iter() {
+            try!(writeln!(f,
+                          "{:>6} {:<16} {:>10.5} {:>10} {:>12.2} {:>8.3}",
+                          d.id,
+                          display_name(d.id, &d.name),
+                          d.weight,
+                          d.placements,
+                          d.expected,
+                          d.ratio));
+        }
+        if let Some(ref domain) = self.failure_domain {
+            try!(writeln!(f, "per {} totals:", domain));
+            for d in self.domains.iter() {
+                try!(writeln!(f,
+                              "{:>6} {:<16} {:>10} {:>12.2}",
+                              d.id,
+                              display_name(d.id, &d.name),
+                              d.placements,
+                              d.expected));
+            }
+        }
+        try!(write!(f,
+                    "stddev {:.2}  max deviation {:.2}  min deviation {:.2}",
+                    self.stddev,
+                    self.max_deviation,
+                    self.min_deviation));
+        if let Some(ref tries) = self.choose_tries {
+            try!(write!(f, "\nchoose tries:"));
+            for (n, count) in tries.histogram.iter().enumerate() {
+                try!(write!(f, "\n{:>3}: {:>10}", n, count));
+            }
+            try!(write!(f,
+                        "\ngave up after choose_total_tries: {}",
+                        tries.exhausted_total));
+            try!(write!(f,
+                        "\nran out of choose_local_tries: {}",
+                        tries.exhausted_local));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+//! The hierarchy as a table
+//!
+//! Lists every bucket and device depth first beneath the roots, the way
+//! `ceph osd tree` does.  The device class shadow trees are left out.
+//!
+use std::fmt;
+
+use ::{BucketId, CrushIndex, CrushMap, Item, TypeId};
+
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct TreeEntry {
+    pub id: i32,
+    pub name: Option<String>,
+    pub type_name: Option<String>,
+    /// Devices only
+    pub class: Option<String>,
+    /// CRUSH weight.  A device's is its weight in the bucket it is listed
+    /// beneath.
+    pub weight: f64,
+    /// How many buckets up the root is
+    pub depth: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct Tree {
+    pub entries: Vec<TreeEntry>,
+}
+
+// Lists id and everything beneath it.  path holds the buckets above id so
+// that buckets holding each other are cut off where the cycle closes.
+fn add_entries(index: &CrushIndex,
+               id: Item,
+               weight: u32,
+               depth: usize,
+               path: &mut Vec<BucketId>,
+               entries: &mut Vec<TreeEntry>) {
+    let (type_id, class) = match id {
+        Item::Device(device) => (TypeId(0), index.device_class(device).map(String::from)),
+        Item::Bucket(bucket) => {
+            let type_id = index.bucket_by_id(bucket)
+                .and_then(|b| b.bucket())
+                .map(|b| b.bucket_type.0)
+                .unwrap_or(TypeId(0));
+            (type_id, None)
+        }
+    };
+    entries.push(TreeEntry {
+        id: id.id(),
+        name: index.name(id).map(String::from),
+        type_name: index.type_name(type_id).map(String::from),
+        class: class,
+        weight: weight as f64 / 0x10000 as f64,
+        depth: depth,
+    });
+    if let Item::Bucket(bucket) = id {
+        if path.contains(&bucket) {
+            return;
+        }
+        path.push(bucket);
+        for (child, child_weight) in index.items_of(bucket) {
+            add_entries(index, child, child_weight, depth + 1, path, entries);
+        }
+        path.pop();
+    }
+}
+
+impl Tree {
+    pub fn new(map: &CrushMap) -> Tree {
+        let index = CrushIndex::new(map);
+        let buckets: Vec<_> = map.buckets
+            .iter()
+            .filter_map(|b| b.bucket())
+            .filter(|b| !index.is_shadow(b.id))
+            .collect();
+        let mut entries = vec![];
+        for root in buckets.iter()
+            .filter(|b| !buckets.iter().any(|p| p.items.iter().any(|i| i.0 == b.id.into()))) {
+            add_entries(&index, root.id.into(), root.weight, 0, &mut vec![], &mut entries);
+        }
+        Tree { entries: entries }
+    }
+}
+
+impl fmt::Display for Tree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{:>5} {:<6} {:>9}  TYPE NAME", "ID", "CLASS", "WEIGHT"));
+        for entry in self.entries.iter() {
+            let name = entry.name.clone().unwrap_or_else(|| entry.id.to_string());
+            let label = match entry.type_name {
+                Some(ref t) if entry.id < 0 => format!("{} {}", t, name),
+                _ => name,
+            };
+            try!(write!(f,
+                        "\n{:>5} {:<6} {:>9.5}  {}{}",
+                        entry.id,
+                        entry.class.clone().unwrap_or_default(),
+                        entry.weight,
+                        "    ".repeat(entry.depth),
+                        label));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,224 @@
+//! An offline upmap balancer
+//!
+//! Simulates the placement groups of a set of pools and moves individual
+//! replicas from the most overfull devices to underfull ones with
+//! pg-upmap-items entries, the same way `osdmaptool --upmap` does.  A move
+//! is only made when the new device keeps the placement group within the
+//! failure domains its rule asks for.
+//!
+use std::collections::HashMap;
+use std::fmt;
+
+use mapper::{check_mappable, CRUSH_ITEM_NONE};
+use pool::Pool;
+use statistics::{expected_placements, rule_layout};
+use ::{BucketId, CrushIndex, CrushMap, DeviceId, RuleId, TypeId};
+
+/// The upmap items for a single placement group
+#[derive(Clone, Debug, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct UpmapItem {
+    pub pool: u32,
+    pub ps: u32,
+    /// (from, to) device pairs
+    pub mappings: Vec<(i32, i32)>,
+}
+
+impl fmt::Display for UpmapItem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "ceph osd pg-upmap-items {}.{:x}", self.pool, self.ps));
+        for &(from, to) in self.mappings.iter() {
+            try!(write!(f, " {} {}", from, to));
+        }
+        Ok(())
+    }
+}
+
+struct PgState {
+    pool: usize,
+    ps: u32,
+    osds: Vec<i32>,
+    items: Vec<(i32, i32)>,
+}
+
+// Which bucket of each of a rule's choose step types a device lives under
+struct RuleDomains {
+    candidates: Vec<(DeviceId, u32)>,
+    /// Device id to the bucket it lives under
+    levels: Vec<HashMap<i32, BucketId>>,
+}
+
+impl RuleDomains {
+    fn new(map: &CrushMap, ruleno: RuleId) -> RuleDomains {
+        let (roots, domain_types) = rule_layout(map, ruleno);
+        let index = CrushIndex::new(map);
+        let candidates = index.devices_under(&roots, 0x10000);
+        let levels = domain_types.iter()
+            .filter(|t| **t != TypeId(0))
+            .map(|t| {
+                let mut level = HashMap::new();
+                for bucket in map.buckets.iter().filter_map(|b| b.bucket()) {
+                    if bucket.bucket_type.0 != *t {
+                        continue;
+                    }
+                    for member in index.devices_under(&[bucket.id.into()], 0x10000) {
+                        level.insert((member.0).0, bucket.id);
+                    }
+                }
+                level
+            })
+            .collect();
+        RuleDomains {
+            candidates: candidates,
+            levels: levels,
+        }
+    }
+
+    fn contains(&self, osd: i32) -> bool {
+        self.candidates.iter().any(|c| (c.0).0 == osd)
+    }
+
+    // Can `to` replace `from` in osds without breaking the rule?  Every
+    // level but the last has to stay in the same bucket.  The last level
+    // may move to a bucket no other replica is using.
+    fn can_replace(&self, osds: &[i32], from: i32, to: i32) -> bool {
+        for (depth, level) in self.levels.iter().enumerate() {
+            let from_domain = level.get(&from);
+            let to_domain = level.get(&to);
+            if to_domain.is_none() {
+                return false;
+            }
+            if from_domain == to_domain {
+                continue;
+            }
+            if depth + 1 < self.levels.len() {
+                return false;
+            }
+            if osds.iter().any(|o| *o != from && level.get(o) == to_domain) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+fn record_move(items: &mut Vec<(i32, i32)>, from: i32, to: i32) {
+    // Moving a replica that an earlier item already moved just retargets
+    // that item
+    if let Some(pos) = items.iter().position(|i| i.1 == from) {
+        if items[pos].0 == to {
+            items.remove(pos);
+        } else {
+            items[pos].1 = to;
+        }
+        return;
+    }
+    items.push((from, to));
+}
+
+/// Calculate pg-upmap-items that bring every device to within
+/// `max_deviation` placement groups of its weighted target.
+///
+/// At most `max_optimizations` replicas are moved.  `weights` are the
+/// device reweight values, see mapper::do_rule.  Fails if a pool's rule is
+/// one the mapper can't place with, see mapper::check_mappable.
+pub fn calc_pg_upmaps(map: &CrushMap,
+                      pools: &[Pool],
+                      max_deviation: f64,
+                      max_optimizations: usize,
+                      weights: &[u32])
+                      -> Result<Vec<UpmapItem>, String> {
+    for pool in pools.iter() {
+        try!(check_mappable(map, pool.rule));
+    }
+    let domains: Vec<RuleDomains> = pools.iter().map(|p| RuleDomains::new(map, p.rule)).collect();
+
+    let mut pgs: Vec<PgState> = vec![];
+    for (pool_idx, pool) in pools.iter().enumerate() {
+        for ps in 0..pool.pg_num {
+            pgs.push(PgState {
+                pool: pool_idx,
+                ps: ps,
+                osds: pool.raw_pg_to_osds(map, ps, weights),
+                items: vec![],
+            });
+        }
+    }
+
+    // Each pool's replicas are spread over the devices its rule can reach
+    // in proportion to their weight
+    let mut targets: HashMap<i32, f64> = HashMap::new();
+    for pool in pools.iter() {
+        let replicas = pool.pg_num as f64 * pool.size as f64;
+        for (id, expected) in expected_placements(map, pool.rule, replicas, weights) {
+            *targets.entry(id.0).or_insert(0.0) += expected;
+        }
+    }
+
+    for _ in 0..max_optimizations {
+        let mut counts: HashMap<i32, f64> = targets.keys().map(|k| (*k, 0.0)).collect();
+        for pg in pgs.iter() {
+            for osd in pg.osds.iter().filter(|o| **o != CRUSH_ITEM_NONE) {
+                *counts.entry(*osd).or_insert(0.0) += 1.0;
+            }
+        }
+        let mut deviations: Vec<(i32, f64)> = counts.iter()
+            .map(|(osd, count)| (*osd, count - targets.get(osd).cloned().unwrap_or(0.0)))
+            .collect();
+        // Most overfull first, ties broken by id so results are repeatable
+        deviations.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then(a.0.cmp(&b.0)));
+
+        if deviations.iter().all(|d| d.1.abs() <= max_deviation) {
+            break;
+        }
+        // Prefer the devices outside of max_deviation.  Only when one side
+        // has none do we fall back to anything above or below its target.
+        let over_limit = deviations.iter().any(|d| d.1 > max_deviation);
+        let under_limit = deviations.iter().any(|d| d.1 < -max_deviation);
+        let overfull: Vec<i32> = deviations.iter()
+            .filter(|d| if over_limit { d.1 > max_deviation } else { d.1 > 0.0 })
+            .filter(|d| targets.contains_key(&d.0))
+            .map(|d| d.0)
+            .collect();
+        let underfull: Vec<i32> = deviations.iter()
+            .rev()
+            .filter(|d| if under_limit { d.1 < -max_deviation } else { d.1 < 0.0 })
+            .filter(|d| targets.get(&d.0).map(|t| *t > 0.0).unwrap_or(false))
+            .map(|d| d.0)
+            .collect();
+
+        let mut moved = false;
+        'search: for from in overfull.iter() {
+            for pg in pgs.iter_mut() {
+                let pos = match pg.osds.iter().position(|o| o == from) {
+                    Some(pos) => pos,
+                    None => continue,
+                };
+                let domain = &domains[pg.pool];
+                for to in underfull.iter() {
+                    if pg.osds.contains(to) || !domain.contains(*to) ||
+                       !domain.can_replace(&pg.osds, *from, *to) {
+                        continue;
+                    }
+                    pg.osds[pos] = *to;
+                    record_move(&mut pg.items, *from, *to);
+                    moved = true;
+                    break 'search;
+                }
+            }
+        }
+        if !moved {
+            break;
+        }
+    }
+
+    Ok(pgs.into_iter()
+        .filter(|pg| !pg.items.is_empty())
+        .map(|pg| {
+            UpmapItem {
+                pool: pools[pg.pool].id,
+                ps: pg.ps,
+                mappings: pg.items,
+            }
+        })
+        .collect())
+}
@@ -0,0 +1,171 @@
+//! Structural checks
+//!
+//! A map can decode fine and still be broken: a bucket sitting at the
+//! wrong index, an item that doesn't exist, a weight that doesn't add up or
+//! a rule taking a bucket that is gone.  Ceph refuses some of these and
+//! quietly mismaps on others.
+//!
+use satisfiability::check_rules;
+use statistics::describe_item;
+use ::{BucketId, CrushIndex, CrushMap, Item, Step};
+
+fn bucket_exists(map: &CrushMap, id: BucketId) -> bool {
+    map.buckets
+        .get(id.index())
+        .and_then(|b| b.bucket())
+        .map(|b| b.id == id)
+        .unwrap_or(false)
+}
+
+fn item_exists(map: &CrushMap, id: Item) -> bool {
+    match id {
+        Item::Device(device) => device.0 < map.max_devices,
+        Item::Bucket(bucket) => bucket_exists(map, bucket),
+    }
+}
+
+fn find_cycle(map: &CrushMap, id: BucketId, path: &mut Vec<BucketId>) -> Option<Vec<BucketId>> {
+    if path.contains(&id) {
+        let mut cycle = path.clone();
+        cycle.push(id);
+        return Some(cycle);
+    }
+    if !bucket_exists(map, id) {
+        return None;
+    }
+    path.push(id);
+    let items: Vec<BucketId> = map.buckets[id.index()]
+        .bucket()
+        .unwrap()
+        .items
+        .iter()
+        .filter_map(|i| i.0.bucket())
+        .collect();
+    for item in items {
+        if let Some(cycle) = find_cycle(map, item, path) {
+            return Some(cycle);
+        }
+    }
+    path.pop();
+    None
+}
+
+/// Every problem found with map, described in words.  Empty if there are
+/// none.  Rules that no size can be fully placed with are included, see
+/// check_rules.
+pub fn validate(map: &CrushMap) -> Vec<String> {
+    let mut problems = vec![];
+
+    if map.max_buckets < map.buckets.len() as i32 {
+        problems.push(format!("max_buckets is {} but there are {} buckets",
+                              map.max_buckets,
+                              map.buckets.len()));
+    }
+    let index = CrushIndex::new(map);
+    let mut parents: Vec<(BucketId, Vec<BucketId>)> = vec![];
+    for (position, b) in map.buckets.iter().enumerate() {
+        let bucket = match b.bucket() {
+            Some(bucket) => bucket,
+            None => continue,
+        };
+        let name = describe_item(&index, bucket.id.into());
+        if bucket.id != BucketId::from_index(position) {
+            problems.push(format!("bucket {} is at the index of bucket {}",
+                                  name,
+                                  BucketId::from_index(position)));
+        }
+        if bucket.size as usize != bucket.items.len() {
+            problems.push(format!("bucket {} has size {} but {} items",
+                                  name,
+                                  bucket.size,
+                                  bucket.items.len()));
+        }
+        let weights = b.item_weights();
+        let total = weights.iter().fold(0u32, |sum, w| sum.wrapping_add(*w));
+        if total != bucket.weight {
+            problems.push(format!("bucket {} weighs {:.5} but its items add up to {:.5}",
+                                  name,
+                                  bucket.weight as f64 / 0x10000 as f64,
+                                  total as f64 / 0x10000 as f64));
+        }
+        for (item, weight) in bucket.items.iter().map(|i| i.0).zip(weights) {
+            if !item_exists(map, item) {
+                problems.push(format!("bucket {} holds {} which doesn't exist", name, item));
+                continue;
+            }
+            if let Item::Bucket(item) = item {
+                let child = map.buckets[item.index()].bucket().unwrap().weight;
+                if child != weight {
+                    problems.push(format!("bucket {} gives {} a weight of {:.5} instead of its \
+                                           {:.5}",
+                                          name,
+                                          describe_item(&index, item.into()),
+                                          weight as f64 / 0x10000 as f64,
+                                          child as f64 / 0x10000 as f64));
+                }
+                match parents.iter().position(|p| p.0 == item) {
+                    Some(p) => parents[p].1.push(bucket.id),
+                    None => parents.push((item, vec![bucket.id])),
+                }
+            }
+        }
+    }
+    for &(child, ref holders) in parents.iter().filter(|p| p.1.len() > 1) {
+        let names: Vec<String> =
+            holders.iter().map(|h| describe_item(&index, (*h).into())).collect();
+        problems.push(format!("bucket {} is in more than one bucket: {}",
+                              describe_item(&index, child.into()),
+                              names.join(", ")));
+    }
+    for b in map.buckets.iter().filter_map(|b| b.bucket()) {
+        if let Some(cycle) = find_cycle(map, b.id, &mut vec![]) {
+            let names: Vec<String> =
+                cycle.iter().map(|i| describe_item(&index, (*i).into())).collect();
+            problems.push(format!("buckets hold each other: {}", names.join(" -> ")));
+            break;
+        }
+    }
+
+    for (i, n) in map.name_map.iter().enumerate() {
+        if map.name_map[..i].iter().any(|m| m.1 == n.1) {
+            problems.push(format!("{} names more than one item", n.1));
+        }
+    }
+
+    let type_exists = |id| map.type_map.is_empty() || map.type_map.iter().any(|t| t.0 == id);
+    for (ruleno, rule) in map.rules.iter().enumerate() {
+        let rule = match *rule {
+            Some(ref rule) => rule,
+            None => continue,
+        };
+        for (index, step) in rule.steps.iter().enumerate() {
+            match *step.effective() {
+                Step::Take { ref item } if !item_exists(map, item.0) => {
+                    problems.push(format!("rule {} step {} takes {} which doesn't exist",
+                                          ruleno,
+                                          index,
+                                          item.0))
+                }
+                Step::Choose { ref bucket_type, .. } if !type_exists(bucket_type.0) => {
+                    problems.push(format!("rule {} step {} chooses type {} which doesn't exist",
+                                          ruleno,
+                                          index,
+                                          bucket_type.0))
+                }
+                _ => {}
+            }
+        }
+        if rule.steps.last().map(|s| *s.effective() != Step::Emit).unwrap_or(true) {
+            problems.push(format!("rule {} doesn't end with an emit step", ruleno));
+        }
+    }
+    for check in check_rules(map).iter() {
+        if check.accepts_no_size() {
+            problems.push(format!("rule {} accepts no size: min_size is above max_size",
+                                  check.rule));
+        } else if check.never_satisfiable() {
+            problems.push(format!("rule {} can't fully place any size it accepts", check.rule));
+        }
+    }
+    problems
+}
@@ -0,0 +1,200 @@
+//! choose_args weight-set optimizer, the offline equivalent of the
+//! balancer's crush-compat mode.
+//!
+//! The CRUSH weights stay untouched.  Instead the weights straw2 buckets
+//! use for placement are nudged up or down, one iteration at a time,
+//! until each device receives close to the share of placement groups its
+//! CRUSH weight asks for.
+//!
+use std::collections::HashMap;
+
+use mapper::{check_mappable, do_rule_with_choose_args, CRUSH_ITEM_NONE};
+use pool::Pool;
+use statistics::{expected_placements, rule_layout};
+use ::{BucketTypes, ChooseArg, CrushMap, DeviceId, Item};
+
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct WeightSetOptimization {
+    /// Number of iterations that were run
+    pub iterations: usize,
+    /// Standard deviation of the per device placement group counts from
+    /// their targets, before and after
+    pub initial_stddev: f64,
+    pub final_stddev: f64,
+}
+
+// A weight-set with one position for every straw2 bucket, seeded with the
+// bucket's own weights
+fn initial_choose_args(map: &CrushMap) -> Vec<ChooseArg> {
+    map.buckets
+        .iter()
+        .filter_map(|b| match *b {
+            BucketTypes::Straw2(ref straw) => {
+                Some(ChooseArg {
+                    bucket_id: straw.bucket.id,
+                    weight_set: vec![straw.item_weights.clone()],
+                    ids: vec![],
+                })
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn stddev(map: &CrushMap,
+          pool: &Pool,
+          targets: &[(DeviceId, f64)],
+          weights: &[u32],
+          choose_args: &[ChooseArg])
+          -> (f64, HashMap<i32, f64>) {
+    let mut counts: HashMap<i32, f64> = HashMap::new();
+    for ps in 0..pool.pg_num {
+        let osds = do_rule_with_choose_args(map,
+                                            pool.rule,
+                                            pool.pg_to_pps(ps) as i32,
+                                            pool.size,
+                                            weights,
+                                            choose_args);
+        for osd in osds.into_iter().filter(|o| *o != CRUSH_ITEM_NONE) {
+            *counts.entry(osd).or_insert(0.0) += 1.0;
+        }
+    }
+    let deviations: Vec<f64> = targets.iter()
+        .filter(|t| t.1 > 0.0)
+        .map(|t| counts.get(&(t.0).0).cloned().unwrap_or(0.0) - t.1)
+        .collect();
+    if deviations.is_empty() {
+        return (0.0, counts);
+    }
+    let variance = deviations.iter().map(|d| d * d).sum::<f64>() / deviations.len() as f64;
+    (variance.sqrt(), counts)
+}
+
+// Make every bucket's weight in its parent's weight-set the sum of its own
+// weight-set, bottom up.  Returns the total weight of item.
+fn propagate(map: &CrushMap, choose_args: &mut Vec<ChooseArg>, item: Item) -> Vec<u32> {
+    let id = match item {
+        Item::Bucket(id) => id,
+        Item::Device(_) => return vec![],
+    };
+    let bucket = match map.buckets.get(id.index()).and_then(|b| b.bucket()) {
+        Some(b) => b,
+        None => return vec![],
+    };
+    let children: Vec<Vec<u32>> = bucket.items
+        .iter()
+        .map(|i| propagate(map, choose_args, i.0))
+        .collect();
+    let arg = match choose_args.iter_mut().find(|a| a.bucket_id == id) {
+        Some(arg) => arg,
+        None => return vec![bucket.weight],
+    };
+    for (position, weights) in arg.weight_set.iter_mut().enumerate() {
+        for (weight, child) in weights.iter_mut().zip(children.iter()) {
+            if !child.is_empty() {
+                *weight = child[::std::cmp::min(position, child.len() - 1)];
+            }
+        }
+    }
+    arg.weight_set
+        .iter()
+        .map(|weights| weights.iter().fold(0u32, |sum, w| sum.saturating_add(*w)))
+        .collect()
+}
+
+/// Adjust the weight-set stored under `choose_args_index` (a pool id or
+/// -1 for the compat weight-set) so that `pool`'s placement groups are
+/// spread over the devices as evenly as their CRUSH weights allow.  The
+/// best weight-set found within `max_iterations` is written back into
+/// map.choose_args.  `weights` are the device reweight values, see
+/// mapper::do_rule.  Fails, leaving map alone, if the pool's rule is one
+/// the mapper can't place with, see mapper::check_mappable.
+pub fn optimize_choose_args(map: &mut CrushMap,
+                            pool: &Pool,
+                            choose_args_index: i64,
+                            max_iterations: usize,
+                            weights: &[u32])
+                            -> Result<WeightSetOptimization, String> {
+    try!(check_mappable(map, pool.rule));
+    let mut choose_args = map.choose_args
+        .as_ref()
+        .and_then(|all| all.iter().find(|a| a.0 == choose_args_index))
+        .map(|a| a.1.clone())
+        .unwrap_or_else(|| initial_choose_args(map));
+
+    // A weight-set read from the map may not match its bucket any more, if
+    // the bucket was edited since.  Those start over from the bucket's
+    // own weights.
+    for arg in choose_args.iter_mut() {
+        let item_weights = match map.buckets.get(arg.bucket_id.index()) {
+            Some(b) if b.bucket().is_some() => b.item_weights(),
+            _ => continue,
+        };
+        for weights in arg.weight_set.iter_mut() {
+            if weights.len() != item_weights.len() {
+                *weights = item_weights.clone();
+            }
+        }
+    }
+
+    let replicas = pool.pg_num as f64 * pool.size as f64;
+    let targets = expected_placements(map, pool.rule, replicas, weights);
+    let (roots, _) = rule_layout(map, pool.rule);
+
+    let (initial_stddev, mut counts) = stddev(map, pool, &targets, weights, &choose_args);
+    let mut best = (initial_stddev, choose_args.clone(), counts.clone());
+    let mut iterations = 0;
+    // How far to move towards the ideal weight each iteration
+    let mut step = 0.5;
+
+    while iterations < max_iterations && best.0 > 0.0 {
+        iterations += 1;
+        for &(osd, target) in targets.iter() {
+            if target <= 0.0 {
+                continue;
+            }
+            let count = counts.get(&osd.0).cloned().unwrap_or(0.0).max(0.5);
+            let factor = (target / count).powf(step);
+            for arg in choose_args.iter_mut() {
+                let pos = match map.buckets
+                    .get(arg.bucket_id.index())
+                    .and_then(|b| b.bucket())
+                    .and_then(|b| b.items.iter().position(|i| i.0 == osd.into())) {
+                    Some(pos) => pos,
+                    None => continue,
+                };
+                for weight in arg.weight_set.iter_mut().filter_map(|w| w.get_mut(pos)) {
+                    let adjusted = (*weight as f64 * factor).round();
+                    *weight = adjusted.max(1.0).min(u32::max_value() as f64) as u32;
+                }
+            }
+        }
+        for root in roots.iter() {
+            propagate(map, &mut choose_args, *root);
+        }
+
+        let (current, current_counts) = stddev(map, pool, &targets, weights, &choose_args);
+        if current < best.0 {
+            counts = current_counts;
+            best = (current, choose_args.clone(), counts.clone());
+        } else {
+            // Overshot.  Go back to the best weights and take smaller steps.
+            choose_args = best.1.clone();
+            counts = best.2.clone();
+            step /= 2.0;
+        }
+    }
+
+    let (final_stddev, best_args, _) = best;
+    let mut all = map.choose_args.take().unwrap_or(vec![]);
+    all.retain(|a| a.0 != choose_args_index);
+    all.push((choose_args_index, best_args));
+    all.sort_by_key(|a| a.0);
+    map.choose_args = Some(all);
+
+    Ok(WeightSetOptimization {
+        iterations: iterations,
+        initial_stddev: initial_stddev,
+        final_stddev: final_stddev,
+    })
+}
@@ -0,0 +1,366 @@
+extern crate crushtool;
+#[macro_use]
+extern crate quickcheck;
+
+use quickcheck::{Arbitrary, Gen};
+
+use crushtool::{CrushMap, CrushIndex, DocumentBucket, DocumentChooseArg, DocumentChooseArgs,
+                DocumentDevice, DocumentItem, DocumentRule, DocumentShadow, DocumentStep,
+                DocumentTunables, DocumentType, Item, MapDocument, RuleId, CRUSH_ITEM_NONE,
+                decode_crushmap, default_weights, do_rule, encode_crushmap,
+                set_tunables_argonaut, set_tunables_bobtail, set_tunables_firefly,
+                set_tunables_hammer, set_tunables_jewel, validate};
+
+static ALGS: [&'static str; 5] = ["uniform", "list", "tree", "straw", "straw2"];
+
+/// A map validate() finds nothing wrong with.  A root holds hosts, sometimes
+/// grouped in racks, and the rules spread replicas over the hosts or racks,
+/// with choose or chooseleaf, firstn or indep and a few set steps.  The
+/// tunables are those of a random release and end where that release's maps
+/// did.  Luminous maps may have device classes, with their shadow trees and
+/// rules taking them, and a weight-set.
+#[derive(Clone, Debug)]
+struct ValidMap(CrushMap);
+
+fn choose<'a, G: Gen, T>(g: &mut G, values: &'a [T]) -> &'a T {
+    &values[g.gen_range(0, values.len())]
+}
+
+// Between 0.25 and 4, in steps a 16.16 fixed point weight holds exactly
+fn weight<G: Gen>(g: &mut G) -> f64 {
+    g.gen_range(4, 65) as f64 / 16.0
+}
+
+fn item(name: &str, weight: Option<f64>) -> DocumentItem {
+    DocumentItem {
+        name: name.to_string(),
+        weight: weight,
+    }
+}
+
+fn bucket(id: i32, name: &str, type_name: &str, alg: &str, items: Vec<DocumentItem>)
+          -> DocumentBucket {
+    DocumentBucket {
+        id: id,
+        name: Some(name.to_string()),
+        type_name: type_name.to_string(),
+        alg: alg.to_string(),
+        hash: "rjenkins1".to_string(),
+        items: items,
+        straws: None,
+    }
+}
+
+fn step(op: &str, item: Option<&str>, num: Option<i32>, type_name: Option<&str>) -> DocumentStep {
+    DocumentStep {
+        op: op.to_string(),
+        item: item.map(|i| i.to_string()),
+        num: num,
+        type_name: type_name.map(|t| t.to_string()),
+        args: None,
+    }
+}
+
+// A rule taking one of roots and separating replicas by one of domains
+fn rule<G: Gen>(g: &mut G, id: usize, roots: &[String], domains: &[&str]) -> DocumentRule {
+    let mode = *choose(g, &["firstn", "indep"]);
+    let domain = *choose(g, domains);
+    let mut steps = vec![];
+    if g.gen() {
+        steps.push(step("set_choose_tries", None, Some(g.gen_range(50, 101)), None));
+    }
+    if g.gen() {
+        steps.push(step("set_chooseleaf_tries", None, Some(g.gen_range(1, 6)), None));
+    }
+    if g.gen() {
+        steps.push(step("set_chooseleaf_vary_r", None, Some(g.gen_range(0, 2)), None));
+    }
+    if g.gen() {
+        steps.push(step("set_chooseleaf_stable", None, Some(g.gen_range(0, 2)), None));
+    }
+    steps.push(step("take", Some(&choose(g, roots)[..]), None, None));
+    if g.gen() {
+        steps.push(step(&format!("chooseleaf_{}", mode), None, Some(0), Some(domain)));
+    } else {
+        steps.push(step(&format!("choose_{}", mode), None, Some(0), Some(domain)));
+        steps.push(step(&format!("choose_{}", mode), None, Some(1), Some("osd")));
+    }
+    steps.push(step("emit", None, None, None));
+    DocumentRule {
+        id: id,
+        name: Some(format!("rule{}", id)),
+        ruleset: id as u8,
+        rule_type: if mode == "firstn" { "replicated" } else { "erasure" }.to_string(),
+        min_size: 1,
+        max_size: 20,
+        steps: steps,
+    }
+}
+
+// Add the shadow of each of hierarchy to buckets for class, holding only
+// the devices of that class, the way Ceph builds them.  hierarchy is in the
+// order it was built, so a bucket's items come before it.
+fn shadows(hierarchy: &[DocumentBucket],
+           devices: &[DocumentDevice],
+           class: &str,
+           buckets: &mut Vec<DocumentBucket>,
+           shadows: &mut Vec<DocumentShadow>) {
+    for b in hierarchy.iter() {
+        let items = b.items
+            .iter()
+            .filter_map(|i| {
+                match devices.iter().find(|d| d.name.as_ref() == Some(&i.name)) {
+                    Some(d) if d.class.as_ref().map(|c| c == class).unwrap_or(false) => {
+                        Some(i.clone())
+                    }
+                    Some(_) => None,
+                    None => Some(item(&format!("{}~{}", i.name, class), None)),
+                }
+            })
+            .collect();
+        let name = b.name.clone().unwrap();
+        let shadow = format!("{}~{}", name, class);
+        let id = -1 - buckets.len() as i32;
+        buckets.push(bucket(id, &shadow, &b.type_name, &b.alg, items));
+        shadows.push(DocumentShadow {
+            bucket: name,
+            class: class.to_string(),
+            shadow: shadow,
+        });
+    }
+}
+
+// The tunables of a release, left out past the last one it knew about
+fn tunables<G: Gen>(g: &mut G) -> DocumentTunables {
+    let mut map = CrushMap::default();
+    let known = match g.gen_range(0, 5) {
+        0 => {
+            set_tunables_argonaut(&mut map);
+            0
+        }
+        1 => {
+            set_tunables_bobtail(&mut map);
+            4
+        }
+        2 => {
+            set_tunables_firefly(&mut map);
+            5
+        }
+        3 => {
+            set_tunables_hammer(&mut map);
+            7
+        }
+        _ => {
+            set_tunables_jewel(&mut map);
+            8
+        }
+    };
+    let mut tunables = DocumentTunables {
+        choose_local_tries: map.choose_local_tries,
+        choose_local_fallback_tries: map.choose_local_fallback_tries,
+        choose_total_tries: map.choose_total_tries,
+        chooseleaf_descend_once: map.chooseleaf_descend_once,
+        chooseleaf_vary_r: map.chooseleaf_vary_r,
+        straw_calc_version: map.straw_calc_version,
+        allowed_bucket_algorithms: map.allowed_bucket_algorithms,
+        chooseleaf_stable: map.chooseleaf_stable,
+        msr_descents: None,
+        msr_collision_tries: None,
+    };
+    if known < 8 {
+        tunables.chooseleaf_stable = None;
+    }
+    if known < 7 {
+        tunables.allowed_bucket_algorithms = None;
+        tunables.straw_calc_version = None;
+    }
+    if known < 5 {
+        tunables.chooseleaf_vary_r = None;
+    }
+    if known < 4 {
+        tunables = DocumentTunables::default();
+    }
+    tunables
+}
+
+impl Arbitrary for ValidMap {
+    fn arbitrary<G: Gen>(g: &mut G) -> ValidMap {
+        let tunables = tunables(g);
+        let luminous = tunables.chooseleaf_stable.is_some();
+        let class_names = if luminous && g.gen() {
+            vec!["hdd", "ssd"]
+        } else {
+            vec![]
+        };
+
+        let mut devices = vec![];
+        let mut buckets = vec![];
+        let mut hosts = 0;
+        // No racks means the hosts are right beneath the root
+        let racks = g.gen_range(0, 4);
+        let mut top = vec![];
+        for rack in 0..racks.max(1) {
+            let mut rack_items = vec![];
+            for _ in 0..g.gen_range(1, 5) {
+                let host = format!("host{}", hosts);
+                hosts += 1;
+                // Every item of a uniform bucket weighs the same
+                let alg = *choose(g, &ALGS);
+                let uniform_weight = weight(g);
+                let mut items = vec![];
+                for _ in 0..g.gen_range(1, 5) {
+                    let id = devices.len() as i32;
+                    let name = format!("osd.{}", id);
+                    let w = if alg == "uniform" { uniform_weight } else { weight(g) };
+                    items.push(item(&name, Some(w)));
+                    devices.push(DocumentDevice {
+                        id: id,
+                        name: Some(name),
+                        class: if class_names.is_empty() {
+                            None
+                        } else {
+                            Some(choose(g, &class_names).to_string())
+                        },
+                    });
+                }
+                buckets.push(bucket(-2 - buckets.len() as i32, &host, "host", alg, items));
+                rack_items.push(item(&host, None));
+            }
+            if racks == 0 {
+                top = rack_items;
+            } else {
+                let name = format!("rack{}", rack);
+                let alg = *choose(g, &ALGS[1..]);
+                buckets.push(bucket(-2 - buckets.len() as i32, &name, "rack", alg, rack_items));
+                top.push(item(&name, None));
+            }
+        }
+        let alg = *choose(g, &ALGS[1..]);
+        buckets.push(bucket(-1, "default", "root", alg, top));
+
+        let mut shadow_list = vec![];
+        let mut roots = vec!["default".to_string()];
+        let hierarchy = buckets.clone();
+        for class in class_names.iter() {
+            shadows(&hierarchy, &devices, class, &mut buckets, &mut shadow_list);
+            // A rule taking a class without devices can't place anything
+            if devices.iter().any(|d| d.class.as_ref().map(|c| c == class).unwrap_or(false)) {
+                roots.push(format!("default~{}", class));
+            }
+        }
+
+        let domains = if racks > 0 { vec!["host", "rack"] } else { vec!["host"] };
+        let rules: Vec<DocumentRule> =
+            (0..g.gen_range(1, 4)).map(|id| rule(g, id, &roots, &domains)).collect();
+
+        // Luminous maps carry all the tunables and the device classes, and
+        // maybe a weight-set
+        let choose_args = if luminous && g.gen() {
+            let args = buckets.iter()
+                .map(|b| {
+                    DocumentChooseArg {
+                        bucket: b.name.clone().unwrap(),
+                        weight_set: vec![b.items.iter().map(|_| weight(g)).collect()],
+                        ids: vec![],
+                    }
+                })
+                .collect();
+            Some(vec![DocumentChooseArgs {
+                          id: -1,
+                          buckets: args,
+                      }])
+        } else {
+            None
+        };
+        let classes = if !class_names.is_empty() || choose_args.is_some() {
+            Some(class_names.iter()
+                .enumerate()
+                .map(|(id, name)| {
+                    DocumentType {
+                        id: id as i32,
+                        name: name.to_string(),
+                    }
+                })
+                .collect())
+        } else {
+            None
+        };
+
+        let doc = MapDocument {
+            max_buckets: buckets.len() as i32,
+            max_rules: rules.len() as u32,
+            max_devices: devices.len() as i32,
+            tunables: tunables,
+            types: CrushMap::default()
+                .type_map
+                .iter()
+                .map(|t| {
+                    DocumentType {
+                        id: t.0.into(),
+                        name: t.1.clone(),
+                    }
+                })
+                .collect(),
+            classes: classes,
+            devices: devices,
+            buckets: buckets,
+            shadows: shadow_list,
+            rules: rules,
+            choose_args: choose_args,
+        };
+        ValidMap(doc.to_crushmap().unwrap())
+    }
+}
+
+// Each rule placing as many replicas as there are hosts, for a few inputs
+fn placements(map: &CrushMap, x: i32) -> Vec<Vec<i32>> {
+    let index = CrushIndex::new(map);
+    let hosts = map.type_map.iter().find(|t| t.1 == "host").unwrap().0;
+    let num_rep = map.buckets
+        .iter()
+        .filter_map(|b| b.bucket())
+        .filter(|b| b.bucket_type.0 == hosts && !index.is_shadow(b.id))
+        .count();
+    let weights = default_weights(map);
+    let mut results = vec![];
+    for rule in (0..map.rules.len()).map(|r| RuleId(r as i32)) {
+        for i in 0..8 {
+            let placed = do_rule(map, rule, x.wrapping_add(i), num_rep, &weights);
+            results.push(placed.into_iter().filter(|d| *d != CRUSH_ITEM_NONE).collect());
+        }
+    }
+    results
+}
+
+quickcheck! {
+    fn it_round_trips_random_maps(map: ValidMap) -> bool {
+        let encoded = encode_crushmap(map.0.clone()).unwrap();
+        decode_crushmap(&encoded) == Ok(map.0)
+    }
+
+    fn it_validates_random_maps(map: ValidMap) -> bool {
+        validate(&map.0).is_empty()
+    }
+
+    fn it_places_on_distinct_devices(map: ValidMap, x: i32) -> bool {
+        placements(&map.0, x).iter().all(|placed| {
+            placed.iter().enumerate().all(|(i, d)| !placed[..i].contains(d))
+        })
+    }
+
+    // Rules separating racks separate the hosts in them as well
+    fn it_places_in_distinct_failure_domains(map: ValidMap, x: i32) -> bool {
+        let index = CrushIndex::new(&map.0);
+        placements(&map.0, x).iter().all(|placed| {
+            let hosts: Vec<_> = placed.iter()
+                .map(|d| index.parent_of(Item::from(*d)).unwrap())
+                .collect();
+            hosts.iter().enumerate().all(|(i, h)| !hosts[..i].contains(h))
+        })
+    }
+
+    fn it_places_deterministically(map: ValidMap, x: i32) -> bool {
+        let decoded = decode_crushmap(&encode_crushmap(map.0.clone()).unwrap()).unwrap();
+        placements(&map.0, x) == placements(&decoded, x)
+    }
+}
@@ -1,9 +1,28 @@
 extern crate nom;
 extern crate crushtool;
+
+use std::ffi::{CStr, CString};
+use std::fs::{self, File};
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::ptr;
+
 use crushtool::{CrushMap, BucketTypes, CrushBucketStraw, CrushBucketStraw2, OpCode, BucketAlg,
-                CrushRuleStep, Bucket, CrushRuleMask, CrushHash, Rule, RuleType, decode_crushmap,
-                encode_crushmap, set_tunables_jewel, set_tunables_argonaut, set_tunables_bobtail,
-                set_tunables_firefly, set_tunables_hammer};
+                Step, ChooseMode, Bucket, CrushRuleMask, CrushHash, Rule, RuleType, decode_crushmap,
+                decode_crushmap_trailing, encode_crushmap, set_tunables_jewel,
+                set_tunables_argonaut, set_tunables_bobtail, set_tunables_firefly,
+                set_tunables_hammer, default_weights, do_rule, find_rule,
+                simulate, UtilizationReport, calc_pg_upmaps, Pool, ChooseArg,
+                do_rule_with_choose_args, do_rule_for_pool, DEFAULT_CHOOSE_ARGS,
+                optimize_choose_args, resolve_item, simulate_failure,
+                check_rule, check_rules, RuleBuilder, RuleBuilderError, ErasureCodeProfile,
+                CRUSH_ITEM_NONE, explain, TraceEvent, RejectReason, simulate_counting_tries,
+                Reclassify, compare_maps, to_dot, DotOptions, create_bucket, add_device,
+                move_bucket, reweight_device, remove_item, validate, diff_maps, Tree, encode_yaml,
+                decode_yaml, encode_toml, decode_toml, to_yaml, from_yaml, to_toml, from_toml,
+                CephCrushMap, CephBucket, CephBucketType, CephHost, CrushIndex, DocumentDevice,
+                MapDocument, BucketId, DeviceId, Item, RuleId, Ruleset, TypeId, check_mappable,
+                ffi};
 
 fn get_crushmap() -> CrushMap {
     CrushMap {
@@ -13,17 +32,17 @@ fn get_crushmap() -> CrushMap {
         max_devices: 3,
         buckets: vec![],
         rules: vec![],
-        type_map: vec![(0, "osd".to_string()),
-                       (1, "host".to_string()),
-                       (2, "chassis".to_string()),
-                       (3, "rack".to_string()),
-                       (4, "row".to_string()),
-                       (5, "pdu".to_string()),
-                       (6, "pod".to_string()),
-                       (7, "room".to_string()),
-                       (8, "datacenter".to_string()),
-                       (9, "region".to_string()),
-                       (10, "root".to_string())],
+        type_map: vec![(TypeId(0), "osd".to_string()),
+                       (TypeId(1), "host".to_string()),
+                       (TypeId(2), "chassis".to_string()),
+                       (TypeId(3), "rack".to_string()),
+                       (TypeId(4), "row".to_string()),
+                       (TypeId(5), "pdu".to_string()),
+                       (TypeId(6), "pod".to_string()),
+                       (TypeId(7), "room".to_string()),
+                       (TypeId(8), "datacenter".to_string()),
+                       (TypeId(9), "region".to_string()),
+                       (TypeId(10), "root".to_string())],
         name_map: vec![],
         rule_name_map: vec![],
         choose_local_tries: Some(0),
@@ -34,6 +53,12 @@ fn get_crushmap() -> CrushMap {
         straw_calc_version: Some(0),
         allowed_bucket_algorithms: Some(0),
         chooseleaf_stable: Some(1),
+        msr_descents: None,
+        msr_collision_tries: None,
+        class_map: None,
+        class_name: None,
+        class_bucket: None,
+        choose_args: None,
     }
 }
 
@@ -47,17 +72,17 @@ fn it_sets_tunables_for_argonaut() {
         max_devices: 3,
         buckets: vec![],
         rules: vec![],
-        type_map: vec![(0, "osd".to_string()),
-                       (1, "host".to_string()),
-                       (2, "chassis".to_string()),
-                       (3, "rack".to_string()),
-                       (4, "row".to_string()),
-                       (5, "pdu".to_string()),
-                       (6, "pod".to_string()),
-                       (7, "room".to_string()),
-                       (8, "datacenter".to_string()),
-                       (9, "region".to_string()),
-                       (10, "root".to_string())],
+        type_map: vec![(TypeId(0), "osd".to_string()),
+                       (TypeId(1), "host".to_string()),
+                       (TypeId(2), "chassis".to_string()),
+                       (TypeId(3), "rack".to_string()),
+                       (TypeId(4), "row".to_string()),
+                       (TypeId(5), "pdu".to_string()),
+                       (TypeId(6), "pod".to_string()),
+                       (TypeId(7), "room".to_string()),
+                       (TypeId(8), "datacenter".to_string()),
+                       (TypeId(9), "region".to_string()),
+                       (TypeId(10), "root".to_string())],
         name_map: vec![],
         rule_name_map: vec![],
         choose_local_tries: Some(2),
@@ -68,6 +93,12 @@ fn it_sets_tunables_for_argonaut() {
         straw_calc_version: Some(0),
         allowed_bucket_algorithms: Some(22),
         chooseleaf_stable: Some(0),
+        msr_descents: None,
+        msr_collision_tries: None,
+        class_map: None,
+        class_name: None,
+        class_bucket: None,
+        choose_args: None,
     };
     set_tunables_argonaut(&mut crushmap);
     assert_eq!(expected, crushmap);
@@ -83,17 +114,17 @@ fn it_sets_tunables_for_bobtail() {
         max_devices: 3,
         buckets: vec![],
         rules: vec![],
-        type_map: vec![(0, "osd".to_string()),
-                       (1, "host".to_string()),
-                       (2, "chassis".to_string()),
-                       (3, "rack".to_string()),
-                       (4, "row".to_string()),
-                       (5, "pdu".to_string()),
-                       (6, "pod".to_string()),
-                       (7, "room".to_string()),
-                       (8, "datacenter".to_string()),
-                       (9, "region".to_string()),
-                       (10, "root".to_string())],
+        type_map: vec![(TypeId(0), "osd".to_string()),
+                       (TypeId(1), "host".to_string()),
+                       (TypeId(2), "chassis".to_string()),
+                       (TypeId(3), "rack".to_string()),
+                       (TypeId(4), "row".to_string()),
+                       (TypeId(5), "pdu".to_string()),
+                       (TypeId(6), "pod".to_string()),
+                       (TypeId(7), "room".to_string()),
+                       (TypeId(8), "datacenter".to_string()),
+                       (TypeId(9), "region".to_string()),
+                       (TypeId(10), "root".to_string())],
         name_map: vec![],
         rule_name_map: vec![],
         choose_local_tries: Some(0),
@@ -104,6 +135,12 @@ fn it_sets_tunables_for_bobtail() {
         straw_calc_version: Some(0),
         allowed_bucket_algorithms: Some(22),
         chooseleaf_stable: Some(0),
+        msr_descents: None,
+        msr_collision_tries: None,
+        class_map: None,
+        class_name: None,
+        class_bucket: None,
+        choose_args: None,
     };
     set_tunables_bobtail(&mut crushmap);
     assert_eq!(expected, crushmap);
@@ -119,17 +156,17 @@ fn it_sets_tunables_for_firefly() {
         max_devices: 3,
         buckets: vec![],
         rules: vec![],
-        type_map: vec![(0, "osd".to_string()),
-                       (1, "host".to_string()),
-                       (2, "chassis".to_string()),
-                       (3, "rack".to_string()),
-                       (4, "row".to_string()),
-                       (5, "pdu".to_string()),
-                       (6, "pod".to_string()),
-                       (7, "room".to_string()),
-                       (8, "datacenter".to_string()),
-                       (9, "region".to_string()),
-                       (10, "root".to_string())],
+        type_map: vec![(TypeId(0), "osd".to_string()),
+                       (TypeId(1), "host".to_string()),
+                       (TypeId(2), "chassis".to_string()),
+                       (TypeId(3), "rack".to_string()),
+                       (TypeId(4), "row".to_string()),
+                       (TypeId(5), "pdu".to_string()),
+                       (TypeId(6), "pod".to_string()),
+                       (TypeId(7), "room".to_string()),
+                       (TypeId(8), "datacenter".to_string()),
+                       (TypeId(9), "region".to_string()),
+                       (TypeId(10), "root".to_string())],
         name_map: vec![],
         rule_name_map: vec![],
         choose_local_tries: Some(0),
@@ -140,6 +177,12 @@ fn it_sets_tunables_for_firefly() {
         straw_calc_version: Some(0),
         allowed_bucket_algorithms: Some(22),
         chooseleaf_stable: Some(0),
+        msr_descents: None,
+        msr_collision_tries: None,
+        class_map: None,
+        class_name: None,
+        class_bucket: None,
+        choose_args: None,
     };
     set_tunables_firefly(&mut crushmap);
     assert_eq!(expected, crushmap);
@@ -155,17 +198,17 @@ fn it_sets_tunables_for_hammer() {
         max_devices: 3,
         buckets: vec![],
         rules: vec![],
-        type_map: vec![(0, "osd".to_string()),
-                       (1, "host".to_string()),
-                       (2, "chassis".to_string()),
-                       (3, "rack".to_string()),
-                       (4, "row".to_string()),
-                       (5, "pdu".to_string()),
-                       (6, "pod".to_string()),
-                       (7, "room".to_string()),
-                       (8, "datacenter".to_string()),
-                       (9, "region".to_string()),
-                       (10, "root".to_string())],
+        type_map: vec![(TypeId(0), "osd".to_string()),
+                       (TypeId(1), "host".to_string()),
+                       (TypeId(2), "chassis".to_string()),
+                       (TypeId(3), "rack".to_string()),
+                       (TypeId(4), "row".to_string()),
+                       (TypeId(5), "pdu".to_string()),
+                       (TypeId(6), "pod".to_string()),
+                       (TypeId(7), "room".to_string()),
+                       (TypeId(8), "datacenter".to_string()),
+                       (TypeId(9), "region".to_string()),
+                       (TypeId(10), "root".to_string())],
         name_map: vec![],
         rule_name_map: vec![],
         choose_local_tries: Some(0),
@@ -176,6 +219,12 @@ fn it_sets_tunables_for_hammer() {
         straw_calc_version: Some(0),
         allowed_bucket_algorithms: Some(54),
         chooseleaf_stable: Some(0),
+        msr_descents: None,
+        msr_collision_tries: None,
+        class_map: None,
+        class_name: None,
+        class_bucket: None,
+        choose_args: None,
     };
     set_tunables_hammer(&mut crushmap);
     assert_eq!(expected, crushmap);
@@ -191,17 +240,17 @@ fn it_sets_tunables_for_jewel() {
         max_devices: 3,
         buckets: vec![],
         rules: vec![],
-        type_map: vec![(0, "osd".to_string()),
-                       (1, "host".to_string()),
-                       (2, "chassis".to_string()),
-                       (3, "rack".to_string()),
-                       (4, "row".to_string()),
-                       (5, "pdu".to_string()),
-                       (6, "pod".to_string()),
-                       (7, "room".to_string()),
-                       (8, "datacenter".to_string()),
-                       (9, "region".to_string()),
-                       (10, "root".to_string())],
+        type_map: vec![(TypeId(0), "osd".to_string()),
+                       (TypeId(1), "host".to_string()),
+                       (TypeId(2), "chassis".to_string()),
+                       (TypeId(3), "rack".to_string()),
+                       (TypeId(4), "row".to_string()),
+                       (TypeId(5), "pdu".to_string()),
+                       (TypeId(6), "pod".to_string()),
+                       (TypeId(7), "room".to_string()),
+                       (TypeId(8), "datacenter".to_string()),
+                       (TypeId(9), "region".to_string()),
+                       (TypeId(10), "root".to_string())],
         name_map: vec![],
         rule_name_map: vec![],
         choose_local_tries: Some(0),
@@ -212,6 +261,12 @@ fn it_sets_tunables_for_jewel() {
         straw_calc_version: Some(0),
         allowed_bucket_algorithms: Some(54),
         chooseleaf_stable: Some(1),
+        msr_descents: None,
+        msr_collision_tries: None,
+        class_map: None,
+        class_name: None,
+        class_bucket: None,
+        choose_args: None,
     };
     set_tunables_jewel(&mut crushmap);
     assert_eq!(expected, crushmap);
@@ -220,7 +275,7 @@ fn it_sets_tunables_for_jewel() {
 #[test]
 fn it_gets_next_id_correctly() {
     let crushmap = CrushMap::default();
-    assert_eq!(crushmap.next_bucket_id(), -1);
+    assert_eq!(crushmap.next_bucket_id(), BucketId(-1));
 }
 
 #[test]
@@ -275,15 +330,15 @@ fn test_decode_crushmap() {
         max_devices: 3,
         buckets: vec![BucketTypes::Straw(CrushBucketStraw {
                           bucket: Bucket {
-                              id: -1,
-                              bucket_type: OpCode::SetChooseLocalTries,
+                              id: BucketId(-1),
+                              bucket_type: (TypeId(10), Some("root".to_string())),
                               alg: BucketAlg::Straw,
                               hash: CrushHash::RJenkins1,
                               weight: 0,
                               size: 3,
-                              items: vec![(-2, Some("ip-172-31-43-147".to_string())),
-                                          (-3, Some("ip-172-31-22-2".to_string())),
-                                          (-4, Some("ip-172-31-4-56".to_string()))],
+                              items: vec![(Item::from(-2), Some("ip-172-31-43-147".to_string())),
+                                          (Item::from(-3), Some("ip-172-31-22-2".to_string())),
+                                          (Item::from(-4), Some("ip-172-31-4-56".to_string()))],
                               perm_n: 0,
                               perm: 3,
                           },
@@ -291,13 +346,13 @@ fn test_decode_crushmap() {
                       }),
                       BucketTypes::Straw(CrushBucketStraw {
                           bucket: Bucket {
-                              id: -2,
-                              bucket_type: OpCode::Take,
+                              id: BucketId(-2),
+                              bucket_type: (TypeId(1), Some("host".to_string())),
                               alg: BucketAlg::Straw,
                               hash: CrushHash::RJenkins1,
                               weight: 0,
                               size: 1,
-                              items: vec![(0, Some("osd.0".to_string()))],
+                              items: vec![(Item::from(0), Some("osd.0".to_string()))],
                               perm_n: 0,
                               perm: 1,
                           },
@@ -305,13 +360,13 @@ fn test_decode_crushmap() {
                       }),
                       BucketTypes::Straw(CrushBucketStraw {
                           bucket: Bucket {
-                              id: -3,
-                              bucket_type: OpCode::Take,
+                              id: BucketId(-3),
+                              bucket_type: (TypeId(1), Some("host".to_string())),
                               alg: BucketAlg::Straw,
                               hash: CrushHash::RJenkins1,
                               weight: 0,
                               size: 1,
-                              items: vec![(1, Some("osd.1".to_string()))],
+                              items: vec![(Item::from(1), Some("osd.1".to_string()))],
                               perm_n: 0,
                               perm: 1,
                           },
@@ -319,13 +374,13 @@ fn test_decode_crushmap() {
                       }),
                       BucketTypes::Straw(CrushBucketStraw {
                           bucket: Bucket {
-                              id: -4,
-                              bucket_type: OpCode::Take,
+                              id: BucketId(-4),
+                              bucket_type: (TypeId(1), Some("host".to_string())),
                               alg: BucketAlg::Straw,
                               hash: CrushHash::RJenkins1,
                               weight: 0,
                               size: 1,
-                              items: vec![(2, Some("osd.2".to_string()))],
+                              items: vec![(Item::from(2), Some("osd.2".to_string()))],
                               perm_n: 0,
                               perm: 1,
                           },
@@ -337,46 +392,40 @@ fn test_decode_crushmap() {
                       BucketTypes::Unknown],
         rules: vec![Some(Rule {
                         mask: CrushRuleMask {
-                            ruleset: 0,
+                            ruleset: Ruleset(0),
                             rule_type: RuleType::Replicated,
                             min_size: 1,
                             max_size: 10,
                         },
-                        steps: vec![CrushRuleStep {
-                                        op: OpCode::Take,
-                                        arg1: (-1, None),
-                                        arg2: (0, Some("osd".to_string())),
+                        steps: vec![Step::Take {
+                                        item: (Item::from(-1), Some("default".to_string())),
                                     },
-                                    CrushRuleStep {
-                                        op: OpCode::ChooseLeafFirstN,
-                                        arg1: (0, Some("osd".to_string())),
-                                        arg2: (1, Some("host".to_string())),
+                                    Step::Choose {
+                                        mode: ChooseMode::LeafFirstN,
+                                        num: 0,
+                                        bucket_type: (TypeId(1), Some("host".to_string())),
                                     },
-                                    CrushRuleStep {
-                                        op: OpCode::Emit,
-                                        arg1: (0, Some("osd".to_string())),
-                                        arg2: (0, Some("osd".to_string())),
-                                    }],
+                                    Step::Emit],
                     })],
-        type_map: vec![(0, "osd".to_string()),
-                       (1, "host".to_string()),
-                       (2, "chassis".to_string()),
-                       (3, "rack".to_string()),
-                       (4, "row".to_string()),
-                       (5, "pdu".to_string()),
-                       (6, "pod".to_string()),
-                       (7, "room".to_string()),
-                       (8, "datacenter".to_string()),
-                       (9, "region".to_string()),
-                       (10, "root".to_string())],
-        name_map: vec![(-4, "ip-172-31-4-56".to_string()),
-                       (-3, "ip-172-31-22-2".to_string()),
-                       (-2, "ip-172-31-43-147".to_string()),
-                       (-1, "default".to_string()),
-                       (0, "osd.0".to_string()),
-                       (1, "osd.1".to_string()),
-                       (2, "osd.2".to_string())],
-        rule_name_map: vec![(0, "replicated_ruleset".to_string())],
+        type_map: vec![(TypeId(0), "osd".to_string()),
+                       (TypeId(1), "host".to_string()),
+                       (TypeId(2), "chassis".to_string()),
+                       (TypeId(3), "rack".to_string()),
+                       (TypeId(4), "row".to_string()),
+                       (TypeId(5), "pdu".to_string()),
+                       (TypeId(6), "pod".to_string()),
+                       (TypeId(7), "room".to_string()),
+                       (TypeId(8), "datacenter".to_string()),
+                       (TypeId(9), "region".to_string()),
+                       (TypeId(10), "root".to_string())],
+        name_map: vec![(Item::from(-4), "ip-172-31-4-56".to_string()),
+                       (Item::from(-3), "ip-172-31-22-2".to_string()),
+                       (Item::from(-2), "ip-172-31-43-147".to_string()),
+                       (Item::from(-1), "default".to_string()),
+                       (Item::from(0), "osd.0".to_string()),
+                       (Item::from(1), "osd.1".to_string()),
+                       (Item::from(2), "osd.2".to_string())],
+        rule_name_map: vec![(RuleId(0), "replicated_ruleset".to_string())],
         choose_local_tries: Some(0),
         choose_local_fallback_tries: Some(0),
         choose_total_tries: Some(50),
@@ -385,6 +434,12 @@ fn test_decode_crushmap() {
         straw_calc_version: Some(0),
         allowed_bucket_algorithms: Some(0),
         chooseleaf_stable: Some(0),
+        msr_descents: None,
+        msr_collision_tries: None,
+        class_map: None,
+        class_name: None,
+        class_bucket: None,
+        choose_args: None,
     };
     let result = decode_crushmap(&crushmap_compiled);
     println!("crushmap {:?}", result);
@@ -441,13 +496,15 @@ fn test_encode_crushmap() {
         max_devices: 3,
         buckets: vec![BucketTypes::Straw(CrushBucketStraw {
                           bucket: Bucket {
-                              id: -1,
-                              bucket_type: OpCode::SetChooseLocalTries,
+                              id: BucketId(-1),
+                              bucket_type: (TypeId(10), Some("root".to_string())),
                               alg: BucketAlg::Straw,
                               hash: CrushHash::RJenkins1,
                               weight: 0,
                               size: 3,
-                              items: vec![(-2, None), (-3, None), (-4, None)],
+                              items: vec![(Item::from(-2), None),
+                                          (Item::from(-3), None),
+                                          (Item::from(-4), None)],
                               perm_n: 0,
                               perm: 3,
                           },
@@ -455,13 +512,13 @@ fn test_encode_crushmap() {
                       }),
                       BucketTypes::Straw(CrushBucketStraw {
                           bucket: Bucket {
-                              id: -2,
-                              bucket_type: OpCode::Take,
+                              id: BucketId(-2),
+                              bucket_type: (TypeId(1), Some("host".to_string())),
                               alg: BucketAlg::Straw,
                               hash: CrushHash::RJenkins1,
                               weight: 0,
                               size: 1,
-                              items: vec![(0, None)],
+                              items: vec![(Item::from(0), None)],
                               perm_n: 0,
                               perm: 1,
                           },
@@ -469,13 +526,13 @@ fn test_encode_crushmap() {
                       }),
                       BucketTypes::Straw(CrushBucketStraw {
                           bucket: Bucket {
-                              id: -3,
-                              bucket_type: OpCode::Take,
+                              id: BucketId(-3),
+                              bucket_type: (TypeId(1), Some("host".to_string())),
                               alg: BucketAlg::Straw,
                               hash: CrushHash::RJenkins1,
                               weight: 0,
                               size: 1,
-                              items: vec![(1, None)],
+                              items: vec![(Item::from(1), None)],
                               perm_n: 0,
                               perm: 1,
                           },
@@ -483,13 +540,13 @@ fn test_encode_crushmap() {
                       }),
                       BucketTypes::Straw(CrushBucketStraw {
                           bucket: Bucket {
-                              id: -4,
-                              bucket_type: OpCode::Take,
+                              id: BucketId(-4),
+                              bucket_type: (TypeId(1), Some("host".to_string())),
                               alg: BucketAlg::Straw,
                               hash: CrushHash::RJenkins1,
                               weight: 0,
                               size: 1,
-                              items: vec![(2, None)],
+                              items: vec![(Item::from(2), None)],
                               perm_n: 0,
                               perm: 1,
                           },
@@ -501,46 +558,38 @@ fn test_encode_crushmap() {
                       BucketTypes::Unknown],
         rules: vec![Some(Rule {
                         mask: CrushRuleMask {
-                            ruleset: 0,
+                            ruleset: Ruleset(0),
                             rule_type: RuleType::Replicated,
                             min_size: 1,
                             max_size: 10,
                         },
-                        steps: vec![CrushRuleStep {
-                                        op: OpCode::Take,
-                                        arg1: (-1, None),
-                                        arg2: (0, None),
+                        steps: vec![Step::Take { item: (Item::from(-1), None) },
+                                    Step::Choose {
+                                        mode: ChooseMode::LeafFirstN,
+                                        num: 0,
+                                        bucket_type: (TypeId(1), None),
                                     },
-                                    CrushRuleStep {
-                                        op: OpCode::ChooseLeafFirstN,
-                                        arg1: (0, None),
-                                        arg2: (1, None),
-                                    },
-                                    CrushRuleStep {
-                                        op: OpCode::Emit,
-                                        arg1: (0, None),
-                                        arg2: (0, None),
-                                    }],
+                                    Step::Emit],
                     })],
-        type_map: vec![(0, "osd".to_string()),
-                       (1, "host".to_string()),
-                       (2, "chassis".to_string()),
-                       (3, "rack".to_string()),
-                       (4, "row".to_string()),
-                       (5, "pdu".to_string()),
-                       (6, "pod".to_string()),
-                       (7, "room".to_string()),
-                       (8, "datacenter".to_string()),
-                       (9, "region".to_string()),
-                       (10, "root".to_string())],
-        name_map: vec![(-4, "ip-172-31-4-56".to_string()),
-                       (-3, "ip-172-31-22-2".to_string()),
-                       (-2, "ip-172-31-43-147".to_string()),
-                       (-1, "default".to_string()),
-                       (0, "osd.0".to_string()),
-                       (1, "osd.1".to_string()),
-                       (2, "osd.2".to_string())],
-        rule_name_map: vec![(0, "replicated_ruleset".to_string())],
+        type_map: vec![(TypeId(0), "osd".to_string()),
+                       (TypeId(1), "host".to_string()),
+                       (TypeId(2), "chassis".to_string()),
+                       (TypeId(3), "rack".to_string()),
+                       (TypeId(4), "row".to_string()),
+                       (TypeId(5), "pdu".to_string()),
+                       (TypeId(6), "pod".to_string()),
+                       (TypeId(7), "room".to_string()),
+                       (TypeId(8), "datacenter".to_string()),
+                       (TypeId(9), "region".to_string()),
+                       (TypeId(10), "root".to_string())],
+        name_map: vec![(Item::from(-4), "ip-172-31-4-56".to_string()),
+                       (Item::from(-3), "ip-172-31-22-2".to_string()),
+                       (Item::from(-2), "ip-172-31-43-147".to_string()),
+                       (Item::from(-1), "default".to_string()),
+                       (Item::from(0), "osd.0".to_string()),
+                       (Item::from(1), "osd.1".to_string()),
+                       (Item::from(2), "osd.2".to_string())],
+        rule_name_map: vec![(RuleId(0), "replicated_ruleset".to_string())],
         choose_local_tries: Some(0),
         choose_local_fallback_tries: Some(0),
         choose_total_tries: Some(50),
@@ -549,6 +598,12 @@ fn test_encode_crushmap() {
         straw_calc_version: Some(1),
         allowed_bucket_algorithms: Some(0),
         chooseleaf_stable: Some(0),
+        msr_descents: None,
+        msr_collision_tries: None,
+        class_map: None,
+        class_name: None,
+        class_bucket: None,
+        choose_args: None,
     };
     let result = encode_crushmap(crushmap);
     assert_eq!(expected_result, result.unwrap());
@@ -607,15 +662,15 @@ fn test_decode_straw2_crushmap() {
         max_devices: 3,
         buckets: vec![BucketTypes::Straw(CrushBucketStraw {
                           bucket: Bucket {
-                              id: -1,
-                              bucket_type: OpCode::SetChooseLocalTries,
+                              id: BucketId(-1),
+                              bucket_type: (TypeId(10), Some("root".to_string())),
                               alg: BucketAlg::Straw,
                               hash: CrushHash::RJenkins1,
                               weight: 570,
                               size: 3,
-                              items: vec![(-2, Some("ip-172-31-9-110".to_string())),
-                                          (-3, Some("ip-172-31-34-140".to_string())),
-                                          (-4, Some("ip-172-31-19-205".to_string()))],
+                              items: vec![(Item::from(-2), Some("ip-172-31-9-110".to_string())),
+                                          (Item::from(-3), Some("ip-172-31-34-140".to_string())),
+                                          (Item::from(-4), Some("ip-172-31-19-205".to_string()))],
                               perm_n: 0,
                               perm: 3,
                           },
@@ -623,13 +678,13 @@ fn test_decode_straw2_crushmap() {
                       }),
                       BucketTypes::Straw(CrushBucketStraw {
                           bucket: Bucket {
-                              id: -2,
-                              bucket_type: OpCode::Take,
+                              id: BucketId(-2),
+                              bucket_type: (TypeId(1), Some("host".to_string())),
                               alg: BucketAlg::Straw,
                               hash: CrushHash::RJenkins1,
                               weight: 190,
                               size: 1,
-                              items: vec![(0, Some("osd.0".to_string()))],
+                              items: vec![(Item::from(0), Some("osd.0".to_string()))],
                               perm_n: 0,
                               perm: 1,
                           },
@@ -637,13 +692,13 @@ fn test_decode_straw2_crushmap() {
                       }),
                       BucketTypes::Straw(CrushBucketStraw {
                           bucket: Bucket {
-                              id: -3,
-                              bucket_type: OpCode::Take,
+                              id: BucketId(-3),
+                              bucket_type: (TypeId(1), Some("host".to_string())),
                               alg: BucketAlg::Straw,
                               hash: CrushHash::RJenkins1,
                               weight: 190,
                               size: 1,
-                              items: vec![(1, Some("osd.1".to_string()))],
+                              items: vec![(Item::from(1), Some("osd.1".to_string()))],
                               perm_n: 0,
                               perm: 1,
                           },
@@ -651,13 +706,13 @@ fn test_decode_straw2_crushmap() {
                       }),
                       BucketTypes::Straw(CrushBucketStraw {
                           bucket: Bucket {
-                              id: -4,
-                              bucket_type: OpCode::Take,
+                              id: BucketId(-4),
+                              bucket_type: (TypeId(1), Some("host".to_string())),
                               alg: BucketAlg::Straw,
                               hash: CrushHash::RJenkins1,
                               weight: 190,
                               size: 1,
-                              items: vec![(2, Some("osd.2".to_string()))],
+                              items: vec![(Item::from(2), Some("osd.2".to_string()))],
                               perm_n: 0,
                               perm: 1,
                           },
@@ -665,8 +720,8 @@ fn test_decode_straw2_crushmap() {
                       }),
                       BucketTypes::Straw2(CrushBucketStraw2 {
                           bucket: Bucket {
-                              id: -5,
-                              bucket_type: OpCode::ChooseIndep,
+                              id: BucketId(-5),
+                              bucket_type: (TypeId(3), Some("rack".to_string())),
                               alg: BucketAlg::Straw2,
                               hash: CrushHash::RJenkins1,
                               weight: 0,
@@ -682,47 +737,41 @@ fn test_decode_straw2_crushmap() {
                       BucketTypes::Unknown],
         rules: vec![Some(Rule {
                         mask: CrushRuleMask {
-                            ruleset: 0,
+                            ruleset: Ruleset(0),
                             rule_type: RuleType::Replicated,
                             min_size: 1,
                             max_size: 10,
                         },
-                        steps: vec![CrushRuleStep {
-                                        op: OpCode::Take,
-                                        arg1: (-1, None),
-                                        arg2: (0, Some("osd".to_string())),
+                        steps: vec![Step::Take {
+                                        item: (Item::from(-1), Some("default".to_string())),
                                     },
-                                    CrushRuleStep {
-                                        op: OpCode::ChooseLeafFirstN,
-                                        arg1: (0, Some("osd".to_string())),
-                                        arg2: (1, Some("host".to_string())),
+                                    Step::Choose {
+                                        mode: ChooseMode::LeafFirstN,
+                                        num: 0,
+                                        bucket_type: (TypeId(1), Some("host".to_string())),
                                     },
-                                    CrushRuleStep {
-                                        op: OpCode::Emit,
-                                        arg1: (0, Some("osd".to_string())),
-                                        arg2: (0, Some("osd".to_string())),
-                                    }],
+                                    Step::Emit],
                     })],
-        type_map: vec![(0, "osd".to_string()),
-                       (1, "host".to_string()),
-                       (2, "chassis".to_string()),
-                       (3, "rack".to_string()),
-                       (4, "row".to_string()),
-                       (5, "pdu".to_string()),
-                       (6, "pod".to_string()),
-                       (7, "room".to_string()),
-                       (8, "datacenter".to_string()),
-                       (9, "region".to_string()),
-                       (10, "root".to_string())],
-        name_map: vec![(-5, "test".to_string()),
-                       (-4, "ip-172-31-19-205".to_string()),
-                       (-3, "ip-172-31-34-140".to_string()),
-                       (-2, "ip-172-31-9-110".to_string()),
-                       (-1, "default".to_string()),
-                       (0, "osd.0".to_string()),
-                       (1, "osd.1".to_string()),
-                       (2, "osd.2".to_string())],
-        rule_name_map: vec![(0, "replicated_ruleset".to_string())],
+        type_map: vec![(TypeId(0), "osd".to_string()),
+                       (TypeId(1), "host".to_string()),
+                       (TypeId(2), "chassis".to_string()),
+                       (TypeId(3), "rack".to_string()),
+                       (TypeId(4), "row".to_string()),
+                       (TypeId(5), "pdu".to_string()),
+                       (TypeId(6), "pod".to_string()),
+                       (TypeId(7), "room".to_string()),
+                       (TypeId(8), "datacenter".to_string()),
+                       (TypeId(9), "region".to_string()),
+                       (TypeId(10), "root".to_string())],
+        name_map: vec![(Item::from(-5), "test".to_string()),
+                       (Item::from(-4), "ip-172-31-19-205".to_string()),
+                       (Item::from(-3), "ip-172-31-34-140".to_string()),
+                       (Item::from(-2), "ip-172-31-9-110".to_string()),
+                       (Item::from(-1), "default".to_string()),
+                       (Item::from(0), "osd.0".to_string()),
+                       (Item::from(1), "osd.1".to_string()),
+                       (Item::from(2), "osd.2".to_string())],
+        rule_name_map: vec![(RuleId(0), "replicated_ruleset".to_string())],
         choose_local_tries: Some(0),
         choose_local_fallback_tries: Some(0),
         choose_total_tries: Some(50),
@@ -731,8 +780,1304 @@ fn test_decode_straw2_crushmap() {
         straw_calc_version: Some(1),
         allowed_bucket_algorithms: Some(54),
         chooseleaf_stable: Some(1),
+        msr_descents: None,
+        msr_collision_tries: None,
+        class_map: None,
+        class_name: None,
+        class_bucket: None,
+        choose_args: None,
     };
     let result = decode_crushmap(&crushmap_compiled);
     println!("straw2 crushmap {:?}", result);
     assert_eq!(Ok(expected_result), result);
 }
+
+fn straw2_bucket(id: i32, bucket_type: i32, items: Vec<(i32, u32)>) -> BucketTypes {
+    BucketTypes::Straw2(CrushBucketStraw2 {
+        bucket: Bucket {
+            id: BucketId(id),
+            bucket_type: (TypeId(bucket_type), None),
+            alg: BucketAlg::Straw2,
+            hash: CrushHash::RJenkins1,
+            weight: items.iter().map(|i| i.1).sum(),
+            size: items.len() as u32,
+            items: items.iter().map(|i| (Item::from(i.0), None)).collect(),
+            perm_n: 0,
+            perm: items.len() as u32,
+        },
+        item_weights: items.iter().map(|i| i.1).collect(),
+    })
+}
+
+// A root with 3 hosts of 2 osds each and a replicated rule that separates
+// replicas across hosts.
+fn get_test_hierarchy() -> CrushMap {
+    let mut crushmap = CrushMap {
+        max_buckets: 4,
+        max_rules: 1,
+        max_devices: 6,
+        buckets: vec![straw2_bucket(-1, 10, vec![(-2, 0x20000), (-3, 0x20000), (-4, 0x20000)]),
+                      straw2_bucket(-2, 1, vec![(0, 0x10000), (1, 0x10000)]),
+                      straw2_bucket(-3, 1, vec![(2, 0x10000), (3, 0x10000)]),
+                      straw2_bucket(-4, 1, vec![(4, 0x10000), (5, 0x10000)])],
+        rules: vec![Some(Rule {
+                        mask: CrushRuleMask {
+                            ruleset: Ruleset(0),
+                            rule_type: RuleType::Replicated,
+                            min_size: 1,
+                            max_size: 10,
+                        },
+                        steps: vec![Step::Take { item: (Item::from(-1), None) },
+                                    Step::Choose {
+                                        mode: ChooseMode::LeafFirstN,
+                                        num: 0,
+                                        bucket_type: (TypeId(1), None),
+                                    },
+                                    Step::Emit],
+                    })],
+        name_map: vec![(Item::from(-4), "host3".to_string()),
+                       (Item::from(-3), "host2".to_string()),
+                       (Item::from(-2), "host1".to_string()),
+                       (Item::from(-1), "default".to_string()),
+                       (Item::from(0), "osd.0".to_string()),
+                       (Item::from(1), "osd.1".to_string()),
+                       (Item::from(2), "osd.2".to_string()),
+                       (Item::from(3), "osd.3".to_string()),
+                       (Item::from(4), "osd.4".to_string()),
+                       (Item::from(5), "osd.5".to_string())],
+        rule_name_map: vec![(RuleId(0), "replicated_ruleset".to_string())],
+        ..Default::default()
+    };
+    set_tunables_jewel(&mut crushmap);
+    crushmap
+}
+
+#[test]
+fn it_places_replicas_on_distinct_hosts() {
+    let crushmap = get_test_hierarchy();
+    let weights = default_weights(&crushmap);
+    assert_eq!(Some(RuleId(0)), find_rule(&crushmap, Ruleset(0), RuleType::Replicated, 3));
+    for x in 0..1000 {
+        let result = do_rule(&crushmap, RuleId(0), x, 3, &weights);
+        assert_eq!(3, result.len());
+        let mut hosts: Vec<i32> = result.iter().map(|osd| osd / 2).collect();
+        hosts.sort();
+        hosts.dedup();
+        assert_eq!(3, hosts.len());
+        assert_eq!(result, do_rule(&crushmap, RuleId(0), x, 3, &weights));
+    }
+}
+
+#[test]
+fn it_chooses_from_tree_buckets_with_nothing_in() {
+    let mut crushmap = get_test_hierarchy();
+    crushmap.type_map = vec![(TypeId(0), "osd".to_string()), (TypeId(1), "host".to_string()),
+                             (TypeId(10), "root".to_string())];
+    // Three devices that weigh nothing in a tree bucket, which keeps going
+    // right past the last of them
+    let mut doc = MapDocument::new(&crushmap);
+    doc.max_devices = 7;
+    doc.devices.push(DocumentDevice {
+        id: 6,
+        name: Some("osd.6".to_string()),
+        class: None,
+    });
+    for bucket in doc.buckets.iter_mut().filter(|b| b.name == Some("host1".to_string())) {
+        bucket.alg = "tree".to_string();
+        bucket.items.push(bucket.items[0].clone());
+        bucket.items[2].name = "osd.6".to_string();
+        for item in bucket.items.iter_mut() {
+            item.weight = Some(0.0);
+        }
+    }
+    let mut crushmap = doc.to_crushmap().unwrap();
+    let ruleno = RuleBuilder::replicated("host1")
+        .take("host1")
+        .choose_firstn(0, "osd")
+        .emit()
+        .add_to(&mut crushmap)
+        .unwrap();
+    let weights = default_weights(&crushmap);
+    for x in 0..100 {
+        for osd in do_rule(&crushmap, ruleno, x, 3, &weights) {
+            assert!(osd == 0 || osd == 1 || osd == 6);
+        }
+    }
+}
+
+#[test]
+fn it_reports_utilization() {
+    let crushmap = get_test_hierarchy();
+    let weights = default_weights(&crushmap);
+    let mappings = simulate(&crushmap, RuleId(0), 3, 0, 1023, &weights).unwrap();
+    let report = UtilizationReport::new(&crushmap, RuleId(0), 3, &mappings, &weights);
+
+    assert_eq!(1024, report.inputs);
+    assert_eq!(0, report.short_mappings);
+    assert_eq!(3072, report.total_placements);
+    assert_eq!(6, report.devices.len());
+    assert_eq!(3072, report.devices.iter().map(|d| d.placements).sum::<u64>());
+    assert!(report.devices.iter().all(|d| d.expected == 512.0));
+    assert_eq!(Some("host".to_string()), report.failure_domain);
+    // Every input lands on every host exactly once
+    assert_eq!(3, report.domains.len());
+    assert!(report.domains.iter().all(|d| d.placements == 1024));
+}
+
+#[test]
+fn it_reports_deviations_when_every_device_is_under() {
+    let crushmap = get_test_hierarchy();
+    let mut weights = default_weights(&crushmap);
+    // osd.5 is out but still got a placement, so the rest all fall short
+    weights[5] = 0;
+    let mappings = vec![vec![5], vec![0], vec![1], vec![2], vec![3], vec![4]];
+    let report = UtilizationReport::new(&crushmap, RuleId(0), 1, &mappings, &weights);
+    assert!((report.max_deviation + 0.2).abs() < 1e-9);
+    assert!((report.min_deviation + 0.2).abs() < 1e-9);
+}
+
+#[test]
+fn it_simulates_up_to_the_largest_input() {
+    let crushmap = get_test_hierarchy();
+    let weights = default_weights(&crushmap);
+    let max = i32::max_value();
+    assert_eq!(2, simulate(&crushmap, RuleId(0), 3, max - 1, max, &weights).unwrap().len());
+    let (mappings, _) =
+        simulate_counting_tries(&crushmap, RuleId(0), 3, max - 1, max, &weights).unwrap();
+    assert_eq!(2, mappings.len());
+}
+
+#[test]
+fn it_refuses_to_simulate_missing_rules() {
+    let mut crushmap = get_test_hierarchy();
+    let weights = default_weights(&crushmap);
+    // An empty slot and one past the end
+    crushmap.rules.push(None);
+    for ruleno in vec![RuleId(1), RuleId(5)] {
+        let missing = Err(format!("No rule {}", ruleno));
+        assert_eq!(missing, simulate(&crushmap, ruleno, 3, 0, 15, &weights));
+        assert!(simulate_counting_tries(&crushmap, ruleno, 3, 0, 15, &weights).is_err());
+        assert!(simulate_failure(&crushmap, ruleno, 3, 0, 15, &weights, &[]).is_err());
+        assert!(explain(&crushmap, ruleno, 1, 3, &weights, &[]).is_err());
+    }
+}
+
+#[test]
+fn it_skips_buckets_it_cannot_index() {
+    let mut crushmap = get_test_hierarchy();
+    let weights = default_weights(&crushmap);
+    // A choose_args shorter than its bucket leaves the missing items out
+    let choose_args = vec![ChooseArg {
+                               bucket_id: BucketId(-1),
+                               weight_set: vec![vec![0x10000]],
+                               ids: vec![-2],
+                           }];
+    for x in 0..100 {
+        let result = do_rule_with_choose_args(&crushmap, RuleId(0), x, 3, &weights, &choose_args);
+        assert!(result.iter().all(|d| *d == 0 || *d == 1));
+    }
+
+    // host2 sits in host3's slot, so neither can be chosen
+    crushmap.buckets.swap(2, 3);
+    for x in 0..100 {
+        let result = do_rule(&crushmap, RuleId(0), x, 3, &weights);
+        assert!(result.iter().all(|d| *d == 0 || *d == 1));
+    }
+}
+
+#[test]
+fn it_balances_with_upmap_items() {
+    let crushmap = get_test_hierarchy();
+    let weights = default_weights(&crushmap);
+    let pool = Pool {
+        id: 1,
+        pg_num: 128,
+        size: 3,
+        rule: RuleId(0),
+    };
+    let items = calc_pg_upmaps(&crushmap, &[pool.clone()], 1.0, 100, &weights).unwrap();
+
+    let mut counts = vec![0; 6];
+    for ps in 0..pool.pg_num {
+        let mut osds = pool.raw_pg_to_osds(&crushmap, ps, &weights);
+        if let Some(item) = items.iter().find(|i| i.ps == ps) {
+            assert_eq!(format!("ceph osd pg-upmap-items 1.{:x}", ps),
+                       item.to_string().split(' ').take(4).collect::<Vec<&str>>().join(" "));
+            for &(from, to) in item.mappings.iter() {
+                let pos = osds.iter().position(|o| *o == from).unwrap();
+                osds[pos] = to;
+            }
+        }
+        // Still one replica per host
+        let mut hosts: Vec<i32> = osds.iter().map(|osd| osd / 2).collect();
+        hosts.sort();
+        hosts.dedup();
+        assert_eq!(3, hosts.len());
+        for osd in osds {
+            counts[osd as usize] += 1;
+        }
+    }
+    // 128 * 3 replicas over 6 equally weighted osds
+    assert!(counts.iter().all(|c| (*c as f64 - 64.0).abs() <= 1.0), "{:?}", counts);
+}
+
+#[test]
+fn it_round_trips_choose_args() {
+    let mut crushmap = get_test_hierarchy();
+    crushmap.class_map = Some(vec![(DeviceId(0), 0), (DeviceId(1), 0), (DeviceId(2), 1)]);
+    crushmap.class_name = Some(vec![(0, "hdd".to_string()), (1, "ssd".to_string())]);
+    crushmap.class_bucket = Some(vec![(BucketId(-1), vec![(0, BucketId(-5)), (1, BucketId(-6))])]);
+    crushmap.choose_args = Some(vec![(-1,
+                                      vec![ChooseArg {
+                                               bucket_id: BucketId(-2),
+                                               weight_set: vec![vec![0x10000, 0x8000]],
+                                               ids: vec![],
+                                           },
+                                           ChooseArg {
+                                               bucket_id: BucketId(-1),
+                                               weight_set: vec![vec![0x18000, 0x20000,
+                                                                     0x20000]],
+                                               ids: vec![-2, -3, -4],
+                                           }])]);
+    let encoded = encode_crushmap(crushmap.clone()).unwrap();
+    let mut decoded = decode_crushmap(&encoded).unwrap();
+    // The decoder resolves names the test map leaves out
+    decoded.buckets = crushmap.buckets.clone();
+    decoded.rules = crushmap.rules.clone();
+    assert_eq!(crushmap, decoded);
+}
+
+#[test]
+fn it_places_with_choose_args() {
+    let crushmap = get_test_hierarchy();
+    let weights = default_weights(&crushmap);
+    // Give osd.0 no weight for placement without touching its CRUSH weight
+    let choose_args = vec![ChooseArg {
+                               bucket_id: BucketId(-2),
+                               weight_set: vec![vec![0, 0x10000]],
+                               ids: vec![],
+                           }];
+    for x in 0..100 {
+        let result = do_rule_with_choose_args(&crushmap, RuleId(0), x, 3, &weights, &choose_args);
+        assert_eq!(3, result.len());
+        assert!(!result.contains(&0));
+    }
+
+    // The weight-sets in the map are used as well, a pool's own before
+    // the default one
+    let mut with_args = crushmap.clone();
+    let osd1_out = vec![ChooseArg {
+                            bucket_id: BucketId(-2),
+                            weight_set: vec![vec![0x10000, 0]],
+                            ids: vec![],
+                        }];
+    with_args.choose_args = Some(vec![(DEFAULT_CHOOSE_ARGS, choose_args), (2, osd1_out)]);
+    let pool = |id| {
+        Pool {
+            id: id,
+            pg_num: 64,
+            size: 3,
+            rule: RuleId(0),
+        }
+    };
+    for x in 0..100 {
+        assert!(!do_rule(&with_args, RuleId(0), x, 3, &weights).contains(&0));
+        assert!(!do_rule_for_pool(&with_args, 1, RuleId(0), x, 3, &weights).contains(&0));
+        assert!(!do_rule_for_pool(&with_args, 2, RuleId(0), x, 3, &weights).contains(&1));
+    }
+    for ps in 0..64 {
+        assert!(!pool(1).raw_pg_to_osds(&with_args, ps, &weights).contains(&0));
+        assert!(!pool(2).raw_pg_to_osds(&with_args, ps, &weights).contains(&1));
+    }
+}
+
+#[test]
+fn it_optimizes_choose_args() {
+    let mut crushmap = get_test_hierarchy();
+    let weights = default_weights(&crushmap);
+    let pool = Pool {
+        id: 1,
+        pg_num: 128,
+        size: 3,
+        rule: RuleId(0),
+    };
+    let result = optimize_choose_args(&mut crushmap, &pool, -1, 20, &weights).unwrap();
+    assert!(result.final_stddev <= result.initial_stddev);
+    assert!(result.iterations <= 20);
+
+    let choose_args = crushmap.choose_args.clone().unwrap();
+    assert_eq!(1, choose_args.len());
+    assert_eq!(-1, choose_args[0].0);
+    // Every straw2 bucket gets a weight-set
+    assert_eq!(4, choose_args[0].1.len());
+
+    // The weight-set survives encoding
+    let decoded = decode_crushmap(&encode_crushmap(crushmap.clone()).unwrap()).unwrap();
+    assert_eq!(crushmap.choose_args, decoded.choose_args);
+
+    // A weight-set that no longer matches its bucket starts over from the
+    // bucket's weights
+    crushmap.choose_args.as_mut().unwrap()[0].1[1].weight_set = vec![vec![0x10000]];
+    optimize_choose_args(&mut crushmap, &pool, -1, 5, &weights).unwrap();
+    for arg in crushmap.choose_args.unwrap()[0].1.iter() {
+        let bucket = crushmap.buckets[arg.bucket_id.index()].bucket().unwrap();
+        assert!(arg.weight_set.iter().all(|w| w.len() == bucket.items.len()));
+    }
+}
+
+#[test]
+fn it_simulates_a_host_failure() {
+    let crushmap = get_test_hierarchy();
+    let weights = default_weights(&crushmap);
+    let failed = vec![resolve_item(&crushmap, "host1").unwrap()];
+    assert_eq!(vec![Item::from(-2)], failed);
+    let report = simulate_failure(&crushmap, RuleId(0), 3, 0, 255, &weights, &failed).unwrap();
+
+    assert_eq!(vec![0, 1], report.out);
+    // Every input had a replica on host1 and only two hosts are left
+    assert_eq!(256, report.degraded.len());
+    assert_eq!(256, report.undersized.len());
+    assert_eq!(768, report.total_replicas);
+    for change in report.changes.iter() {
+        assert_eq!(2, change.after.len());
+        assert!(!change.after.contains(&0) && !change.after.contains(&1));
+    }
+    // Nothing moves onto the remaining hosts, it just goes missing
+    assert_eq!(0, report.remapped_replicas);
+    assert!(report.movements.iter().all(|m| m.id < 2 && m.gained == 0));
+}
+
+#[test]
+fn it_simulates_a_device_failure() {
+    let crushmap = get_test_hierarchy();
+    let weights = default_weights(&crushmap);
+    let failed = vec![resolve_item(&crushmap, "osd.0").unwrap()];
+    let report = simulate_failure(&crushmap, RuleId(0), 3, 0, 255, &weights, &failed).unwrap();
+
+    assert_eq!(vec![0], report.out);
+    assert!(report.undersized.is_empty());
+    // osd.0's replicas all move to osd.1, the only other device on its host
+    let lost = report.movements.iter().find(|m| m.id == 0).unwrap().lost;
+    assert!(lost > 0);
+    assert_eq!(lost, report.remapped_replicas);
+    assert_eq!(lost, report.movements.iter().find(|m| m.id == 1).unwrap().gained);
+}
+
+#[test]
+fn it_checks_rule_satisfiability() {
+    let mut crushmap = get_test_hierarchy();
+    let check = check_rule(&crushmap, RuleId(0)).unwrap();
+    assert_eq!(10, check.sizes.len());
+    // Three hosts can hold up to three replicas
+    for size in 1..4 {
+        assert!(check.is_satisfiable(size));
+    }
+    assert!(!check.is_satisfiable(4));
+    assert!(!check.never_satisfiable());
+    let shortfall = &check.sizes[3].shortfalls[0];
+    assert_eq!((-1, 1, -1, 1),
+               (shortfall.take, shortfall.step, shortfall.item, shortfall.domain_type));
+    assert_eq!((4, 3), (shortfall.wanted, shortfall.available));
+
+    // Separating replicas by root can never work beneath a single root
+    if let Some(Some(ref mut rule)) = crushmap.rules.get_mut(0) {
+        rule.steps[1] = Step::Choose {
+            mode: ChooseMode::LeafFirstN,
+            num: 0,
+            bucket_type: (TypeId(10), None),
+        };
+    }
+    assert!(check_rule(&crushmap, RuleId(0)).unwrap().never_satisfiable());
+
+    // A mask whose min_size is above its max_size accepts nothing at all
+    if let Some(Some(ref mut rule)) = crushmap.rules.get_mut(0) {
+        rule.mask.min_size = 5;
+        rule.mask.max_size = 4;
+    }
+    let check = check_rule(&crushmap, RuleId(0)).unwrap();
+    assert!(check.accepts_no_size());
+    assert!(!check.never_satisfiable());
+    assert!(validate(&crushmap).iter().any(|p| p.contains("accepts no size")));
+}
+
+#[test]
+fn it_builds_rules() {
+    let mut crushmap = get_test_hierarchy();
+    let ruleno = RuleBuilder::erasure("ec")
+        .take("default")
+        .chooseleaf_indep(0, "host")
+        .emit()
+        .add_to(&mut crushmap)
+        .unwrap();
+    assert_eq!(RuleId(1), ruleno);
+    assert_eq!(2, crushmap.max_rules);
+    assert!(crushmap.rule_name_map.contains(&(RuleId(1), "ec".to_string())));
+
+    let rule = crushmap.rules[1].clone().unwrap();
+    assert_eq!(Ruleset(1), rule.mask.ruleset);
+    assert_eq!(RuleType::Erasure, rule.mask.rule_type);
+    let ops: Vec<OpCode> = rule.steps.iter().map(|s| s.op()).collect();
+    assert_eq!(vec![OpCode::SetChooseLeafTries,
+                    OpCode::SetChooseTries,
+                    OpCode::Take,
+                    OpCode::ChooseLeafIndep,
+                    OpCode::Emit],
+               ops);
+    assert_eq!(vec![Step::SetChooseLeafTries(5), Step::SetChooseTries(100)],
+               rule.steps[..2].to_vec());
+    assert_eq!(Some(Item::from(-1)), rule.steps[2].item());
+    assert_eq!((0, 1), rule.steps[3].args());
+
+    // The replicated rule in the hierarchy is the same as one built by name
+    let built = RuleBuilder::replicated("replicated")
+        .take("default")
+        .chooseleaf_firstn(0, "host")
+        .emit()
+        .build(&crushmap)
+        .unwrap();
+    let existing = crushmap.rules[0].clone().unwrap();
+    assert_eq!(existing.mask, built.mask);
+    let step_args = |rule: &Rule| -> Vec<(OpCode, (i32, i32))> {
+        rule.steps.iter().map(|s| (s.op(), s.args())).collect()
+    };
+    assert_eq!(step_args(&existing), step_args(&built));
+
+    assert_eq!(Err(RuleBuilderError::DuplicateName("ec".to_string())),
+               RuleBuilder::replicated("ec").add_to(&mut crushmap));
+    assert_eq!(Err(RuleBuilderError::UnknownItem("nowhere".to_string())),
+               RuleBuilder::replicated("r").take("nowhere").build(&crushmap).map(|_| ()));
+    assert_eq!(Err(RuleBuilderError::UnknownType("shelf".to_string())),
+               RuleBuilder::replicated("r")
+                   .take("default")
+                   .choose_firstn(0, "shelf")
+                   .build(&crushmap)
+                   .map(|_| ()));
+}
+
+#[test]
+fn it_generates_erasure_rules() {
+    let mut crushmap = get_test_hierarchy();
+    let profile = ErasureCodeProfile::new(2, 1);
+    let ruleno = profile.add_rule(&mut crushmap, "ec21").unwrap();
+    let rule = crushmap.rule(ruleno).unwrap().clone();
+    assert_eq!(RuleType::Erasure, rule.mask.rule_type);
+    assert_eq!((3, 3), (rule.mask.min_size, rule.mask.max_size));
+    assert_eq!(OpCode::ChooseLeafIndep, rule.steps[3].op());
+    assert!(check_rule(&crushmap, ruleno).unwrap().is_satisfiable(3));
+    let osds = do_rule(&crushmap, ruleno, 1, 3, &default_weights(&crushmap));
+    assert_eq!(3, osds.iter().filter(|o| **o != CRUSH_ITEM_NONE).count());
+
+    // 4+2 in groups of 3 is two hosts of 3+1 chunks
+    let mut lrc = ErasureCodeProfile::new(4, 2);
+    lrc.failure_domain = "osd".to_string();
+    lrc.locality = Some(3);
+    lrc.locality_domain = Some("host".to_string());
+    assert_eq!(8, lrc.size());
+    let rule = lrc.build_rule(&crushmap, "lrc").unwrap();
+    let steps: Vec<(OpCode, (i32, i32))> = rule.steps.iter().map(|s| (s.op(), s.args())).collect();
+    assert_eq!(vec![(OpCode::ChooseIndep, (2, 1)), (OpCode::ChooseLeafIndep, (4, 0))],
+               steps[3..5].to_vec());
+    lrc.locality = Some(4);
+    assert!(lrc.build_rule(&crushmap, "lrc").is_err());
+
+    // Device classes take the class's shadow bucket
+    crushmap.class_name = Some(vec![(0, "ssd".to_string())]);
+    crushmap.class_bucket = Some(vec![(BucketId(-1), vec![(0, BucketId(-5))])]);
+    let mut ssd = ErasureCodeProfile::new(2, 1);
+    ssd.device_class = Some("ssd".to_string());
+    let rule = ssd.build_rule(&crushmap, "ssd").unwrap();
+    assert_eq!(Step::Take { item: (Item::from(-5), Some("default~ssd".to_string())) },
+               rule.steps[2]);
+    ssd.device_class = Some("nvme".to_string());
+    assert_eq!(Err(RuleBuilderError::UnknownDeviceClass("nvme".to_string())),
+               ssd.build_rule(&crushmap, "nvme").map(|_| ()));
+}
+
+#[test]
+fn it_round_trips_new_opcodes() {
+    let mut crushmap = get_test_hierarchy();
+    let ruleno = RuleBuilder::msr_indep("msr")
+        .set_chooseleaf_stable(1)
+        .set_msr_descents(10)
+        .set_msr_collision_tries(20)
+        .take("default")
+        .choose_msr(0, "host")
+        .choose_msr(1, "osd")
+        .emit()
+        .add_to(&mut crushmap)
+        .unwrap();
+    let encoded = encode_crushmap(crushmap.clone()).unwrap();
+    let decoded = decode_crushmap(&encoded).unwrap();
+    let rule = decoded.rule(ruleno).unwrap().clone();
+    assert_eq!(RuleType::MsrIndep, rule.mask.rule_type);
+    let ops: Vec<OpCode> = rule.steps.iter().map(|s| s.op()).collect();
+    assert_eq!(vec![OpCode::SetChooseLeafStable,
+                    OpCode::SetMSRDescents,
+                    OpCode::SetMSRCollisionTries,
+                    OpCode::Take,
+                    OpCode::ChooseMSR,
+                    OpCode::ChooseMSR,
+                    OpCode::Emit],
+               ops);
+
+    assert!(check_mappable(&decoded, ruleno).is_ok());
+    assert_eq!(Err("No rule 7".to_string()), check_mappable(&decoded, RuleId(7)));
+}
+
+#[test]
+fn it_places_with_msr_rules() {
+    let mut crushmap = get_test_hierarchy();
+    crushmap.type_map = vec![(TypeId(0), "osd".to_string()), (TypeId(1), "host".to_string()),
+                             (TypeId(10), "root".to_string())];
+    let host_of = |osd: i32| osd / 2;
+    let indep = RuleBuilder::msr_indep("msr_indep")
+        .take("default")
+        .choose_msr(0, "host")
+        .choose_msr(1, "osd")
+        .emit()
+        .add_to(&mut crushmap)
+        .unwrap();
+    let firstn = RuleBuilder::msr_firstn("msr_firstn")
+        .take("default")
+        .choose_msr(0, "host")
+        .choose_msr(1, "osd")
+        .emit()
+        .add_to(&mut crushmap)
+        .unwrap();
+    let pairs = RuleBuilder::msr_indep("msr_pairs")
+        .set_msr_descents(10)
+        .take("default")
+        .choose_msr(2, "host")
+        .choose_msr(2, "osd")
+        .emit()
+        .add_to(&mut crushmap)
+        .unwrap();
+    let mut weights = default_weights(&crushmap);
+    for x in 0..100 {
+        let placed = do_rule(&crushmap, indep, x, 3, &weights);
+        let mut hosts: Vec<i32> = placed.iter().map(|o| host_of(*o)).collect();
+        hosts.sort();
+        assert_eq!(vec![0, 1, 2], hosts);
+
+        // There are only three hosts to place four replicas on
+        let placed = do_rule(&crushmap, indep, x, 4, &weights);
+        assert_eq!(1, placed.iter().filter(|o| **o == CRUSH_ITEM_NONE).count());
+        assert_eq!(3, do_rule(&crushmap, firstn, x, 4, &weights).len());
+
+        // Two hosts with both of their devices, in positions 0, 1 and 2, 3
+        let placed = do_rule(&crushmap, pairs, x, 4, &weights);
+        assert_eq!(host_of(placed[0]), host_of(placed[1]));
+        assert_eq!(host_of(placed[2]), host_of(placed[3]));
+        assert!(host_of(placed[0]) != host_of(placed[2]));
+        assert!(placed[0] != placed[1] && placed[2] != placed[3]);
+    }
+
+    // An out device is replaced by a device of a host the rule hasn't used
+    // yet, and the positions that didn't change keep their devices
+    let before: Vec<Vec<i32>> =
+        (0..100).map(|x| do_rule(&crushmap, indep, x, 2, &weights)).collect();
+    weights[0] = 0;
+    for (x, old) in before.iter().enumerate() {
+        let new = do_rule(&crushmap, indep, x as i32, 2, &weights);
+        assert!(!new.contains(&0) && !new.contains(&CRUSH_ITEM_NONE));
+        assert!(host_of(new[0]) != host_of(new[1]));
+        for (o, n) in old.iter().zip(new.iter()) {
+            assert!(*o == 0 || o == n);
+        }
+    }
+    assert!(simulate(&crushmap, indep, 3, 0, 15, &weights).is_ok());
+    let explanation = explain(&crushmap, indep, 1, 3, &weights, &[]).unwrap();
+    assert_eq!(3, explanation.result.len());
+}
+
+#[test]
+fn it_tells_rule_ids_from_rulesets() {
+    let mut crushmap = get_test_hierarchy();
+    crushmap.rule_name_map = vec![(RuleId(0), "replicated_rule".to_string())];
+    let ruleno = RuleBuilder::msr_firstn("msr")
+        .take("default")
+        .choose_msr(0, "host")
+        .choose_msr(1, "osd")
+        .emit()
+        .add_to(&mut crushmap)
+        .unwrap();
+    assert_eq!(Some(RuleId(0)), crushmap.rule_id("replicated_rule"));
+    assert_eq!(Some(ruleno), crushmap.rule_id("msr"));
+    assert_eq!(None, crushmap.rule_id("missing"));
+    assert!(!crushmap.has_legacy_rulesets());
+
+    // A pre-Luminous map with two rules in ruleset 0 decodes as it was
+    let mut second = crushmap.rules[0].clone().unwrap();
+    second.mask.min_size = 11;
+    second.mask.max_size = 20;
+    crushmap.rules[1] = Some(second);
+    assert!(crushmap.has_legacy_rulesets());
+    let decoded = decode_crushmap(&encode_crushmap(crushmap.clone()).unwrap()).unwrap();
+    let masks = |map: &CrushMap| -> Vec<CrushRuleMask> {
+        map.rules.iter().map(|r| r.clone().unwrap().mask).collect()
+    };
+    assert_eq!(masks(&crushmap), masks(&decoded));
+    assert!(decoded.has_legacy_rulesets());
+    assert_eq!(Some(RuleId(1)), find_rule(&decoded, Ruleset(0), RuleType::Replicated, 12));
+    assert_eq!(vec![RuleId(0), RuleId(1)], decoded.rules_in(Ruleset(0)));
+    assert_eq!(decoded.rules[1].as_ref(), decoded.rule(RuleId(1)));
+    assert!(decoded.rule(RuleId(-1)).is_none());
+}
+
+#[test]
+fn it_decodes_custom_bucket_types() {
+    let mut crushmap = get_test_hierarchy();
+    crushmap.type_map.push((TypeId(20), "site".to_string()));
+    crushmap.buckets.push(straw2_bucket(-5, 20, vec![(-1, 0x60000)]));
+    crushmap.buckets.push(straw2_bucket(-6, 11, vec![]));
+    crushmap.max_buckets = 6;
+    let decoded = decode_crushmap(&encode_crushmap(crushmap).unwrap()).unwrap();
+    let types: Vec<(i32, Option<String>)> = decoded.buckets
+        .iter()
+        .map(|b| b.bucket().unwrap().bucket_type.clone())
+        .map(|t| (t.0.into(), t.1))
+        .collect();
+    assert_eq!(vec![(10, Some("root".to_string())),
+                    (1, Some("host".to_string())),
+                    (1, Some("host".to_string())),
+                    (1, Some("host".to_string())),
+                    (20, Some("site".to_string())),
+                    (11, None)],
+               types);
+}
+
+#[test]
+fn it_explains_a_placement() {
+    let crushmap = get_test_hierarchy();
+    let mut weights = default_weights(&crushmap);
+    weights[2] = 0;
+    for x in 0..32 {
+        let explanation = explain(&crushmap, RuleId(0), x, 3, &weights, &[]).unwrap();
+        // Tracing doesn't change where things go
+        assert_eq!(do_rule(&crushmap, RuleId(0), x, 3, &weights), explanation.result);
+        match explanation.events[0] {
+            TraceEvent::Step { index: 0, ref op, arg1: -1, .. } => assert_eq!(OpCode::Take, *op),
+            ref other => panic!("unexpected first event {:?}", other),
+        }
+        assert_eq!(Some(&TraceEvent::Emit { items: explanation.result.clone() }),
+                   explanation.events.last());
+        let placed = explanation.events
+            .iter()
+            .filter(|e| match **e {
+                TraceEvent::Placed { .. } => true,
+                _ => false,
+            })
+            .count();
+        // One for each host and one for each device beneath them
+        assert_eq!(6, placed);
+        // osd.2 is out so whenever it gets picked it has to be rejected
+        let picked_out = explanation.events.iter().any(|e| match *e {
+            TraceEvent::Choose { item: 2, .. } => true,
+            _ => false,
+        });
+        let rejected_out = explanation.events.contains(&TraceEvent::Reject {
+            item: 2,
+            reason: RejectReason::Out,
+        });
+        assert_eq!(picked_out, rejected_out);
+    }
+}
+
+#[test]
+fn it_counts_choose_tries() {
+    let crushmap = get_test_hierarchy();
+    let weights = default_weights(&crushmap);
+    let (mappings, tries) =
+        simulate_counting_tries(&crushmap, RuleId(0), 3, 0, 255, &weights).unwrap();
+    assert_eq!(simulate(&crushmap, RuleId(0), 3, 0, 255, &weights).unwrap(), mappings);
+    // A host and a device beneath it for every replica
+    assert_eq!(256 * 3 * 2, tries.histogram.iter().sum::<u64>());
+    assert!(tries.histogram[0] > tries.histogram.iter().skip(1).sum::<u64>());
+    assert_eq!(0, tries.exhausted_total);
+
+    // With every device out nothing can be placed and the tries run out
+    let (_, tries) = simulate_counting_tries(&crushmap, RuleId(0), 3, 0, 15, &vec![0; 6]).unwrap();
+    assert_eq!(0, tries.histogram.iter().sum::<u64>());
+    assert!(tries.exhausted_total >= 16 * 3);
+
+    let report = UtilizationReport::new(&crushmap, RuleId(0), 3, &mappings, &weights)
+        .with_choose_tries(tries);
+    assert!(report.to_string().contains("gave up after choose_total_tries"));
+}
+
+// A pre-Luminous layout: hdds beneath default and ssds beneath a parallel
+// ssd root, with a rule for each
+fn get_legacy_hierarchy() -> CrushMap {
+    let rule = |take: i32| {
+        Some(Rule {
+            mask: CrushRuleMask {
+                ruleset: Ruleset(0),
+                rule_type: RuleType::Replicated,
+                min_size: 1,
+                max_size: 3,
+            },
+            steps: vec![Step::Take { item: (Item::from(take), None) },
+                        Step::Choose {
+                            mode: ChooseMode::LeafFirstN,
+                            num: 0,
+                            bucket_type: (TypeId(1), None),
+                        },
+                        Step::Emit],
+        })
+    };
+    let mut crushmap = CrushMap {
+        max_buckets: 8,
+        max_rules: 2,
+        max_devices: 9,
+        buckets: vec![straw2_bucket(-1, 10, vec![(-2, 0x20000), (-3, 0x20000), (-4, 0x20000)]),
+                      straw2_bucket(-2, 1, vec![(0, 0x10000), (1, 0x10000)]),
+                      straw2_bucket(-3, 1, vec![(2, 0x10000), (3, 0x10000)]),
+                      straw2_bucket(-4, 1, vec![(4, 0x10000), (5, 0x10000)]),
+                      straw2_bucket(-5, 10, vec![(-6, 0x10000), (-7, 0x10000), (-8, 0x10000)]),
+                      straw2_bucket(-6, 1, vec![(6, 0x10000)]),
+                      straw2_bucket(-7, 1, vec![(7, 0x10000)]),
+                      straw2_bucket(-8, 1, vec![(8, 0x10000)])],
+        rules: vec![rule(-1), rule(-5)],
+        type_map: vec![(TypeId(0), "osd".to_string()),
+                       (TypeId(1), "host".to_string()),
+                       (TypeId(10), "root".to_string())],
+        name_map: vec![(Item::from(-1), "default".to_string()),
+                       (Item::from(-2), "node1".to_string()),
+                       (Item::from(-3), "node2".to_string()),
+                       (Item::from(-4), "node3".to_string()),
+                       (Item::from(-5), "ssd".to_string()),
+                       (Item::from(-6), "node1-ssd".to_string()),
+                       (Item::from(-7), "node2-ssd".to_string()),
+                       (Item::from(-8), "node4-ssd".to_string())],
+        rule_name_map: vec![(RuleId(0), "hdd_rule".to_string()),
+                            (RuleId(1), "ssd_rule".to_string())],
+        ..Default::default()
+    };
+    for osd in 0..9 {
+        crushmap.name_map.push((Item::from(osd), format!("osd.{}", osd)));
+    }
+    set_tunables_jewel(&mut crushmap);
+    crushmap
+}
+
+#[test]
+fn it_reclassifies_legacy_hierarchies() {
+    let legacy = get_legacy_hierarchy();
+    let mut crushmap = legacy.clone();
+    Reclassify::new()
+        .root("default", "hdd")
+        .bucket("%-ssd", "ssd", "default")
+        .bucket("ssd", "ssd", "default")
+        .apply(&mut crushmap)
+        .unwrap();
+
+    let id = |name: &str| crushmap.name_map.iter().find(|n| n.1 == name).map(|n| n.0.id());
+    // The per-class buckets are gone and node4 was created for node4-ssd
+    assert_eq!(None, id("ssd"));
+    assert_eq!(None, id("node1-ssd"));
+    assert!(id("node4").is_some());
+    // Their ids, and the ones of the hdd tree, now belong to the shadows
+    assert_eq!(Some(-1), id("default~hdd"));
+    assert_eq!(Some(-2), id("node1~hdd"));
+    assert_eq!(Some(-5), id("default~ssd"));
+    assert_eq!(Some(-6), id("node1~ssd"));
+    assert_eq!(Some(-8), id("node4~ssd"));
+    let class_map = crushmap.class_map.clone().unwrap();
+    let class_names = crushmap.class_name.clone().unwrap();
+    let class = |osd: i32| {
+        class_map.iter()
+            .find(|c| c.0 == DeviceId(osd))
+            .and_then(|c| class_names.iter().find(|n| n.0 == c.1))
+            .map(|n| n.1.clone())
+    };
+    assert_eq!(Some("hdd".to_string()), class(0));
+    assert_eq!(Some("ssd".to_string()), class(6));
+    // Both classes' devices are beneath default now
+    let default = crushmap.buckets[(-1 - id("default").unwrap()) as usize].bucket().unwrap();
+    assert_eq!(9 * 0x10000, default.weight);
+
+    let rules: Vec<Option<String>> = crushmap.rules
+        .iter()
+        .map(|r| match r.as_ref().unwrap().steps[0] {
+            Step::Take { ref item } => item.1.clone(),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(vec![Some("default~hdd".to_string()), Some("default~ssd".to_string())],
+               rules);
+    let comparisons = compare_maps(&legacy, &crushmap, 0, 1023);
+    assert_eq!(6, comparisons.len());
+    assert!(comparisons.iter().all(|c| c.mappings == 1024 && c.mismatched == 0));
+
+    // The reclassified map still encodes and decodes
+    let encoded = encode_crushmap(crushmap.clone()).unwrap();
+    assert_eq!(crushmap.class_bucket, decode_crushmap(&encoded).unwrap().class_bucket);
+}
+
+#[test]
+fn it_renders_dot() {
+    let mut crushmap = get_test_hierarchy();
+    crushmap.type_map = vec![(TypeId(0), "osd".to_string()), (TypeId(1), "host".to_string()),
+                             (TypeId(10), "root".to_string())];
+    let dot = to_dot(&crushmap, &Default::default());
+    assert!(dot.starts_with("digraph crushmap {"));
+    assert!(dot.contains(r#""-1" [label="default\nroot straw2\n6.000"];"#));
+    assert!(dot.contains(r#""0" [label="osd.0\n1.000", shape=ellipse];"#));
+    assert!(dot.contains(r#""-1" -> "-2";"#));
+    assert!(dot.contains(r#""-2" -> "0";"#));
+    assert!(!dot.contains("cluster"));
+
+    let options = DotOptions {
+        cluster_by_type: true,
+        rule: Some(RuleId(0)),
+        shadow_buckets: false,
+    };
+    let dot = to_dot(&crushmap, &options);
+    assert!(dot.contains(r#"subgraph "cluster_1" {"#));
+    assert!(dot.contains(r#"label="host";"#));
+    assert!(dot.contains("color=red, penwidth=3"));
+    // Every host is a failure domain of the rule
+    assert_eq!(3, dot.matches("fillcolor=lightblue").count());
+}
+
+#[test]
+fn it_edits_the_hierarchy() {
+    let mut crushmap = get_test_hierarchy();
+    crushmap.type_map = vec![(TypeId(0), "osd".to_string()), (TypeId(1), "host".to_string()),
+                             (TypeId(10), "root".to_string())];
+    let root_weight = |map: &CrushMap| map.buckets[0].bucket().unwrap().weight;
+
+    assert_eq!(Ok(BucketId(-5)), create_bucket(&mut crushmap, "host4", "host"));
+    assert!(create_bucket(&mut crushmap, "host4", "host").is_err());
+    add_device(&mut crushmap, DeviceId(6), "osd.6", 0x10000, "host4").unwrap();
+    move_bucket(&mut crushmap, "host4", "default").unwrap();
+    assert_eq!(0x70000, root_weight(&crushmap));
+    assert!(validate(&crushmap).is_empty());
+
+    reweight_device(&mut crushmap, "osd.6", 0x20000).unwrap();
+    assert_eq!(0x80000, root_weight(&crushmap));
+    // A bucket can't end up beneath itself
+    assert!(move_bucket(&mut crushmap, "default", "host4").is_err());
+
+    // Buckets have to be emptied first and the rule still takes the root
+    assert!(remove_item(&mut crushmap, "host4").is_err());
+    remove_item(&mut crushmap, "osd.6").unwrap();
+    remove_item(&mut crushmap, "host4").unwrap();
+    assert!(remove_item(&mut crushmap, "default").is_err());
+    assert_eq!(0x60000, root_weight(&crushmap));
+    assert!(validate(&crushmap).is_empty());
+    assert_eq!(None, resolve_item(&crushmap, "osd.6"));
+}
+
+#[test]
+fn it_validates_and_diffs_maps() {
+    let before = get_test_hierarchy();
+    assert!(validate(&before).is_empty());
+    assert!(diff_maps(&before, &before).is_empty());
+
+    let mut after = before.clone();
+    reweight_device(&mut after, "osd.0", 0x20000).unwrap();
+    let lines = diff_maps(&before, &after);
+    assert!(lines.contains(&"~ bucket host1: osd.0 weight 1.00000 -> 2.00000".to_string()));
+    assert!(lines.contains(&"~ bucket default: weight 6.00000 -> 7.00000".to_string()));
+
+    let mut broken = before.clone();
+    if let BucketTypes::Straw2(ref mut bucket) = broken.buckets[1] {
+        bucket.item_weights[0] = 0x20000;
+    }
+    broken.rules[0].as_mut().unwrap().steps.pop();
+    let problems = validate(&broken);
+    assert!(problems.iter().any(|p| p.contains("items add up to")));
+    assert!(problems.iter().any(|p| p == "rule 0 doesn't end with an emit step"));
+}
+
+#[test]
+fn it_lists_the_tree() {
+    let mut crushmap = get_test_hierarchy();
+    crushmap.type_map = vec![(TypeId(0), "osd".to_string()), (TypeId(1), "host".to_string()),
+                             (TypeId(10), "root".to_string())];
+    let tree = Tree::new(&crushmap);
+    assert_eq!(10, tree.entries.len());
+    assert_eq!((-1, 0, 6.0), (tree.entries[0].id, tree.entries[0].depth, tree.entries[0].weight));
+    assert_eq!((1, 2), (tree.entries[3].id, tree.entries[3].depth));
+    let text = tree.to_string();
+    assert!(text.contains("-2          2.00000      host host1"));
+    assert!(text.contains("    0          1.00000          osd.0"));
+}
+
+#[test]
+fn it_walks_buckets_that_hold_each_other() {
+    let mut crushmap = get_test_hierarchy();
+    crushmap.buckets[1] = straw2_bucket(-2, 1, vec![(0, 0x10000), (1, 0x10000), (-3, 0x20000)]);
+    crushmap.buckets[2] = straw2_bucket(-3, 1, vec![(2, 0x10000), (3, 0x10000), (-2, 0x20000)]);
+    // A type nothing has, so every walk goes round the cycle
+    if let Step::Choose { ref mut bucket_type, .. } = crushmap.rules[0].as_mut().unwrap().steps[1] {
+        *bucket_type = (TypeId(5), None);
+    }
+
+    assert_eq!(18, Tree::new(&crushmap).entries.len());
+    let options = DotOptions { rule: Some(RuleId(0)), ..Default::default() };
+    assert!(!to_dot(&crushmap, &options).contains("fillcolor"));
+    assert!(check_rules(&crushmap)[0].never_satisfiable());
+    assert!(move_bucket(&mut crushmap, "default", "host2").is_err());
+}
+
+#[test]
+fn it_converts_typed_ids() {
+    assert_eq!(Item::Bucket(BucketId(-3)), Item::from(-3));
+    assert_eq!(Item::Device(DeviceId(0)), Item::from(0));
+    assert_eq!(2, BucketId(-3).index());
+    assert_eq!(BucketId(-3), BucketId::from_index(2));
+    assert_eq!(Some(BucketId(-3)), Item::from(-3).bucket());
+    assert_eq!(None, Item::from(4).bucket());
+    let items = vec![Item::from(-1), Item::from(2)];
+    assert!(items[0] < items[1]);
+    // They're written as the bare numbers
+    assert_eq!(to_yaml(&vec![-1, 2]).unwrap(), to_yaml(&items).unwrap());
+}
+
+#[test]
+fn it_converts_steps_to_and_from_op_codes() {
+    let step = Step::from_op(OpCode::ChooseFirstN, 3, 1);
+    assert_eq!(Step::Choose {
+                   mode: ChooseMode::FirstN,
+                   num: 3,
+                   bucket_type: (TypeId(1), None),
+               },
+               step);
+    assert_eq!((OpCode::ChooseFirstN, (3, 1)), (step.op(), step.args()));
+    assert_eq!(Some(Item::from(-2)), Step::from_op(OpCode::Take, -2, 0).item());
+    let vary_r = Step::from_op(OpCode::SetChooseLeafVaryR, 1, 0);
+    assert_eq!((OpCode::SetChooseLeafVaryR, (1, 0)), (vary_r.op(), vary_r.args()));
+}
+
+#[test]
+fn it_round_trips_junk_in_unused_step_args() {
+    let clean = get_test_hierarchy();
+    let mut crushmap = clean.clone();
+    {
+        let steps = &mut crushmap.rules[0].as_mut().unwrap().steps;
+        steps[0] = Step::from_op(OpCode::Take, -1, 77);
+        steps.insert(1, Step::from_op(OpCode::Noop, -5, 9));
+        steps[3] = Step::from_op(OpCode::Emit, 1, 2);
+    }
+    let steps = |map: &CrushMap| -> Vec<(OpCode, (i32, i32))> {
+        map.rules[0].as_ref().unwrap().steps.iter().map(|s| (s.op(), s.args())).collect()
+    };
+    assert_eq!(Step::Unknown {
+                   op: OpCode::Take,
+                   arg1: -1,
+                   arg2: 77,
+               },
+               crushmap.rules[0].as_ref().unwrap().steps[0]);
+    assert_eq!(vec![(OpCode::Take, (-1, 77)),
+                    (OpCode::Noop, (-5, 9)),
+                    (OpCode::ChooseLeafFirstN, (0, 1)),
+                    (OpCode::Emit, (1, 2))],
+               steps(&crushmap));
+
+    // The arguments survive being encoded and decoded, as binary and as YAML
+    let compiled = encode_crushmap(crushmap.clone()).unwrap();
+    let decoded = decode_crushmap(&compiled).unwrap();
+    assert_eq!(steps(&crushmap), steps(&decoded));
+    let yaml = encode_yaml(&decoded).unwrap();
+    assert_eq!(compiled, encode_crushmap(decode_yaml(&yaml).unwrap()).unwrap());
+
+    // but the rule acts the way it did without them
+    assert_eq!(Some(Item::from(-1)), decoded.rules[0].as_ref().unwrap().steps[0].item());
+    assert!(validate(&decoded).is_empty());
+    let weights = default_weights(&clean);
+    for x in 0..100 {
+        assert_eq!(do_rule(&clean, RuleId(0), x, 3, &weights),
+                   do_rule(&decoded, RuleId(0), x, 3, &weights));
+    }
+}
+
+#[test]
+fn it_counts_the_bytes_it_did_not_decode() {
+    // A map with every section known here, so nothing can follow it
+    let mut crushmap = get_test_hierarchy();
+    crushmap.choose_args = Some(vec![]);
+    crushmap.msr_descents = Some(100);
+    crushmap.msr_collision_tries = Some(100);
+    let mut compiled = encode_crushmap(crushmap).unwrap();
+    let (crushmap, trailing) = decode_crushmap_trailing(&compiled).unwrap();
+    assert_eq!(0, trailing);
+    // Sections from a newer release are left at the end
+    compiled.extend_from_slice(&[0xff; 7]);
+    assert_eq!((crushmap.clone(), 7), decode_crushmap_trailing(&compiled).unwrap());
+    assert_eq!(crushmap, decode_crushmap(&compiled).unwrap());
+}
+
+#[test]
+fn it_encodes_into_a_writer() {
+    let crushmap = get_test_hierarchy();
+    let mut streamed = vec![];
+    crushmap.encode_to(&mut streamed).unwrap();
+    assert_eq!(encode_crushmap(crushmap.clone()).unwrap(), streamed);
+
+    // Running out of room is passed on
+    let mut buffer = [0u8; 16];
+    assert!(crushmap.encode_to(&mut Cursor::new(&mut buffer[..])).is_err());
+}
+
+#[test]
+fn it_decodes_from_a_reader() {
+    let mut crushmap = get_test_hierarchy();
+    crushmap.class_map = Some(vec![]);
+    crushmap.class_name = Some(vec![]);
+    crushmap.class_bucket = Some(vec![]);
+    crushmap.choose_args = Some(vec![]);
+    crushmap.msr_descents = Some(100);
+    crushmap.msr_collision_tries = Some(100);
+    let encoded = encode_crushmap(crushmap).unwrap();
+    let decoded = decode_crushmap(&encoded).unwrap();
+    assert_eq!(decoded, CrushMap::decode_from(&mut Cursor::new(&encoded)).unwrap());
+    assert!(CrushMap::decode_file("/nonexistent/crushmap").is_err());
+
+    // Sections from a newer release are skipped, but a reader only does
+    // that when asked to
+    let mut newer = encoded.clone();
+    newer.extend(&[1, 0, 0, 0, 2, 0, 0, 0]);
+    assert_eq!(decoded, decode_crushmap(&newer).unwrap());
+    assert_eq!(Err("8 bytes at the end weren't decoded".to_string()),
+               CrushMap::decode_from(&mut Cursor::new(&newer)));
+    assert_eq!(Ok((decoded, 8)), CrushMap::decode_from_trailing(&mut Cursor::new(&newer)));
+}
+
+#[test]
+fn it_indexes_names_and_parents() {
+    let mut crushmap = get_test_hierarchy();
+    crushmap.type_map = vec![(TypeId(0), "osd".to_string()), (TypeId(1), "host".to_string()),
+                             (TypeId(10), "root".to_string())];
+    let index = CrushIndex::new(&crushmap);
+    assert_eq!(BucketId(-3), index.bucket_by_name("host2").unwrap().id());
+    assert_eq!(Some(BucketId(-1)), index.bucket_by_id(BucketId(-1)).map(|b| b.id()));
+    assert!(index.bucket_by_id(BucketId(-9)).is_none());
+    assert_eq!(Some("osd.4"), index.device_name(DeviceId(4)));
+    assert_eq!(None, index.device_name(DeviceId(-1)));
+    assert_eq!(Some(TypeId(1)), index.type_id("host"));
+    assert_eq!(Some(BucketId(-4)), index.parent_of(Item::from(5)));
+    assert_eq!(None, index.parent_of(Item::from(-1)));
+    assert_eq!(vec![Item::from(2), Item::from(3)], index.children_of(BucketId(-3)));
+    assert!(index.children_of(BucketId(3)).is_empty());
+    assert_eq!(vec![BucketId(-4), BucketId(-1)], index.ancestors(Item::from(5)));
+    let subtree: Vec<i32> = index.subtree(Item::from(-1)).into_iter().map(i32::from).collect();
+    assert_eq!(vec![-1, -2, 0, 1, -3, 2, 3, -4, 4, 5], subtree);
+
+    // Every device beneath some items, each one once
+    let devices: Vec<i32> = index.devices_under(&[Item::from(-3), Item::from(-1)], 0)
+        .into_iter()
+        .map(|d| (d.0).0)
+        .collect();
+    assert_eq!(vec![2, 3, 0, 1, 4, 5], devices);
+    assert_eq!(vec![(Item::from(2), 0x10000), (Item::from(3), 0x10000)],
+               index.items_of(BucketId(-3)));
+}
+
+#[test]
+fn it_round_trips_yaml_and_toml() {
+    let crushmap = get_test_hierarchy();
+    let compiled = encode_crushmap(crushmap.clone()).unwrap();
+
+    let yaml = encode_yaml(&crushmap).unwrap();
+    assert!(yaml.contains("name: osd.0"));
+    assert!(yaml.contains("item: default"));
+    assert!(yaml.contains("op: chooseleaf_firstn"));
+    assert_eq!(compiled, encode_crushmap(decode_yaml(&yaml).unwrap()).unwrap());
+
+    let toml = encode_toml(&crushmap).unwrap();
+    // Bucket items weigh what the bucket does unless they say otherwise
+    assert!(toml.contains("[[buckets.items]]\nname = \"host1\"\n\n"));
+    assert!(toml.contains("[[buckets.items]]\nname = \"osd.5\"\nweight = 1.0\n"));
+    assert_eq!(compiled, encode_crushmap(decode_toml(&toml).unwrap()).unwrap());
+
+    let edited = toml.replace("name = \"osd.5\"\nweight = 1.0", "name = \"osd.5\"\nweight = 1.5");
+    let reweighted = decode_toml(&edited).unwrap();
+    assert_eq!(0x68000, reweighted.buckets[0].bucket().unwrap().weight);
+    assert!(decode_yaml(&yaml.replace("item: default", "item: nowhere")).is_err());
+
+    // Rule ids stay below max_rules, which stays within what Ceph allows
+    let mut doc = MapDocument::new(&crushmap);
+    doc.rules[0].id = 1 << 30;
+    assert_eq!(Err(format!("Rule {} is past max_rules 1", 1 << 30)), doc.to_crushmap());
+    doc.max_rules = u32::max_value();
+    assert!(doc.to_crushmap().is_err());
+    let mut doc = MapDocument::new(&crushmap);
+    let twice = doc.rules[0].clone();
+    doc.rules.push(twice);
+    doc.max_rules = 2;
+    assert_eq!(Err("Rule 0 is listed twice".to_string()), doc.to_crushmap());
+
+    let ceph = CephCrushMap {
+        failure_domain: "host".to_string(),
+        buckets: vec![CephBucket {
+                          name: "default".to_string(),
+                          buckets: vec![CephBucketType::Host(CephHost {
+                                            hostname: Some("host1".to_string()),
+                                            disks: vec![],
+                                        })],
+                      }],
+        pools: vec![],
+    };
+    assert_eq!(ceph, from_yaml(&to_yaml(&ceph).unwrap()).unwrap());
+    assert_eq!(ceph, from_toml(&to_toml(&ceph).unwrap()).unwrap());
+}
+
+#[test]
+fn it_round_trips_the_corpus_byte_for_byte() {
+    let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+    let mut maps = 0;
+    for entry in fs::read_dir(&corpus).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().map(|e| e != "bin").unwrap_or(true) {
+            continue;
+        }
+        let mut original = vec![];
+        File::open(&path).unwrap().read_to_end(&mut original).unwrap();
+        let crushmap = decode_crushmap(&original).unwrap();
+        let encoded = encode_crushmap(crushmap).unwrap();
+        assert!(encoded == original, "{} changed on the way back", path.display());
+        maps += 1;
+    }
+    assert!(maps >= 2);
+}
+
+// Maps written by each release since Luminous, see tests/corpus/README.md.
+// They can only come from clusters of those releases, so this stays ignored
+// until they are checked in; run it with --ignored to see what's missing.
+#[test]
+#[ignore]
+fn it_has_a_map_from_every_release() {
+    let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+    let wanted = ["luminous.bin",
+                  "nautilus.bin",
+                  "quincy.bin",
+                  "reef.bin",
+                  "squid.bin",
+                  "weight-set.bin",
+                  "msr.bin"];
+    let missing: Vec<&str> =
+        wanted.iter().cloned().filter(|name| !corpus.join(name).exists()).collect();
+    assert!(missing.is_empty(), "missing from the corpus: {}", missing.join(", "));
+
+    let decode = |name: &str| {
+        let mut map = vec![];
+        File::open(corpus.join(name)).unwrap().read_to_end(&mut map).unwrap();
+        decode_crushmap(&map).unwrap()
+    };
+    assert!(decode("weight-set.bin").choose_args.map(|a| !a.is_empty()).unwrap_or(false));
+    let msr = decode("msr.bin");
+    assert!(msr.msr_descents.is_some() && msr.msr_collision_tries.is_some());
+    assert!(msr.rules.iter().filter_map(|r| r.as_ref()).any(|r| r.is_msr()));
+}
+
+#[test]
+fn it_round_trips_the_layouts_of_older_releases() {
+    // The tunables at the end of a Jewel map, in the order releases added
+    // them
+    let sizes = [4, 4, 4, 4, 1, 1, 4, 1];
+    // How many of them each release wrote
+    let releases = [("argonaut", 0), ("bobtail", 4), ("firefly", 5), ("hammer", 7), ("jewel", 8)];
+    for name in ["jewel.bin", "jewel-straw2.bin"].iter() {
+        let mut map = vec![];
+        File::open(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus").join(name))
+            .unwrap()
+            .read_to_end(&mut map)
+            .unwrap();
+        let tunables = map.len() - sizes.iter().sum::<usize>();
+        for &(release, known) in releases.iter() {
+            let cut = &map[..tunables + sizes[..known].iter().sum::<usize>()];
+            let crushmap = decode_crushmap(cut).unwrap();
+            assert_eq!(known > 0, crushmap.choose_local_tries.is_some(), "{}", release);
+            assert_eq!(known > 4, crushmap.chooseleaf_vary_r.is_some(), "{}", release);
+            assert_eq!(known > 7, crushmap.chooseleaf_stable.is_some(), "{}", release);
+            assert!(encode_crushmap(crushmap).unwrap() == cut,
+                    "{} cut off as {} changed on the way back",
+                    name,
+                    release);
+        }
+    }
+}
+
+#[test]
+fn it_refuses_counts_the_input_cannot_hold() {
+    let mut map = vec![];
+    File::open(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus/jewel.bin"))
+        .unwrap()
+        .read_to_end(&mut map)
+        .unwrap();
+
+    // 2^31 buckets in a few hundred bytes
+    let mut buckets = map.clone();
+    buckets[4..8].copy_from_slice(&[0xff, 0xff, 0xff, 0x7f]);
+    assert!(decode_crushmap(&buckets).is_err());
+
+    // Not a crushmap at all
+    let mut magic = map.clone();
+    magic[2] = 0x02;
+    assert!(decode_crushmap(&magic).is_err());
+
+    // Cut off in the middle of the last tunable
+    assert!(decode_crushmap(&map[..map.len() - 2]).is_err());
+    assert!(decode_crushmap(&map[..map.len() - 1]).is_ok());
+}
+
+#[test]
+fn it_decodes_and_places_through_the_c_interface() {
+    let crushmap = get_test_hierarchy();
+    let compiled = encode_crushmap(crushmap.clone()).unwrap();
+    unsafe {
+        let map = ffi::crush_map_decode(compiled.as_ptr(), compiled.len());
+        assert!(!map.is_null());
+
+        // A NULL buffer asks for the size
+        let len = ffi::crush_map_encode(map, ptr::null_mut(), 0);
+        let mut encoded = vec![0; len];
+        assert_eq!(len, ffi::crush_map_encode(map, encoded.as_mut_ptr(), len));
+        assert_eq!(compiled, encoded);
+        // and one that is too small is left alone
+        let mut short = vec![0; len - 1];
+        assert_eq!(0, ffi::crush_map_encode(map, short.as_mut_ptr(), len - 1));
+        assert!(short.iter().all(|b| *b == 0));
+        let err = CStr::from_ptr(ffi::crush_last_error());
+        assert_eq!(format!("The map needs {} bytes, the buffer holds {}", len, len - 1),
+                   err.to_str().unwrap());
+
+        let mut id = 0;
+        let host = CString::new("host3").unwrap();
+        assert_eq!(0, ffi::crush_map_item_id(map, host.as_ptr(), &mut id));
+        assert_eq!(-4, id);
+        let rule_name = CString::new("replicated_ruleset").unwrap();
+        let rule = ffi::crush_map_rule_id(map, rule_name.as_ptr());
+        assert_eq!(0, rule);
+
+        let mut result = [0; 3];
+        let placed = ffi::crush_map_do_rule(map, rule, 1, result.as_mut_ptr(), 3, ptr::null(), 0);
+        let expected = do_rule(&crushmap, RuleId(0), 1, 3, &default_weights(&crushmap));
+        assert_eq!(expected.len() as i32, placed);
+        assert_eq!(&expected[..], &result[..placed as usize]);
+
+        // Failures leave a message behind
+        assert!(ffi::crush_map_decode(compiled.as_ptr(), 10).is_null());
+        assert!(!ffi::crush_last_error().is_null());
+        let missing = CString::new("host9").unwrap();
+        assert_eq!(-1, ffi::crush_map_item_id(map, missing.as_ptr(), &mut id));
+        assert_eq!(-1, ffi::crush_map_do_rule(map, 7, 1, result.as_mut_ptr(), 3, ptr::null(), 0));
+        let err = CStr::from_ptr(ffi::crush_last_error());
+        assert_eq!("No rule 7", err.to_str().unwrap());
+
+        // and so do NULL pointers
+        assert!(ffi::crush_map_decode(ptr::null(), 0).is_null());
+        assert_eq!(0, ffi::crush_map_encode(ptr::null(), ptr::null_mut(), 0));
+        assert_eq!(-1, ffi::crush_map_item_id(map, host.as_ptr(), ptr::null_mut()));
+        assert_eq!(-1, ffi::crush_map_item_id(map, ptr::null(), &mut id));
+        assert_eq!(-1, ffi::crush_map_rule_id(ptr::null(), rule_name.as_ptr()));
+        assert_eq!(-1, ffi::crush_map_do_rule(map, rule, 1, ptr::null_mut(), 3, ptr::null(), 0));
+        let err = CStr::from_ptr(ffi::crush_last_error());
+        assert_eq!("The result is NULL", err.to_str().unwrap());
+        ffi::crush_map_free(ptr::null_mut());
+
+        ffi::crush_map_free(map);
+    }
+}